@@ -10,6 +10,7 @@ const std = @import("std");
 const fmt = std.fmt;
 
 pub const press_fn = *const fn (freq: u32, note: u8, param0: i8, param1: i8) callconv(.C) void;
+pub const press_with_params_fn = *const fn (freq: u32, note: u8, param0: i8, param1: i8, param2: i8, param3: i8) callconv(.C) void;
 pub const release_fn = *const fn (freq: u32, note: u8, t: u32) callconv(.C) void;
 pub const frame_fn = *const fn (freq: u32, note: u8, t: u32) callconv(.C) void;
 pub const set_param_fn = *const fn (value: i8) callconv(.C) void;
@@ -17,10 +18,18 @@ pub const set_param_fn = *const fn (value: i8) callconv(.C) void;
 // These few functions defines the WebAssembly interface between the guest (instruments) and the host (Chiptrack).
 extern fn print([*:0]const u8) void;
 extern fn gba_set_sound_reg(addr: u32, value: u32) void;
+extern fn gba_set_sound_reg_at(cycle_offset: u32, addr: u32, value: u32) void;
 extern fn gba_set_wave_table(table: [*]const u8, table_len: u32) void;
+extern fn gba_set_sample(sample: u32, data: [*]const i8, data_len: u32) void;
+extern fn gba_play_sample(fifo: u32, sample: u32, rate: u32) void;
 /// returns the host's instrument handle.
 extern fn set_instrument_at_column(id: [*:0]const u8, col: u32, frames_after_release: u32, press: ?press_fn, release: ?release_fn, frame: ?frame_fn) u8;
 extern fn define_param(instrument_handle: u8, param_num: u8, name: [*:0]const u8, default: i8, min: i8, max: i8, set_param: ?set_param_fn) void;
+/// Replaces the press function of the instrument with one that receives all 4 parameters instead of only the first two.
+extern fn set_instrument_press_with_params(instrument_handle: u8, press: ?press_with_params_fn) void;
+/// Makes the frame function of the instrument run ticks_per_frame times per frame (1 to 4), with the
+/// sound register writes of each tick applied at an even fraction of the frame.
+extern fn set_instrument_ticks_per_frame(instrument_handle: u8, ticks_per_frame: u8) void;
 
 /// Instructs Chiptrack to log a message to the console during an instrument's callback function.
 /// This is useful for debugging the instrument's behavior and can be used like this:
@@ -63,25 +72,36 @@ pub fn setInstrument(id: [*:0]const u8, col: u32, instrument: Instrument) void {
 /// Registers an instrument struct that has the following mandatory public static declaration (not field):
 /// - id: A null-terminated string identifying the instrument in the song's pattern definitions
 /// And the following optional public static declarations (not fields):
-/// - press: a function called at the start of each sequencer press step
+/// - press: a function called at the start of each sequencer press step,
+///   either a press_fn receiving param_0 and param_1, or a press_with_params_fn receiving all 4 parameters
 /// - release: a function called at the end of each sequencer release step
 /// - frame: a function called on every frame between press and release
 /// - frames_after_release: a u32 that can extend the number of frames for which the frame function is called after the release step
+/// - ticks_per_frame: a u8 from 1 to 4 making the frame function run that many times per frame (e.g. 4 for 239 Hz envelopes),
+///   in which case the t argument of the frame and release functions counts ticks instead of frames
 /// - param_0: a Parameter struct defining the first parameter
 /// - param_1: a Parameter struct defining the second parameter
+/// - param_2, param_3: Parameter structs defining the third and fourth parameters
 /// If any optional declaration is mis-spelled or non-public, it will be silently ignored.
 pub fn registerInstrument(comptime instrument: anytype, col: u32) void {
-    const press: ?press_fn = if (@hasDecl(instrument, "press")) instrument.press else null;
+    const press_with_params = @hasDecl(instrument, "press") and @typeInfo(@TypeOf(instrument.press)).Fn.params.len == 6;
+    const press: ?press_fn = if (@hasDecl(instrument, "press") and !press_with_params) instrument.press else null;
     const release: ?release_fn = if (@hasDecl(instrument, "release")) instrument.release else null;
     const frame: ?frame_fn = if (@hasDecl(instrument, "frame")) instrument.frame else null;
     const far: u32 = if (@hasDecl(instrument, "frames_after_release")) instrument.frames_after_release else 0;
     const handle = set_instrument_at_column(instrument.id, col, far, press, release, frame);
     if (handle != 255) {
-        if (@hasDecl(instrument, "param_0")) {
-            define_param(handle, 0, instrument.param_0.name, instrument.param_0.default, instrument.param_0.min, instrument.param_0.max, instrument.param_0.set_param);
+        if (press_with_params) {
+            set_instrument_press_with_params(handle, instrument.press);
         }
-        if (@hasDecl(instrument, "param_1")) {
-            define_param(handle, 1, instrument.param_1.name, instrument.param_1.default, instrument.param_1.min, instrument.param_1.max, instrument.param_1.set_param);
+        if (@hasDecl(instrument, "ticks_per_frame")) {
+            set_instrument_ticks_per_frame(handle, instrument.ticks_per_frame);
+        }
+        inline for (.{ "param_0", "param_1", "param_2", "param_3" }, 0..) |name, i| {
+            if (@hasDecl(instrument, name)) {
+                const param: Parameter = @field(instrument, name);
+                define_param(handle, i, param.name, param.default, param.min, param.max, param.set_param);
+            }
         }
     }
 }
@@ -107,6 +127,9 @@ pub const gba = struct {
     pub const nr43_44 = 0x400007C;
     pub const nr50_51 = 0x4000080;
 
+    /// The number of CPU cycles in a 59.73 Hz frame, the exclusive upper bound of gba_set_sound_reg_at offsets.
+    pub const frame_cycles: u32 = 280896;
+
     pub fn encodeSquareFreq(freq: u32) u11 {
         return @truncate(2048 - ((131072 * 256) / freq));
     }
@@ -201,6 +224,10 @@ pub const gba = struct {
         pub fn writeTo(self: Sweep, channel: Channel) void {
             gba_set_sound_reg(address(channel), @as(u16, @bitCast(self)));
         }
+        /// Like writeTo, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeToAt(self: Sweep, channel: Channel, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, address(channel), @as(u16, @bitCast(self)));
+        }
     };
 
     /// (NRx1, NRx2) - Duty/Len/Envelope (R/W)
@@ -256,6 +283,10 @@ pub const gba = struct {
         pub fn writeTo(self: EnvDutyLen, channel: Channel) void {
             gba_set_sound_reg(address(channel), @as(u16, @bitCast(self)));
         }
+        /// Like writeTo, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeToAt(self: EnvDutyLen, channel: Channel, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, address(channel), @as(u16, @bitCast(self)));
+        }
     };
 
     /// (NRx3, NRx4) - Frequency/Control (R/W)
@@ -309,6 +340,10 @@ pub const gba = struct {
         pub fn writeTo(self: CtrlFreq, channel: Channel) void {
             gba_set_sound_reg(address(channel), @as(u16, @bitCast(self)));
         }
+        /// Like writeTo, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeToAt(self: CtrlFreq, channel: Channel, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, address(channel), @as(u16, @bitCast(self)));
+        }
     };
 
     pub const WavTable = struct { v: [16]u8 };
@@ -350,6 +385,10 @@ pub const gba = struct {
         pub fn writeTo(self: WaveRam, channel: Channel) void {
             gba_set_sound_reg(address(channel), @as(u16, @bitCast(self)));
         }
+        /// Like writeTo, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeToAt(self: WaveRam, channel: Channel, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, address(channel), @as(u16, @bitCast(self)));
+        }
 
         var cur_table: ?*const WavTable = null;
         pub fn setTable(table: *const WavTable) void {
@@ -396,6 +435,10 @@ pub const gba = struct {
         pub fn writeTo(self: WaveVolLen, channel: Channel) void {
             gba_set_sound_reg(address(channel), @as(u16, @bitCast(self)));
         }
+        /// Like writeTo, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeToAt(self: WaveVolLen, channel: Channel, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, address(channel), @as(u16, @bitCast(self)));
+        }
     };
 
     /// (NR43, NR44) - Channel 4 Frequency/Control (R/W)
@@ -452,6 +495,33 @@ pub const gba = struct {
         pub fn writeTo(self: NoiseCtrlFreq, channel: Channel) void {
             gba_set_sound_reg(address(channel), @as(u16, @bitCast(self)));
         }
+        /// Like writeTo, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeToAt(self: NoiseCtrlFreq, channel: Channel, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, address(channel), @as(u16, @bitCast(self)));
+        }
+    };
+
+    /// Direct Sound FIFOs, each playing one 8 bits PCM sample at a time.
+    /// FIFO A is used for the sync pulse when sync output is enabled, which silences samples on both.
+    pub const Fifo = enum(u32) { a = 0, b = 1 };
+
+    /// Signed 8 bits PCM data registered with the host under an ID from 0 to 15.
+    /// All samples must fit in 16kb together, and also take space in the instruments of .sav exports.
+    pub const Sample = struct {
+        id: u32,
+
+        /// Registers the data, typically from @embedFile, during the instruments' main function.
+        pub fn init(id: u32, data: []const i8) Sample {
+            gba_set_sample(id, data.ptr, data.len);
+            return Sample{ .id = id };
+        }
+        /// Plays the sample from its beginning on the FIFO, at a rate in Hz up to about 18157.
+        pub fn play(self: Sample, fifo: Fifo, rate: u32) void {
+            gba_play_sample(@intFromEnum(fifo), self.id, rate);
+        }
+        pub fn stop(fifo: Fifo) void {
+            gba_play_sample(@intFromEnum(fifo), 0, 0);
+        }
     };
 
     /// (NR50, NR51) - Channel L/R Volume/Enable (R/W)
@@ -532,5 +602,9 @@ pub const gba = struct {
         pub fn write(self: SoundCtrl) void {
             gba_set_sound_reg(nr50_51, @as(u16, @bitCast(self)));
         }
+        /// Like write, but queued to be applied cycle_offset CPU cycles into the frame.
+        pub fn writeAt(self: SoundCtrl, cycle_offset: u32) void {
+            gba_set_sound_reg_at(cycle_offset, nr50_51, @as(u16, @bitCast(self)));
+        }
     };
 };
@@ -0,0 +1,516 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Subcommands that work on project files without opening a window.
+
+use crate::sequencer::{ImportReport, ModuleImportOptions, SmfExportOptions, SmfImportOptions, SmfSource};
+use crate::sound_engine::{fits_in_gba_sram, SoundEngine, GBA_SRAM_SIZE, NUM_INSTRUMENT_PARAMS};
+use crate::sound_renderer::offline::{
+    new_headless_engine, render_song, write_stem_files, write_wav_file, RenderOptions, SampleFormat,
+};
+use crate::sound_renderer::vgm::{record_song, write_vgm};
+use crate::synth_script::trace::{trace_audition, trace_song, write_csv, write_json};
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const USAGE: &str = "Usage:
+  chiptrack [<song.ct.md> | <gist URL>]
+  chiptrack validate <song> [--instruments <file>]
+  chiptrack render <song> <output.wav> [--instruments <file>] [--sample-rate <hz>] [--tail-frames <n>] [--float] [--stems]
+  chiptrack vgm <song> <output.vgm> [--instruments <file>]
+  chiptrack trace <song> <output.json | output.csv> [--instruments <file>]
+                  [--audition <instrument id> [--note <0-127>] [--press-frames <n>] [--release-frames <n>]]
+  chiptrack export-midi <song> <output.mid> [--instruments <file>] [--param<0-3>-cc <n>]...
+  chiptrack import-midi <input.mid> <output song> [--instruments <file>] [--assign <track | chN>=<instrument id>]...
+                        [--param<0-3>-cc <n>]...
+  chiptrack import-module <input.mod | input.xm> <output song> [--instruments <file>] [--assign <channel>=<instrument id>]...
+                          [--rows-per-step <n>] [--volume-param <0-3>] [--sample-param <0-3>]
+  chiptrack convert <input song> <output song> [--instruments <file>]
+  chiptrack info <song> [--instruments <file>]
+
+Songs can be .ct.md, .sav or .postcard files. Postcard songs don't contain instruments
+and need to be given an instruments file with --instruments.";
+
+// The Game Boy's vertical refresh rate that drives the sequencer.
+const FRAMES_PER_SECOND: f64 = 4194304.0 / 70224.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProjectFormat {
+    Markdown,
+    GbaSav,
+    Postcard,
+}
+
+impl ProjectFormat {
+    fn from_path(path: &Path) -> Result<ProjectFormat, String> {
+        let file_name = path.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("md") if file_name.ends_with(".ct.md") => Ok(ProjectFormat::Markdown),
+            Some("sav") | Some("srm") => Ok(ProjectFormat::GbaSav),
+            Some("postcard") => Ok(ProjectFormat::Postcard),
+            _ => Err(format!(
+                "Unsupported file extension for file {:?}, expected .ct.md, .sav or .postcard",
+                path
+            )),
+        }
+    }
+}
+
+struct ParsedArgs {
+    positional: Vec<String>,
+    values: Vec<(&'static str, String)>,
+    flags: Vec<&'static str>,
+}
+
+impl ParsedArgs {
+    fn parse(
+        args: &[String],
+        value_options: &[&'static str],
+        flag_options: &[&'static str],
+    ) -> Result<ParsedArgs, String> {
+        let mut parsed = ParsedArgs {
+            positional: Vec::new(),
+            values: Vec::new(),
+            flags: Vec::new(),
+        };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if let Some(&name) = value_options.iter().find(|o| **o == arg) {
+                let value = iter.next().ok_or_else(|| format!("Missing value for {}", name))?;
+                parsed.values.push((name, value.clone()));
+            } else if let Some(&name) = flag_options.iter().find(|o| **o == arg) {
+                parsed.flags.push(name);
+            } else if arg.starts_with("--") {
+                return Err(format!("Unknown option {}", arg));
+            } else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn paths<const N: usize>(&self) -> Result<[PathBuf; N], String> {
+        if self.positional.len() != N {
+            return Err(format!(
+                "Expected {} file arguments but got {}",
+                N,
+                self.positional.len()
+            ));
+        }
+        Ok(core::array::from_fn(|i| PathBuf::from(&self.positional[i])))
+    }
+
+    fn value<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.values.iter().rev().find(|(n, _)| *n == name) {
+            Some((_, v)) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("Invalid value for {}: {}", name, v)),
+            None => Ok(None),
+        }
+    }
+
+    fn all_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.values
+            .iter()
+            .filter(move |(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+}
+
+/// Runs the subcommand given as first argument, returning the process exit code,
+/// or returns None if the arguments aren't for a subcommand.
+pub fn run_subcommand(args: Vec<String>) -> Option<i32> {
+    let result = match args.first().map(|a| a.as_str()) {
+        Some("validate") => validate(&args[1..]),
+        Some("render") => render(&args[1..]),
+        Some("vgm") => vgm(&args[1..]),
+        Some("trace") => trace(&args[1..]),
+        Some("export-midi") => export_midi(&args[1..]),
+        Some("import-midi") => import_midi(&args[1..]),
+        Some("import-module") => import_module(&args[1..]),
+        Some("convert") => convert(&args[1..]),
+        Some("info") => info(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => return None,
+    };
+    Some(match result {
+        Ok(()) => 0,
+        Err(err) => {
+            elog!("Error: {}", err);
+            1
+        }
+    })
+}
+
+fn load_project(engine: &mut SoundEngine, path: &Path, instruments_path: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    match ProjectFormat::from_path(path)? {
+        ProjectFormat::Markdown => engine.try_load_md_file(path)?,
+        ProjectFormat::GbaSav => engine.try_import_project_from_gba_sav(path)?,
+        ProjectFormat::Postcard => {
+            let instruments_path = instruments_path.ok_or("Postcard songs need an --instruments file")?;
+            engine.try_import_project_from_postcard(path, instruments_path)?
+        }
+    }
+    if !engine.is_ready() {
+        return Err("The instruments didn't register any instrument".into());
+    }
+    Ok(())
+}
+
+fn load_project_from_args(args: &ParsedArgs, path: &Path) -> Result<SoundEngine, Box<dyn Error>> {
+    let instruments_path: Option<PathBuf> = args.value("--instruments")?;
+    let mut engine = new_headless_engine(RenderOptions::default().sample_rate);
+    load_project(&mut engine, path, instruments_path.as_deref())?;
+    Ok(engine)
+}
+
+/// Returns an error listing the instrument IDs used by the song but missing from its instruments.
+fn check_instrument_ids(engine: &SoundEngine) -> Result<(), Box<dyn Error>> {
+    let sequencer = engine.sequencer.borrow();
+    let unknown: Vec<&str> = sequencer
+        .song_instrument_ids()
+        .into_iter()
+        .filter_map(|(id, known)| if known { None } else { Some(id) })
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("Unknown instrument ids used by the song: {}", unknown.join(", ")).into());
+    }
+    Ok(())
+}
+
+fn validate(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(args, &["--instruments"], &[])?;
+    let [song_path] = args.paths()?;
+    let engine = load_project_from_args(&args, &song_path)?;
+    check_instrument_ids(&engine)?;
+    println!("{:?} is valid.", song_path);
+    Ok(())
+}
+
+fn render(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(
+        args,
+        &["--instruments", "--sample-rate", "--tail-frames"],
+        &["--float", "--stems"],
+    )?;
+    let [song_path, wav_path] = args.paths()?;
+    let instruments_path: Option<PathBuf> = args.value("--instruments")?;
+    let mut options = RenderOptions::default();
+    if let Some(sample_rate) = args.value("--sample-rate")? {
+        options.sample_rate = sample_rate;
+    }
+    if let Some(tail_frames) = args.value("--tail-frames")? {
+        options.release_tail_frames = tail_frames;
+    }
+    if args.flag("--float") {
+        options.format = SampleFormat::Float32;
+    }
+
+    let rendered = render_song(
+        |engine| load_project(engine, &song_path, instruments_path.as_deref()),
+        &options,
+    )?;
+    write_wav_file(&wav_path, &rendered.mix, 2, &options)?;
+    println!("Wrote {:?}", wav_path);
+    if args.flag("--stems") {
+        for path in write_stem_files(&wav_path, &rendered, &options)? {
+            println!("Wrote {:?}", path);
+        }
+    }
+    Ok(())
+}
+
+fn vgm(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(args, &["--instruments"], &[])?;
+    let [song_path, vgm_path] = args.paths()?;
+    let instruments_path: Option<PathBuf> = args.value("--instruments")?;
+
+    let recorded = record_song(|engine| load_project(engine, &song_path, instruments_path.as_deref()))?;
+    let mut writer = BufWriter::new(File::create(&vgm_path)?);
+    write_vgm(&mut writer, &recorded)?;
+    writer.flush()?;
+    println!("Wrote {:?}", vgm_path);
+    Ok(())
+}
+
+fn trace(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(
+        args,
+        &[
+            "--instruments",
+            "--audition",
+            "--note",
+            "--press-frames",
+            "--release-frames",
+        ],
+        &[],
+    )?;
+    let [song_path, trace_path] = args.paths()?;
+    let instruments_path: Option<PathBuf> = args.value("--instruments")?;
+    let as_json = match trace_path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => true,
+        Some("csv") => false,
+        _ => {
+            return Err(format!(
+                "Unsupported trace file extension for {:?}, expected .json or .csv",
+                trace_path
+            )
+            .into())
+        }
+    };
+    let load = |engine: &mut SoundEngine| load_project(engine, &song_path, instruments_path.as_deref());
+
+    let (trace, instrument_ids) = match args.value::<String>("--audition")? {
+        Some(instrument_id) => trace_audition(
+            load,
+            &instrument_id,
+            args.value("--note")?.unwrap_or(60),
+            args.value("--press-frames")?.unwrap_or(30),
+            args.value("--release-frames")?.unwrap_or(60),
+        )?,
+        None => trace_song(load)?,
+    };
+
+    let mut writer = BufWriter::new(File::create(&trace_path)?);
+    if as_json {
+        write_json(&mut writer, &trace, &instrument_ids)?;
+    } else {
+        write_csv(&mut writer, &trace, &instrument_ids)?;
+    }
+    writer.flush()?;
+    println!("Wrote {} register writes to {:?}", trace.len(), trace_path);
+    Ok(())
+}
+
+const PARAM_CC_OPTIONS: [&str; NUM_INSTRUMENT_PARAMS] = ["--param0-cc", "--param1-cc", "--param2-cc", "--param3-cc"];
+
+fn parse_param_controllers(args: &ParsedArgs, controllers: &mut [u8]) -> Result<(), Box<dyn Error>> {
+    for (param_num, name) in PARAM_CC_OPTIONS.iter().enumerate() {
+        if let Some(controller) = args.value::<u8>(name)? {
+            if controller > 127 {
+                return Err(format!("Invalid value for {}: controller numbers go up to 127", name).into());
+            }
+            controllers[param_num] = controller;
+        }
+    }
+    Ok(())
+}
+
+fn export_midi(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(args, &[&["--instruments"][..], &PARAM_CC_OPTIONS].concat(), &[])?;
+    let [song_path, midi_path] = args.paths()?;
+    let mut options = SmfExportOptions::default();
+    parse_param_controllers(&args, &mut options.param_controllers)?;
+
+    let engine = load_project_from_args(&args, &song_path)?;
+    let smf = engine.sequencer.borrow().export_smf(&options)?;
+    std::fs::write(&midi_path, smf)?;
+    println!("Wrote {:?}", midi_path);
+    Ok(())
+}
+
+fn import_midi(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(
+        args,
+        &[&["--instruments", "--assign"][..], &PARAM_CC_OPTIONS].concat(),
+        &[],
+    )?;
+    let [midi_path, output_path] = args.paths()?;
+    let output_format = ProjectFormat::from_path(&output_path)?;
+    let instruments_path: Option<PathBuf> = args.value("--instruments")?;
+    let mut options = SmfImportOptions::default();
+    parse_param_controllers(&args, &mut options.param_controllers)?;
+    for assignment in args.all_values("--assign") {
+        let (source, id) = assignment.split_once('=').ok_or_else(|| {
+            format!(
+                "Invalid assignment {}, expected <track | chN>=<instrument id>",
+                assignment
+            )
+        })?;
+        options.assignments.push((source.parse::<SmfSource>()?, id.to_owned()));
+    }
+
+    let mut engine = new_headless_engine(RenderOptions::default().sample_rate);
+    let report = engine.try_import_project_from_smf(&midi_path, instruments_path.as_deref(), &options)?;
+    print_import_report(&engine, &report);
+
+    save_project(&mut engine, &output_path, output_format)?;
+    println!("Wrote {:?}", output_path);
+    Ok(())
+}
+
+fn import_module(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(
+        args,
+        &[
+            "--instruments",
+            "--assign",
+            "--rows-per-step",
+            "--volume-param",
+            "--sample-param",
+        ],
+        &[],
+    )?;
+    let [module_path, output_path] = args.paths()?;
+    let output_format = ProjectFormat::from_path(&output_path)?;
+    let instruments_path: Option<PathBuf> = args.value("--instruments")?;
+    let mut options = ModuleImportOptions::default();
+    for assignment in args.all_values("--assign") {
+        let invalid = || format!("Invalid assignment {}, expected <channel>=<instrument id>", assignment);
+        let (channel, id) = assignment.split_once('=').ok_or_else(invalid)?;
+        match channel.parse::<usize>() {
+            Ok(c @ 1..) => options.assignments.push((c, id.to_owned())),
+            _ => return Err(invalid().into()),
+        }
+    }
+    if let Some(rows_per_step) = args.value::<usize>("--rows-per-step")? {
+        if rows_per_step == 0 {
+            return Err("Invalid value for --rows-per-step: it must be at least 1".into());
+        }
+        options.rows_per_step = rows_per_step;
+    }
+    for (name, param) in [
+        ("--volume-param", &mut options.volume_param),
+        ("--sample-param", &mut options.sample_param),
+    ] {
+        if let Some(param_num) = args.value::<u8>(name)? {
+            if param_num as usize >= NUM_INSTRUMENT_PARAMS {
+                return Err(format!(
+                    "Invalid value for {}: expected a param number below {}",
+                    name, NUM_INSTRUMENT_PARAMS
+                )
+                .into());
+            }
+            *param = Some(param_num);
+        }
+    }
+
+    let mut engine = new_headless_engine(RenderOptions::default().sample_rate);
+    let report = engine.try_import_project_from_module(&module_path, instruments_path.as_deref(), &options)?;
+    print_import_report(&engine, &report);
+
+    save_project(&mut engine, &output_path, output_format)?;
+    println!("Wrote {:?}", output_path);
+    Ok(())
+}
+
+fn print_import_report(engine: &SoundEngine, report: &ImportReport) {
+    for (source, id) in &report.assigned {
+        println!("Imported {} into instrument {}", source, id);
+    }
+    for source in &report.unmatched {
+        elog!("Warning: no instrument for {}, use --assign to import it.", source);
+    }
+    if report.dropped_notes > 0 {
+        elog!(
+            "Warning: dropped {} notes that were pressed on the same step as a higher note of the same instrument.",
+            report.dropped_notes
+        );
+    }
+    if let Err(e) = check_instrument_ids(engine) {
+        elog!("Warning: {}", e);
+    }
+}
+
+fn convert(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(args, &["--instruments"], &[])?;
+    let [input_path, output_path] = args.paths()?;
+    let output_format = ProjectFormat::from_path(&output_path)?;
+    let mut engine = load_project_from_args(&args, &input_path)?;
+
+    save_project(&mut engine, &output_path, output_format)?;
+    println!("Wrote {:?}", output_path);
+    Ok(())
+}
+
+fn save_project(
+    engine: &mut SoundEngine,
+    output_path: &Path,
+    output_format: ProjectFormat,
+) -> Result<(), Box<dyn Error>> {
+    match output_format {
+        ProjectFormat::Markdown => engine.save_project_to(output_path)?,
+        ProjectFormat::GbaSav => {
+            let sav = engine.gba_sav_bytes()?;
+            if !fits_in_gba_sram(sav.len()) {
+                return Err(format!(
+                    "The save file is {} bytes and won't fit in the {} bytes of GBA SRAM.",
+                    sav.len(),
+                    GBA_SRAM_SIZE
+                )
+                .into());
+            }
+            std::fs::write(output_path, sav)?
+        }
+        ProjectFormat::Postcard => {
+            // A postcard file only holds one song, don't silently drop the rest of the bank.
+            let num_songs = engine.sequencer.borrow().num_bank_songs();
+            if num_songs > 1 {
+                return Err(format!(
+                    "The project has {} songs but postcard files can only hold one, use a .sav or .md output instead.",
+                    num_songs
+                )
+                .into());
+            }
+            let song = engine.sequencer.borrow().serialize_to_postcard()?;
+            std::fs::write(output_path, song)?
+        }
+    }
+    Ok(())
+}
+
+fn info(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = ParsedArgs::parse(args, &["--instruments"], &[])?;
+    let [song_path] = args.paths()?;
+    let engine = load_project_from_args(&args, &song_path)?;
+    let sequencer = engine.sequencer.borrow();
+
+    let song_patterns = sequencer.song_patterns();
+    let mut patterns = song_patterns.to_vec();
+    patterns.sort_unstable();
+    patterns.dedup();
+    let frames = sequencer.song_length_in_frames();
+    let sav = engine.gba_sav_bytes()?;
+    let (instruments_len, songs_len) = SoundEngine::gba_sav_lengths(&sav);
+
+    println!("Songs in bank: {}", sequencer.num_bank_songs());
+    println!("Song patterns: {}", song_patterns.len());
+    println!(
+        "Patterns used: {}",
+        patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    );
+    println!("Instruments used:");
+    for (id, known) in sequencer.song_instrument_ids() {
+        println!("  {}{}", id, if known { "" } else { " (unknown)" });
+    }
+    println!(
+        "Frames per step: {}, steps: {}",
+        sequencer.frames_per_step(),
+        sequencer.song_length_in_steps()
+    );
+    println!(
+        "Song length: {} frames ({:.2} seconds)",
+        frames,
+        frames as f64 / FRAMES_PER_SECOND
+    );
+    println!(
+        "Size in SRAM: {} of {} bytes (instruments: {} bytes, songs: {} bytes)",
+        sav.len(),
+        GBA_SRAM_SIZE,
+        instruments_len,
+        songs_len
+    );
+    Ok(())
+}
@@ -7,6 +7,7 @@ pub mod renderer;
 
 use crate::elog;
 use crate::log;
+use crate::sound_renderer::gba_sound::{on_timer0_overflow, on_vblank};
 use crate::sound_renderer::SoundRenderer;
 use crate::ui::MainWindow;
 
@@ -92,6 +93,15 @@ extern "C" fn irq_handler(b: IrqBits) {
     // IntrWait won't tell us which interrupts made it return from sleep,
     // so gather this information in the interrupt handler where this is told to us.
     TRIGGERED_IRQS.write(TRIGGERED_IRQS.read() | b.to_u16());
+    // TIMER0 applies the sound register writes that instruments scheduled within the frame.
+    // Handle it first so that it doesn't apply the next frame's writes early.
+    if b.timer0() {
+        on_timer0_overflow();
+    }
+    // The timed writes and PCM samples of each frame start on vblank.
+    if b.vblank() {
+        on_vblank();
+    }
 }
 
 pub fn init() {
@@ -113,7 +123,7 @@ pub fn init() {
             .with_l(true)
             .with_irq_enabled(true),
     );
-    IE.write(IrqBits::VBLANK.with_keypad(true));
+    IE.write(IrqBits::VBLANK.with_keypad(true).with_timer0(true));
     IME.write(true);
 
     // 16.78 MHz / (16*1024) = 1024 overflows per second
@@ -135,23 +145,25 @@ pub fn set_main_window(main_window: slint::Weak<MainWindow>) {
     unsafe { WINDOW = Some(main_window) }
 }
 
+fn millis_since_start() -> u32 {
+    let timer3_read = TIMER3_COUNT.read();
+    // FIXME: Don't use static mut
+    unsafe {
+        if timer3_read < LAST_TIMER3_READ {
+            BASE_MILLIS_SINCE_START += 0xffff;
+        }
+        LAST_TIMER3_READ = timer3_read;
+        BASE_MILLIS_SINCE_START + LAST_TIMER3_READ as u32
+    }
+}
+
 impl slint::platform::Platform for GbaPlatform {
     fn create_window_adapter(&self) -> Result<Rc<dyn slint::platform::WindowAdapter>, PlatformError> {
         Ok(self.window.clone())
     }
 
     fn duration_since_start(&self) -> core::time::Duration {
-        let timer3_read = TIMER3_COUNT.read();
-        // FIXME: Don't use static mut
-        let ms = unsafe {
-            if timer3_read < LAST_TIMER3_READ {
-                BASE_MILLIS_SINCE_START += 0xffff;
-            }
-            LAST_TIMER3_READ = timer3_read;
-            BASE_MILLIS_SINCE_START + LAST_TIMER3_READ as u32
-        };
-
-        let total_ms = ms as u64;
+        let total_ms = millis_since_start() as u64;
         let secs = total_ms / 1000;
         let sub_ms = total_ms % 1000;
         let nanos = (sub_ms * 1_000_000) as u32;
@@ -211,22 +223,17 @@ impl slint::platform::Platform for GbaPlatform {
             let process_keys = process_vblank || TRIGGERED_IRQS.read() & IrqBits::KEYPAD.to_u16() != 0;
             TRIGGERED_IRQS.write(0);
 
-            let cps = 16 * 1024 * 1024 / 1024;
             // Run main_screen.draw() before key handling to avoid missing the vblank window due to the heaving
             // processing happening in key handlers.
             if process_vblank {
-                TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
-                TIMER0_RELOAD.write(0);
-                TIMER0_CONTROL.write(TimerControl::new().with_scale(TimerScale::_1024).with_enabled(true));
+                let start = millis_since_start();
                 screen_controller.draw_active_screen();
-                let time = TIMER0_COUNT.read() as u32 * 1000 / cps;
+                let time = millis_since_start() - start;
                 if time > 0 {
                     log!("--- main_screen.draw(ms) {}", time);
                 }
 
-                TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
-                TIMER0_RELOAD.write(0);
-                TIMER0_CONTROL.write(TimerControl::new().with_scale(TimerScale::_1024).with_enabled(true));
+                let start = millis_since_start();
                 unsafe {
                     SOUND_RENDERER
                         .as_ref()
@@ -235,7 +242,7 @@ impl slint::platform::Platform for GbaPlatform {
                         .sound_engine
                         .advance_frame();
                 }
-                let time = TIMER0_COUNT.read() as u32 * 1000 / cps;
+                let time = millis_since_start() - start;
                 if time > 0 {
                     log!("--- sound_engine.advance_frame(ms) {}", time);
                 }
@@ -251,9 +258,7 @@ impl slint::platform::Platform for GbaPlatform {
             }
 
             if process_keys {
-                TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
-                TIMER0_RELOAD.write(0);
-                TIMER0_CONTROL.write(TimerControl::new().with_scale(TimerScale::_1024).with_enabled(true));
+                let start = millis_since_start();
 
                 let released_keys = KEYINPUT.read().to_u16();
                 let switched_keys = released_keys ^ prev_keys;
@@ -317,7 +322,7 @@ impl slint::platform::Platform for GbaPlatform {
                     }
                 }
 
-                let time = TIMER0_COUNT.read() as u32 * 1000 / cps;
+                let time = millis_since_start() - start;
                 if time > 0 {
                     log!("--- process_key(ms) {}", time);
                 }
@@ -10,6 +10,7 @@ use crate::ui::GlobalEngine;
 use crate::ui::GlobalUI;
 use crate::ui::MainWindow;
 use crate::ui::ReleasePos;
+use crate::ui::StepData;
 use crate::utils::MidiNote;
 
 use alloc::boxed::Box;
@@ -22,6 +23,7 @@ use gba::prelude::*;
 use i_slint_core::model::ModelChangeListenerContainer;
 use slint::Global;
 use slint::Model;
+use slint::SharedString;
 use voladdress::{Safe, VolBlock};
 
 // Default palette
@@ -250,7 +252,13 @@ impl ScreenController {
 
 pub struct MenuScreen {
     focused_row_checker: ChangeChecker<i32>,
+    bank_song_checker: ChangeChecker<(i32, i32)>,
     frames_per_step_checker: ChangeChecker<i32>,
+    /// SharedString isn't Copy, so this keeps the last drawn groove instead of using a ChangeChecker.
+    last_groove: Option<SharedString>,
+    bpm_checker: ChangeChecker<i32>,
+    loop_start_checker: ChangeChecker<i32>,
+    stop_at_end_checker: ChangeChecker<bool>,
     sync_enabled_checker: ChangeChecker<bool>,
 }
 
@@ -259,26 +267,42 @@ impl MenuScreen {
         let tsb = TEXT_SCREENBLOCKS.get_frame(MENU_SCREENBLOCK as usize).unwrap();
         draw_ascii_ref(tsb.get_row(1).unwrap(), 1.., b"File", NORMAL_TEXT);
         draw_ascii_ref(tsb.get_row(2).unwrap(), 1.., b"----------------", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(5).unwrap(), 1.., b"Song:", NORMAL_TEXT);
 
         draw_ascii_ref(tsb.get_row(7).unwrap(), 1.., b"Song Settings", NORMAL_TEXT);
         draw_ascii_ref(tsb.get_row(8).unwrap(), 1.., b"----------------", NORMAL_TEXT);
         draw_ascii_ref(tsb.get_row(9).unwrap(), 1.., b"Frames per step:", NORMAL_TEXT);
         draw_ascii_ref(tsb.get_row(9).unwrap(), 20.., b"(    BPM)", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(10).unwrap(), 1.., b"Groove:", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(11).unwrap(), 1.., b"BPM:", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(12).unwrap(), 1.., b"Loop start:", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(13).unwrap(), 1.., b"At end:", NORMAL_TEXT);
 
-        draw_ascii_ref(tsb.get_row(12).unwrap(), 1.., b"Settings", NORMAL_TEXT);
-        draw_ascii_ref(tsb.get_row(13).unwrap(), 1.., b"----------------", NORMAL_TEXT);
-        draw_ascii_ref(tsb.get_row(14).unwrap(), 1.., b"Sync Mode:", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(15).unwrap(), 1.., b"Settings", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(16).unwrap(), 1.., b"----------------", NORMAL_TEXT);
+        draw_ascii_ref(tsb.get_row(17).unwrap(), 1.., b"Sync Mode:", NORMAL_TEXT);
 
         Self {
             focused_row_checker: ChangeChecker::new(-1),
+            bank_song_checker: ChangeChecker::new((-1, -1)),
             frames_per_step_checker: ChangeChecker::new(0),
+            last_groove: None,
+            bpm_checker: ChangeChecker::new(-1),
+            loop_start_checker: ChangeChecker::new(-1),
+            stop_at_end_checker: ChangeChecker::new(true),
             sync_enabled_checker: ChangeChecker::new(true),
         }
     }
 
     pub fn draw(&mut self, window: MainWindow) {
         let focused_row = self.focused_row_checker.check(window.get_focused_menu_row());
+        let bank_song = self
+            .bank_song_checker
+            .check((window.get_bank_song(), window.get_num_bank_songs()));
         let frames_per_step = self.frames_per_step_checker.check(window.get_frames_per_step());
+        let bpm = self.bpm_checker.check(window.get_bpm());
+        let loop_start = self.loop_start_checker.check(window.get_loop_start());
+        let stop_at_end = self.stop_at_end_checker.check(window.get_stop_at_end());
         let sync_enabled = self.sync_enabled_checker.check(window.get_sync_enabled());
         let tsb = TEXT_SCREENBLOCKS.get_frame(MENU_SCREENBLOCK as usize).unwrap();
 
@@ -298,12 +322,24 @@ impl MenuScreen {
             draw_ascii_ref(tsb.get_row(4).unwrap(), 1.., b"Clear song (default instr.)", palbank);
         }
 
-        if focused_row.dirty() || frames_per_step.dirty() {
+        if focused_row.dirty() || bank_song.dirty() {
             let palbank = if focused_row.current != 2 {
                 NORMAL_TEXT
             } else {
                 SELECTED_TEXT
             };
+            let (song, num_songs) = bank_song.current;
+            draw_ascii(tsb.get_row(5).unwrap(), 7.., to_dec(song as u8 + 1), palbank);
+            draw_ascii_byte(tsb.get_row(5).unwrap(), 9, b'/', palbank);
+            draw_ascii(tsb.get_row(5).unwrap(), 10.., to_dec(num_songs as u8), palbank);
+        }
+
+        if focused_row.dirty() || frames_per_step.dirty() {
+            let palbank = if focused_row.current != 3 {
+                NORMAL_TEXT
+            } else {
+                SELECTED_TEXT
+            };
             draw_ascii(
                 tsb.get_row(9).unwrap(),
                 17..,
@@ -314,22 +350,90 @@ impl MenuScreen {
             draw_ascii(tsb.get_row(9).unwrap(), 21.., to_dec3(bpm as u16), NORMAL_TEXT);
         }
 
+        let groove = window.get_groove();
+        if focused_row.dirty() || self.last_groove.as_ref() != Some(&groove) {
+            let palbank = if focused_row.current != 4 {
+                NORMAL_TEXT
+            } else {
+                SELECTED_TEXT
+            };
+            let text = if groove.is_empty() { "Straight" } else { groove.as_str() };
+            draw_ascii_ref(tsb.get_row(10).unwrap(), 9..29, text.as_bytes(), palbank);
+            draw_ascii_chars(
+                tsb.get_row(10).unwrap(),
+                9 + text.len().min(20)..29,
+                repeat(' '),
+                NORMAL_TEXT,
+            );
+            self.last_groove = Some(groove);
+        }
+
+        if focused_row.dirty() || bpm.dirty() {
+            let palbank = if focused_row.current != 5 {
+                NORMAL_TEXT
+            } else {
+                SELECTED_TEXT
+            };
+            if bpm.current > 0 {
+                draw_ascii(tsb.get_row(11).unwrap(), 6.., to_dec3(bpm.current as u16), palbank);
+            } else {
+                draw_ascii_ref(tsb.get_row(11).unwrap(), 6.., b"Off", palbank);
+            }
+        }
+
+        if focused_row.dirty() || loop_start.dirty() {
+            let palbank = if focused_row.current != 6 {
+                NORMAL_TEXT
+            } else {
+                SELECTED_TEXT
+            };
+            // Song patterns are numbered from 1 in the UI.
+            draw_ascii(
+                tsb.get_row(12).unwrap(),
+                13..,
+                to_dec3(loop_start.current as u16 + 1),
+                palbank,
+            );
+        }
+
+        if focused_row.dirty() || stop_at_end.dirty() {
+            let palbank = if focused_row.current != 7 {
+                NORMAL_TEXT
+            } else {
+                SELECTED_TEXT
+            };
+            if stop_at_end.current {
+                draw_ascii_ref(tsb.get_row(13).unwrap(), 9.., b"Stop", palbank);
+            } else {
+                draw_ascii_ref(tsb.get_row(13).unwrap(), 9.., b"Loop", palbank);
+            }
+        }
+
         if focused_row.dirty() || sync_enabled.dirty() {
-            let palbank = if focused_row.current != 3 {
+            let palbank = if focused_row.current != 8 {
                 NORMAL_TEXT
             } else {
                 SELECTED_TEXT
             };
             if sync_enabled.current {
-                draw_ascii_ref(tsb.get_row(14).unwrap(), 11.., b"PO SY1", palbank);
+                draw_ascii_ref(tsb.get_row(17).unwrap(), 11.., b"PO SY1", palbank);
             } else {
-                draw_ascii_ref(tsb.get_row(14).unwrap(), 11.., b"Off", palbank);
-                draw_ascii_chars(tsb.get_row(14).unwrap(), 14..17, repeat(' '), NORMAL_TEXT);
+                draw_ascii_ref(tsb.get_row(17).unwrap(), 11.., b"Off", palbank);
+                draw_ascii_chars(tsb.get_row(17).unwrap(), 14..17, repeat(' '), NORMAL_TEXT);
             }
         }
     }
 }
 
+fn step_param(row_data: &StepData, param_num: i32) -> Option<i32> {
+    match param_num {
+        0 => Some(row_data.param0_val).filter(|_| row_data.param0_set),
+        1 => Some(row_data.param1_val).filter(|_| row_data.param1_set),
+        2 => Some(row_data.param2_val).filter(|_| row_data.param2_set),
+        _ => Some(row_data.param3_val).filter(|_| row_data.param3_set),
+    }
+}
+
 pub struct MainScreen {
     sequencer_song_patterns_tracker: Pin<Box<i_slint_core::model::ModelChangeListenerContainer<ModelDirtinessTracker>>>,
     sequencer_steps_tracker: Pin<Box<i_slint_core::model::ModelChangeListenerContainer<ModelDirtinessTracker>>>,
@@ -338,6 +442,7 @@ pub struct MainScreen {
     instruments_tracker: Pin<Box<i_slint_core::model::ModelChangeListenerContainer<ModelDirtinessTracker>>>,
     focused_panel_checker: ChangeChecker<FocusedPanel>,
     selected_column_checker: ChangeChecker<i32>,
+    first_displayed_param_checker: ChangeChecker<i32>,
     selected_step_checker: ChangeChecker<i32>,
     selected_step_range_first_checker: ChangeChecker<i32>,
     sequencer_pattern_instruments_len_checker: ChangeChecker<usize>,
@@ -357,6 +462,7 @@ impl MainScreen {
             instruments_tracker: Box::pin(ModelChangeListenerContainer::<ModelDirtinessTracker>::default()),
             focused_panel_checker: ChangeChecker::new(FocusedPanel::Steps),
             selected_column_checker: ChangeChecker::new(0),
+            first_displayed_param_checker: ChangeChecker::new(0),
             selected_step_checker: ChangeChecker::new(0),
             selected_step_range_first_checker: ChangeChecker::new(-1),
             sequencer_pattern_instruments_len_checker: ChangeChecker::new(0),
@@ -391,6 +497,9 @@ impl MainScreen {
         let global_ui = GlobalUI::get(&window);
         let focused_panel = self.focused_panel_checker.check(window.get_focused_panel());
         let selected_column = self.selected_column_checker.check(global_ui.get_selected_column());
+        let first_displayed_param = self
+            .first_displayed_param_checker
+            .check(global_ui.get_first_displayed_param());
         let selected_step = self.selected_step_checker.check(global_ui.get_selected_step());
         let selected_step_range_first = self
             .selected_step_range_first_checker
@@ -442,10 +551,26 @@ impl MainScreen {
 
         if sequencer_song_pattern_active.dirty() || sequencer_song_patterns_dirty {
             let pattern_model = global_engine.get_sequencer_song_patterns();
-            draw_ascii_ref(tsb.get_row(0).unwrap(), 0.., b"Sng", NORMAL_TEXT);
 
             // sequencer_song_patterns_tracker will be dirty and trigger a redraw any time this is changed.
             let sequencer_song_pattern_selected = global_engine.get_sequencer_song_pattern_selected() as usize;
+
+            // There is no room beside each song pattern, so show the transpose of the selected one in the header.
+            let transpose = pattern_model
+                .row_data(sequencer_song_pattern_selected)
+                .map_or(0, |row_data| row_data.transpose);
+            if transpose != 0 {
+                let sign = if transpose > 0 { b'+' } else { b'-' };
+                draw_ascii_byte(tsb.get_row(0).unwrap(), 0, sign, NORMAL_TEXT);
+                draw_ascii(
+                    tsb.get_row(0).unwrap(),
+                    1..,
+                    to_dec(transpose.unsigned_abs() as u8),
+                    NORMAL_TEXT,
+                );
+            } else {
+                draw_ascii_ref(tsb.get_row(0).unwrap(), 0.., b"Sng", NORMAL_TEXT);
+            }
             // The display area is 16 rows, scroll the selected pattern into the middle of the screen
             // by scrolling the top of the screen to the selected pattern - 8.
             // Also make sure that the last pattern is at the bottom of the screen when possible.
@@ -481,6 +606,7 @@ impl MainScreen {
         if sequencer_steps_dirty
             || sequencer_step_active.dirty()
             || selected_column.dirty()
+            || first_displayed_param.dirty()
             || selected_step.dirty()
             || selected_step_range_first.dirty()
             || focused_panel.dirty()
@@ -491,8 +617,8 @@ impl MainScreen {
             let vid_row = tsb.get_row(0).unwrap();
 
             // Don't check for dirtiness, assume this changes together with sequencer_steps_dirty.
-            let param_0_def = global_engine.get_instrument_param_0();
-            let param_1_def = global_engine.get_instrument_param_1();
+            let param_0_def = global_ui.invoke_param_def(first_displayed_param.current);
+            let param_1_def = global_ui.invoke_param_def(first_displayed_param.current + 1);
             draw_ascii_chars(
                 vid_row,
                 PARAMS_START_X..PARAMS_START_X + 3,
@@ -521,8 +647,24 @@ impl MainScreen {
 
             // == Steps ==
             let sequencer_steps = global_engine.get_sequencer_steps();
-            for i in 0..sequencer_steps.row_count() {
-                let vid_row = tsb.get_row(i + 1).unwrap();
+            let num_steps = sequencer_steps.row_count();
+            // The display area is 16 rows, scroll the selected step of longer patterns into the middle of the screen.
+            let scroll_pos = if num_steps > 16 {
+                (selected_step.current.max(0) as usize).clamp(8, num_steps - 8) - 8
+            } else {
+                0
+            };
+            for i in scroll_pos..(scroll_pos + 16) {
+                let vid_row = tsb.get_row(i - scroll_pos + 1).unwrap();
+                if i >= num_steps {
+                    draw_ascii(
+                        vid_row,
+                        PARAMS_START_X - 1..STEPS_START_X + 4,
+                        repeat(b' '),
+                        NORMAL_TEXT,
+                    );
+                    continue;
+                }
                 let row_data = sequencer_steps.row_data(i).unwrap();
 
                 let ii = i as i32;
@@ -557,13 +699,10 @@ impl MainScreen {
                 };
 
                 // Draw params
-                if row_data.param0_set {
-                    draw_ascii(
-                        vid_row,
-                        PARAMS_START_X..,
-                        to_hex(row_data.param0_val as u8),
-                        param0_bank,
-                    );
+                let param_0 = step_param(&row_data, first_displayed_param.current);
+                let param_1 = step_param(&row_data, first_displayed_param.current + 1);
+                if let Some(val) = param_0 {
+                    draw_ascii(vid_row, PARAMS_START_X.., to_hex(val as u8), param0_bank);
                 } else {
                     draw_ascii(
                         vid_row,
@@ -573,13 +712,8 @@ impl MainScreen {
                     );
                 }
                 draw_ascii_byte(vid_row, PARAMS_START_X + 2, b'/', FADED_TEXT);
-                if row_data.param1_set {
-                    draw_ascii(
-                        vid_row,
-                        PARAMS_START_X + 3..,
-                        to_hex(row_data.param1_val as u8),
-                        param1_bank,
-                    );
+                if let Some(val) = param_1 {
+                    draw_ascii(vid_row, PARAMS_START_X + 3.., to_hex(val as u8), param1_bank);
                 } else {
                     draw_ascii(
                         vid_row,
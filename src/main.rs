@@ -8,11 +8,14 @@
 
 extern crate alloc;
 
+#[cfg(feature = "desktop_native")]
+mod cli;
 #[cfg(feature = "gba")]
 mod gba_platform;
 mod log;
 #[cfg(feature = "desktop")]
 mod midi;
+mod observer;
 mod sequencer;
 mod sound_engine;
 mod sound_renderer;
@@ -25,8 +28,8 @@ use wasm_bindgen::prelude::*;
 
 #[cfg(feature = "desktop")]
 use crate::midi::Midi;
+use crate::sound_engine::DEFAULT_NUM_STEPS;
 use crate::sound_engine::NUM_INSTRUMENTS;
-use crate::sound_engine::NUM_STEPS;
 use crate::sound_renderer::new_sound_renderer;
 use crate::sound_renderer::SoundRendererTrait;
 
@@ -50,6 +53,12 @@ pub fn main() {
     #[cfg(all(debug_assertions, target_arch = "wasm32"))]
     console_error_panic_hook::set_once();
 
+    // Subcommands run without opening a window.
+    #[cfg(feature = "desktop_native")]
+    if let Some(exit_code) = cli::run_subcommand(env::args().skip(1).collect()) {
+        std::process::exit(exit_code);
+    }
+
     run_main()
 }
 
@@ -66,7 +75,10 @@ fn run_main() {
     #[cfg(feature = "desktop")]
     let parsed_arguments = parse_command_arguments();
 
-    let sequencer_step_model = Rc::new(slint::VecModel::<_>::from(vec![ui::StepData::default(); NUM_STEPS]));
+    let sequencer_step_model = Rc::new(slint::VecModel::<_>::from(vec![
+        ui::StepData::default();
+        DEFAULT_NUM_STEPS
+    ]));
     let instruments_model = Rc::new(slint::VecModel::<_>::from(vec![
         ui::InstrumentData::default();
         NUM_INSTRUMENTS
@@ -0,0 +1,126 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+use crate::sequencer::{Instrument, InstrumentStep, ReleasePos, SongSettings};
+use crate::sound_engine::NUM_INSTRUMENT_PARAMS;
+
+use alloc::string::String;
+
+/// Receives the state changes of the Sequencer, SoundEngine and Synth.
+///
+/// Every method is called on the sound thread and does nothing by default, so that implementations
+/// only need to handle what they care about. The Slint UI uses ui::SlintObserver, while
+/// headless users like the offline renderer can use NoopObserver.
+pub trait EngineObserver {
+    fn song_pattern_activated(&self, _song_pattern: usize) {}
+    /// The active step is -1 when the active song pattern isn't the displayed one.
+    fn step_activated(&self, _active_step: i32) {}
+    fn song_pattern_displayed(&self, _song_pattern: usize, _previous: usize) {}
+    fn instrument_displayed(&self, _instrument: u8, _param_names: [Option<&str>; NUM_INSTRUMENT_PARAMS]) {}
+    fn instrument_selection_unpinned(&self) {}
+    fn instrument_muted(&self, _instrument: u8, _muted: bool) {}
+    /// The steps of the displayed instrument changed, or another instrument or song pattern was displayed.
+    /// `steps` is None if the displayed pattern doesn't have the instrument, but it still has `num_steps` steps.
+    /// `pattern_instruments` has to be cycled through from `first_other_instrument`, taking
+    /// `num_other_instruments`, to get the other instruments in the displayed pattern.
+    fn steps_changed(
+        &self,
+        _steps: Option<&[InstrumentStep]>,
+        _num_steps: usize,
+        _active_step: i32,
+        _pattern_instruments: &[Instrument],
+        _first_other_instrument: usize,
+        _num_other_instruments: usize,
+    ) {
+    }
+    /// A single step of the displayed instrument changed, None values are left unchanged.
+    fn step_changed(
+        &self,
+        _step: usize,
+        _press_note: Option<Option<u8>>,
+        _release_pos: Option<ReleasePos>,
+        _params: Option<[Option<i8>; NUM_INSTRUMENT_PARAMS]>,
+    ) {
+    }
+    /// The delay of a single step of the displayed instrument changed.
+    fn step_delay_changed(&self, _step: usize, _delay: u8) {}
+    fn playing_changed(&self, _playing: bool) {}
+    fn recording_changed(&self, _recording: bool) {}
+    /// The recording value of an instrument parameter changed.
+    fn instrument_param_changed(&self, _instrument: u8, _param_num: u8, _value: i8) {}
+    /// The pattern played at a given song pattern slot changed.
+    fn song_pattern_changed(&self, _song_pattern: usize, _pattern: usize) {}
+    /// The stub song pattern at the end of the song was committed and a new stub follows it.
+    fn song_pattern_committed(&self, _song_pattern: usize, _pattern: usize) {}
+    /// The semitones that a song pattern slot transposes its pattern by changed.
+    fn song_pattern_transpose_changed(&self, _song_pattern: usize, _transpose: i8) {}
+    fn last_song_pattern_removed(&self) {}
+    /// Another song of the song bank is being edited.
+    fn bank_song_selected(&self, _bank_song: usize, _num_bank_songs: usize) {}
+    /// A song was loaded, `song_patterns` contains the pattern and transpose of each song pattern slot.
+    fn song_changed(&self, _song_patterns: &[(usize, i8)], _settings: &SongSettings) {}
+    /// The instruments script registered its instruments, default_params is None for undefined parameters.
+    fn instruments_defined(&self, _ids: &[String], _default_params: &[[Option<i8>; NUM_INSTRUMENT_PARAMS]]) {}
+
+    /// An event was sent to an instrument. `pressed_note` and `released_note` are only
+    /// set for the displayed instrument.
+    fn instrument_note_event(
+        &self,
+        _instrument: u8,
+        _pressed: bool,
+        _pressed_note: Option<u8>,
+        _released_note: Option<u8>,
+    ) {
+    }
+    fn note_pressed(&self, _note: u8) {}
+    fn note_released(&self, _note: u8) {}
+    fn all_notes_released(&self) {}
+    fn instruments_muted(&self) {}
+
+    /// The frequency and volume of each synth channel after rendering a frame.
+    fn synth_channels_changed(&self, _frame_number: i32, _states: &[(Option<f64>, u8)]) {}
+}
+
+/// Ignores every change, for when there is nobody to show them to.
+#[cfg(feature = "desktop_native")]
+pub struct NoopObserver;
+
+#[cfg(feature = "desktop_native")]
+impl EngineObserver for NoopObserver {}
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub enum ObservedEvent {
+    StepActivated(i32),
+    PlayingChanged(bool),
+    RecordingChanged(bool),
+    SongChanged(alloc::vec::Vec<usize>, u32),
+}
+
+/// Keeps some of the notified changes so that tests can check them.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingObserver {
+    pub events: core::cell::RefCell<alloc::vec::Vec<ObservedEvent>>,
+}
+
+#[cfg(test)]
+impl EngineObserver for RecordingObserver {
+    fn step_activated(&self, active_step: i32) {
+        self.events.borrow_mut().push(ObservedEvent::StepActivated(active_step));
+    }
+    fn playing_changed(&self, playing: bool) {
+        self.events.borrow_mut().push(ObservedEvent::PlayingChanged(playing));
+    }
+    fn recording_changed(&self, recording: bool) {
+        self.events
+            .borrow_mut()
+            .push(ObservedEvent::RecordingChanged(recording));
+    }
+    fn song_changed(&self, song_patterns: &[(usize, i8)], settings: &SongSettings) {
+        self.events.borrow_mut().push(ObservedEvent::SongChanged(
+            song_patterns.iter().map(|(pattern, _)| *pattern).collect(),
+            settings.frames_per_step,
+        ));
+    }
+}
@@ -1,42 +1,50 @@
 // Copyright © 2021 Jocelyn Turcotte <turcotte.j@gmail.com>
 // SPDX-License-Identifier: MIT
 
+#[cfg(feature = "desktop_native")]
+mod import;
 #[cfg(feature = "desktop")]
 mod markdown;
-
+#[cfg(feature = "desktop_native")]
+mod smf;
+#[cfg(feature = "desktop_native")]
+mod tracker_module;
+
+use crate::observer::EngineObserver;
+use crate::sound_engine::DEFAULT_NUM_STEPS;
+use crate::sound_engine::MAX_BANK_SONGS;
+use crate::sound_engine::MAX_NUM_STEPS;
 use crate::sound_engine::NUM_INSTRUMENTS;
 use crate::sound_engine::NUM_INSTRUMENT_PARAMS;
 use crate::sound_engine::NUM_PATTERNS;
-use crate::sound_engine::NUM_STEPS;
-use crate::ui;
-use crate::ui::GlobalEngine;
-use crate::ui::GlobalSettings;
-use crate::ui::GlobalUI;
-use crate::ui::ParamData;
-use crate::ui::SongPatternData;
-use crate::ui::SongSettings;
 use crate::utils::MidiNote;
-use crate::utils::WeakWindowWrapper;
 use core::convert::TryFrom;
 use core::fmt;
+use core::ops::RangeInclusive;
 use core::primitive::i8;
 use serde::de::{self, Deserializer, SeqAccess, Visitor};
 use serde::ser::{SerializeStruct, Serializer};
 
+#[cfg(feature = "desktop_native")]
+pub use import::ImportReport;
 #[cfg(feature = "desktop")]
-use markdown::{parse_markdown_song, save_markdown_song};
+use markdown::{parse_markdown_song_bank, save_markdown_song_bank};
 use postcard::from_bytes;
-use postcard::to_allocvec;
 use serde::Deserialize;
 use serde::Serialize;
-use slint::Global;
-use slint::Model;
-use slint::SharedString;
-use slint::VecModel;
+#[cfg(feature = "desktop_native")]
+pub use smf::{SmfExportOptions, SmfImportOptions, SmfSource};
+#[cfg(feature = "desktop_native")]
+pub use tracker_module::ModuleImportOptions;
 
 use alloc::borrow::ToOwned;
+#[cfg(feature = "desktop_native")]
+use alloc::collections::BTreeMap;
 use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::rc::Rc;
 use alloc::string::String;
+use alloc::string::ToString;
 use alloc::vec;
 use alloc::vec::Vec;
 #[cfg(feature = "desktop")]
@@ -52,7 +60,7 @@ enum KeyEvent {
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum StepEvent {
-    Press(u8, i8, i8), // note, p0, p1
+    Press(u8, [i8; NUM_INSTRUMENT_PARAMS]), // note, params
     Release,
     SetParam(u8, i8), // param_num, val
 }
@@ -67,14 +75,6 @@ pub enum ReleasePos {
 }
 
 impl ReleasePos {
-    pub fn to_ui(self) -> ui::ReleasePos {
-        match self {
-            ReleasePos::NotReleased => ui::ReleasePos::NotReleased,
-            ReleasePos::Half => ui::ReleasePos::Half,
-            ReleasePos::Full => ui::ReleasePos::Full,
-        }
-    }
-
     pub fn non_empty(self) -> bool {
         self != ReleasePos::NotReleased
     }
@@ -93,19 +93,116 @@ impl TryFrom<u8> for ReleasePos {
     }
 }
 
+/// Limits the repeats of its song pattern on which a step plays.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum StepCondition {
+    /// Plays on one repeat of every cycle of repeats, e.g. (1, 4) on the first of every 4, written 1:4.
+    Every(u8, u8),
+    /// Plays from the given repeat on, e.g. 2 to skip the first one, written 2+.
+    FromRepeat(u8),
+    /// Plays with this percentage of chance, written 50%.
+    Chance(u8),
+}
+
+impl StepCondition {
+    /// `repeat` counts the previous repeats of the song pattern since playback started.
+    fn is_met(self, repeat: u32, rng: &mut StepRng) -> bool {
+        match self {
+            StepCondition::Every(nth, of) => repeat % of.max(1) as u32 + 1 == nth as u32,
+            StepCondition::FromRepeat(nth) => repeat + 1 >= nth as u32,
+            StepCondition::Chance(percent) => rng.next_percent() < percent,
+        }
+    }
+}
+
+impl fmt::Display for StepCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepCondition::Every(nth, of) => write!(f, "{}:{}", nth, of),
+            StepCondition::FromRepeat(nth) => write!(f, "{}+", nth),
+            StepCondition::Chance(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl core::str::FromStr for StepCondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let condition = if let Some((nth, of)) = s.split_once(':') {
+            match (nth.parse::<u8>(), of.parse::<u8>()) {
+                (Ok(nth), Ok(of)) if nth >= 1 && nth <= of => Some(StepCondition::Every(nth, of)),
+                _ => None,
+            }
+        } else if let Some(nth) = s.strip_suffix('+') {
+            nth.parse::<u8>()
+                .ok()
+                .filter(|n| *n >= 1)
+                .map(StepCondition::FromRepeat)
+        } else if let Some(percent) = s.strip_suffix('%') {
+            percent
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= 100)
+                .map(StepCondition::Chance)
+        } else {
+            None
+        };
+        condition.ok_or_else(|| format!("Invalid step condition [{}], expected e.g. 1:4, 2+ or 50%", s))
+    }
+}
+
+/// A xorshift generator for step chances. It's seeded when playback starts so that
+/// every playback or render of a song plays the same steps.
+struct StepRng(u32);
+
+impl StepRng {
+    const SEED: u32 = 0x2545_f491;
+
+    fn new() -> Self {
+        StepRng(Self::SEED)
+    }
+
+    fn next_percent(&mut self) -> u8 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        (x % 100) as u8
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
-struct InstrumentStep {
+pub struct InstrumentStep {
     note: u8,
     release_pos: ReleasePos,
-    param0: Option<i8>,
-    param1: Option<i8>,
+    params: [Option<i8>; NUM_INSTRUMENT_PARAMS],
+    /// Events of the step, including its release, only happen when it's met.
+    condition: Option<StepCondition>,
+    /// Number of times the pressed note is hit within the step, from 2 to MAX_RATCHET.
+    ratchet: Option<u8>,
+    /// Frames by which every event of the step is played late, within the length of the step.
+    delay: u8,
 }
 
 impl InstrumentStep {
-    const FIELDS: &'static [&'static str] = &["note", "flags", "param0", "param1"];
+    const FIELDS: &'static [&'static str] = &[
+        "note",
+        "flags",
+        "param0",
+        "param1",
+        "param2",
+        "param3",
+        "extensions",
+        "condition",
+        "ratchet",
+        "delay",
+    ];
 
+    /// A condition, ratchet or delay alone doesn't make a step non-empty since it has nothing to play.
     pub fn is_empty(&self) -> bool {
-        self.note == 0 && !self.release_pos.non_empty() && self.param0.is_none() && self.param1.is_none()
+        self.note == 0 && !self.release_pos.non_empty() && self.params.iter().all(Option::is_none)
     }
     pub fn set_press_note(&mut self, note: Option<u8>) {
         match note {
@@ -121,6 +218,23 @@ impl InstrumentStep {
         self.note != 0
     }
 
+    pub fn release_pos(&self) -> ReleasePos {
+        self.release_pos
+    }
+
+    pub fn params(&self) -> [Option<i8>; NUM_INSTRUMENT_PARAMS] {
+        self.params
+    }
+
+    pub fn delay(&self) -> u8 {
+        self.delay
+    }
+
+    /// The frame of a step of `step_frames` at which its events start, the delay being cut to fit the step.
+    fn delay_frames(&self, step_frames: u32) -> u32 {
+        (self.delay as u32).min(step_frames - 1)
+    }
+
     pub fn press_note(&self) -> Option<u8> {
         // 0 is a valid MIDI note, but the GBA hardware doesn't support that frequency, so use it to represent "no press".
         if self.note == 0 {
@@ -129,33 +243,106 @@ impl InstrumentStep {
             Some(self.note)
         }
     }
+
+    /// Frames within a step of `step_frames` at which a ratcheted note is released and pressed again.
+    /// Hits are spread like the half-step release, and the ones from a half-step release on are dropped,
+    /// as well as the ones that a delay pushes past the end of the step.
+    fn retrigger_frames(&self, step_frames: u32) -> impl Iterator<Item = u32> {
+        let hits = self.ratchet.unwrap_or(1) as u32;
+        let end_frame = match self.release_pos {
+            ReleasePos::Half => step_frames.div_ceil(2),
+            _ => step_frames,
+        };
+        let delay = self.delay_frames(step_frames);
+        let hit_frame = move |hit: u32| (hit * step_frames).div_ceil(hits);
+        // Steps shorter than the number of hits merge hits landing on the same frame.
+        (1..hits)
+            .filter(move |hit| hit_frame(*hit) != hit_frame(hit - 1))
+            .map(hit_frame)
+            .filter(move |frame| *frame < end_frame && delay + frame < step_frames)
+            .map(move |frame| delay + frame)
+    }
+
+    /// The frame within a step of `step_frames` at which the step releases its note, if it does.
+    /// It's past the end of the step for full-step releases, or when a delay pushes a half-step release there.
+    fn release_frame(&self, step_frames: u32) -> Option<u32> {
+        let release_frame = match self.release_pos {
+            ReleasePos::NotReleased => return None,
+            // Use div_ceil to prefer having the note play half a frame longer instead of shorter.
+            ReleasePos::Half => step_frames.div_ceil(2),
+            ReleasePos::Full => step_frames,
+        };
+        Some(self.delay_frames(step_frames) + release_frame)
+    }
 }
 
+const MAX_RATCHET: u8 = 4;
+
+/// Bits of the mask of extensions that a step has, which are serialized after its params in this order.
+const EXTENSION_CONDITION: u8 = 1 << 0;
+const EXTENSION_RATCHET: u8 = 1 << 1;
+const EXTENSION_DELAY: u8 = 1 << 2;
+
 /// Use a custom serializer instead of derived to represent None parameters as single bits instead of separate 0 bytes
-/// so that we need 2 bytes per step instead of 4.
+/// so that we need 2 bytes per step instead of 2 + NUM_INSTRUMENT_PARAMS.
+/// Each param has a presence bit in the low nibble of the flags byte, so songs saved with fewer params
+/// load unchanged.
+/// Steps with extensions clear the release bit and instead put their release pos, in half steps plus one,
+/// in the high nibble, which is otherwise 0 without the release bit. Steps with only a condition leave it like this,
+/// steps with only a ratchet add 3, and steps with both add 6. Steps with a delay add 9 and write their
+/// extensions mask after the params. The extensions then follow.
 impl Serialize for InstrumentStep {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let num_params = self.param0.is_some() as usize + self.param1.is_some() as usize;
-        let (release_bit, release_pos) = match self.release_pos as u8 {
-            0 => (false, 0),
+        let num_params = self.params.iter().filter(|p| p.is_some()).count();
+        let extensions = self.condition.map_or(0, |_| EXTENSION_CONDITION)
+            | self.ratchet.map_or(0, |_| EXTENSION_RATCHET)
+            | if self.delay > 0 { EXTENSION_DELAY } else { 0 };
+        let kind = match extensions {
+            0 => None,
+            EXTENSION_CONDITION => Some(0),
+            EXTENSION_RATCHET => Some(1),
+            _ if extensions & EXTENSION_DELAY == 0 => Some(2),
+            _ => Some(3),
+        };
+        let (release_bit, release_pos) = match (kind, self.release_pos as u8) {
+            (None, 0) => (false, 0),
             // 0 represents an unreleased note, 16 represents a note released at 16/16 of the step, 8 a note released at 8/16 of the step, etc.
             // To encode the release pos in 4 bits, remove the unreleased state by subtracting 1 when it's not 0.
-            pos => (true, pos - 1),
+            (None, pos) => (true, pos - 1),
+            (Some(kind), pos) => (false, kind * 3 + pos / 8 + 1),
         };
 
-        let flags = (self.param1.is_some() as u8) << 1 | self.param0.is_some() as u8;
+        let flags = self
+            .params
+            .iter()
+            .enumerate()
+            .fold(0u8, |flags, (i, p)| flags | (p.is_some() as u8) << i);
 
-        let mut rgb = serializer.serialize_struct("InstrumentStep", 2 + num_params)?;
+        let mut rgb = serializer.serialize_struct(
+            "InstrumentStep",
+            2 + num_params + (kind == Some(3)) as usize + extensions.count_ones() as usize,
+        )?;
         rgb.serialize_field(InstrumentStep::FIELDS[0], &((release_bit as u8) << 7 | self.note))?;
         rgb.serialize_field(InstrumentStep::FIELDS[1], &(release_pos << 4 | flags))?;
-        if let Some(val) = self.param0 {
-            rgb.serialize_field(InstrumentStep::FIELDS[2], &val)?;
+        for (i, param) in self.params.iter().enumerate() {
+            if let Some(val) = param {
+                rgb.serialize_field(InstrumentStep::FIELDS[2 + i], val)?;
+            }
         }
-        if let Some(val) = self.param1 {
-            rgb.serialize_field(InstrumentStep::FIELDS[3], &val)?;
+        if kind == Some(3) {
+            rgb.serialize_field(InstrumentStep::FIELDS[6], &extensions)?;
+        }
+        if let Some(condition) = &self.condition {
+            rgb.serialize_field(InstrumentStep::FIELDS[7], condition)?;
+        }
+        if let Some(ratchet) = &self.ratchet {
+            rgb.serialize_field(InstrumentStep::FIELDS[8], ratchet)?;
+        }
+        if self.delay > 0 {
+            rgb.serialize_field(InstrumentStep::FIELDS[9], &self.delay)?;
         }
         rgb.end()
     }
@@ -182,26 +369,71 @@ impl<'de> Deserialize<'de> for InstrumentStep {
             {
                 let note: u8 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(0, &self))?;
                 let flags: u8 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                let release_pos = match (note & 0x80 != 0, flags >> 4) {
-                    (false, _) => ReleasePos::NotReleased,
-                    (true, pos) => ReleasePos::try_from(pos + 1)
-                        .map_err(|_| de::Error::invalid_value(de::Unexpected::Unsigned(pos as u64), &self))?,
+                let invalid_pos = |pos: u8| de::Error::invalid_value(de::Unexpected::Unsigned(pos as u64), &self);
+                let (release_pos, kind) = match (note & 0x80 != 0, flags >> 4) {
+                    (false, 0) => (ReleasePos::NotReleased, None),
+                    (false, ext @ 1..=12) => (
+                        ReleasePos::try_from((ext - 1) % 3 * 8).map_err(|_| invalid_pos(ext))?,
+                        Some((ext - 1) / 3),
+                    ),
+                    (false, ext) => return Err(invalid_pos(ext)),
+                    (true, pos) => (ReleasePos::try_from(pos + 1).map_err(|_| invalid_pos(pos))?, None),
                 };
                 let mut i = InstrumentStep {
                     note: note & 0x7f,
                     release_pos,
-                    param0: None,
-                    param1: None,
+                    params: [None; NUM_INSTRUMENT_PARAMS],
+                    condition: None,
+                    ratchet: None,
+                    delay: 0,
+                };
+                let mut num_read = 2;
+                for param_num in 0..NUM_INSTRUMENT_PARAMS {
+                    if flags & 1 << param_num != 0 {
+                        let val: i8 = seq
+                            .next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(num_read, &self))?;
+                        i.params[param_num] = Some(val);
+                        num_read += 1;
+                    }
+                }
+                let extensions = match kind {
+                    None => 0,
+                    Some(0) => EXTENSION_CONDITION,
+                    Some(1) => EXTENSION_RATCHET,
+                    Some(2) => EXTENSION_CONDITION | EXTENSION_RATCHET,
+                    _ => {
+                        let mask: u8 = seq
+                            .next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(num_read, &self))?;
+                        num_read += 1;
+                        mask
+                    }
                 };
-                if flags & 0b01 != 0 {
-                    let val: i8 = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(2, &self))?;
-                    i.param0 = Some(val);
+                if extensions & EXTENSION_CONDITION != 0 {
+                    i.condition = Some(
+                        seq.next_element()?
+                            .ok_or_else(|| de::Error::invalid_length(num_read, &self))?,
+                    );
+                    num_read += 1;
                 }
-                if flags & 0b10 != 0 {
-                    let val: i8 = seq
+                if extensions & EXTENSION_RATCHET != 0 {
+                    let ratchet: u8 = seq
                         .next_element()?
-                        .ok_or_else(|| de::Error::invalid_length(2 + flags.count_ones() as usize - 1, &self))?;
-                    i.param1 = Some(val);
+                        .ok_or_else(|| de::Error::invalid_length(num_read, &self))?;
+                    if !(2..=MAX_RATCHET).contains(&ratchet) {
+                        return Err(de::Error::invalid_value(
+                            de::Unexpected::Unsigned(ratchet as u64),
+                            &self,
+                        ));
+                    }
+                    i.ratchet = Some(ratchet);
+                    num_read += 1;
+                }
+                if extensions & EXTENSION_DELAY != 0 {
+                    i.delay = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(num_read, &self))?;
                 }
                 Ok(i)
             }
@@ -217,14 +449,82 @@ fn postcard_serialize() -> Result<(), Box<dyn Error>> {
         InstrumentStep {
             note: 36,
             release_pos: ReleasePos::Full,
-            param0: None,
-            param1: Some(8),
+            params: [None, Some(8), None, None],
+            condition: None,
+            ratchet: None,
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::Half,
+            params: [Some(1), Some(-1), None, None],
+            condition: None,
+            ratchet: None,
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::NotReleased,
+            params: [None, Some(2), Some(-3), Some(4)],
+            condition: None,
+            ratchet: None,
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::NotReleased,
+            params: [None, Some(2), None, None],
+            condition: Some(StepCondition::Every(1, 4)),
+            ratchet: None,
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::Half,
+            params: [None; NUM_INSTRUMENT_PARAMS],
+            condition: Some(StepCondition::Chance(25)),
+            ratchet: None,
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 0,
+            release_pos: ReleasePos::Full,
+            params: [Some(1), None, None, None],
+            condition: Some(StepCondition::FromRepeat(2)),
+            ratchet: None,
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::Full,
+            params: [None; NUM_INSTRUMENT_PARAMS],
+            condition: None,
+            ratchet: Some(3),
+            delay: 0,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::NotReleased,
+            params: [Some(-2), None, None, None],
+            condition: Some(StepCondition::Every(2, 2)),
+            ratchet: Some(4),
+            delay: 0,
         },
         InstrumentStep {
             note: 36,
             release_pos: ReleasePos::Half,
-            param0: Some(1),
-            param1: Some(-1),
+            params: [None; NUM_INSTRUMENT_PARAMS],
+            condition: None,
+            ratchet: None,
+            delay: 3,
+        },
+        InstrumentStep {
+            note: 36,
+            release_pos: ReleasePos::Full,
+            params: [None, Some(5), None, None],
+            condition: Some(StepCondition::Chance(75)),
+            ratchet: Some(2),
+            delay: 1,
         },
     ];
 
@@ -233,25 +533,70 @@ fn postcard_serialize() -> Result<(), Box<dyn Error>> {
         let r: InstrumentStep = postcard::from_bytes(&ser)?;
         assert!(r == *step);
     }
+
+    // Steps saved when instruments had only two params.
+    let r: InstrumentStep = postcard::from_bytes(&[0x80 | 36, 15 << 4 | 0b10, 8])?;
+    assert!(r == cases[1]);
     Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-struct Instrument {
+pub struct Instrument {
     id: String,
     #[serde(skip)]
     synth_index: Option<u8>,
-    steps: [InstrumentStep; NUM_STEPS],
+    steps: Vec<InstrumentStep>,
+}
+
+impl Instrument {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn synth_index(&self) -> Option<u8> {
+        self.synth_index
+    }
+
+    pub fn steps(&self) -> &[InstrumentStep] {
+        &self.steps
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Pattern {
+    /// Between 1 and MAX_NUM_STEPS, every instrument has exactly this many steps.
+    num_steps: usize,
     instruments: Vec<Instrument>,
+    /// Overrides the song's groove for the steps of this pattern.
+    groove: Option<Vec<u32>>,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern {
+            num_steps: DEFAULT_NUM_STEPS,
+            instruments: Vec::new(),
+            groove: None,
+        }
+    }
 }
 
 impl Pattern {
     fn is_empty(&self) -> bool {
-        self.instruments.is_empty()
+        self.instruments.is_empty() && self.num_steps == DEFAULT_NUM_STEPS && self.groove.is_none()
+    }
+
+    fn num_steps(&self) -> usize {
+        self.num_steps
+    }
+
+    /// Truncates or extends the steps of every instrument, removing the ones left without any event.
+    fn set_num_steps(&mut self, num_steps: usize) {
+        self.num_steps = num_steps;
+        for instrument in &mut self.instruments {
+            instrument.steps.resize(num_steps, InstrumentStep::default());
+        }
+        self.instruments.retain(|i| !i.steps.iter().all(|s| s.is_empty()));
     }
 
     fn next_instrument(&self, current_instrument: u8, forward: bool) -> Option<u8> {
@@ -330,18 +675,18 @@ impl Pattern {
         self.instruments.retain(|i| i.synth_index != Some(instrument));
     }
 
-    fn get_steps(&self, instrument: u8) -> Option<&[InstrumentStep; NUM_STEPS]> {
+    fn get_steps(&self, instrument: u8) -> Option<&[InstrumentStep]> {
         self.instruments
             .iter()
             .find(|i| i.synth_index == Some(instrument))
-            .map(|i| &i.steps)
+            .map(|i| i.steps.as_slice())
     }
 
-    fn get_steps_mut(&mut self, instrument: u8) -> Option<&mut [InstrumentStep; NUM_STEPS]> {
+    fn get_steps_mut(&mut self, instrument: u8) -> Option<&mut [InstrumentStep]> {
         self.instruments
             .iter_mut()
             .find(|i| i.synth_index == Some(instrument))
-            .map(|i| &mut i.steps)
+            .map(|i| i.steps.as_mut_slice())
     }
 
     /// This might insert a new instrument if not there already and thus
@@ -350,14 +695,14 @@ impl Pattern {
         &'a mut self,
         instrument_id: &str,
         synth_index: Option<u8>,
-    ) -> &'a mut [InstrumentStep; NUM_STEPS] {
+    ) -> &'a mut [InstrumentStep] {
         let ii = match self.instruments.iter().position(|i| i.id == instrument_id) {
             Some(ii) => ii,
             None => {
                 self.instruments.push(Instrument {
                     id: instrument_id.to_owned(),
                     synth_index,
-                    steps: Default::default(),
+                    steps: vec![InstrumentStep::default(); self.num_steps],
                 });
                 self.instruments.len() - 1
             }
@@ -373,7 +718,7 @@ impl Pattern {
         step: usize,
         set_press_note: Option<Option<u8>>,
         set_release_pos: Option<ReleasePos>,
-        set_params: Option<(Option<i8>, Option<i8>)>,
+        set_params: Option<[Option<i8>; NUM_INSTRUMENT_PARAMS]>,
     ) -> InstrumentStep {
         let instrument_steps = self.get_steps_mut_or_insert(instrument_id, Some(instrument));
         let step = &mut instrument_steps[step];
@@ -388,10 +733,17 @@ impl Pattern {
             something_added |= note.is_some();
             step.set_press_note(note);
         }
-        if let Some((param0, param1)) = set_params {
-            something_added |= param0.is_some() || param1.is_some();
-            step.param0 = param0;
-            step.param1 = param1;
+        if let Some(params) = set_params {
+            something_added |= params.iter().any(Option::is_some);
+            step.params = params;
+        }
+        // The condition and delay go away with the last event of their step, and the ratchet with its press.
+        if step.is_empty() {
+            step.condition = None;
+            step.delay = 0;
+        }
+        if !step.press() {
+            step.ratchet = None;
         }
 
         if !something_added && instrument_steps.iter().all(|s| s.is_empty()) {
@@ -401,7 +753,7 @@ impl Pattern {
         previous
     }
 
-    fn update_synth_index(&mut self, new_instrument_ids: &[SharedString]) {
+    fn update_synth_index(&mut self, new_instrument_ids: &[String]) {
         for instrument in &mut self.instruments {
             let index = new_instrument_ids
                 .iter()
@@ -414,11 +766,54 @@ impl Pattern {
     }
 }
 
+/// A slot of the song arrangement.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+struct SongPattern {
+    pattern: usize,
+    /// Overrides the song's frames_per_step while this slot plays.
+    frames_per_step: Option<u32>,
+    /// Semitones added to the notes pressed while this slot plays.
+    transpose: i8,
+}
+
+impl SongPattern {
+    fn new(pattern: usize) -> Self {
+        SongPattern {
+            pattern,
+            frames_per_step: None,
+            transpose: 0,
+        }
+    }
+}
+
+/// The range that song patterns can be transposed by, in semitones.
+const MAX_TRANSPOSE: i8 = 48;
+/// Tempos outside of this range would overflow step lengths or make no musical sense.
+const BPM_RANGE: RangeInclusive<f32> = 20.0..=999.0;
+/// Frame counts of steps, grooves and song pattern slots. Longer steps would overflow step lengths with a BPM.
+const FRAMES_PER_STEP_RANGE: RangeInclusive<u32> = 1..=255;
+
+fn transpose_note(note: u8, transpose: i8) -> u8 {
+    // Note 0 is used by steps without a press.
+    (note as i16 + transpose as i16).clamp(1, 127) as u8
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SequencerSong {
-    song_patterns: Vec<usize>,
+    song_patterns: Vec<SongPattern>,
     patterns: Vec<Pattern>,
     frames_per_step: u32,
+    /// The number of frames of each step, repeated over the steps of every pattern,
+    /// e.g. [8, 6] to swing every other step. Steps last frames_per_step frames if empty.
+    groove: Vec<u32>,
+    /// Sets the tempo instead of frames_per_step when set, grooves are then scaled from frames_per_step.
+    bpm: Option<f32>,
+    /// The song pattern that song playback continues from after the last one.
+    loop_start: usize,
+    /// Stops song playback after the last song pattern instead of looping.
+    stop_at_end: bool,
+    /// Instruments that play their notes as-is in transposed song patterns, like drums.
+    untransposed_instruments: Vec<String>,
     #[serde(skip)]
     #[cfg(feature = "desktop")]
     markdown_header: String,
@@ -435,13 +830,13 @@ impl Default for SequencerSong {
     fn default() -> Self {
         SequencerSong {
             song_patterns: Vec::new(),
-            patterns: vec![
-                Pattern {
-                    instruments: Vec::new(),
-                };
-                NUM_PATTERNS
-            ],
+            patterns: vec![Pattern::default(); NUM_PATTERNS],
             frames_per_step: 7,
+            groove: Vec::new(),
+            bpm: None,
+            loop_start: 0,
+            stop_at_end: false,
+            untransposed_instruments: Vec::new(),
             #[cfg(feature = "desktop")]
             markdown_header: String::new(),
             #[cfg(feature = "desktop")]
@@ -450,9 +845,222 @@ impl Default for SequencerSong {
     }
 }
 
+/// Starts postcard songs, followed by the layout version. Songs saved before don't have it,
+/// and can't start with these bytes since they'd reference pattern 84.
+const POSTCARD_SONG_MAGIC: &[u8] = b"CTSG";
+const POSTCARD_SONG_VERSION: u8 = 1;
+
+impl SequencerSong {
+    #[cfg(not(target_arch = "wasm32"))]
+    fn to_postcard_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
+        let mut header = POSTCARD_SONG_MAGIC.to_vec();
+        header.push(POSTCARD_SONG_VERSION);
+        postcard::to_extend(self, header)
+    }
+
+    fn from_postcard_bytes(bytes: &[u8]) -> Result<SequencerSong, postcard::Error> {
+        match bytes.strip_prefix(POSTCARD_SONG_MAGIC) {
+            Some([POSTCARD_SONG_VERSION, song @ ..]) => from_bytes::<SequencerSong>(song),
+            Some(_) => Err(postcard::Error::DeserializeBadEncoding),
+            None => from_bytes::<FixedLengthSong>(bytes).map(SequencerSong::from),
+        }
+    }
+
+    /// The frames unit of a BPM tempo, so that step lengths remain integers in step_length.
+    fn bpm_unit(&self) -> u64 {
+        match self.bpm {
+            // Round without f32::round, which isn't available without std.
+            Some(bpm) => FRAME_CYCLES * ((bpm * 100.0 + 0.5) as u64).max(1),
+            None => 1,
+        }
+    }
+
+    /// Step lengths are given in frames divided by this.
+    fn step_length_denominator(&self) -> u64 {
+        self.frames_per_step as u64 * self.bpm_unit()
+    }
+
+    /// The exact length of a step of a song pattern following its groove and tempo, in frames
+    /// multiplied by step_length_denominator.
+    fn step_length(&self, song_pattern: &SongPattern, step: usize) -> u64 {
+        let groove = self.patterns[song_pattern.pattern]
+            .groove
+            .as_ref()
+            .unwrap_or(&self.groove);
+        let frames = if groove.is_empty() {
+            self.frames_per_step
+        } else {
+            groove[step % groove.len()]
+        };
+        // Grooves are scaled from frames_per_step to the slot's or song's tempo.
+        frames as u64
+            * match (song_pattern.frames_per_step, self.bpm) {
+                (Some(frames_per_step), _) => frames_per_step as u64 * self.bpm_unit(),
+                (None, Some(_)) => BPM_STEP_LENGTH,
+                (None, None) => self.frames_per_step as u64,
+            }
+    }
+
+    /// The number of frames that a step of a song pattern lasts, see StepClock.
+    fn step_frames(&self, song_pattern: &SongPattern, step: usize, clock: &mut StepClock) -> u32 {
+        let length = clock.remainder + self.step_length(song_pattern, step);
+        let denominator = self.step_length_denominator();
+        clock.remainder = length % denominator;
+        ((length / denominator) as u32).max(1)
+    }
+
+    /// The number of frames needed to play every step of a song pattern once.
+    #[cfg(feature = "desktop_native")]
+    fn song_pattern_frames(&self, song_pattern: &SongPattern, clock: &mut StepClock) -> u32 {
+        (0..self.patterns[song_pattern.pattern].num_steps)
+            .map(|s| self.step_frames(song_pattern, s, clock))
+            .sum()
+    }
+}
+
+/// The number of CPU cycles of a frame, at 4194304 Hz.
+const FRAME_CYCLES: u64 = 70224;
+/// The length of a step at 1 BPM with 4 steps per beat, in frames multiplied by FRAME_CYCLES and 100,
+/// since SequencerSong::bpm_unit keeps two decimals of the BPM.
+const BPM_STEP_LENGTH: u64 = 60 * 4194304 / 4 * 100;
+
+/// Rounds the exact length of consecutive steps to whole frames, carrying the remaining fraction of a
+/// frame to the next step so that each step starts on the frame nearest to its exact start.
+/// This keeps the average tempo exact when a BPM doesn't give a whole number of frames per step.
+#[derive(Clone, Copy, Debug)]
+struct StepClock {
+    remainder: u64,
+}
+
+impl StepClock {
+    fn new(song: &SequencerSong) -> Self {
+        StepClock {
+            remainder: song.step_length_denominator() / 2,
+        }
+    }
+}
+
+/// Parses a groove written as the number of frames of each step separated by slashes, e.g. 8/6.
+/// An empty string gives an empty groove.
+pub fn parse_groove(groove: &str) -> Result<Vec<u32>, String> {
+    if groove.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    groove
+        .split('/')
+        .map(|frames| match frames.trim().parse::<u32>() {
+            Ok(frames) if FRAMES_PER_STEP_RANGE.contains(&frames) => Ok(frames),
+            _ => Err(format!(
+                "Invalid groove [{}], expected frame counts from {} to {} like 8/6",
+                groove,
+                FRAMES_PER_STEP_RANGE.start(),
+                FRAMES_PER_STEP_RANGE.end()
+            )),
+        })
+        .collect()
+}
+
+pub fn groove_to_string(groove: &[u32]) -> String {
+    groove
+        .iter()
+        .map(|frames| frames.to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The postcard layout of songs saved before patterns had a number of steps, when they all had 16.
+#[derive(Deserialize)]
+struct FixedLengthInstrument {
+    id: String,
+    steps: [InstrumentStep; DEFAULT_NUM_STEPS],
+}
+
+#[derive(Deserialize)]
+struct FixedLengthPattern {
+    instruments: Vec<FixedLengthInstrument>,
+}
+
+#[derive(Deserialize)]
+struct FixedLengthSong {
+    song_patterns: Vec<usize>,
+    patterns: Vec<FixedLengthPattern>,
+    frames_per_step: u32,
+}
+
+impl From<FixedLengthSong> for SequencerSong {
+    fn from(song: FixedLengthSong) -> Self {
+        let patterns = song
+            .patterns
+            .into_iter()
+            .map(|p| Pattern {
+                num_steps: DEFAULT_NUM_STEPS,
+                instruments: p
+                    .instruments
+                    .into_iter()
+                    .map(|i| Instrument {
+                        id: i.id,
+                        synth_index: None,
+                        steps: i.steps.to_vec(),
+                    })
+                    .collect(),
+                groove: None,
+            })
+            .collect();
+        SequencerSong {
+            song_patterns: song.song_patterns.into_iter().map(SongPattern::new).collect(),
+            patterns,
+            frames_per_step: song.frames_per_step,
+            ..Default::default()
+        }
+    }
+}
+
+#[test]
+fn postcard_fixed_length_song() -> Result<(), Box<dyn Error>> {
+    // A song with one pattern where instrument "a" plays C5 on the first of its 16 steps.
+    let mut bytes = vec![1, 0, 1, 1, 1, b'a', 0x80 | 60, 0xf0];
+    bytes.extend([0; 15 * 2]);
+    bytes.push(7);
+
+    let song = SequencerSong::from_postcard_bytes(&bytes)?;
+    assert_eq!(song.song_patterns, [SongPattern::new(0)]);
+    assert_eq!(song.patterns[0].num_steps(), DEFAULT_NUM_STEPS);
+    let steps = song.patterns[0].instruments[0].steps();
+    assert_eq!(steps.len(), DEFAULT_NUM_STEPS);
+    assert_eq!(steps[0].press_note(), Some(60));
+    assert_eq!(steps[0].release_pos(), ReleasePos::Full);
+    assert_eq!(song.frames_per_step, 7);
+
+    let reloaded = SequencerSong::from_postcard_bytes(&song.to_postcard_bytes()?)?;
+    assert_eq!(reloaded.patterns[0].instruments, song.patterns[0].instruments);
+    Ok(())
+}
+
+#[test]
+fn postcard_groove() -> Result<(), Box<dyn Error>> {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    song.groove = vec![8, 6];
+    song.patterns[1].groove = Some(vec![5]);
+
+    let reloaded = SequencerSong::from_postcard_bytes(&song.to_postcard_bytes()?)?;
+    assert_eq!(reloaded.groove, [8, 6]);
+    assert_eq!(reloaded.patterns[0].groove, None);
+    assert_eq!(reloaded.patterns[1].groove, Some(vec![5]));
+    let mut clock = StepClock::new(&reloaded);
+    assert_eq!(reloaded.step_frames(&SongPattern::new(0), 3, &mut clock), 6);
+    assert_eq!(reloaded.step_frames(&SongPattern::new(1), 3, &mut clock), 5);
+
+    assert!(matches!(
+        SequencerSong::from_postcard_bytes(b"CTSG\x02"),
+        Err(postcard::Error::DeserializeBadEncoding)
+    ));
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct InstrumentParamDef {
-    pub name: SharedString,
+    pub name: String,
     pub default: i8,
     pub min: i8,
     pub max: i8,
@@ -474,6 +1082,20 @@ impl Default for InstrumentParamDef {
     }
 }
 
+/// The song-wide settings that the UI edits.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SongSettings {
+    pub frames_per_step: u32,
+    /// Frames of consecutive steps, like 8/6, or empty to use frames_per_step for every step.
+    pub groove: String,
+    /// Sets the tempo instead of frames_per_step when above 0.
+    pub bpm: f32,
+    /// The song pattern that playback continues from after the last one.
+    pub loop_start: usize,
+    /// Stops playback after the last song pattern instead of looping.
+    pub stop_at_end: bool,
+}
+
 struct NoteClipboard {
     note: u8,
     release: ReleasePos,
@@ -485,6 +1107,23 @@ enum SelectionClipboard {
     WholeSteps(Vec<InstrumentStep>),
 }
 
+/// How many edits can be undone, older ones are forgotten.
+const MAX_UNDO_GROUPS: usize = 32;
+
+/// The state of the song before an edit, to be put back on undo.
+enum UndoEntry {
+    Pattern(usize, Pattern),
+    /// The song pattern slots, without the stub.
+    SongPatterns(Vec<SongPattern>),
+}
+
+fn push_undo_group(stack: &mut Vec<Vec<UndoEntry>>, group: Vec<UndoEntry>) {
+    if stack.len() >= MAX_UNDO_GROUPS {
+        stack.remove(0);
+    }
+    stack.push(group);
+}
+
 #[derive(PartialEq)]
 pub enum OnEmpty {
     PasteOnEmpty,
@@ -493,7 +1132,15 @@ pub enum OnEmpty {
 
 pub struct Sequencer {
     pub song: SequencerSong,
+    /// The songs of the project, which share its instruments. The selected one is moved
+    /// into `song` while it's being edited and its place here is left empty.
+    song_bank: Vec<SequencerSong>,
+    bank_song: usize,
     active_frame: Option<u32>,
+    /// The frame within the active step, which lasts for active_step_frames.
+    active_step_frame: u32,
+    active_step_frames: u32,
+    step_clock: StepClock,
     active_step: usize,
     active_song_pattern: usize,
     /// Keeps track of which instrument was explicitly selected by the user and not snapped to during song playback.
@@ -503,15 +1150,24 @@ pub struct Sequencer {
     playing: bool,
     play_song_mode: bool,
     recording: bool,
+    /// Whether the UI selection follows playback, in which case activated song patterns are also displayed.
+    pin_selection_to_active: bool,
     erasing: bool,
     has_stub_pattern: bool,
     next_cycle_song_pattern_start_can_have_new: bool,
     pub received_instruments_ids_after_load: bool,
     last_press_frame: Option<u32>,
     just_recorded_over_next_step: bool,
+    /// How many times each song pattern started playing since playback started, for step conditions.
+    song_pattern_plays: Vec<u32>,
+    step_rng: StepRng,
+    /// Instruments whose condition on the active step wasn't met, to also skip its release.
+    unmet_condition_instruments: BTreeSet<u8>,
+    /// Instruments whose delayed step releases its note during the active step, at the given frame.
+    delayed_releases: Vec<(u8, u32)>,
     // FIXME: Use a bitset
     muted_instruments: BTreeSet<u8>,
-    synth_instrument_ids: Vec<SharedString>,
+    synth_instrument_ids: Vec<String>,
     synth_instrument_param_defs: Vec<[Option<InstrumentParamDef>; NUM_INSTRUMENT_PARAMS]>,
     /// Current instrument parameters used for recording.
     instrument_params: Vec<[Option<i8>; NUM_INSTRUMENT_PARAMS]>,
@@ -520,14 +1176,25 @@ pub struct Sequencer {
     /// The pattern that will be used when a new song pattern slot is added.
     default_song_pattern_clipboard: usize,
     selection_clipboard: SelectionClipboard,
-    main_window: WeakWindowWrapper,
+    /// Each group holds the entries of one edit, or of a whole recording pass.
+    undo_stack: Vec<Vec<UndoEntry>>,
+    redo_stack: Vec<Vec<UndoEntry>>,
+    /// While set, edits are added to this group instead of getting their own.
+    undo_group: Option<Vec<UndoEntry>>,
+    undo_group_is_live: bool,
+    observer: Rc<dyn EngineObserver>,
 }
 
 impl Sequencer {
-    pub fn new(main_window: WeakWindowWrapper) -> Sequencer {
+    pub fn new(observer: Rc<dyn EngineObserver>) -> Sequencer {
         Sequencer {
             song: Default::default(),
+            song_bank: vec![Default::default()],
+            bank_song: 0,
             active_frame: None,
+            active_step_frame: 0,
+            active_step_frames: 0,
+            step_clock: StepClock { remainder: 0 },
             active_step: 0,
             active_song_pattern: 0,
             user_displayed_instrument: 0,
@@ -536,23 +1203,32 @@ impl Sequencer {
             playing: false,
             play_song_mode: false,
             recording: true,
+            pin_selection_to_active: false,
             erasing: false,
             has_stub_pattern: false,
             next_cycle_song_pattern_start_can_have_new: false,
             received_instruments_ids_after_load: false,
             last_press_frame: None,
             just_recorded_over_next_step: false,
+            song_pattern_plays: Vec::new(),
+            step_rng: StepRng::new(),
+            unmet_condition_instruments: BTreeSet::new(),
+            delayed_releases: Vec::new(),
             muted_instruments: BTreeSet::new(),
-            synth_instrument_ids: vec![SharedString::new(); NUM_INSTRUMENTS],
-            synth_instrument_param_defs: vec![[None, None]; NUM_INSTRUMENTS],
+            synth_instrument_ids: vec![String::new(); NUM_INSTRUMENTS],
+            synth_instrument_param_defs: vec![Default::default(); NUM_INSTRUMENTS],
             instrument_params: vec![[None; NUM_INSTRUMENT_PARAMS]; NUM_INSTRUMENTS],
             default_note_clipboard: NoteClipboard {
                 note: DEFAULT_NOTE,
                 release: ReleasePos::Full,
             },
             default_song_pattern_clipboard: 0,
-            main_window: main_window.clone(),
+            observer,
             selection_clipboard: SelectionClipboard::Empty,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            undo_group: None,
+            undo_group_is_live: false,
         }
     }
 
@@ -562,7 +1238,7 @@ impl Sequencer {
 
     /// Pattern number in a given `song_pattern_idx`.
     fn pattern_idx(&self, song_pattern_idx: usize) -> usize {
-        self.song.song_patterns[song_pattern_idx]
+        self.song.song_patterns[song_pattern_idx].pattern
     }
 
     /// The pattern number of the active song pattern.
@@ -575,71 +1251,80 @@ impl Sequencer {
         self.pattern_idx(self.displayed_song_pattern)
     }
 
-    pub fn activate_song_pattern(&mut self, song_pattern: usize, with_nearest_instrument: bool) {
-        self.active_song_pattern = song_pattern;
+    /// Sets the number of frames of the active step, once it starts playing.
+    fn update_active_step_frames(&mut self) {
+        self.active_step_frames = match self.song.song_patterns.get(self.active_song_pattern) {
+            Some(sp) => self.song.step_frames(sp, self.active_step, &mut self.step_clock),
+            None => self.song.frames_per_step,
+        };
+    }
 
-        #[cfg(not(feature = "gba"))]
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let engine = GlobalEngine::get(&handle);
-                let ui = GlobalUI::get(&handle);
-
-                engine.set_sequencer_song_pattern_active(song_pattern as i32);
-                if ui.get_playing() && ui.get_pin_selection_to_active() {
-                    if ui.get_recording() || !with_nearest_instrument {
-                        // When recording, keep displaying the current instrument to allow recording
-                        // an instrument over patterns that don't have notes for it yet.
-                        engine.invoke_display_song_pattern(song_pattern as i32);
-                    } else {
-                        engine.invoke_display_song_pattern_with_nearest_instrument(song_pattern as i32);
-                    }
-                }
-            })
-            .unwrap();
+    /// The number of steps of the active song pattern.
+    fn active_num_steps(&self) -> usize {
+        // The song might not have any song pattern yet while it's being set.
+        self.song
+            .song_patterns
+            .get(self.active_song_pattern)
+            .map_or(DEFAULT_NUM_STEPS, |sp| self.song.patterns[sp.pattern].num_steps())
+    }
 
-        // There is currently no event loop on the GBA and the UI lives on a separate thread
-        // on the desktop. So the implementation needs to be separate to avoid the reentrancy
-        // caused by the UI callback invoking the GlobalEngine, requiring a RefCell that
-        // is already held.
-        #[cfg(feature = "gba")]
-        {
-            let should_display = self.main_window.run_direct(move |handle| {
-                GlobalEngine::get(&handle).set_sequencer_song_pattern_active(song_pattern as i32);
-                let ui = GlobalUI::get(&handle);
-                ui.get_playing() && ui.get_pin_selection_to_active()
-            });
-            if should_display {
-                // TODO: No recording yet happen on the GBA, always use the nearest instrument when requested instead of tweaking self.recording.
-                if !with_nearest_instrument {
-                    // When recording, keep displaying the current instrument to allow recording
-                    // an instrument over patterns that don't have notes for it yet.
-                    self.display_song_pattern(song_pattern);
-                } else {
-                    self.display_song_pattern_with_nearest_instrument(song_pattern);
-                }
+    pub fn activate_song_pattern(&mut self, song_pattern: usize, with_nearest_instrument: bool) {
+        self.active_song_pattern = song_pattern;
+        // The new pattern might be shorter, the UI also moves its selection in that case.
+        self.active_step = self.active_step.min(self.active_num_steps() - 1);
+
+        self.observer.song_pattern_activated(song_pattern);
+        if self.playing && self.pin_selection_to_active {
+            // TODO: No recording yet happen on the GBA, always use the nearest instrument when requested there.
+            let recording = self.recording && !cfg!(feature = "gba");
+            if recording || !with_nearest_instrument {
+                // When recording, keep displaying the current instrument to allow recording
+                // an instrument over patterns that don't have notes for it yet.
+                self.display_song_pattern(song_pattern);
+            } else {
+                self.display_song_pattern_with_nearest_instrument(song_pattern);
             }
         }
     }
 
     pub fn activate_step(&mut self, step: usize) {
-        self.active_step = step;
+        self.active_step = step.min(self.active_num_steps() - 1);
 
         let active_step = if self.active_song_pattern == self.displayed_song_pattern {
-            step as i32
+            self.active_step as i32
         } else {
             -1
         };
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                GlobalEngine::get(&handle).set_sequencer_step_active(active_step);
-                GlobalUI::get(&handle).invoke_update_selected_step();
-            })
-            .unwrap();
+        self.observer.step_activated(active_step);
     }
 
     pub fn apply_song_settings(&mut self, settings: &SongSettings) {
-        self.song.frames_per_step = settings.frames_per_step as u32;
+        self.song.frames_per_step = settings
+            .frames_per_step
+            .clamp(*FRAMES_PER_STEP_RANGE.start(), *FRAMES_PER_STEP_RANGE.end());
+        match parse_groove(&settings.groove) {
+            Ok(groove) => self.song.groove = groove,
+            Err(e) => elog!("{}", e),
+        }
+        // 0 turns BPM tempo off.
+        self.song.bpm = Some(settings.bpm)
+            .filter(|bpm| *bpm > 0.0)
+            .map(|bpm| bpm.clamp(*BPM_RANGE.start(), *BPM_RANGE.end()));
+        self.song.loop_start = settings.loop_start;
+        self.song.stop_at_end = settings.stop_at_end;
+        // The fraction of a frame carried by the clock depends on the tempo.
+        self.step_clock = StepClock::new(&self.song);
+    }
+
+    fn song_settings(&self) -> SongSettings {
+        SongSettings {
+            frames_per_step: self.song.frames_per_step,
+            groove: groove_to_string(&self.song.groove),
+            bpm: self.song.bpm.unwrap_or(0.0),
+            loop_start: self.song.loop_start,
+            stop_at_end: self.song.stop_at_end,
+        }
     }
 
     pub fn display_song_pattern_with_nearest_instrument(&mut self, song_pattern: usize) {
@@ -666,19 +1351,7 @@ impl Sequencer {
         let prev_selected = self.displayed_song_pattern;
         self.displayed_song_pattern = song_pattern;
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                GlobalEngine::get(&handle).set_sequencer_song_pattern_selected(song_pattern as i32);
-                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
-                if let Some(mut row_data) = model.row_data(prev_selected) {
-                    row_data.selected = false;
-                    model.set_row_data(prev_selected, row_data);
-                }
-                let mut row_data = model.row_data(song_pattern).unwrap();
-                row_data.selected = true;
-                model.set_row_data(song_pattern, row_data);
-            })
-            .unwrap();
+        self.observer.song_pattern_displayed(song_pattern, prev_selected);
 
         self.update_steps();
     }
@@ -695,30 +1368,10 @@ impl Sequencer {
     pub fn display_instrument_but_do_not_update_steps(&mut self, instrument: u8) {
         self.displayed_instrument = instrument;
 
-        let param_0 = self.synth_instrument_param_defs[instrument as usize][0]
-            .as_ref()
-            .map(|def| ParamData {
-                name: def.name.clone(),
-                defined: true,
-            })
-            .unwrap_or_default();
-        let param_1 = self.synth_instrument_param_defs[instrument as usize][1]
-            .as_ref()
-            .map(|def| ParamData {
-                name: def.name.clone(),
-                defined: true,
-            })
-            .unwrap_or_default();
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let engine = &GlobalEngine::get(&handle);
-                engine.set_displayed_instrument(instrument as i32);
-                engine.set_instrument_param_0(param_0);
-                engine.set_instrument_param_1(param_1);
-
-                GlobalUI::get(&handle).invoke_adjust_user_selected_column();
-            })
-            .unwrap();
+        let param_names = self.synth_instrument_param_defs[instrument as usize]
+            .each_ref()
+            .map(|maybe_def| maybe_def.as_ref().map(|def| def.name.as_str()));
+        self.observer.instrument_displayed(instrument, param_names);
     }
 
     pub fn cycle_instrument(&mut self, col_delta: i32, row_delta: i32) {
@@ -730,11 +1383,10 @@ impl Sequencer {
 
         // When cycling from the instrument screen, also unpin to avoid going to the nearest instrument
         // on the next pattern change.
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                GlobalUI::get(&handle).invoke_disable_pin_selection_to_active_if_playing();
-            })
-            .unwrap();
+        if self.playing {
+            self.pin_selection_to_active = false;
+        }
+        self.observer.instrument_selection_unpinned();
     }
 
     pub fn cycle_pattern_instrument(&mut self, forward: bool) {
@@ -751,19 +1403,12 @@ impl Sequencer {
             self.muted_instruments.insert(instrument);
         }
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = GlobalEngine::get(&handle).get_instruments();
-                let mut row_data = model.row_data(instrument as usize).unwrap();
-                row_data.muted = !was_muted;
-                model.set_row_data(instrument as usize, row_data);
-            })
-            .unwrap();
+        self.observer.instrument_muted(instrument, !was_muted);
     }
 
     fn update_steps(&mut self) {
         let pattern = &self.song.patterns[self.displayed_pattern_idx()];
-        let maybe_steps = pattern.get_steps(self.displayed_instrument).copied();
+        let maybe_steps = pattern.get_steps(self.displayed_instrument);
 
         let active_step = if self.active_song_pattern == self.displayed_song_pattern {
             self.active_step as i32
@@ -771,12 +1416,7 @@ impl Sequencer {
             -1
         };
 
-        // Don't clone the pattern instruments if the closure will be handled synchronously.
-        #[cfg(not(feature = "std"))]
         let instruments = pattern.instruments();
-        #[cfg(feature = "std")]
-        let instruments = pattern.instruments().clone();
-
         let (instruments_to_skip, instruments_len) =
             match pattern.find_nearest_instrument(self.displayed_instrument, true) {
                 // Advance once more to not show the found instrument both in the patterns and pattern_instruments models
@@ -785,63 +1425,19 @@ impl Sequencer {
                 None => (0, 0),
             };
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = GlobalEngine::get(&handle).get_sequencer_steps();
-                for (i, step) in maybe_steps.unwrap_or_default().iter().enumerate() {
-                    let mut row_data = model.row_data(i).unwrap();
-                    row_data.press = step.press();
-                    row_data.release_pos = step.release_pos.to_ui();
-                    row_data.note = step.press_note().unwrap_or(0) as i32;
-                    match step.param0 {
-                        Some(v) => {
-                            row_data.param0_set = true;
-                            row_data.param0_val = v as i32;
-                        }
-                        None => row_data.param0_set = false,
-                    }
-                    match step.param1 {
-                        Some(v) => {
-                            row_data.param1_set = true;
-                            row_data.param1_val = v as i32;
-                        }
-                        None => row_data.param1_set = false,
-                    }
-
-                    model.set_row_data(i, row_data);
-                }
-
-                GlobalEngine::get(&handle).set_sequencer_step_active(active_step);
-
-                let model2 = GlobalEngine::get(&handle).get_sequencer_pattern_instruments();
-                let len = instruments_len.min(model2.row_count());
-                GlobalEngine::get(&handle).set_sequencer_pattern_instruments_len(len as i32);
-
-                instruments
-                    .iter()
-                    .cycle()
-                    .skip(instruments_to_skip)
-                    .take(len)
-                    .enumerate()
-                    .for_each(|(idx, mi)| {
-                        let mut instrument_data = model2.row_data(idx).unwrap();
-
-                        let notes_model = &instrument_data.notes;
-                        mi.steps.iter().enumerate().for_each(|(idx, s)| {
-                            notes_model.set_row_data(idx, s.press_note().map_or(-1, |n| n as i32));
-                        });
-
-                        instrument_data.id = (&mi.id).into();
-                        instrument_data.synth_index = mi.synth_index.map_or(-1, |i| i as i32);
-                        model2.set_row_data(idx, instrument_data);
-                    });
-            })
-            .unwrap();
+        self.observer.steps_changed(
+            maybe_steps,
+            pattern.num_steps(),
+            active_step,
+            instruments,
+            instruments_to_skip,
+            instruments_len,
+        );
     }
 
     pub fn toggle_step(&mut self, step: usize) {
         let maybe_steps = self.song.patterns[self.displayed_pattern_idx()].get_steps(self.displayed_instrument);
-        let pressed = maybe_steps.map_or(false, |ss| ss[step].press());
+        let pressed = maybe_steps.and_then(|ss| ss.get(step)).map_or(false, |s| s.press());
         if pressed {
             self.cut_step_range_note(step, step);
         } else {
@@ -851,8 +1447,9 @@ impl Sequencer {
 
     pub fn set_default_step_note(&mut self, step: usize) {
         let maybe_steps = self.song.patterns[self.displayed_pattern_idx()].get_steps(self.displayed_instrument);
-        let pressed_note_and_release =
-            maybe_steps.and_then(|ss| ss[step].press_note().map(|p| (p, ss[step].release_pos)));
+        let pressed_note_and_release = maybe_steps
+            .and_then(|ss| ss.get(step))
+            .and_then(|s| s.press_note().map(|p| (p, s.release_pos)));
         if let Some((note, release)) = pressed_note_and_release {
             self.default_note_clipboard = NoteClipboard { note, release };
         }
@@ -861,27 +1458,34 @@ impl Sequencer {
     pub fn copy_step_range_note(&mut self, step_range_first: usize, step_range_last: usize) {
         let maybe_steps = self.song.patterns[self.displayed_pattern_idx()].get_steps(self.displayed_instrument);
 
-        self.selection_clipboard = SelectionClipboard::WholeSteps(maybe_steps.map_or_else(
-            || vec![InstrumentStep::default(); step_range_last - step_range_first + 1],
-            |ss| ss[step_range_first..=step_range_last].to_vec(),
-        ));
+        self.selection_clipboard = SelectionClipboard::WholeSteps(
+            maybe_steps
+                .and_then(|ss| ss.get(step_range_first..=step_range_last))
+                .map_or_else(
+                    || vec![InstrumentStep::default(); step_range_last - step_range_first + 1],
+                    |ss| ss.to_vec(),
+                ),
+        );
     }
 
     pub fn cut_step_range_note(&mut self, step_range_first: usize, step_range_last: usize) {
         let displayed_pattern_idx = self.displayed_pattern_idx();
+        self.save_pattern_for_undo(displayed_pattern_idx);
         let pattern = &mut self.song.patterns[displayed_pattern_idx];
         let mut maybe_steps = pattern.get_steps_mut(self.displayed_instrument);
-        let steps = maybe_steps.as_mut().map_or_else(
-            || vec![InstrumentStep::default(); step_range_last - step_range_first + 1],
-            |ss| {
-                let slice = &mut ss[step_range_first..=step_range_last];
-                // Take a copy
-                let r = slice.to_vec();
-                // Replace the cut steps with an empty step
-                slice.fill(InstrumentStep::default());
-                r
-            },
-        );
+        let steps = maybe_steps
+            .as_mut()
+            .and_then(|ss| ss.get_mut(step_range_first..=step_range_last))
+            .map_or_else(
+                || vec![InstrumentStep::default(); step_range_last - step_range_first + 1],
+                |slice| {
+                    // Take a copy
+                    let r = slice.to_vec();
+                    // Replace the cut steps with an empty step
+                    slice.fill(InstrumentStep::default());
+                    r
+                },
+            );
         if maybe_steps.map_or(false, |ss| ss.iter().all(|s| s.is_empty())) {
             pattern.remove_instrument(self.displayed_instrument);
         }
@@ -900,7 +1504,7 @@ impl Sequencer {
             self.displayed_song_pattern,
             Some(None),
             Some(ReleasePos::NotReleased),
-            Some((None, None)),
+            Some([None; NUM_INSTRUMENT_PARAMS]),
         );
 
         if !cut_step.is_empty() {
@@ -909,10 +1513,7 @@ impl Sequencer {
     }
 
     pub fn paste_step_range_note(&mut self, at_step: usize) {
-        // If editing the stub pattern, commit it to a real pattern now.
-        if self.has_stub_pattern {
-            self.commit_stub_song_pattern();
-        }
+        self.commit_stub_and_save_pattern_for_undo(self.displayed_song_pattern);
 
         if let SelectionClipboard::WholeSteps(clip_steps) = &self.selection_clipboard {
             let instrument_id = &self.synth_instrument_ids[self.displayed_instrument as usize];
@@ -924,13 +1525,11 @@ impl Sequencer {
             for (i, step) in clip_steps.iter().enumerate() {
                 let mut copy = *step;
                 // Clamp the parameters to the destination parameter definition min and max.
-                copy.param0 = param_defs[0]
-                    .as_ref()
-                    .and_then(|def| copy.param0.map(|v| v.clamp(def.min, def.max)));
-                copy.param1 = param_defs[1]
-                    .as_ref()
-                    .and_then(|def| copy.param1.map(|v| v.clamp(def.min, def.max)));
-                steps[(at_step + i) % NUM_STEPS] = copy;
+                for (param, def) in copy.params.iter_mut().zip(param_defs) {
+                    *param = def.as_ref().and_then(|def| param.map(|v| v.clamp(def.min, def.max)));
+                }
+                let num_steps = steps.len();
+                steps[(at_step + i) % num_steps] = copy;
             }
 
             self.update_steps();
@@ -939,7 +1538,10 @@ impl Sequencer {
 
     pub fn toggle_step_release(&mut self, step: usize) {
         let maybe_steps = self.song.patterns[self.active_pattern_idx()].get_steps(self.displayed_instrument);
-        let toggled = match maybe_steps.map_or(ReleasePos::NotReleased, |ss| ss[step].release_pos) {
+        let toggled = match maybe_steps
+            .and_then(|ss| ss.get(step))
+            .map_or(ReleasePos::NotReleased, |s| s.release_pos)
+        {
             ReleasePos::NotReleased => ReleasePos::Full,
             ReleasePos::Half => ReleasePos::NotReleased,
             ReleasePos::Full => ReleasePos::NotReleased,
@@ -952,13 +1554,19 @@ impl Sequencer {
     pub fn cycle_step_release(&mut self, step: usize, forward: bool) {
         let maybe_steps = self.song.patterns[self.active_pattern_idx()].get_steps(self.displayed_instrument);
         let toggled = if forward {
-            match maybe_steps.map_or(ReleasePos::NotReleased, |ss| ss[step].release_pos) {
+            match maybe_steps
+                .and_then(|ss| ss.get(step))
+                .map_or(ReleasePos::NotReleased, |s| s.release_pos)
+            {
                 ReleasePos::NotReleased => ReleasePos::Half,
                 ReleasePos::Half => ReleasePos::Full,
                 ReleasePos::Full => ReleasePos::NotReleased,
             }
         } else {
-            match maybe_steps.map_or(ReleasePos::NotReleased, |ss| ss[step].release_pos) {
+            match maybe_steps
+                .and_then(|ss| ss.get(step))
+                .map_or(ReleasePos::NotReleased, |s| s.release_pos)
+            {
                 ReleasePos::NotReleased => ReleasePos::Full,
                 ReleasePos::Half => ReleasePos::NotReleased,
                 ReleasePos::Full => ReleasePos::Half,
@@ -970,14 +1578,48 @@ impl Sequencer {
         self.set_default_step_note(step);
     }
 
+    pub fn cycle_step_delay(&mut self, step: usize, forward: bool) {
+        let song_pattern = self.displayed_song_pattern;
+        let frames_per_step = self
+            .song
+            .song_patterns
+            .get(song_pattern)
+            .and_then(|sp| sp.frames_per_step)
+            .unwrap_or(self.song.frames_per_step);
+        let max_delay = frames_per_step.saturating_sub(1).min(u8::MAX as u32) as u8;
+        let pattern = self.pattern_idx(song_pattern);
+        let delay = match self.song.patterns[pattern].get_steps(self.displayed_instrument) {
+            // Empty steps have nothing to delay.
+            Some(ss) => match ss.get(step) {
+                Some(s) if !s.is_empty() => s.delay,
+                _ => return,
+            },
+            None => return,
+        };
+        let cycled = if forward {
+            (delay + 1).min(max_delay)
+        } else {
+            delay.saturating_sub(1)
+        };
+        if cycled == delay {
+            return;
+        }
+
+        self.commit_stub_and_save_pattern_for_undo(song_pattern);
+        let pattern = self.pattern_idx(song_pattern);
+        if let Some(s) = self.song.patterns[pattern]
+            .get_steps_mut(self.displayed_instrument)
+            .and_then(|ss| ss.get_mut(step))
+        {
+            s.delay = cycled;
+        }
+        self.observer.step_delay_changed(step, cycled);
+    }
+
     fn advance_step(&mut self) {
         let next_step = if self.play_song_mode {
-            let (next_step, next_song_pattern) = Self::next_step_and_pattern_and_song_pattern(
-                true,
-                self.active_step,
-                self.active_song_pattern,
-                self.num_song_patterns(),
-            );
+            let (next_step, next_song_pattern) =
+                self.next_step_and_pattern_and_song_pattern(true, self.active_step, self.active_song_pattern);
 
             if next_song_pattern != self.active_song_pattern {
                 self.activate_song_pattern(next_song_pattern, true);
@@ -985,11 +1627,12 @@ impl Sequencer {
             next_step
         } else {
             // In pattern playback, continue playing from the displayed pattern if it changed.
-            if self.active_step == NUM_STEPS - 1 && self.displayed_song_pattern != self.active_song_pattern {
+            let num_steps = self.active_num_steps();
+            if self.active_step + 1 >= num_steps && self.displayed_song_pattern != self.active_song_pattern {
                 self.activate_song_pattern(self.displayed_song_pattern, true);
                 0
             } else {
-                (self.active_step + 1) % NUM_STEPS
+                (self.active_step + 1) % num_steps
             }
         };
 
@@ -1002,24 +1645,22 @@ impl Sequencer {
         song_pattern: usize,
         set_press_note: Option<Option<u8>>,
         set_release_pos: Option<ReleasePos>,
-        set_params: Option<(Option<i8>, Option<i8>)>,
+        set_params: Option<[Option<i8>; NUM_INSTRUMENT_PARAMS]>,
     ) -> InstrumentStep {
-        // If editing the stub pattern, commit it to a real pattern now.
-        if self.has_stub_pattern {
-            self.commit_stub_song_pattern();
+        // The UI might still show the steps of a longer pattern.
+        if step >= self.song.patterns[self.pattern_idx(song_pattern)].num_steps() {
+            return InstrumentStep::default();
         }
+        self.commit_stub_and_save_pattern_for_undo(song_pattern);
 
         // Filter out the params that are undefined so that they stay None in the song.
         // When a note is pressed, a param is always set to the clipboard or default value.
         // A param can also come from the clipboard of a different instrument.
         // It could make sense to try to be smart about this, but for now it's simpler to just allow the
         // user to set or paste anything and just remove it here when a target parameter is undefined.
-        let adjusted_set_params = set_params.map(|(p0, p1)| {
-            (
-                p0.filter(|_| self.synth_instrument_param_defs[self.displayed_instrument as usize][0].is_some()),
-                p1.filter(|_| self.synth_instrument_param_defs[self.displayed_instrument as usize][1].is_some()),
-            )
-        });
+        let param_defs = &self.synth_instrument_param_defs[self.displayed_instrument as usize];
+        let adjusted_set_params =
+            set_params.map(|params| core::array::from_fn(|i| params[i].filter(|_| param_defs[i].is_some())));
 
         let pattern = self.pattern_idx(song_pattern);
         let instrument_id = &self.synth_instrument_ids[self.displayed_instrument as usize];
@@ -1033,36 +1674,16 @@ impl Sequencer {
         );
 
         if song_pattern == self.displayed_song_pattern {
-            self.main_window
-                .upgrade_in_event_loop(move |handle| {
-                    let steps = GlobalEngine::get(&handle).get_sequencer_steps();
-                    let mut step_row_data = steps.row_data(step).unwrap();
-                    if let Some(maybe_note) = set_press_note {
-                        step_row_data.press = maybe_note.is_some();
-                        step_row_data.note = maybe_note.unwrap_or(0) as i32;
-                    }
-                    if let Some(release_pos) = set_release_pos {
-                        step_row_data.release_pos = release_pos.to_ui();
-                    }
-                    if let Some((param0, param1)) = adjusted_set_params {
-                        match param0 {
-                            Some(v) => {
-                                step_row_data.param0_set = true;
-                                step_row_data.param0_val = v as i32;
-                            }
-                            None => step_row_data.param0_set = false,
-                        }
-                        match param1 {
-                            Some(v) => {
-                                step_row_data.param1_set = true;
-                                step_row_data.param1_val = v as i32;
-                            }
-                            None => step_row_data.param1_set = false,
-                        }
-                    }
-                    steps.set_row_data(step, step_row_data);
-                })
-                .unwrap();
+            self.observer
+                .step_changed(step, set_press_note, set_release_pos, adjusted_set_params);
+            // Emptying the step also removes its delay.
+            if previous.delay > 0
+                && self.song.patterns[pattern]
+                    .get_steps(self.displayed_instrument)
+                    .map_or(true, |ss| ss[step].delay == 0)
+            {
+                self.observer.step_delay_changed(step, 0);
+            }
         }
 
         previous
@@ -1078,35 +1699,49 @@ impl Sequencer {
         // steps and that record_press would record any key while
         // stopped to the current frame and not the next.
         self.active_frame = None;
+        self.close_live_undo_group();
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                GlobalUI::get(&handle).set_playing(val);
-            })
-            .unwrap();
+        self.observer.playing_changed(val);
     }
     pub fn set_recording(&mut self, val: bool) {
         self.recording = val;
+        self.close_live_undo_group();
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                GlobalUI::get(&handle).set_recording(val);
-            })
-            .unwrap();
+        self.observer.recording_changed(val);
+    }
+    pub fn set_pin_selection_to_active(&mut self, val: bool) {
+        self.pin_selection_to_active = val;
     }
     pub fn set_erasing(&mut self, val: bool) {
         self.erasing = val;
         // Already remove the current step.
-        self.set_pattern_step_events(
-            self.active_step,
-            self.active_song_pattern,
-            Some(None),
-            Some(ReleasePos::NotReleased),
-            Some((None, None)),
-        );
+        self.group_live_undo(|s| {
+            s.set_pattern_step_events(
+                s.active_step,
+                s.active_song_pattern,
+                Some(None),
+                Some(ReleasePos::NotReleased),
+                Some([None; NUM_INSTRUMENT_PARAMS]),
+            )
+        });
+        if !val {
+            self.close_live_undo_group();
+        }
+    }
+
+    fn count_song_pattern_play(&mut self) {
+        let song_pattern = self.active_song_pattern;
+        if self.song_pattern_plays.len() <= song_pattern {
+            self.song_pattern_plays.resize(song_pattern + 1, 0);
+        }
+        self.song_pattern_plays[song_pattern] += 1;
     }
 
     fn handle_active_step_presses_and_params(&mut self, note_events: &mut Vec<(u8, StepEvent)>) {
+        let repeat = self
+            .song_pattern_plays
+            .get(self.active_song_pattern)
+            .map_or(0, |plays| plays.saturating_sub(1));
         for instrument in &self.song.patterns[self.active_pattern_idx()].instruments {
             let i = match instrument.synth_index {
                 Some(i) => i,
@@ -1118,55 +1753,57 @@ impl Sequencer {
             if self.muted_instruments.contains(&i) {
                 continue;
             }
+            let maybe_step = self.song.patterns[self.active_pattern_idx()]
+                .get_steps(i)
+                .map(|ss| ss[self.active_step]);
+            // Delayed steps start later than the first frame of the step.
+            if maybe_step.map_or(0, |s| s.delay_frames(self.active_step_frames)) != self.active_step_frame {
+                continue;
+            }
             if self.just_recorded_over_next_step && i == self.displayed_instrument {
                 self.just_recorded_over_next_step = false;
                 continue;
             }
 
-            if let Some(step) = self.song.patterns[self.active_pattern_idx()]
-                .get_steps(i)
-                .map(|ss| ss[self.active_step])
-            {
+            if let Some(step) = maybe_step {
+                if step.condition.map_or(false, |c| !c.is_met(repeat, &mut self.step_rng)) {
+                    self.unmet_condition_instruments.insert(i);
+                    continue;
+                }
                 if let Some(note) = step.press_note() {
-                    let param_defs = &self.synth_instrument_param_defs[i as usize];
-                    // Use the param's default if the sequencer didn't have any param set for that press
-                    // or pass DEFAULT_PARAM_VAL but in the latter case the instrument will normally
-                    // not care as it didn't define the parameter.
-                    let p0 = step
-                        .param0
-                        .or(param_defs[0].as_ref().map(|p| p.default))
-                        .unwrap_or(DEFAULT_PARAM_VAL);
-                    let p1 = step
-                        .param1
-                        .or(param_defs[1].as_ref().map(|p| p.default))
-                        .unwrap_or(DEFAULT_PARAM_VAL);
+                    // The press replaces the note that a delay kept playing from the previous step.
+                    self.delayed_releases.retain(|(released, _)| *released != i);
+                    let note = transpose_note(note, self.transpose(self.active_song_pattern, i));
+                    let params = self.params_or_default(i, step.params);
                     log!(
-                        "➕ PRS {} note {} params {} / {}",
+                        "➕ PRS {} note {} params {:?}",
                         self.synth_instrument_ids[i as usize],
                         MidiNote(note as i32).name(),
-                        p0,
-                        p1
+                        params
                     );
-                    note_events.push((i, StepEvent::Press(step.note, p0, p1)));
+                    note_events.push((i, StepEvent::Press(note, params)));
                 } else {
-                    if let Some(val) = step.param0 {
-                        log!("✖️ PAR {} param {} = {}", self.synth_instrument_ids[i as usize], 0, val);
-                        note_events.push((i, StepEvent::SetParam(0, val)));
-                    }
-                    if let Some(val) = step.param1 {
-                        log!("✖️ PAR {} param {} = {}", self.synth_instrument_ids[i as usize], 1, val);
-                        note_events.push((i, StepEvent::SetParam(1, val)));
+                    for (param_num, param) in step.params.iter().enumerate() {
+                        if let Some(val) = *param {
+                            log!(
+                                "✖️ PAR {} param {} = {}",
+                                self.synth_instrument_ids[i as usize],
+                                param_num,
+                                val
+                            );
+                            note_events.push((i, StepEvent::SetParam(param_num as u8, val)));
+                        }
                     }
                 }
             }
         }
     }
 
-    fn handle_active_step_releases(
-        &mut self,
-        executing_release_pos: ReleasePos,
-        note_events: &mut Vec<(u8, StepEvent)>,
-    ) {
+    /// Triggers the releases of the active step that are due at the active step frame.
+    /// At the end of the step, the releases that a delay pushes into the next step are kept in delayed_releases.
+    fn handle_active_step_releases(&mut self, step_end: bool, note_events: &mut Vec<(u8, StepEvent)>) {
+        let step_frames = self.active_step_frames;
+        let frame = if step_end { step_frames } else { self.active_step_frame };
         for instrument in &self.song.patterns[self.active_pattern_idx()].instruments {
             let i = match instrument.synth_index {
                 Some(i) => i,
@@ -1182,18 +1819,82 @@ impl Sequencer {
                 // Let the press loop further down reset the flag.
                 continue;
             }
-            if let Some(step) = self.song.patterns[self.active_pattern_idx()]
+            if self.unmet_condition_instruments.contains(&i) {
+                continue;
+            }
+            if let Some(release_frame) = self.song.patterns[self.active_pattern_idx()]
                 .get_steps(i)
-                .map(|ss| ss[self.active_step])
+                .and_then(|ss| ss[self.active_step].release_frame(step_frames))
             {
-                if step.release_pos == executing_release_pos {
+                if release_frame == frame {
                     log!("➖ REL {}", self.synth_instrument_ids[i as usize]);
                     note_events.push((i, StepEvent::Release));
+                } else if step_end && release_frame > frame {
+                    self.delayed_releases.push((i, release_frame - frame));
                 }
             }
         }
     }
 
+    /// Triggers the releases that a delay pushed from the previous step into the active one once they're due,
+    /// or all of them at the end of the active step if it was too short to reach them.
+    fn handle_delayed_releases(&mut self, step_end: bool, note_events: &mut Vec<(u8, StepEvent)>) {
+        let frame = self.active_step_frame;
+        let synth_instrument_ids = &self.synth_instrument_ids;
+        self.delayed_releases.retain(|&(i, release_frame)| {
+            let due = step_end || release_frame == frame;
+            if due {
+                log!("➖ REL {}", synth_instrument_ids[i as usize]);
+                note_events.push((i, StepEvent::Release));
+            }
+            !due
+        });
+    }
+
+    /// Presses ratcheted notes again when the active step frame reaches one of their hits.
+    fn handle_active_step_retriggers(&self, note_events: &mut Vec<(u8, StepEvent)>) {
+        for instrument in &self.song.patterns[self.active_pattern_idx()].instruments {
+            let i = match instrument.synth_index {
+                Some(i) => i,
+                None => {
+                    // instruments don't define it, ignore
+                    continue;
+                }
+            };
+            if self.muted_instruments.contains(&i) || self.unmet_condition_instruments.contains(&i) {
+                continue;
+            }
+            let step = instrument.steps[self.active_step];
+            if let Some(note) = step.press_note() {
+                if step
+                    .retrigger_frames(self.active_step_frames)
+                    .any(|frame| frame == self.active_step_frame)
+                {
+                    let note = transpose_note(note, self.transpose(self.active_song_pattern, i));
+                    let params = self.params_or_default(i, step.params);
+                    log!(
+                        "🔁 RTG {} note {}",
+                        self.synth_instrument_ids[i as usize],
+                        MidiNote(note as i32).name()
+                    );
+                    // Release first so that the instrument's release callback runs between hits.
+                    note_events.push((i, StepEvent::Release));
+                    note_events.push((i, StepEvent::Press(note, params)));
+                }
+            }
+        }
+    }
+
+    /// Releases every defined instrument, for when song playback reaches the end of the song.
+    fn release_all_instruments(&self, note_events: &mut Vec<(u8, StepEvent)>) {
+        for (i, id) in self.synth_instrument_ids.iter().enumerate() {
+            if !id.is_empty() && !self.muted_instruments.contains(&(i as u8)) {
+                log!("➖ REL {}", id);
+                note_events.push((i as u8, StepEvent::Release));
+            }
+        }
+    }
+
     pub fn advance_frame(&mut self) -> (Option<u32>, Vec<(u8, StepEvent)>) {
         let mut note_events: Vec<(u8, StepEvent)> = Vec::new();
 
@@ -1202,7 +1903,7 @@ impl Sequencer {
         }
 
         // Trigger events on frame transitions.
-        // So active_frame % frames_per_step going from (frames_per_step - 1) to 0 means transitioning from
+        // So active_step_frame going from (active_step_frames - 1) to 0 means transitioning from
         // the end of the previous step's last frame to the start of the next step's first frame.
         // The synth state is updated between frames and the PSG generates sound during that frame.
         let (next_frame, first_step) = match self.active_frame {
@@ -1210,33 +1911,57 @@ impl Sequencer {
             Some(last_frame) => (last_frame.wrapping_add(1), false),
         };
         self.active_frame = Some(next_frame);
+        self.active_step_frame = if first_step || self.active_step_frame + 1 >= self.active_step_frames {
+            0
+        } else {
+            self.active_step_frame + 1
+        };
 
-        if next_frame % self.song.frames_per_step == 0 {
-            if !first_step {
+        if self.active_step_frame == 0 {
+            if first_step {
+                self.step_clock = StepClock::new(&self.song);
+                self.song_pattern_plays.clear();
+                self.step_rng = StepRng::new();
+                self.delayed_releases.clear();
+                self.count_song_pattern_play();
+            } else {
                 // Release are at then end of a step, so start by triggering any release of the
                 // previous frame.
-                self.handle_active_step_releases(ReleasePos::Full, &mut note_events);
+                self.handle_delayed_releases(true, &mut note_events);
+                self.handle_active_step_releases(true, &mut note_events);
 
+                if self.play_song_mode && self.song.stop_at_end && self.is_last_song_step() {
+                    self.release_all_instruments(&mut note_events);
+                    self.set_playing(false, false);
+                    return (None, note_events);
+                }
                 self.advance_step();
+                if self.active_step == 0 {
+                    self.count_song_pattern_play();
+                }
                 if self.erasing {
-                    self.set_pattern_step_events(
-                        self.active_step,
-                        self.active_song_pattern,
-                        Some(None),
-                        Some(ReleasePos::NotReleased),
-                        Some((None, None)),
-                    );
+                    self.group_live_undo(|s| {
+                        s.set_pattern_step_events(
+                            s.active_step,
+                            s.active_song_pattern,
+                            Some(None),
+                            Some(ReleasePos::NotReleased),
+                            Some([None; NUM_INSTRUMENT_PARAMS]),
+                        )
+                    });
                 }
             }
+            self.update_active_step_frames();
+            self.unmet_condition_instruments.clear();
 
             self.handle_active_step_presses_and_params(&mut note_events);
             (Some(self.active_step as u32), note_events)
-        } else if next_frame % self.song.frames_per_step == self.song.frames_per_step.div_ceil(2) {
-            // Process half-step releases
-            // Use div_ceil to prefer having the note play half a frame longer instead of shorter.
-            self.handle_active_step_releases(ReleasePos::Half, &mut note_events);
-            (None, note_events)
         } else {
+            // Process half-step releases and the events of delayed steps.
+            self.handle_delayed_releases(false, &mut note_events);
+            self.handle_active_step_releases(false, &mut note_events);
+            self.handle_active_step_presses_and_params(&mut note_events);
+            self.handle_active_step_retriggers(&mut note_events);
             (None, note_events)
         }
     }
@@ -1245,7 +1970,12 @@ impl Sequencer {
         self.default_note_clipboard.note
     }
 
-    fn record_key_event(&mut self, event: KeyEvent, note: Option<u8>, params: Option<(Option<i8>, Option<i8>)>) {
+    fn record_key_event(
+        &mut self,
+        event: KeyEvent,
+        note: Option<u8>,
+        params: Option<[Option<i8>; NUM_INSTRUMENT_PARAMS]>,
+    ) {
         if !self.recording {
             return;
         }
@@ -1279,16 +2009,11 @@ impl Sequencer {
                     None,
                     // Try to clamp the event to the nearest frame.
                     // Use 4 instead of 3 just to try to compensate for the key press to visual and audible delay.
-                    if self.active_frame_or_zero() % self.song.frames_per_step < self.snap_at_step_frame() {
+                    if self.active_step_frame < self.snap_at_step_frame() {
                         (self.active_step, self.active_song_pattern)
                     } else {
                         self.just_recorded_over_next_step = true;
-                        Self::next_step_and_pattern_and_song_pattern(
-                            true,
-                            self.active_step,
-                            self.active_song_pattern,
-                            self.num_song_patterns(),
-                        )
+                        self.next_step_and_pattern_and_song_pattern(true, self.active_step, self.active_song_pattern)
                     },
                 )
             }
@@ -1299,27 +2024,30 @@ impl Sequencer {
                 // one step later just because the press would already have been on the step's edge itself.
                 // To do so, first find the frames length rounded to the number of frames per step,
                 // and add it to the press frame.
+                // With a groove, steps have different lengths, so this uses the active step's length as an
+                // approximation and positions the end relative to the start of the active step.
                 fn round(n: u32, to: u32) -> u32 {
                     (n + to / 2) / to * to
                 }
+                let step_frames = self.active_step_frames;
                 let pressed_frames = self.active_frame_or_zero() - self.last_press_frame.unwrap();
-                let rounded_steps_note_length = round(pressed_frames, self.song.frames_per_step);
+                let rounded_steps_note_length = round(pressed_frames, step_frames);
                 // Only record half-step releases when the length is less than what seems to be one frame.
                 // It could make sense to snap the release to a length of e.g. 1.5 or 2.5 but given
                 // that the press must be aligned to step transitions would require complex logic
                 // and probably feel unreliable.
-                let release_pos = if pressed_frames < self.song.frames_per_step * 3 / 4 {
+                let release_pos = if pressed_frames < step_frames * 3 / 4 {
                     ReleasePos::Half
                 } else {
                     ReleasePos::Full
                 };
 
-                let rounded_end_frame =
-                    self.last_press_frame.unwrap() + rounded_steps_note_length.max(self.song.frames_per_step);
+                let rounded_end_step_frame = self.active_step_frame as i64 - pressed_frames as i64
+                    + rounded_steps_note_length.max(step_frames) as i64;
 
-                let is_end_in_prev_or_current_step = rounded_end_frame / self.song.frames_per_step
-                    <= self.active_frame_or_zero() / self.song.frames_per_step;
-                let ends_before_snap_frame = rounded_end_frame % self.song.frames_per_step < self.snap_at_step_frame();
+                let is_end_in_prev_or_current_step = rounded_end_step_frame < step_frames as i64;
+                let ends_before_snap_frame =
+                    rounded_end_step_frame.rem_euclid(step_frames as i64) < self.snap_at_step_frame() as i64;
                 (
                     None,
                     Some(release_pos),
@@ -1328,12 +2056,7 @@ impl Sequencer {
                         // OR it ends in the current step, also before the snap frame
                         // (since the length is rounded this means that the press is also in the previous step)
                         // Register the release at the end of the previous step.
-                        Self::next_step_and_pattern_and_song_pattern(
-                            false,
-                            self.active_step,
-                            self.active_song_pattern,
-                            self.num_song_patterns(),
-                        )
+                        self.next_step_and_pattern_and_song_pattern(false, self.active_step, self.active_song_pattern)
                     } else {
                         // It ends between the snap frame of the previous step and the snap frame of the current step
                         // Register the release at the end of the current step.
@@ -1342,15 +2065,39 @@ impl Sequencer {
                 )
             }
         };
-        self.set_pattern_step_events(step, song_pattern, press_note, release, params);
+        // Store the note that gives the played one once the song pattern is transposed.
+        let transpose = self.transpose(song_pattern, self.displayed_instrument);
+        let press_note = press_note.map(|n| n.map(|n| transpose_note(n, -transpose)));
+        if self.playing {
+            self.group_live_undo(|s| s.set_pattern_step_events(step, song_pattern, press_note, release, params));
+        } else {
+            self.set_pattern_step_events(step, song_pattern, press_note, release, params);
+        }
     }
 
-    pub fn record_press(&mut self, note: u8) -> (i8, i8) {
-        let [p0, p1] = self.displayed_instrument_params();
+    /// The transpose of a song pattern for the given instrument.
+    fn transpose(&self, song_pattern: usize, instrument: u8) -> i8 {
+        let transpose = self.song.song_patterns.get(song_pattern).map_or(0, |sp| sp.transpose);
+        let is_untransposed = || {
+            let instrument_id = &self.synth_instrument_ids[instrument as usize];
+            self.song
+                .untransposed_instruments
+                .iter()
+                .any(|id| id == instrument_id.as_str())
+        };
+        if transpose == 0 || is_untransposed() {
+            0
+        } else {
+            transpose
+        }
+    }
+
+    pub fn record_press(&mut self, note: u8) -> [i8; NUM_INSTRUMENT_PARAMS] {
+        let params = self.displayed_instrument_params();
         self.record_key_event(
             KeyEvent::Press,
             Some(note),
-            Some((Some(p0).filter(|v| *v != 0), Some(p1).filter(|v| *v != 0))),
+            Some(params.map(|p| Some(p).filter(|v| *v != 0))),
         );
         self.last_press_frame = Some(self.active_frame_or_zero());
 
@@ -1358,7 +2105,7 @@ impl Sequencer {
         // but the fact that recording affects the active step instead of selected one
         // makes it difficult to find the right compromise.
         self.default_note_clipboard.note = note;
-        (p0, p1)
+        params
     }
 
     pub fn record_release(&mut self, _note: u8) {
@@ -1373,18 +2120,16 @@ impl Sequencer {
         forward: Option<bool>,
         large_inc: bool,
         on_empty: OnEmpty,
-    ) -> (u8, i8, i8) {
+    ) -> (u8, [i8; NUM_INSTRUMENT_PARAMS]) {
         // The GBA only handles frequencies from C1 upwards.
         const LOWEST_NOTE: u8 = 24;
 
-        let (maybe_selected_note, p0, p1) = self.song.patterns[self.displayed_pattern_idx()]
+        let (maybe_selected_note, step_params) = self.song.patterns[self.displayed_pattern_idx()]
             .get_steps(self.displayed_instrument)
-            .map_or((None, None, None), |ss| {
-                let s = ss[step];
-                (s.press_note(), s.param0, s.param1)
-            });
+            .and_then(|ss| ss.get(step))
+            .map_or((None, [None; NUM_INSTRUMENT_PARAMS]), |s| (s.press_note(), s.params));
         if maybe_selected_note.is_none() && on_empty == OnEmpty::EmptyOnEmpty {
-            return (0, 0, 0);
+            return (0, [0; NUM_INSTRUMENT_PARAMS]);
         }
         let inc = if large_inc { 12 } else { 1 };
         let active_note = maybe_selected_note.unwrap_or(self.default_note_clipboard.note);
@@ -1401,10 +2146,10 @@ impl Sequencer {
         let (set_release, set_params) = if maybe_selected_note.is_none() {
             (
                 Some(self.default_note_clipboard.release),
-                (p0.or(instrument_params[0]), p1.or(instrument_params[1])),
+                core::array::from_fn(|i| step_params[i].or(instrument_params[i])),
             )
         } else {
-            (None, (p0, p1))
+            (None, step_params)
         };
         self.set_pattern_step_events(
             step,
@@ -1414,33 +2159,30 @@ impl Sequencer {
             Some(set_params),
         );
 
-        let param_defs = &self.synth_instrument_param_defs[self.displayed_instrument as usize];
-        // Use the param's default if the sequencer didn't have any param set for that press
-        // or pass DEFAULT_PARAM_VAL but in the latter case the instrument will normally
-        // not care as it didn't define the parameter.
-        (
-            new_note,
-            set_params
-                .0
-                .or(param_defs[0].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-            set_params
-                .1
-                .or(param_defs[1].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-        )
+        (new_note, self.params_or_default(self.displayed_instrument, set_params))
     }
 
-    pub fn displayed_instrument_params(&self) -> [i8; NUM_INSTRUMENT_PARAMS] {
-        let instrument = self.displayed_instrument as usize;
-        let param_defs = &self.synth_instrument_param_defs[instrument];
-        let mut params = [DEFAULT_PARAM_VAL; NUM_INSTRUMENT_PARAMS];
-        for i in 0..NUM_INSTRUMENT_PARAMS {
-            params[i] = self.instrument_params[instrument][i]
+    /// Use the param's default if the sequencer didn't have any param set for that press
+    /// or pass DEFAULT_PARAM_VAL but in the latter case the instrument will normally
+    /// not care as it didn't define the parameter.
+    fn params_or_default(
+        &self,
+        instrument: u8,
+        params: [Option<i8>; NUM_INSTRUMENT_PARAMS],
+    ) -> [i8; NUM_INSTRUMENT_PARAMS] {
+        let param_defs = &self.synth_instrument_param_defs[instrument as usize];
+        core::array::from_fn(|i| {
+            params[i]
                 .or(param_defs[i].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL);
-        }
-        params
+                .unwrap_or(DEFAULT_PARAM_VAL)
+        })
+    }
+
+    pub fn displayed_instrument_params(&self) -> [i8; NUM_INSTRUMENT_PARAMS] {
+        self.params_or_default(
+            self.displayed_instrument,
+            self.instrument_params[self.displayed_instrument as usize],
+        )
     }
 
     /// Returns the parameters value for playback, falling back to the default.
@@ -1467,29 +2209,11 @@ impl Sequencer {
             }
         }
 
-        let v_ui = v.unwrap_or(param_def.default) as i32;
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let instruments_model = GlobalEngine::get(&handle).get_instruments();
-                let mut row_data = instruments_model.row_data(instrument).unwrap();
-                if param_num == 0 {
-                    row_data.param0 = v_ui;
-                } else {
-                    row_data.param1 = v_ui;
-                }
-                instruments_model.set_row_data(instrument, row_data);
-            })
-            .unwrap();
+        let v_ui = v.unwrap_or(param_def.default);
+        self.observer
+            .instrument_param_changed(instrument as u8, param_num, v_ui);
 
-        let param_defs = &self.synth_instrument_param_defs[self.displayed_instrument as usize];
-        [
-            instrument_params[0]
-                .or(param_defs[0].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-            instrument_params[1]
-                .or(param_defs[1].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-        ]
+        self.displayed_instrument_params()
     }
 
     pub fn cycle_step_param(
@@ -1499,36 +2223,24 @@ impl Sequencer {
         forward: Option<bool>,
         large_inc: bool,
         on_empty: OnEmpty,
-    ) -> (u8, i8, i8) {
+    ) -> (u8, [i8; NUM_INSTRUMENT_PARAMS]) {
         let (maybe_selected_note, mut step_parameters) = self.song.patterns[self.displayed_pattern_idx()]
             .get_steps(self.displayed_instrument)
-            .map_or((None, (None, None)), |ss| {
-                let s = ss[step];
-                (s.press_note(), (s.param0, s.param1))
-            });
+            .and_then(|ss| ss.get(step))
+            .map_or((None, [None; NUM_INSTRUMENT_PARAMS]), |s| (s.press_note(), s.params));
 
         let instrument_params = self.instrument_params[self.displayed_instrument as usize];
         let param_def = self.synth_instrument_param_defs[self.displayed_instrument as usize][param_num as usize]
             .as_ref()
             .expect("UI attempted to cycle a non-defined parameter");
 
-        let val = if param_num == 0 {
-            if step_parameters.0.is_none() {
-                if on_empty == OnEmpty::EmptyOnEmpty {
-                    return (0, 0, 0);
-                }
-                step_parameters.0 = Some(instrument_params[0].unwrap_or(param_def.default));
+        let val = &mut step_parameters[param_num as usize];
+        if val.is_none() {
+            if on_empty == OnEmpty::EmptyOnEmpty {
+                return (0, [0; NUM_INSTRUMENT_PARAMS]);
             }
-            &mut step_parameters.0
-        } else {
-            if step_parameters.1.is_none() {
-                if on_empty == OnEmpty::EmptyOnEmpty {
-                    return (0, 0, 0);
-                }
-                step_parameters.1 = Some(instrument_params[1].unwrap_or(param_def.default));
-            }
-            &mut step_parameters.1
-        };
+            *val = Some(instrument_params[param_num as usize].unwrap_or(param_def.default));
+        }
 
         let inc = if large_inc { 0x10 } else { 0x01 };
 
@@ -1555,57 +2267,36 @@ impl Sequencer {
 
         self.set_pattern_step_events(step, self.displayed_song_pattern, None, None, Some(step_parameters));
 
-        let param_defs = &self.synth_instrument_param_defs[self.displayed_instrument as usize];
-
         (
             maybe_selected_note.unwrap_or(self.default_note_clipboard.note),
-            step_parameters
-                .0
-                .or(param_defs[0].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-            step_parameters
-                .1
-                .or(param_defs[1].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
+            self.params_or_default(self.displayed_instrument, step_parameters),
         )
     }
 
     /// Default parameters are set for each instrument and also act as a clipboard for new note presses.
     /// Returns all parameters
-    pub fn set_default_step_params(&mut self, step: usize, only_param_num: Option<u8>) -> (Option<i8>, Option<i8>) {
+    pub fn set_default_step_params(
+        &mut self,
+        step: usize,
+        only_param_num: Option<u8>,
+    ) -> [Option<i8>; NUM_INSTRUMENT_PARAMS] {
         let step_parameters = self.song.patterns[self.displayed_pattern_idx()]
             .get_steps(self.displayed_instrument)
-            .map_or((None, None), |ss| {
-                let s = ss[step];
-                (s.param0, s.param1)
-            });
+            .and_then(|ss| ss.get(step))
+            .map_or([None; NUM_INSTRUMENT_PARAMS], |s| s.params);
         let instrument = self.displayed_instrument as usize;
         let instrument_params = &mut self.instrument_params[instrument];
-        if only_param_num.map_or(true, |p| p == 0) {
-            instrument_params[0] = step_parameters.0;
-        }
-        if only_param_num.map_or(true, |p| p == 1) {
-            instrument_params[1] = step_parameters.1;
+        for (param_num, param) in step_parameters.iter().enumerate() {
+            if only_param_num.map_or(true, |p| p as usize == param_num) {
+                instrument_params[param_num] = *param;
+            }
         }
 
-        let param_defs = &self.synth_instrument_param_defs[self.displayed_instrument as usize];
-        let ui_copy = [
-            instrument_params[0]
-                .or(param_defs[0].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-            instrument_params[1]
-                .or(param_defs[1].as_ref().map(|p| p.default))
-                .unwrap_or(DEFAULT_PARAM_VAL),
-        ];
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let instruments_model = &GlobalEngine::get(&handle).get_instruments();
-                let mut row_data = instruments_model.row_data(instrument).unwrap();
-                row_data.param0 = ui_copy[0] as i32;
-                row_data.param1 = ui_copy[1] as i32;
-                instruments_model.set_row_data(instrument, row_data);
-            })
-            .unwrap();
+        let ui_copy = self.displayed_instrument_params();
+        for (param_num, value) in ui_copy.into_iter().enumerate() {
+            self.observer
+                .instrument_param_changed(instrument as u8, param_num as u8, value);
+        }
 
         step_parameters
     }
@@ -1613,42 +2304,36 @@ impl Sequencer {
     pub fn copy_step_range_param(&mut self, step_range_first: usize, step_range_last: usize, param_num: u8) {
         let maybe_steps = self.song.patterns[self.displayed_pattern_idx()].get_steps(self.displayed_instrument);
 
-        let get_param = if param_num == 0 {
-            |s: &InstrumentStep| s.param0
-        } else {
-            |s: &InstrumentStep| s.param1
-        };
-        self.selection_clipboard = SelectionClipboard::InstrumentParams(maybe_steps.map_or_else(
-            || vec![None; step_range_last - step_range_first + 1],
-            |ss| ss[step_range_first..=step_range_last].iter().map(get_param).collect(),
-        ));
+        let get_param = |s: &InstrumentStep| s.params[param_num as usize];
+        self.selection_clipboard = SelectionClipboard::InstrumentParams(
+            maybe_steps
+                .and_then(|ss| ss.get(step_range_first..=step_range_last))
+                .map_or_else(
+                    || vec![None; step_range_last - step_range_first + 1],
+                    |ss| ss.iter().map(get_param).collect(),
+                ),
+        );
     }
 
     pub fn cut_step_range_param(&mut self, step_range_first: usize, step_range_last: usize, param_num: u8) {
         let displayed_pattern_idx = self.displayed_pattern_idx();
+        self.save_pattern_for_undo(displayed_pattern_idx);
         let maybe_steps = self.song.patterns[displayed_pattern_idx].get_steps_mut(self.displayed_instrument);
-        let get_param = if param_num == 0 {
-            |s: &InstrumentStep| s.param0
-        } else {
-            |s: &InstrumentStep| s.param1
-        };
-        let clear_param = if param_num == 0 {
-            |s: &mut InstrumentStep| s.param0 = None
-        } else {
-            |s: &mut InstrumentStep| s.param1 = None
-        };
-
-        let values = maybe_steps.map_or_else(
-            || vec![None; step_range_last - step_range_first + 1],
-            |ss| {
-                let slice = &mut ss[step_range_first..=step_range_last];
-                // Take a copy
-                let r = slice.iter().map(get_param).collect();
-                // Empty the cut params
-                slice.iter_mut().for_each(clear_param);
-                r
-            },
-        );
+        let get_param = |s: &InstrumentStep| s.params[param_num as usize];
+        let clear_param = |s: &mut InstrumentStep| s.params[param_num as usize] = None;
+
+        let values = maybe_steps
+            .and_then(|ss| ss.get_mut(step_range_first..=step_range_last))
+            .map_or_else(
+                || vec![None; step_range_last - step_range_first + 1],
+                |slice| {
+                    // Take a copy
+                    let r = slice.iter().map(get_param).collect();
+                    // Empty the cut params
+                    slice.iter_mut().for_each(clear_param);
+                    r
+                },
+            );
 
         self.selection_clipboard = SelectionClipboard::InstrumentParams(values);
         self.update_steps();
@@ -1657,33 +2342,30 @@ impl Sequencer {
     /// Cut while not in selection mode, it sets both the edit and selection clipboards.
     pub fn cut_step_single_param(&mut self, step: usize, param_num: u8) {
         let mut cut_params = self.set_default_step_params(step, Some(param_num));
-        if param_num == 0 && cut_params.0.is_some() {
-            self.selection_clipboard = SelectionClipboard::InstrumentParams(vec![cut_params.0.take()]);
-        } else if param_num == 1 && cut_params.1.is_some() {
-            self.selection_clipboard = SelectionClipboard::InstrumentParams(vec![cut_params.1.take()]);
+        if cut_params[param_num as usize].is_some() {
+            self.selection_clipboard =
+                SelectionClipboard::InstrumentParams(vec![cut_params[param_num as usize].take()]);
         }
 
         self.set_pattern_step_events(step, self.active_song_pattern, None, None, Some(cut_params));
     }
 
     pub fn paste_step_range_param(&mut self, at_step: usize, param_num: u8) {
-        let instrument_id = &self.synth_instrument_ids[self.displayed_instrument as usize];
         let displayed_pattern_idx = self.displayed_pattern_idx();
+        self.save_pattern_for_undo(displayed_pattern_idx);
+        let instrument_id = &self.synth_instrument_ids[self.displayed_instrument as usize];
         let steps = self.song.patterns[displayed_pattern_idx]
             .get_steps_mut_or_insert(instrument_id, Some(self.displayed_instrument));
 
         let param_defs = &self.synth_instrument_param_defs[self.displayed_instrument as usize];
         // Skip pasting if the parameter isn't defined anyway.
         if let Some((min, max)) = param_defs[param_num as usize].as_ref().map(|p| (p.min, p.max)) {
-            let set_param = if param_num == 0 {
-                |s: &mut InstrumentStep, v: Option<i8>| s.param0 = v
-            } else {
-                |s: &mut InstrumentStep, v: Option<i8>| s.param1 = v
-            };
+            let set_param = |s: &mut InstrumentStep, v: Option<i8>| s.params[param_num as usize] = v;
 
             if let SelectionClipboard::InstrumentParams(clip_params) = &self.selection_clipboard {
                 for (i, param) in clip_params.iter().enumerate() {
-                    set_param(&mut steps[(at_step + i) % NUM_STEPS], param.map(|v| v.clamp(min, max)));
+                    let num_steps = steps.len();
+                    set_param(&mut steps[(at_step + i) % num_steps], param.map(|v| v.clamp(min, max)));
                 }
             }
 
@@ -1694,7 +2376,13 @@ impl Sequencer {
     /// Return the number of the first pattern that is not referenced by the song and that is still empty,
     /// or None if there is no empty pattern left.
     pub fn find_first_unused_pattern_idx(&self) -> Option<usize> {
-        let pattern_after_max_song_reference = self.song.song_patterns.iter().max().map_or(0, |m| m + 1);
+        let pattern_after_max_song_reference = self
+            .song
+            .song_patterns
+            .iter()
+            .map(|sp| sp.pattern)
+            .max()
+            .map_or(0, |m| m + 1);
         self.song
             .patterns
             .iter()
@@ -1723,7 +2411,7 @@ impl Sequencer {
         // Check if the stub inserted during selection needs to be committed.
         if self.has_stub_pattern {
             // Paste the clipboard into the current stub, then commit it.
-            self.song.song_patterns[self.displayed_song_pattern] = self.default_song_pattern_clipboard;
+            self.song.song_patterns[self.displayed_song_pattern].pattern = self.default_song_pattern_clipboard;
             self.commit_stub_song_pattern();
             self.update_steps();
         }
@@ -1733,27 +2421,23 @@ impl Sequencer {
     }
 
     fn write_displayed_song_pattern(&mut self, new_pattern: usize) {
+        self.save_song_patterns_for_undo();
         let song_pattern_idx = self.displayed_song_pattern;
-        self.song.song_patterns[song_pattern_idx] = new_pattern;
-
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+        self.song.song_patterns[song_pattern_idx].pattern = new_pattern;
 
-                let mut row_data = model.row_data(song_pattern_idx).unwrap();
-                row_data.number = new_pattern as i32;
-                model.set_row_data(song_pattern_idx, row_data);
-            })
-            .unwrap();
+        self.observer.song_pattern_changed(song_pattern_idx, new_pattern);
 
         self.update_steps();
     }
 
     pub fn cycle_song_pattern(&mut self, forward: bool) {
-        // If editing the stub pattern, commit it to a real pattern now.
-        if self.has_stub_pattern {
-            self.commit_stub_song_pattern();
-        }
+        self.group_undo(|s| {
+            // If editing the stub pattern, commit it to a real pattern now.
+            if s.has_stub_pattern {
+                s.commit_stub_song_pattern();
+            }
+            s.save_song_patterns_for_undo();
+        });
 
         let mut pattern = self.displayed_pattern_idx();
         if forward && pattern < NUM_PATTERNS - 1 {
@@ -1773,10 +2457,10 @@ impl Sequencer {
         // To prevent having to check everywhere whether the displayed song pattern is real or not, append
         // a stub pattern only when the user select that slot, and commit it if any edit of an explicit pattern cycle was made.
         // Start with an empty pattern so that the user can move back to the pattern panel and edit it from scratch.
-        self.song.song_patterns.push(
+        self.song.song_patterns.push(SongPattern::new(
             self.find_first_unused_pattern_idx()
                 .unwrap_or(self.default_song_pattern_clipboard),
-        );
+        ));
         self.has_stub_pattern = true;
     }
 
@@ -1795,31 +2479,17 @@ impl Sequencer {
 
     fn commit_stub_song_pattern(&mut self) {
         assert!(self.has_stub_pattern);
+        self.save_song_patterns_for_undo();
         let committed_song_pattern = self.song.song_patterns.len() - 1;
-        let committed_pattern = self.song.song_patterns[committed_song_pattern] as i32;
+        let committed_pattern = self.pattern_idx(committed_song_pattern);
         self.has_stub_pattern = false;
 
         // After the user committed the stub song pattern, allow replacing the automatically
         // inserted clipboard value with the next empty pattern number on a second press.
         self.next_cycle_song_pattern_start_can_have_new = true;
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
-                let vec_model = model.as_any().downcast_ref::<VecModel<SongPatternData>>().unwrap();
-
-                // Show the real pattern number for the previous stub
-                let mut row_data = vec_model.row_data(committed_song_pattern).unwrap();
-                row_data.number = committed_pattern;
-                vec_model.set_row_data(committed_song_pattern, row_data);
-
-                // Append a new UI-only stub
-                vec_model.push(SongPatternData {
-                    number: -1,
-                    selected: false,
-                });
-            })
-            .unwrap();
+        self.observer
+            .song_pattern_committed(committed_song_pattern, committed_pattern);
     }
 
     pub fn remove_last_song_pattern(&mut self) {
@@ -1827,7 +2497,8 @@ impl Sequencer {
         // but for now we only allow appending at the end and removing the last non-stub song pattern,
         // requiring the user to select exactly that one.
         if !self.has_stub_pattern && self.displayed_song_pattern + 1 == self.song.song_patterns.len() {
-            let removed = self.song.song_patterns.remove(self.displayed_song_pattern);
+            self.save_song_patterns_for_undo();
+            let removed = self.song.song_patterns.remove(self.displayed_song_pattern).pattern;
 
             // Make sure that doing Z,X,X doesn't allow picking a new pattern after the removing one
             // since it ends with X,X and the flag might still be set.
@@ -1836,14 +2507,7 @@ impl Sequencer {
             // Cut the removed value into the clipboard.
             self.default_song_pattern_clipboard = removed;
 
-            self.main_window
-                .upgrade_in_event_loop(move |handle| {
-                    let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
-                    let vec_model = model.as_any().downcast_ref::<VecModel<SongPatternData>>().unwrap();
-                    // The last index is the UI-only stub, so remove the one before.
-                    vec_model.remove(vec_model.row_count() - 2);
-                })
-                .unwrap();
+            self.observer.last_song_pattern_removed();
 
             let next_selection = if !self.song.song_patterns.is_empty() {
                 self.song.song_patterns.len() - 1
@@ -1861,64 +2525,306 @@ impl Sequencer {
     pub fn clone_displayed_song_pattern(&mut self) {
         if let Some(new_pattern_idx) = self.find_first_unused_pattern_idx() {
             let displayed_pattern = self.displayed_pattern_idx();
-            self.song.patterns[new_pattern_idx] = self.song.patterns[displayed_pattern].clone();
-            self.write_displayed_song_pattern(new_pattern_idx);
+            self.group_undo(|s| {
+                s.save_pattern_for_undo(new_pattern_idx);
+                s.song.patterns[new_pattern_idx] = s.song.patterns[displayed_pattern].clone();
+                s.write_displayed_song_pattern(new_pattern_idx);
+            });
         } else {
             elog!("Max pattern reached");
         }
     }
 
+    /// Transposes the notes played by the displayed song pattern by one more or less semitone.
+    pub fn cycle_displayed_song_pattern_transpose(&mut self, forward: bool) {
+        self.group_undo(|s| {
+            // If editing the stub pattern, commit it to a real pattern now.
+            if s.has_stub_pattern {
+                s.commit_stub_song_pattern();
+            }
+            s.save_song_patterns_for_undo();
+        });
+
+        let song_pattern_idx = self.displayed_song_pattern;
+        let song_pattern = &mut self.song.song_patterns[song_pattern_idx];
+        song_pattern.transpose = if forward {
+            (song_pattern.transpose + 1).min(MAX_TRANSPOSE)
+        } else {
+            (song_pattern.transpose - 1).max(-MAX_TRANSPOSE)
+        };
+        self.observer
+            .song_pattern_transpose_changed(song_pattern_idx, song_pattern.transpose);
+    }
+
+    /// Adds or removes a step at the end of the displayed pattern.
+    /// Events in removed steps are lost.
+    pub fn cycle_displayed_pattern_num_steps(&mut self, forward: bool) {
+        self.commit_stub_and_save_pattern_for_undo(self.displayed_song_pattern);
+
+        let displayed_pattern_idx = self.displayed_pattern_idx();
+        let pattern = &mut self.song.patterns[displayed_pattern_idx];
+        let num_steps = if forward {
+            (pattern.num_steps() + 1).min(MAX_NUM_STEPS)
+        } else {
+            (pattern.num_steps() - 1).max(1)
+        };
+        pattern.set_num_steps(num_steps);
+
+        // Keep the active step within the pattern if it got shorter.
+        if self.active_step >= self.active_num_steps() {
+            self.activate_step(self.active_step);
+        }
+        self.update_steps();
+    }
+
+    /// Runs edits that should be undone all at once, unless already part of a larger group.
+    pub fn group_undo<R>(&mut self, edits: impl FnOnce(&mut Self) -> R) -> R {
+        if self.undo_group.is_some() && !self.undo_group_is_live {
+            return edits(self);
+        }
+        // Close any live group first to keep the groups in the order of their edits.
+        self.close_undo_group();
+        self.undo_group = Some(Vec::new());
+        let r = edits(self);
+        self.close_undo_group();
+        r
+    }
+
+    /// Runs edits of a recording pass or of erasing during playback, which are undone all at once.
+    /// The group stays open for the next ones until another edit is made or until playback,
+    /// recording or erasing stops.
+    fn group_live_undo<R>(&mut self, edits: impl FnOnce(&mut Self) -> R) -> R {
+        if !self.undo_group_is_live {
+            self.close_undo_group();
+            self.undo_group = Some(Vec::new());
+        }
+        // Groups started by the edits are part of this one.
+        self.undo_group_is_live = false;
+        let r = edits(self);
+        self.undo_group_is_live = true;
+        r
+    }
+
+    fn close_undo_group(&mut self) {
+        self.undo_group_is_live = false;
+        if let Some(group) = self.undo_group.take().filter(|g| !g.is_empty()) {
+            push_undo_group(&mut self.undo_stack, group);
+        }
+    }
+
+    fn close_live_undo_group(&mut self) {
+        if self.undo_group_is_live {
+            self.close_undo_group();
+        }
+    }
+
+    fn push_undo_entry(&mut self, entry: UndoEntry) {
+        self.redo_stack.clear();
+        match &mut self.undo_group {
+            Some(group) => group.push(entry),
+            None => push_undo_group(&mut self.undo_stack, vec![entry]),
+        }
+    }
+
+    /// Keeps a copy of a pattern to restore on undo, before editing it.
+    fn save_pattern_for_undo(&mut self, pattern: usize) {
+        // Only the state before the first edit of a group has to be restored.
+        let saved = self.undo_group.as_ref().map_or(false, |g| {
+            g.iter().any(|e| matches!(e, UndoEntry::Pattern(p, _) if *p == pattern))
+        });
+        if !saved {
+            self.push_undo_entry(UndoEntry::Pattern(pattern, self.song.patterns[pattern].clone()));
+        }
+    }
+
+    /// Keeps a copy of the song pattern slots to restore on undo, before editing them.
+    fn save_song_patterns_for_undo(&mut self) {
+        let saved = self
+            .undo_group
+            .as_ref()
+            .map_or(false, |g| g.iter().any(|e| matches!(e, UndoEntry::SongPatterns(_))));
+        if !saved {
+            let num_song_patterns = self.song.song_patterns.len() - self.has_stub_pattern as usize;
+            let song_patterns = self.song.song_patterns[..num_song_patterns].to_vec();
+            self.push_undo_entry(UndoEntry::SongPatterns(song_patterns));
+        }
+    }
+
+    /// Saves the pattern of a song pattern before editing it.
+    /// If editing the stub pattern, also commit it to a real pattern now, undone together with the edit.
+    fn commit_stub_and_save_pattern_for_undo(&mut self, song_pattern: usize) {
+        self.group_undo(|s| {
+            if s.has_stub_pattern {
+                s.commit_stub_song_pattern();
+            }
+            s.save_pattern_for_undo(s.pattern_idx(song_pattern));
+        });
+    }
+
+    pub fn undo(&mut self) {
+        // An ongoing recording pass is undone up to now.
+        self.close_undo_group();
+        if let Some(group) = self.undo_stack.pop() {
+            let redo_group = self.restore_undo_group(group);
+            push_undo_group(&mut self.redo_stack, redo_group);
+        }
+    }
+
+    pub fn redo(&mut self) {
+        self.close_undo_group();
+        if let Some(group) = self.redo_stack.pop() {
+            let undo_group = self.restore_undo_group(group);
+            push_undo_group(&mut self.undo_stack, undo_group);
+        }
+    }
+
+    /// Puts back the state saved in the group, returning the replaced state to be able to go back to it.
+    fn restore_undo_group(&mut self, group: Vec<UndoEntry>) -> Vec<UndoEntry> {
+        let mut song_patterns_changed = false;
+        let replaced: Vec<UndoEntry> = group
+            .into_iter()
+            .rev()
+            .map(|entry| match entry {
+                UndoEntry::Pattern(p, pattern) => {
+                    UndoEntry::Pattern(p, core::mem::replace(&mut self.song.patterns[p], pattern))
+                }
+                UndoEntry::SongPatterns(song_patterns) => {
+                    if self.has_stub_pattern {
+                        self.song.song_patterns.pop();
+                        self.has_stub_pattern = false;
+                    }
+                    song_patterns_changed = true;
+                    UndoEntry::SongPatterns(core::mem::replace(&mut self.song.song_patterns, song_patterns))
+                }
+            })
+            .collect();
+
+        if song_patterns_changed {
+            self.notify_song_changed();
+            let num_song_patterns = self.song.song_patterns.len();
+            if self.active_song_pattern >= num_song_patterns.max(1) {
+                self.activate_song_pattern(num_song_patterns.saturating_sub(1), false);
+            }
+            // Displaying the slot after the last one appends the stub.
+            self.display_song_pattern(self.displayed_song_pattern.min(num_song_patterns));
+        }
+        // The restored pattern might be shorter.
+        if self.active_step >= self.active_num_steps() {
+            self.activate_step(self.active_step);
+        }
+        if !song_patterns_changed {
+            self.update_steps();
+        }
+        replaced
+    }
+
     fn set_song(&mut self, song: SequencerSong) {
+        self.set_song_bank(vec![song]);
+    }
+
+    /// Replaces the songs of the bank and selects the first one.
+    fn set_song_bank(&mut self, mut songs: Vec<SequencerSong>) {
+        assert!(!songs.is_empty());
+        let first = core::mem::take(&mut songs[0]);
+        self.song_bank = songs;
+        self.bank_song = 0;
+        self.observer.bank_song_selected(0, self.song_bank.len());
+        self.edit_song(first);
+    }
+
+    /// Makes another song of the bank the edited one, selecting past the last song adds an empty one.
+    pub fn select_bank_song(&mut self, bank_song: usize) {
+        if bank_song == self.bank_song || bank_song > self.song_bank.len() || bank_song >= MAX_BANK_SONGS {
+            return;
+        }
+        if bank_song == self.song_bank.len() {
+            let mut new_song = SequencerSong::default();
+            #[cfg(feature = "desktop")]
+            {
+                new_song.instruments_file = self.song.instruments_file.clone();
+            }
+            self.song_bank.push(new_song);
+        }
+
+        // The stub isn't part of the song.
+        if self.has_stub_pattern {
+            self.song.song_patterns.pop();
+        }
+        let next_song = core::mem::take(&mut self.song_bank[bank_song]);
+        self.song_bank[self.bank_song] = core::mem::take(&mut self.song);
+        self.bank_song = bank_song;
+        self.observer.bank_song_selected(bank_song, self.song_bank.len());
+        self.edit_song(next_song);
+    }
+
+    pub fn cycle_bank_song(&mut self, forward: bool) {
+        if forward {
+            self.select_bank_song(self.bank_song + 1);
+        } else if self.bank_song > 0 {
+            self.select_bank_song(self.bank_song - 1);
+        }
+    }
+
+    /// The songs of the bank to save, leaving out songs without song patterns unless all of them are.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn bank_songs(&self) -> Vec<&SequencerSong> {
+        let songs = (0..self.song_bank.len()).map(|i| {
+            if i == self.bank_song {
+                // The stub doesn't count as a song pattern.
+                (
+                    &self.song,
+                    self.song.song_patterns.len() > self.has_stub_pattern as usize,
+                )
+            } else {
+                (&self.song_bank[i], !self.song_bank[i].song_patterns.is_empty())
+            }
+        });
+        let non_empty: Vec<&SequencerSong> = songs.clone().filter(|(_, used)| *used).map(|(s, _)| s).collect();
+        if non_empty.is_empty() {
+            songs.take(1).map(|(s, _)| s).collect()
+        } else {
+            non_empty
+        }
+    }
+
+    fn edit_song(&mut self, song: SequencerSong) {
         // Disable recording when loading a song to make the playback stick to nearest instruments.
         self.set_recording(false);
         self.set_playing(false, false);
 
         self.has_stub_pattern = false;
         self.song = song;
+        // Edits of the previous song can't be undone in this one.
+        self.undo_stack.clear();
+        self.redo_stack.clear();
 
-        let song_patterns = self.song.song_patterns.clone();
-        let frames_per_step = self.song.frames_per_step;
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let mut vec = Vec::new();
-                for number in song_patterns.iter() {
-                    vec.push(SongPatternData {
-                        number: *number as i32,
-                        selected: false,
-                    });
-                }
-                // Append a UI-only stub
-                vec.push(SongPatternData {
-                    number: -1,
-                    selected: false,
-                });
-
-                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
-                let vec_model = model.as_any().downcast_ref::<VecModel<SongPatternData>>().unwrap();
-                vec_model.set_vec(vec);
-
-                let settings = SongSettings {
-                    frames_per_step: frames_per_step as i32,
-                };
-                GlobalSettings::get(&handle).set_song_settings(settings);
-            })
-            .unwrap();
+        self.notify_song_changed();
 
         self.activate_song_pattern(0, true);
         self.display_song_pattern(0);
         self.user_display_instrument(self.displayed_instrument);
     }
 
+    fn notify_song_changed(&self) {
+        let song_patterns: Vec<(usize, i8)> = self
+            .song
+            .song_patterns
+            .iter()
+            .map(|sp| (sp.pattern, sp.transpose))
+            .collect();
+        self.observer.song_changed(&song_patterns, &self.song_settings());
+    }
+
     pub fn clear_song(&mut self) {
         self.set_song(Default::default());
     }
 
     #[cfg(feature = "desktop")]
     pub fn load_str(&mut self, markdown: &str) -> Result<String, Box<dyn Error>> {
-        let song = parse_markdown_song(markdown)?;
+        let songs = parse_markdown_song_bank(markdown)?;
 
-        let instruments_file = song.instruments_file.clone();
-        self.set_song(song);
+        let instruments_file = songs[0].instruments_file.clone();
+        self.set_song_bank(songs);
         Ok(instruments_file)
     }
 
@@ -1926,10 +2832,10 @@ impl Sequencer {
     pub fn load_file(&mut self, song_path: &Path) -> Result<String, Box<dyn Error>> {
         if song_path.exists() {
             let md = std::fs::read_to_string(song_path)?;
-            let song = parse_markdown_song(&md)?;
+            let songs = parse_markdown_song_bank(&md)?;
 
-            let instruments_file = song.instruments_file.clone();
-            self.set_song(song);
+            let instruments_file = songs[0].instruments_file.clone();
+            self.set_song_bank(songs);
             Ok(instruments_file)
         } else {
             Err(format!("Project song file {:?} doesn't exist.", song_path).into())
@@ -1938,37 +2844,60 @@ impl Sequencer {
 
     #[cfg(all(feature = "desktop", not(target_arch = "wasm32")))]
     pub fn save(&self, song_path: &Path) -> Result<(), Box<dyn Error>> {
-        save_markdown_song(&self.song, song_path)
+        save_markdown_song_bank(&self.bank_songs(), song_path)
     }
 
     #[cfg(all(feature = "desktop", not(target_arch = "wasm32")))]
     pub fn save_as(&mut self, song_path: &Path, instruments_path: &Path) -> Result<(), Box<dyn Error>> {
-        self.song.instruments_file = instruments_path
+        let instruments_file = instruments_path
             .file_name()
             .unwrap()
             .to_str()
             .expect("Bad path?")
             .to_owned();
-        save_markdown_song(&self.song, song_path)
+        for song in core::iter::once(&mut self.song).chain(self.song_bank.iter_mut()) {
+            song.instruments_file = instruments_file.clone();
+        }
+        save_markdown_song_bank(&self.bank_songs(), song_path)
     }
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn serialize_to_postcard(&self) -> Result<alloc::vec::Vec<u8>, postcard::Error> {
-        to_allocvec(&self.song)
+        self.song.to_postcard_bytes()
+    }
+
+    /// Serializes each song of the bank like serialize_to_postcard.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn serialize_bank_to_postcard(&self) -> Result<Vec<Vec<u8>>, postcard::Error> {
+        self.bank_songs()
+            .into_iter()
+            .map(SequencerSong::to_postcard_bytes)
+            .collect()
     }
 
     pub fn load_postcard_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
-        let song: SequencerSong = from_bytes(bytes).unwrap();
+        self.load_postcard_bank(&[bytes])
+    }
+
+    /// Loads songs serialized with serialize_bank_to_postcard, the first one is selected.
+    pub fn load_postcard_bank(&mut self, songs: &[&[u8]]) -> Result<(), String> {
+        if songs.is_empty() {
+            return Err("The song bank is empty".to_owned());
+        }
+        let songs = songs
+            .iter()
+            .map(|bytes| SequencerSong::from_postcard_bytes(bytes).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
 
         self.set_recording(false);
 
-        self.set_song(song);
+        self.set_song_bank(songs);
         Ok(())
     }
 
     pub fn set_instrument_def(
         &mut self,
-        instrument_ids: Vec<SharedString>,
+        instrument_ids: Vec<String>,
         synth_instrument_param_defs: Vec<[Option<InstrumentParamDef>; NUM_INSTRUMENT_PARAMS]>,
     ) {
         // Playback can start
@@ -1979,43 +2908,16 @@ impl Sequencer {
         }
         self.update_steps();
 
-        let default_param_values = synth_instrument_param_defs
-            .iter()
-            .map(|p| {
-                (
-                    p[0].as_ref().map_or(-2147483648, |p| p.default as i32),
-                    p[1].as_ref().map_or(-2147483648, |p| p.default as i32),
-                )
-            })
-            .collect::<Vec<_>>();
-
         self.synth_instrument_ids = instrument_ids;
         self.synth_instrument_param_defs = synth_instrument_param_defs;
 
         // When the instrument is updated, overwrite the instrument params with new default values.
         for (i, param_defs) in self.synth_instrument_param_defs.iter().enumerate() {
-            let param0 = param_defs[0].as_ref().map(|p| p.default);
-            let param1 = param_defs[1].as_ref().map(|p| p.default);
-            self.instrument_params[i] = [param0, param1];
-        }
-
-        let ui_copy = self.synth_instrument_ids.clone();
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let engine = &GlobalEngine::get(&handle);
-                let model = engine.get_script_instrument_ids();
-                let vec_model = model.as_any().downcast_ref::<VecModel<SharedString>>().unwrap();
-                vec_model.set_vec(ui_copy);
-
-                let instruments_model = engine.get_instruments();
-                for (i, (p0, p1)) in default_param_values.iter().enumerate() {
-                    let mut row_data = instruments_model.row_data(i).unwrap();
-                    row_data.param0 = *p0;
-                    row_data.param1 = *p1;
-                    instruments_model.set_row_data(i, row_data);
-                }
-            })
-            .unwrap();
+            self.instrument_params[i] = param_defs.each_ref().map(|def| def.as_ref().map(|p| p.default));
+        }
+
+        self.observer
+            .instruments_defined(&self.synth_instrument_ids, &self.instrument_params);
 
         // Re-display the instrument to update the UI with the new param defs.
         self.user_display_instrument(self.displayed_instrument);
@@ -2027,7 +2929,120 @@ impl Sequencer {
 
     fn snap_at_step_frame(&self) -> u32 {
         // Use +1 just to try to compensate for the key press to visual and audible delay.
-        self.song.frames_per_step / 2 + 1
+        self.active_step_frames / 2 + 1
+    }
+
+    /// Returns the ID of every instrument used by the song's patterns, and whether the loaded
+    /// instruments define it.
+    #[cfg(feature = "desktop_native")]
+    pub fn song_instrument_ids(&self) -> BTreeMap<&str, bool> {
+        let mut ids = BTreeMap::new();
+        for instrument in self.song.patterns.iter().flat_map(|p| p.instruments()) {
+            *ids.entry(instrument.id.as_str()).or_insert(false) |= instrument.synth_index.is_some();
+        }
+        ids
+    }
+
+    /// Returns the song as a Standard MIDI File.
+    #[cfg(feature = "desktop_native")]
+    pub fn export_smf(&self, options: &SmfExportOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+        smf::save_smf(&self.song, self.song_pattern_slots(), options)
+    }
+
+    /// Replaces the song with one created from a Standard MIDI File, matching tracks with the current instruments.
+    #[cfg(feature = "desktop_native")]
+    pub fn import_smf(&mut self, bytes: &[u8], options: &SmfImportOptions) -> Result<ImportReport, Box<dyn Error>> {
+        let (song, report) = smf::load_smf(bytes, &self.synth_instrument_ids, options)?;
+        self.set_imported_song(song);
+        Ok(report)
+    }
+
+    /// Replaces the song with one created from the patterns of a MOD or XM file.
+    #[cfg(feature = "desktop_native")]
+    pub fn import_module(
+        &mut self,
+        bytes: &[u8],
+        options: &ModuleImportOptions,
+    ) -> Result<ImportReport, Box<dyn Error>> {
+        let (song, report) = tracker_module::load_module(bytes, options)?;
+        self.set_imported_song(song);
+        Ok(report)
+    }
+
+    #[cfg(feature = "desktop_native")]
+    fn set_imported_song(&mut self, mut song: SequencerSong) {
+        for p in &mut song.patterns {
+            p.update_synth_index(&self.synth_instrument_ids);
+        }
+        self.set_song(song);
+    }
+
+    /// Returns the ID of the instrument at each synth index, empty where none is defined.
+    #[cfg(feature = "desktop_native")]
+    pub fn synth_instrument_ids(&self) -> &[String] {
+        &self.synth_instrument_ids
+    }
+
+    /// Returns the song pattern slots, excluding the stub.
+    #[cfg(feature = "desktop_native")]
+    fn song_pattern_slots(&self) -> &[SongPattern] {
+        &self.song.song_patterns[..self.num_song_patterns()]
+    }
+
+    /// Returns the pattern played at each song pattern slot, excluding the stub.
+    #[cfg(feature = "desktop_native")]
+    pub fn song_patterns(&self) -> Vec<usize> {
+        self.song_pattern_slots().iter().map(|sp| sp.pattern).collect()
+    }
+
+    #[cfg(feature = "desktop_native")]
+    pub fn num_bank_songs(&self) -> usize {
+        self.song_bank.len()
+    }
+
+    #[cfg(feature = "desktop_native")]
+    pub fn frames_per_step(&self) -> u32 {
+        self.song.frames_per_step
+    }
+
+    /// The number of steps needed to play each song pattern once, in song mode.
+    #[cfg(feature = "desktop_native")]
+    pub fn song_length_in_steps(&self) -> usize {
+        self.song_pattern_slots()
+            .iter()
+            .map(|sp| self.song.patterns[sp.pattern].num_steps())
+            .sum()
+    }
+
+    /// The number of frames needed to play each song pattern once, in song mode.
+    #[cfg(feature = "desktop_native")]
+    pub fn song_length_in_frames(&self) -> usize {
+        let mut clock = StepClock::new(&self.song);
+        self.song_pattern_slots()
+            .iter()
+            .map(|sp| self.song.song_pattern_frames(sp, &mut clock) as usize)
+            .sum()
+    }
+
+    /// The frame that song playback loops back to after the last song pattern,
+    /// or None if the song stops at the end.
+    #[cfg(feature = "desktop_native")]
+    pub fn song_loop_start_in_frames(&self) -> Option<usize> {
+        if self.song.stop_at_end {
+            return None;
+        }
+        let slots = self.song_pattern_slots();
+        let mut clock = StepClock::new(&self.song);
+        Some(
+            slots[..self.song.loop_start.min(slots.len().saturating_sub(1))]
+                .iter()
+                .map(|sp| self.song.song_pattern_frames(sp, &mut clock) as usize)
+                .sum(),
+        )
+    }
+
+    fn is_last_song_step(&self) -> bool {
+        self.active_song_pattern + 1 >= self.num_song_patterns() && self.active_step + 1 >= self.active_num_steps()
     }
 
     fn num_song_patterns(&self) -> usize {
@@ -2036,20 +3051,395 @@ impl Sequencer {
         (len - (self.has_stub_pattern && len > 1) as isize) as usize
     }
 
+    /// Returns the step and song pattern following or preceding the given ones, in song mode.
+    /// Each song pattern is played for the number of steps of its pattern, and the last one
+    /// is followed by the loop start.
     fn next_step_and_pattern_and_song_pattern(
+        &self,
         forward: bool,
         from_step: usize,
         from_song_pattern: usize,
-        num_song_patterns: usize,
     ) -> (usize, usize) {
-        let delta = if forward { 1_isize } else { -1 };
-        let next_step = ((from_step as isize + NUM_STEPS as isize + delta) % NUM_STEPS as isize) as usize;
-        let wraps = forward && next_step == 0 || !forward && from_step == 0;
-        if wraps {
-            let next_song_pattern = ((from_song_pattern as isize + num_song_patterns as isize + delta)
-                % num_song_patterns as isize) as usize;
-            return (next_step, next_song_pattern);
+        let num_song_patterns = self.num_song_patterns();
+        let num_steps = |song_pattern| self.song.patterns[self.pattern_idx(song_pattern)].num_steps();
+        if forward && from_step + 1 < num_steps(from_song_pattern) {
+            (from_step + 1, from_song_pattern)
+        } else if forward && from_song_pattern + 1 < num_song_patterns {
+            (0, from_song_pattern + 1)
+        } else if forward {
+            (0, self.song.loop_start.min(num_song_patterns - 1))
+        } else if from_step > 0 {
+            (from_step - 1, from_song_pattern)
+        } else {
+            let prev_song_pattern = (from_song_pattern + num_song_patterns - 1) % num_song_patterns;
+            (num_steps(prev_song_pattern) - 1, prev_song_pattern)
         }
-        (next_step, from_song_pattern)
     }
 }
+
+#[test]
+fn song_playback_notifies_steps() {
+    use crate::observer::{ObservedEvent, RecordingObserver};
+
+    let observer = Rc::new(RecordingObserver::default());
+    let mut sequencer = Sequencer::new(observer.clone());
+    sequencer.clear_song();
+    assert!(observer
+        .events
+        .borrow()
+        .contains(&ObservedEvent::SongChanged(Vec::new(), 7)));
+
+    observer.events.borrow_mut().clear();
+    sequencer.set_playing(true, true);
+    for _ in 0..(7 * 2 + 1) {
+        sequencer.advance_frame();
+    }
+    assert_eq!(
+        *observer.events.borrow(),
+        [
+            ObservedEvent::PlayingChanged(true),
+            ObservedEvent::StepActivated(1),
+            ObservedEvent::StepActivated(2),
+        ]
+    );
+}
+
+/// Returns a sequencer editing the given song, with instrument "1" defined.
+#[cfg(test)]
+fn test_sequencer(song: SequencerSong) -> Sequencer {
+    use crate::observer::RecordingObserver;
+
+    let mut sequencer = Sequencer::new(Rc::new(RecordingObserver::default()));
+    sequencer.set_song(song);
+    sequencer.set_instrument_def(vec!["1".into()], vec![[None, None, None, None]]);
+    sequencer
+}
+
+#[test]
+fn song_patterns_have_their_own_num_steps() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0), SongPattern::new(1)];
+    song.patterns[0].set_num_steps(3);
+    let sequencer = test_sequencer(song);
+
+    assert_eq!(sequencer.next_step_and_pattern_and_song_pattern(true, 1, 0), (2, 0));
+    assert_eq!(sequencer.next_step_and_pattern_and_song_pattern(true, 2, 0), (0, 1));
+    assert_eq!(sequencer.next_step_and_pattern_and_song_pattern(false, 0, 1), (2, 0));
+    assert_eq!(sequencer.next_step_and_pattern_and_song_pattern(false, 0, 0), (15, 1));
+    assert_eq!(sequencer.song_length_in_steps(), 3 + 16);
+}
+
+#[test]
+fn stale_steps_past_the_pattern_end_are_ignored() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    song.patterns[0].set_num_steps(3);
+    let mut sequencer = test_sequencer(song);
+    sequencer.toggle_step(2);
+
+    // The UI could still be showing 16 steps.
+    sequencer.toggle_step(5);
+    sequencer.cycle_step_delay(5, true);
+    sequencer.cycle_step_note(5, Some(true), false, OnEmpty::PasteOnEmpty);
+    sequencer.set_default_step_params(5, None);
+    sequencer.copy_step_range_note(2, 5);
+    sequencer.cut_step_range_note(2, 5);
+    let steps = sequencer.song.patterns[0].get_steps(0).unwrap();
+    assert_eq!(steps.len(), 3);
+    assert!(steps[2].press());
+}
+
+#[test]
+fn groove_sets_step_frames() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0), SongPattern::new(1)];
+    song.groove = vec![3, 1];
+    song.patterns[1].groove = Some(vec![2]);
+    let mut sequencer = test_sequencer(song);
+
+    sequencer.set_playing(true, true);
+    let step_start_frames: Vec<u32> = (0..10).filter(|_| sequencer.advance_frame().0.is_some()).collect();
+    assert_eq!(step_start_frames, [0, 3, 4, 7, 8]);
+    // Pattern 0 alternates steps of 3 and 1 frames, and pattern 1 overrides it with steps of 2 frames.
+    assert_eq!(sequencer.song_length_in_frames(), 8 * (3 + 1) + 16 * 2);
+}
+
+#[test]
+fn song_pattern_tempo_overrides_frames_per_step() {
+    let mut song = SequencerSong::default();
+    let mut slow = SongPattern::new(1);
+    slow.frames_per_step = Some(14);
+    song.song_patterns = vec![SongPattern::new(0), slow];
+    song.patterns[1].groove = Some(vec![8, 6]);
+    let mut sequencer = test_sequencer(song);
+
+    // The groove keeps its proportions at the slot's tempo.
+    let mut clock = StepClock::new(&sequencer.song);
+    assert_eq!(sequencer.song.step_frames(&slow, 0, &mut clock), 16);
+    assert_eq!(sequencer.song.step_frames(&slow, 1, &mut clock), 12);
+    assert_eq!(sequencer.song_length_in_frames(), 16 * 7 + 8 * (16 + 12));
+
+    sequencer.set_playing(true, true);
+    let step_start_frames: Vec<u32> = (0..16 * 7 + 20)
+        .filter(|_| sequencer.advance_frame().0.is_some())
+        .collect();
+    assert_eq!(step_start_frames[15..], [15 * 7, 16 * 7, 16 * 7 + 16]);
+}
+
+#[test]
+fn bpm_steps_average_to_the_exact_tempo() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    // Steps last ~7.466 frames.
+    song.bpm = Some(120.0);
+    let mut sequencer = test_sequencer(song);
+
+    sequencer.set_playing(true, true);
+    let step_start_frames: Vec<u32> = (0..31).filter(|_| sequencer.advance_frame().0.is_some()).collect();
+    assert_eq!(step_start_frames, [0, 7, 15, 22, 30]);
+    assert_eq!(sequencer.song_length_in_frames(), 119);
+}
+
+#[test]
+fn song_loops_to_loop_start_or_stops() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0), SongPattern::new(1), SongPattern::new(2)];
+    for p in &mut song.patterns[0..3] {
+        p.set_num_steps(2);
+    }
+    song.loop_start = 1;
+    let mut sequencer = test_sequencer(song);
+
+    assert_eq!(sequencer.next_step_and_pattern_and_song_pattern(true, 1, 1), (0, 2));
+    assert_eq!(sequencer.next_step_and_pattern_and_song_pattern(true, 1, 2), (0, 1));
+    assert_eq!(sequencer.song_loop_start_in_frames(), Some(2 * 7));
+
+    sequencer.song.stop_at_end = true;
+    assert_eq!(sequencer.song_loop_start_in_frames(), None);
+    sequencer.set_playing(true, true);
+    for _ in 0..3 * 2 * 7 {
+        sequencer.advance_frame();
+    }
+    assert!(sequencer.playing());
+    sequencer.advance_frame();
+    assert!(!sequencer.playing());
+}
+
+#[test]
+fn song_pattern_transpose_skips_untransposed_instruments() {
+    let mut song = SequencerSong::default();
+    let mut transposed = SongPattern::new(0);
+    transposed.transpose = -12;
+    song.song_patterns = vec![transposed];
+    for id in ["1", "D"] {
+        song.patterns[0].get_steps_mut_or_insert(id, None)[0].set_press_note(Some(60));
+    }
+    song.untransposed_instruments = vec!["D".to_owned()];
+    let mut sequencer = test_sequencer(song);
+    sequencer.set_instrument_def(vec!["1".into(), "D".into()], vec![[None, None, None, None]; 2]);
+
+    sequencer.set_playing(true, true);
+    let notes: Vec<(u8, u8)> = sequencer
+        .advance_frame()
+        .1
+        .into_iter()
+        .filter_map(|(i, e)| match e {
+            StepEvent::Press(note, _) => Some((i, note)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(notes, [(0, 48), (1, 60)]);
+}
+
+#[test]
+fn bank_songs_keep_their_content() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(3)];
+    let mut sequencer = test_sequencer(song);
+
+    // Selecting past the last song adds an empty one, which isn't saved.
+    sequencer.cycle_bank_song(true);
+    assert_eq!(sequencer.num_bank_songs(), 2);
+    assert_eq!(sequencer.serialize_bank_to_postcard().unwrap().len(), 1);
+
+    sequencer.song.song_patterns = vec![SongPattern::new(5)];
+    sequencer.has_stub_pattern = false;
+    sequencer.cycle_bank_song(false);
+    assert_eq!(sequencer.song_patterns(), [3]);
+
+    let songs = sequencer.serialize_bank_to_postcard().unwrap();
+    assert_eq!(songs.len(), 2);
+    let song_slices: Vec<&[u8]> = songs.iter().map(|s| s.as_slice()).collect();
+    sequencer.load_postcard_bank(&song_slices).unwrap();
+    sequencer.select_bank_song(1);
+    assert_eq!(sequencer.song_patterns(), [5]);
+}
+
+#[test]
+fn undo_restores_edits_and_recording_passes() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0), SongPattern::new(1)];
+    let mut sequencer = test_sequencer(song);
+    let pressed = |s: &Sequencer| -> Vec<bool> {
+        (0..2)
+            .map(|step| s.song.patterns[0].get_steps(0).map_or(false, |ss| ss[step].press()))
+            .collect()
+    };
+
+    sequencer.toggle_step(0);
+    sequencer.cut_step_range_note(0, 0);
+    assert_eq!(pressed(&sequencer), [false, false]);
+    sequencer.undo();
+    assert_eq!(pressed(&sequencer), [true, false]);
+    sequencer.undo();
+    assert_eq!(pressed(&sequencer), [false, false]);
+    sequencer.redo();
+    assert_eq!(pressed(&sequencer), [true, false]);
+
+    // Notes recorded during playback are undone together.
+    sequencer.cut_step_range_note(0, 0);
+    sequencer.set_recording(true);
+    sequencer.set_playing(true, false);
+    sequencer.advance_frame();
+    sequencer.record_press(60);
+    for _ in 0..sequencer.song.frames_per_step {
+        sequencer.advance_frame();
+    }
+    sequencer.record_press(62);
+    sequencer.set_playing(false, false);
+    assert_eq!(pressed(&sequencer), [true, true]);
+    sequencer.undo();
+    assert_eq!(pressed(&sequencer), [false, false]);
+
+    sequencer.display_song_pattern(1);
+    sequencer.remove_last_song_pattern();
+    assert_eq!(sequencer.song_patterns(), [0]);
+    sequencer.undo();
+    assert_eq!(sequencer.song_patterns(), [0, 1]);
+}
+
+#[test]
+fn undoing_num_steps_keeps_the_active_step_in_the_pattern() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    song.patterns[0].set_num_steps(2);
+    let mut sequencer = test_sequencer(song);
+
+    sequencer.cycle_displayed_pattern_num_steps(true);
+    sequencer.set_playing(true, false);
+    while sequencer.active_step < 2 {
+        sequencer.advance_frame();
+    }
+    sequencer.undo();
+    assert_eq!(sequencer.active_step, 1);
+    for _ in 0..sequencer.song.frames_per_step * 2 {
+        sequencer.advance_frame();
+    }
+    sequencer.redo();
+    assert_eq!(sequencer.song.patterns[0].num_steps(), 3);
+}
+
+#[test]
+fn step_conditions_follow_song_pattern_repeats() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    song.patterns[0].set_num_steps(2);
+    let steps = song.patterns[0].get_steps_mut_or_insert("1", None);
+    steps[0].set_press_note(Some(60));
+    steps[0].condition = Some(StepCondition::Every(2, 2));
+    steps[1].set_press_note(Some(62));
+    steps[1].condition = Some(StepCondition::Chance(50));
+    let mut sequencer = test_sequencer(song);
+
+    let play = |s: &mut Sequencer| -> Vec<u8> {
+        s.activate_step(0);
+        s.set_playing(true, false);
+        let notes = (0..8 * 2 * 7)
+            .flat_map(|_| s.advance_frame().1)
+            .filter_map(|(_, e)| match e {
+                StepEvent::Press(note, _) => Some(note),
+                _ => None,
+            })
+            .collect();
+        s.set_playing(false, false);
+        notes
+    };
+    let notes = play(&mut sequencer);
+    // Only the second of every two repeats presses the first step.
+    assert_eq!(notes.iter().filter(|n| **n == 60).count(), 4);
+    let chance_presses = notes.iter().filter(|n| **n == 62).count();
+    assert!(chance_presses > 0 && chance_presses < 8);
+    // The RNG is reset with each playback.
+    assert_eq!(play(&mut sequencer), notes);
+}
+
+#[test]
+fn ratchets_retrigger_within_the_step() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    let steps = song.patterns[0].get_steps_mut_or_insert("1", None);
+    steps[0].set_press_note(Some(60));
+    steps[0].ratchet = Some(3);
+    steps[1].set_press_note(Some(60));
+    steps[1].release_pos = ReleasePos::Half;
+    steps[1].ratchet = Some(4);
+    let mut sequencer = test_sequencer(song);
+
+    sequencer.set_playing(true, true);
+    let events: Vec<(u32, bool)> = (0..14)
+        .flat_map(|frame| {
+            sequencer
+                .advance_frame()
+                .1
+                .into_iter()
+                .map(move |(_, e)| (frame, matches!(e, StepEvent::Press(..))))
+        })
+        .collect();
+    // Each hit releases the previous one, and hits of the second step from its half-step release on are dropped.
+    assert_eq!(
+        events,
+        [
+            (0, true),
+            (3, false),
+            (3, true),
+            (5, false),
+            (5, true),
+            (7, true),
+            (9, false),
+            (9, true),
+            (11, false)
+        ]
+    );
+}
+
+#[test]
+fn delayed_steps_play_their_events_late() {
+    let mut song = SequencerSong::default();
+    song.song_patterns = vec![SongPattern::new(0)];
+    let steps = song.patterns[0].get_steps_mut_or_insert("1", None);
+    steps[0].set_press_note(Some(60));
+    steps[0].release_pos = ReleasePos::Full;
+    steps[0].delay = 3;
+    steps[1].set_press_note(Some(62));
+    steps[1].release_pos = ReleasePos::Half;
+    steps[1].delay = 1;
+    steps[2].set_press_note(Some(64));
+    steps[2].release_pos = ReleasePos::Half;
+    // Longer than the step, so it's cut to the last frame of the step.
+    steps[2].delay = 20;
+    let mut sequencer = test_sequencer(song);
+
+    sequencer.set_playing(true, true);
+    let events: Vec<(u32, bool)> = (0..4 * 7)
+        .flat_map(|frame| {
+            sequencer
+                .advance_frame()
+                .1
+                .into_iter()
+                .map(move |(_, e)| (frame, matches!(e, StepEvent::Press(..))))
+        })
+        .collect();
+    // The press of the second step replaces the first note before its delayed full-step release.
+    // The half-step release of the last step is pushed into the following step.
+    assert_eq!(events, [(3, true), (8, true), (12, false), (20, true), (24, false)]);
+}
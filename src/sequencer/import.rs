@@ -0,0 +1,184 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Helpers shared by the importers that create songs from the notes of other formats.
+
+use crate::sequencer::Instrument;
+use crate::sequencer::InstrumentStep;
+use crate::sequencer::Pattern;
+use crate::sequencer::ReleasePos;
+use crate::sequencer::SequencerSong;
+use crate::sequencer::SongPattern;
+use crate::sequencer::FRAMES_PER_STEP_RANGE;
+use crate::sound_engine::DEFAULT_NUM_STEPS;
+use crate::sound_engine::NUM_PATTERNS;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+// The duration of a Game Boy frame, which drives the sequencer.
+pub const FRAME_MICROS: f64 = 70224.0 / 4194304.0 * 1_000_000.0;
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// A description of each source that had notes, with the instrument ID it was imported into.
+    pub assigned: Vec<(String, String)>,
+    /// A description of each source with notes that couldn't be assigned to an instrument.
+    pub unmatched: Vec<String>,
+    /// How many notes were dropped to keep instruments monophonic, see quantize_notes.
+    pub dropped_notes: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImportedNote {
+    pub start_tick: u32,
+    /// None if the note keeps playing until the next note.
+    pub end_tick: Option<u32>,
+    pub key: u8,
+}
+
+#[derive(Default)]
+pub struct ImportedInstrument {
+    pub notes: Vec<ImportedNote>,
+    /// The tick, parameter number and value of each parameter change.
+    pub params: Vec<(u32, u8, i8)>,
+}
+
+impl ImportedInstrument {
+    /// Quantizes the notes and parameters to steps, returning them by step index along with
+    /// how many notes were dropped.
+    pub fn into_steps(self, ticks_per_step: f64) -> (BTreeMap<usize, InstrumentStep>, usize) {
+        let (mut steps, dropped) = quantize_notes(self.notes, ticks_per_step);
+        for (tick, param_num, val) in self.params {
+            let step = steps
+                .entry((tick as f64 / ticks_per_step).round() as usize)
+                .or_default();
+            step.params[param_num as usize] = Some(val);
+        }
+        (steps, dropped)
+    }
+}
+
+/// Quantizes notes to steps, presses to the nearest step and releases to the nearest half step.
+///
+/// Instruments can only play one note at a time, so overlapping notes are resolved this way:
+/// - Of notes pressed on the same step, only the highest one is kept.
+/// - A note still held when the next note is pressed is cut by that press, without a release.
+///
+/// Returns the steps by index and how many notes were dropped.
+pub fn quantize_notes(mut notes: Vec<ImportedNote>, ticks_per_step: f64) -> (BTreeMap<usize, InstrumentStep>, usize) {
+    let to_step = |tick: u32| (tick as f64 / ticks_per_step).round() as usize;
+    let to_half_step = |tick: u32| (tick as f64 * 2.0 / ticks_per_step).round() as usize;
+
+    notes.sort_by_key(|n| (to_step(n.start_tick), u8::MAX - n.key));
+    let num_notes = notes.len();
+    // Note 0 is used by steps without a press.
+    notes.retain(|n| n.key != 0);
+    notes.dedup_by_key(|n| to_step(n.start_tick));
+    let dropped = num_notes - notes.len();
+
+    let mut steps: BTreeMap<usize, InstrumentStep> = BTreeMap::new();
+    for (i, note) in notes.iter().enumerate() {
+        let press_step = to_step(note.start_tick);
+        steps.entry(press_step).or_default().set_press_note(Some(note.key));
+
+        let end_tick = match note.end_tick {
+            Some(tick) => tick,
+            None => continue,
+        };
+        // Keep at least half a step between the press and the release.
+        let release_half_step = to_half_step(end_tick).max(press_step * 2 + 1);
+        let next_press_half_step = notes.get(i + 1).map_or(usize::MAX, |n| to_step(n.start_tick) * 2);
+        if release_half_step <= next_press_half_step {
+            let (release_step, release_pos) = if release_half_step % 2 == 1 {
+                (release_half_step / 2, ReleasePos::Half)
+            } else {
+                (release_half_step / 2 - 1, ReleasePos::Full)
+            };
+            steps.entry(release_step).or_default().release_pos = release_pos;
+        }
+    }
+    (steps, dropped)
+}
+
+/// Creates a song from the steps of each instrument, one pattern per 16 steps window.
+/// Windows with identical content share the same pattern.
+/// The song ends after num_steps, or after the last step with content if None.
+pub fn build_song(
+    instrument_steps: &[(String, BTreeMap<usize, InstrumentStep>)],
+    num_steps: Option<usize>,
+    frames_per_step: u32,
+    format_name: &str,
+) -> Result<SequencerSong, Box<dyn Error>> {
+    let mut song = SequencerSong::default();
+    song.frames_per_step = frames_per_step.clamp(*FRAMES_PER_STEP_RANGE.start(), *FRAMES_PER_STEP_RANGE.end());
+
+    let num_steps = instrument_steps
+        .iter()
+        .filter_map(|(_, steps)| steps.keys().next_back())
+        .max()
+        .map_or(0, |last| last + 1)
+        .max(num_steps.unwrap_or(0));
+    let mut num_patterns = 0;
+    for window in 0..num_steps.div_ceil(DEFAULT_NUM_STEPS) {
+        let window_steps = window * DEFAULT_NUM_STEPS..(window + 1) * DEFAULT_NUM_STEPS;
+        let pattern = Pattern {
+            num_steps: DEFAULT_NUM_STEPS,
+            instruments: instrument_steps
+                .iter()
+                .filter(|(_, steps)| steps.range(window_steps.clone()).next().is_some())
+                .map(|(id, steps)| {
+                    let mut instrument = Instrument {
+                        id: id.clone(),
+                        synth_index: None,
+                        steps: vec![InstrumentStep::default(); DEFAULT_NUM_STEPS],
+                    };
+                    for (i, step) in steps.range(window_steps.clone()) {
+                        instrument.steps[i - window_steps.start] = *step;
+                    }
+                    instrument
+                })
+                .collect(),
+            groove: None,
+        };
+        let existing = song.patterns[..num_patterns]
+            .iter()
+            .position(|p| p.instruments == pattern.instruments);
+        let pattern_idx = match existing {
+            Some(p) => p,
+            None if num_patterns < NUM_PATTERNS => {
+                song.patterns[num_patterns] = pattern;
+                num_patterns += 1;
+                num_patterns - 1
+            }
+            None => {
+                return Err(format!(
+                    "The {} needs more than the maximum of {} different patterns",
+                    format_name, NUM_PATTERNS
+                )
+                .into())
+            }
+        };
+        song.song_patterns.push(SongPattern::new(pattern_idx));
+    }
+
+    Ok(song)
+}
+
+#[test]
+fn quantize_overlapping_notes() {
+    let note = |start_tick, end_tick, key| ImportedNote {
+        start_tick,
+        end_tick,
+        key,
+    };
+    // With 4 ticks per step: a chord on step 0 held until step 3, but cut by a note on step 2.
+    let notes = vec![note(0, Some(12), 60), note(1, Some(12), 64), note(8, Some(10), 67)];
+    let (steps, dropped) = quantize_notes(notes, 4.0);
+    assert_eq!(dropped, 1);
+    assert_eq!(steps[&0].press_note(), Some(64));
+    assert_eq!(steps[&0].release_pos(), ReleasePos::NotReleased);
+    assert_eq!(steps[&2].press_note(), Some(67));
+    assert_eq!(steps[&2].release_pos(), ReleasePos::Half);
+    assert_eq!(steps.len(), 2);
+}
@@ -1,11 +1,19 @@
 // Copyright © 2021 Jocelyn Turcotte <turcotte.j@gmail.com>
 // SPDX-License-Identifier: MIT
 
+use crate::sequencer::groove_to_string;
+use crate::sequencer::parse_groove;
 use crate::sequencer::InstrumentStep;
 use crate::sequencer::SequencerSong;
+use crate::sequencer::SongPattern;
+use crate::sequencer::StepCondition;
+use crate::sequencer::BPM_RANGE;
+use crate::sequencer::FRAMES_PER_STEP_RANGE;
+use crate::sequencer::MAX_RATCHET;
+use crate::sequencer::MAX_TRANSPOSE;
+use crate::sound_engine::MAX_NUM_STEPS;
 use crate::sound_engine::NUM_INSTRUMENTS;
 use crate::sound_engine::NUM_PATTERNS;
-use crate::sound_engine::NUM_STEPS;
 use crate::utils::MidiNote;
 
 use pulldown_cmark::Event::*;
@@ -25,6 +33,13 @@ use super::ReleasePos;
 
 const INSTRUMENTS_FILE_SETTING: &str = "InstrumentsFile";
 const FRAMES_PER_STEP_SETTING: &str = "FramesPerStep";
+const GROOVE_SETTING: &str = "Groove";
+const BPM_SETTING: &str = "BPM";
+const LOOP_START_SETTING: &str = "LoopStart";
+const END_MODE_SETTING: &str = "EndMode";
+const UNTRANSPOSED_INSTRUMENTS_SETTING: &str = "UntransposedInstruments";
+/// Starts each song of the song bank after the first one, followed by the song number.
+const BANK_SONG_HEADING: &str = "# Bank Song ";
 
 #[derive(PartialEq)]
 enum Section {
@@ -65,16 +80,27 @@ impl<'a, 'b> MarkdownSongParser<'a, 'b> {
     fn run(mut self) -> Result<SequencerSong, Box<dyn std::error::Error>> {
         let pattern_re = Regex::new(r"Pattern (\d+)").unwrap();
         let setting_re = Regex::new(r"(\w+): (.*)").unwrap();
+        let pattern_groove_setting_re = Regex::new(r"^Pattern(\d+)Groove$").unwrap();
 
         for (event, tag_range) in self.iter.by_ref() {
             match event {
                 Start(tag) => {
                     match tag {
+                        Table(..) => {
+                            if let Section::Pattern(pattern_idx) = self.section {
+                                // Allow any number of rows until the end of the table gives the pattern's length.
+                                self.out.patterns[pattern_idx].num_steps = MAX_NUM_STEPS;
+                            }
+                        }
                         TableRow => {
                             self.table_row = match self.table_row {
                                 Some(r) => {
-                                    if matches!(self.section, Section::Pattern(_)) && r >= 15 {
-                                        return Err("A pattern's steps table shouldn't have more than 16 rows".into());
+                                    if matches!(self.section, Section::Pattern(_)) && r >= MAX_NUM_STEPS - 1 {
+                                        return Err(format!(
+                                            "A pattern's steps table shouldn't have more than {} rows",
+                                            MAX_NUM_STEPS
+                                        )
+                                        .into());
                                     }
                                     Some(r + 1)
                                 }
@@ -107,8 +133,16 @@ impl<'a, 'b> MarkdownSongParser<'a, 'b> {
                             }
                         }
                         Table(..) => {
-                            if matches!(self.section, Section::Pattern(_)) && self.table_row != Some(15) {
-                                return Err("A pattern's steps table should have exactly 16 rows".into());
+                            if let Section::Pattern(pattern_idx) = self.section {
+                                let num_rows = self.table_row.map_or(0, |r| r + 1);
+                                if num_rows == 0 {
+                                    return Err(format!(
+                                        "A pattern's steps table should have between 1 and {} rows",
+                                        MAX_NUM_STEPS
+                                    )
+                                    .into());
+                                }
+                                self.out.patterns[pattern_idx].set_num_steps(num_rows);
                             }
                             self.table_row = None;
                             self.table_instrument_ids.clear();
@@ -140,11 +174,41 @@ impl<'a, 'b> MarkdownSongParser<'a, 'b> {
                             }
                         };
                     } else if self.section == Section::Song && self.tag_stack.contains(&Item) {
-                        let caps = pattern_re
-                            .captures(&text)
+                        // The pattern name is usually a link, followed by the slot's options in a separate text.
+                        let options = match pattern_re.captures(&text) {
+                            Some(caps) => {
+                                let parsed: usize = caps.get(1).unwrap().as_str().parse()?;
+                                self.out.song_patterns.push(SongPattern::new(parsed - 1));
+                                &text[caps.get(0).unwrap().end()..]
+                            }
+                            None => &text[..],
+                        };
+                        let song_pattern = self
+                            .out
+                            .song_patterns
+                            .last_mut()
                             .ok_or_else(|| format!("Invalid song pattern name: [{}]", &*text))?;
-                        let parsed: usize = caps.get(1).unwrap().as_str().parse()?;
-                        self.out.song_patterns.push(parsed - 1);
+                        for option in options.split_whitespace() {
+                            // @5 plays the slot with 5 frames per step, +5 or -5 transposes it by 5 semitones.
+                            if let Some(frames_per_step) = option.strip_prefix('@') {
+                                match frames_per_step.parse::<u32>() {
+                                    Ok(frames_per_step) if FRAMES_PER_STEP_RANGE.contains(&frames_per_step) => {
+                                        song_pattern.frames_per_step = Some(frames_per_step)
+                                    }
+                                    _ => return Err(format!("Invalid song pattern option: [{}]", option).into()),
+                                }
+                            } else {
+                                match option.parse::<i8>() {
+                                    Ok(transpose)
+                                        if option.starts_with(['+', '-'])
+                                            && (-MAX_TRANSPOSE..=MAX_TRANSPOSE).contains(&transpose) =>
+                                    {
+                                        song_pattern.transpose = transpose
+                                    }
+                                    _ => return Err(format!("Invalid song pattern option: [{}]", option).into()),
+                                }
+                            }
+                        }
                     } else if matches!(self.section, Section::Pattern(_)) && self.tag_stack.contains(&TableHead) {
                         let text = &self.source[tag_range].trim();
                         self.table_instrument_ids.push((*text).to_owned());
@@ -162,10 +226,36 @@ impl<'a, 'b> MarkdownSongParser<'a, 'b> {
                             let instrument_id = &self.table_instrument_ids[self.table_column.unwrap()];
                             let step = &mut self.out.patterns[pattern_idx].get_steps_mut_or_insert(instrument_id, None)
                                 [self.table_row.unwrap()];
-                            if !text.is_empty() && text != "-" {
-                                let MidiNote(note) = MidiNote::from_name(text)?;
+                            // The note can be followed by a condition, a ratchet and a delay, e.g. C-5 1:4 x3 >2.
+                            let mut words = text.split_whitespace().peekable();
+                            let note_text = words.next().unwrap_or("");
+                            if !note_text.is_empty() && note_text != "-" {
+                                let MidiNote(note) = MidiNote::from_name(note_text)?;
                                 step.note = note as u8;
                             }
+                            if let Some(condition) = words.next_if(|w| !w.starts_with(['x', '>'])) {
+                                step.condition = Some(condition.parse::<StepCondition>()?);
+                            }
+                            if let Some(ratchet) = words.next_if(|w| w.starts_with('x')) {
+                                let hits = ratchet
+                                    .strip_prefix('x')
+                                    .and_then(|h| h.parse::<u8>().ok())
+                                    .filter(|h| (2..=MAX_RATCHET).contains(h))
+                                    .ok_or_else(|| {
+                                        format!("Invalid ratchet [{}], expected x2 to x{}", ratchet, MAX_RATCHET)
+                                    })?;
+                                step.ratchet = Some(hits);
+                            }
+                            if let Some(delay) = words.next_if(|w| w.starts_with('>')) {
+                                step.delay = delay[1..]
+                                    .parse::<u8>()
+                                    .ok()
+                                    .filter(|d| *d > 0)
+                                    .ok_or_else(|| format!("Invalid delay [{}], expected e.g. >2", delay))?;
+                            }
+                            if let Some(extra) = words.next() {
+                                return Err(format!("Unexpected text in step: [{}]", extra).into());
+                            }
                             if ends_with_period {
                                 step.release_pos = ReleasePos::Full;
                             } else if ends_with_equal {
@@ -180,13 +270,66 @@ impl<'a, 'b> MarkdownSongParser<'a, 'b> {
                         let value = caps.get(2).unwrap().as_str();
                         match name {
                             INSTRUMENTS_FILE_SETTING => self.out.instruments_file = value.into(),
-                            FRAMES_PER_STEP_SETTING => {
-                                self.out.frames_per_step = value.parse().or(Err(format!(
-                                    "Setting {} contains an invalid integer ({}).",
-                                    FRAMES_PER_STEP_SETTING, value
-                                )))?
+                            FRAMES_PER_STEP_SETTING => match value.parse::<u32>() {
+                                Ok(frames_per_step) if FRAMES_PER_STEP_RANGE.contains(&frames_per_step) => {
+                                    self.out.frames_per_step = frames_per_step
+                                }
+                                _ => {
+                                    return Err(format!(
+                                        "Setting {} contains an invalid frame count ({}).",
+                                        FRAMES_PER_STEP_SETTING, value
+                                    )
+                                    .into())
+                                }
+                            },
+                            GROOVE_SETTING => self.out.groove = parse_groove(value)?,
+                            BPM_SETTING => match value.parse::<f32>() {
+                                Ok(bpm) if BPM_RANGE.contains(&bpm) => self.out.bpm = Some(bpm),
+                                _ => {
+                                    return Err(
+                                        format!("Setting {} contains an invalid BPM ({}).", BPM_SETTING, value).into()
+                                    )
+                                }
+                            },
+                            LOOP_START_SETTING => match value.parse::<usize>() {
+                                // Song patterns are numbered from 1 like in the UI.
+                                Ok(n) if n > 0 => self.out.loop_start = n - 1,
+                                _ => {
+                                    return Err(format!(
+                                        "Setting {} contains an invalid song pattern number ({}).",
+                                        LOOP_START_SETTING, value
+                                    )
+                                    .into())
+                                }
+                            },
+                            UNTRANSPOSED_INSTRUMENTS_SETTING => {
+                                self.out.untransposed_instruments = value
+                                    .split(',')
+                                    .map(|id| id.trim().to_owned())
+                                    .filter(|id| !id.is_empty())
+                                    .collect()
                             }
-                            other => elog!("Unknown song setting [{}], ignoring.", other),
+                            END_MODE_SETTING => match value {
+                                "Loop" => self.out.stop_at_end = false,
+                                "Stop" => self.out.stop_at_end = true,
+                                _ => {
+                                    return Err(format!(
+                                        "Setting {} should be Loop or Stop, got ({}).",
+                                        END_MODE_SETTING, value
+                                    )
+                                    .into())
+                                }
+                            },
+                            other => match pattern_groove_setting_re.captures(other) {
+                                Some(caps) => {
+                                    let num: usize = caps.get(1).unwrap().as_str().parse()?;
+                                    if num == 0 || num > NUM_PATTERNS {
+                                        return Err(format!("Invalid pattern number in setting {}", other).into());
+                                    }
+                                    self.out.patterns[num - 1].groove = Some(parse_groove(value)?);
+                                }
+                                None => elog!("Unknown song setting [{}], ignoring.", other),
+                            },
                         }
                     }
                 }
@@ -204,10 +347,9 @@ impl<'a, 'b> MarkdownSongParser<'a, 'b> {
                                 } else {
                                     Some(trimmed.parse::<i8>()?)
                                 };
-                                match i {
-                                    0 => step.param0 = val,
-                                    1 => step.param1 = val,
-                                    _ => Err(format!("Too many param: {}", text))?,
+                                match step.params.get_mut(i) {
+                                    Some(param) => *param = val,
+                                    None => Err(format!("Too many param: {}", text))?,
                                 }
                             }
                         }
@@ -237,18 +379,67 @@ pub fn parse_markdown_song(markdown: &str) -> Result<SequencerSong, Box<dyn std:
     MarkdownSongParser::new(markdown, events).run()
 }
 
-pub fn save_markdown_song(song: &SequencerSong, project_song_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+/// Parses the songs of a song bank, each song after the first one starts with a BANK_SONG_HEADING line.
+pub fn parse_markdown_song_bank(markdown: &str) -> Result<Vec<SequencerSong>, Box<dyn std::error::Error>> {
+    let mut chunks = Vec::new();
+    let mut chunk_start = 0;
+    let mut line_start = 0;
+    for line in markdown.split_inclusive('\n') {
+        if line.starts_with(BANK_SONG_HEADING) {
+            chunks.push(&markdown[chunk_start..line_start]);
+            chunk_start = line_start + line.len();
+        }
+        line_start += line.len();
+    }
+    chunks.push(&markdown[chunk_start..]);
+
+    let mut songs = Vec::new();
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let mut song = parse_markdown_song(chunk)?;
+        if i > 0 {
+            // The blank line after the heading is written back with it.
+            song.markdown_header = song.markdown_header.trim_start_matches('\n').to_owned();
+        }
+        songs.push(song);
+    }
+    Ok(songs)
+}
+
+pub fn save_markdown_song_bank(
+    songs: &[&SequencerSong],
+    project_song_path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
     log!("Saving project song to file {:?}.", project_song_path);
     let f = File::create(project_song_path)?;
     let mut f = BufWriter::new(f);
 
+    for (i, song) in songs.iter().enumerate() {
+        if i > 0 {
+            write!(f, "{}{}\n\n", BANK_SONG_HEADING, i + 1)?;
+        }
+        write_markdown_song(song, &mut f)?;
+    }
+
+    f.flush()?;
+
+    Ok(())
+}
+
+fn write_markdown_song<W: Write>(song: &SequencerSong, f: &mut W) -> Result<(), Box<dyn std::error::Error>> {
     f.write_all(song.markdown_header.as_bytes())?;
 
     if !song.song_patterns.is_empty() {
         write!(f, "## Song\n\n")?;
 
-        for spi in song.song_patterns.iter() {
-            writeln!(f, "- [Pattern {}](#pattern-{})", spi + 1, spi + 1)?;
+        for sp in song.song_patterns.iter() {
+            write!(f, "- [Pattern {}](#pattern-{})", sp.pattern + 1, sp.pattern + 1)?;
+            if sp.transpose != 0 {
+                write!(f, " {:+}", sp.transpose)?;
+            }
+            if let Some(frames_per_step) = sp.frames_per_step {
+                write!(f, " @{}", frames_per_step)?;
+            }
+            writeln!(f)?;
         }
 
         writeln!(f)?;
@@ -278,44 +469,64 @@ pub fn save_markdown_song(song: &SequencerSong, project_song_path: &Path) -> Res
             ai.partial_cmp(&bi).unwrap()
         });
 
+        // The number of steps is given by the table rows, so it's lost for patterns without any note.
         if !non_empty.is_empty() {
             write!(f, "## Pattern {}\n\n", pi + 1)?;
 
             fn params_string(s: &InstrumentStep) -> String {
-                match (s.param0, s.param1) {
-                    (Some(p0), Some(p1)) => format!("`{}/{}`", p0, p1),
-                    (Some(p), None) => format!("`{}`", p),
-                    (None, Some(p)) => format!("`/{}`", p),
-                    (None, None) => String::new(),
+                // Only write up to the last set param, leaving unset ones empty between slashes.
+                match s.params.iter().rposition(Option::is_some) {
+                    Some(last) => {
+                        let params: Vec<String> = s.params[..=last]
+                            .iter()
+                            .map(|p| p.map_or_else(String::new, |v| v.to_string()))
+                            .collect();
+                        format!("`{}`", params.join("/"))
+                    }
+                    None => String::new(),
                 }
             }
-            let param_max_widths: Vec<_> = non_empty
-                .iter()
-                .map(|ii| {
-                    let i = &p.instruments[*ii];
-                    i.steps.iter().map(|s| params_string(s).len()).max().unwrap()
-                })
-                .collect();
-
-            for (ii, param_width) in non_empty.iter().zip(param_max_widths.iter()) {
+            fn modifiers_string(s: &InstrumentStep) -> String {
+                let condition = s.condition.map_or_else(String::new, |c| format!(" {}", c));
+                let ratchet = s.ratchet.map_or_else(String::new, |r| format!(" x{}", r));
+                let delay = if s.delay > 0 {
+                    format!(" >{}", s.delay)
+                } else {
+                    String::new()
+                };
+                condition + &ratchet + &delay
+            }
+            let max_width = |ii: &usize, to_string: fn(&InstrumentStep) -> String| {
+                p.instruments[*ii]
+                    .steps
+                    .iter()
+                    .map(|s| to_string(s).len())
+                    .max()
+                    .unwrap()
+            };
+            let param_max_widths: Vec<_> = non_empty.iter().map(|ii| max_width(ii, params_string)).collect();
+            let modifier_max_widths: Vec<_> = non_empty.iter().map(|ii| max_width(ii, modifiers_string)).collect();
+
+            for (i, ii) in non_empty.iter().enumerate() {
                 let id = &p.instruments[*ii].id;
-                write!(f, "|{: ^1$}", id, 4 + param_width)?;
+                write!(f, "|{: ^1$}", id, 4 + modifier_max_widths[i] + param_max_widths[i])?;
             }
             writeln!(f, "|")?;
-            for (_, param_width) in non_empty.iter().zip(param_max_widths.iter()) {
-                write!(f, "|----{:-^1$}", "", param_width)?;
+            for i in 0..non_empty.len() {
+                write!(f, "|----{:-^1$}", "", modifier_max_widths[i] + param_max_widths[i])?;
             }
             writeln!(f, "|")?;
 
-            for si in 0..NUM_STEPS {
-                for (ii, param_width) in non_empty.iter().zip(param_max_widths.iter()) {
-                    let i = &p.instruments[*ii];
-                    let s = i.steps[si];
+            for si in 0..p.num_steps {
+                for (i, ii) in non_empty.iter().enumerate() {
+                    let (param_width, modifier_width) = (param_max_widths[i], modifier_max_widths[i]);
+                    let s = p.instruments[*ii].steps[si];
                     if let Some(note) = s.press_note() {
                         write!(f, "|{}", MidiNote(note as i32).name())?;
                     } else {
                         write!(f, "| - ")?;
                     }
+                    write!(f, "{:width$}", modifiers_string(&s), width = modifier_width)?;
                     match s.release_pos {
                         ReleasePos::NotReleased => write!(f, " ")?,
                         ReleasePos::Half => write!(f, "=")?,
@@ -332,10 +543,33 @@ pub fn save_markdown_song(song: &SequencerSong, project_song_path: &Path) -> Res
     write!(f, "## Settings\n\n")?;
     writeln!(f, "- {}: {}", INSTRUMENTS_FILE_SETTING, song.instruments_file)?;
     writeln!(f, "- {}: {}", FRAMES_PER_STEP_SETTING, song.frames_per_step)?;
+    if let Some(bpm) = song.bpm {
+        writeln!(f, "- {}: {}", BPM_SETTING, bpm)?;
+    }
+    if !song.groove.is_empty() {
+        writeln!(f, "- {}: {}", GROOVE_SETTING, groove_to_string(&song.groove))?;
+    }
+    if song.loop_start > 0 {
+        writeln!(f, "- {}: {}", LOOP_START_SETTING, song.loop_start + 1)?;
+    }
+    if song.stop_at_end {
+        writeln!(f, "- {}: Stop", END_MODE_SETTING)?;
+    }
+    if !song.untransposed_instruments.is_empty() {
+        writeln!(
+            f,
+            "- {}: {}",
+            UNTRANSPOSED_INSTRUMENTS_SETTING,
+            song.untransposed_instruments.join(", ")
+        )?;
+    }
+    for (pi, p) in song.patterns.iter().enumerate() {
+        if let Some(groove) = p.groove.as_ref().filter(|g| !g.is_empty()) {
+            writeln!(f, "- Pattern{}{}: {}", pi + 1, GROOVE_SETTING, groove_to_string(groove))?;
+        }
+    }
     writeln!(f)?;
 
-    f.flush()?;
-
     Ok(())
 }
 
@@ -356,6 +590,157 @@ fn settings() {
     assert!(parse_markdown_song("## Pattern 1").is_err());
 }
 
+#[test]
+fn song_pattern_tempo() {
+    let song = parse_markdown_song(
+        "
+## Song
+
+- [Pattern 1](#pattern-1)
+- [Pattern 2](#pattern-2) @5
+- [Pattern 2](#pattern-2) -12 @6
+- [Pattern 3](#pattern-3) +5
+
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+- UntransposedInstruments: 0A, 0B
+",
+    )
+    .unwrap();
+    assert_eq!(song.song_patterns[0].frames_per_step, None);
+    assert_eq!(song.song_patterns[1].pattern, 1);
+    assert_eq!(song.song_patterns[1].frames_per_step, Some(5));
+    assert_eq!(song.song_patterns[1].transpose, 0);
+    assert_eq!(song.song_patterns[2].frames_per_step, Some(6));
+    assert_eq!(song.song_patterns[2].transpose, -12);
+    assert_eq!(song.song_patterns[3].transpose, 5);
+    assert_eq!(song.untransposed_instruments, ["0A", "0B"]);
+
+    for invalid in ["@0", "@256"] {
+        assert!(parse_markdown_song(&format!(
+            "
+## Song
+
+- [Pattern 1](#pattern-1) {}
+
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+",
+            invalid
+        ))
+        .is_err());
+    }
+}
+
+#[test]
+fn tempo_settings() {
+    let song = parse_markdown_song(
+        "
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+- BPM: 123.5
+- Groove: 8/6
+- Pattern3Groove: 9/5/7/7
+",
+    )
+    .unwrap();
+    assert_eq!(song.bpm, Some(123.5));
+    assert_eq!(song.groove, [8, 6]);
+    assert_eq!(song.patterns[0].groove, None);
+    assert_eq!(song.patterns[2].groove, Some(vec![9, 5, 7, 7]));
+
+    for invalid in [
+        "Groove: 8/0",
+        "Groove: 8/256",
+        "FramesPerStep: 0",
+        "FramesPerStep: 256",
+        "BPM: 0",
+        "BPM: inf",
+        "BPM: 1e30",
+        "BPM: NaN",
+    ] {
+        assert!(parse_markdown_song(&format!(
+            "
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+- {}
+",
+            invalid
+        ))
+        .is_err());
+    }
+}
+
+#[test]
+fn loop_settings() {
+    let song = parse_markdown_song(
+        "
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+- LoopStart: 3
+- EndMode: Stop
+",
+    )
+    .unwrap();
+    assert_eq!(song.loop_start, 2);
+    assert!(song.stop_at_end);
+
+    assert!(parse_markdown_song(
+        "
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+- LoopStart: 0
+"
+    )
+    .is_err());
+}
+
+#[test]
+fn song_bank() {
+    let songs = parse_markdown_song_bank(
+        "# My songs
+
+## Song
+
+- [Pattern 1](#pattern-1)
+
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+
+# Bank Song 2
+
+## Song
+
+- [Pattern 2](#pattern-2)
+- [Pattern 3](#pattern-3)
+
+## Settings
+
+- InstrumentsFile: some_instruments.wasm
+- FramesPerStep: 6
+",
+    )
+    .unwrap();
+    assert_eq!(songs.len(), 2);
+    assert_eq!(songs[0].markdown_header, "# My songs\n\n");
+    assert_eq!(songs[0].song_patterns, [SongPattern::new(0)]);
+    assert_eq!(songs[1].markdown_header, "");
+    assert_eq!(songs[1].song_patterns, [SongPattern::new(1), SongPattern::new(2)]);
+    assert_eq!(songs[1].frames_per_step, 6);
+
+    let mut bytes = Vec::new();
+    write_markdown_song(&songs[1], &mut bytes).unwrap();
+    let reparsed = parse_markdown_song(core::str::from_utf8(&bytes).unwrap()).unwrap();
+    assert_eq!(reparsed.song_patterns, songs[1].song_patterns);
+}
+
 #[test]
 fn illegal_pattern_num() {
     assert!(parse_markdown_song(
@@ -395,22 +780,24 @@ fn illegal_pattern_num() {
 
 #[test]
 fn pattern_steps() {
-    assert!(parse_markdown_song(
+    let song = parse_markdown_song(
         "
 ## Pattern 1
 
 |0  |
 |---|
-|-  |
+|C-5|
 
 ## Settings
 
 - InstrumentsFile: blah
-"
+",
     )
-    .is_err());
+    .unwrap();
+    assert_eq!(song.patterns[0].num_steps, 1);
+    assert_eq!(song.patterns[0].instruments[0].steps.len(), 1);
 
-    assert!(parse_markdown_song(
+    let song = parse_markdown_song(
         "
 ## Pattern 1
 
@@ -427,19 +814,30 @@ fn pattern_steps() {
 |-  |
 |-  |
 |-  |
-|-  |
-|-  |
-|-  |
-|-  |
-|-  |
-|-  |
-|-  |
+|C-5|
 
 ## Settings
 
 - InstrumentsFile: blah
-"
+",
     )
+    .unwrap();
+    assert_eq!(song.patterns[0].num_steps, 12);
+    assert_eq!(song.patterns[0].instruments[0].steps[11].press_note(), Some(72));
+
+    assert!(parse_markdown_song(&format!(
+        "
+## Pattern 1
+
+|0  |
+|---|
+{}
+## Settings
+
+- InstrumentsFile: blah
+",
+        "|-  |\n".repeat(MAX_NUM_STEPS + 1)
+    ))
     .is_err());
 }
 
@@ -544,7 +942,7 @@ fn param_parse() {
 |C-5. `1/5`|
 |C-5.`1 / 5`|
 |C-5.` 1/5 `|
-|   |
+|`1//-3/4`|
 |   |
 
 ## Settings
@@ -558,71 +956,223 @@ fn param_parse() {
         song.patterns[0].instruments[0].steps[3].release_pos,
         ReleasePos::NotReleased
     );
-    assert_eq!(song.patterns[0].instruments[0].steps[3].param0, None);
-    assert_eq!(song.patterns[0].instruments[0].steps[3].param1, None);
+    assert_eq!(song.patterns[0].instruments[0].steps[3].params[0], None);
+    assert_eq!(song.patterns[0].instruments[0].steps[3].params[1], None);
 
     assert_eq!(song.patterns[0].instruments[0].steps[4].press_note(), Some(72));
     assert_eq!(
         song.patterns[0].instruments[0].steps[4].release_pos,
         ReleasePos::NotReleased
     );
-    assert_eq!(song.patterns[0].instruments[0].steps[4].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[4].param1, Some(5));
+    assert_eq!(song.patterns[0].instruments[0].steps[4].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[4].params[1], Some(5));
 
     assert_eq!(song.patterns[0].instruments[0].steps[5].press_note(), None);
     assert_eq!(
         song.patterns[0].instruments[0].steps[5].release_pos,
         ReleasePos::NotReleased
     );
-    assert_eq!(song.patterns[0].instruments[0].steps[5].param0, Some(0));
-    assert_eq!(song.patterns[0].instruments[0].steps[5].param1, Some(5));
+    assert_eq!(song.patterns[0].instruments[0].steps[5].params[0], Some(0));
+    assert_eq!(song.patterns[0].instruments[0].steps[5].params[1], Some(5));
 
     assert_eq!(song.patterns[0].instruments[0].steps[6].press_note(), None);
     assert_eq!(
         song.patterns[0].instruments[0].steps[6].release_pos,
         ReleasePos::NotReleased
     );
-    assert_eq!(song.patterns[0].instruments[0].steps[6].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[6].param1, Some(4));
+    assert_eq!(song.patterns[0].instruments[0].steps[6].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[6].params[1], Some(4));
 
     assert_eq!(song.patterns[0].instruments[0].steps[7].press_note(), None);
     assert_eq!(
         song.patterns[0].instruments[0].steps[7].release_pos,
         ReleasePos::NotReleased
     );
-    assert_eq!(song.patterns[0].instruments[0].steps[7].param0, Some(0));
-    assert_eq!(song.patterns[0].instruments[0].steps[7].param1, None);
+    assert_eq!(song.patterns[0].instruments[0].steps[7].params[0], Some(0));
+    assert_eq!(song.patterns[0].instruments[0].steps[7].params[1], None);
 
     assert_eq!(song.patterns[0].instruments[0].steps[8].press_note(), None);
     assert_eq!(song.patterns[0].instruments[0].steps[8].release_pos, ReleasePos::Full);
-    assert_eq!(song.patterns[0].instruments[0].steps[8].param0, None);
-    assert_eq!(song.patterns[0].instruments[0].steps[8].param1, Some(3));
+    assert_eq!(song.patterns[0].instruments[0].steps[8].params[0], None);
+    assert_eq!(song.patterns[0].instruments[0].steps[8].params[1], Some(3));
 
     assert_eq!(song.patterns[0].instruments[0].steps[9].press_note(), None);
     assert_eq!(
         song.patterns[0].instruments[0].steps[9].release_pos,
         ReleasePos::NotReleased
     );
-    assert_eq!(song.patterns[0].instruments[0].steps[9].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[9].param1, None);
+    assert_eq!(song.patterns[0].instruments[0].steps[9].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[9].params[1], None);
 
     assert_eq!(song.patterns[0].instruments[0].steps[10].press_note(), Some(72));
     assert_eq!(song.patterns[0].instruments[0].steps[10].release_pos, ReleasePos::Full);
-    assert_eq!(song.patterns[0].instruments[0].steps[10].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[10].param1, Some(5));
+    assert_eq!(song.patterns[0].instruments[0].steps[10].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[10].params[1], Some(5));
 
     assert_eq!(song.patterns[0].instruments[0].steps[11].press_note(), Some(72));
     assert_eq!(song.patterns[0].instruments[0].steps[11].release_pos, ReleasePos::Full);
-    assert_eq!(song.patterns[0].instruments[0].steps[11].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[11].param1, Some(5));
+    assert_eq!(song.patterns[0].instruments[0].steps[11].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[11].params[1], Some(5));
 
     assert_eq!(song.patterns[0].instruments[0].steps[12].press_note(), Some(72));
     assert_eq!(song.patterns[0].instruments[0].steps[12].release_pos, ReleasePos::Full);
-    assert_eq!(song.patterns[0].instruments[0].steps[12].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[12].param1, Some(5));
+    assert_eq!(song.patterns[0].instruments[0].steps[12].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[12].params[1], Some(5));
 
     assert_eq!(song.patterns[0].instruments[0].steps[13].press_note(), Some(72));
     assert_eq!(song.patterns[0].instruments[0].steps[13].release_pos, ReleasePos::Full);
-    assert_eq!(song.patterns[0].instruments[0].steps[13].param0, Some(1));
-    assert_eq!(song.patterns[0].instruments[0].steps[13].param1, Some(5));
+    assert_eq!(song.patterns[0].instruments[0].steps[13].params[0], Some(1));
+    assert_eq!(song.patterns[0].instruments[0].steps[13].params[1], Some(5));
+
+    assert_eq!(
+        song.patterns[0].instruments[0].steps[14].params,
+        [Some(1), None, Some(-3), Some(4)]
+    );
+}
+
+#[test]
+fn step_conditions() {
+    let song = parse_markdown_song(
+        "
+## Pattern 1
+
+|0          |
+|-----------|
+|C-5 1:4    |
+|C-5 2+.    |
+|C-5 50%`1/5`|
+|C-5        |
+
+## Settings
+
+- InstrumentsFile: blah
+",
+    )
+    .unwrap();
+    let steps = &song.patterns[0].instruments[0].steps;
+    assert_eq!(steps[0].condition, Some(StepCondition::Every(1, 4)));
+    assert_eq!(steps[1].condition, Some(StepCondition::FromRepeat(2)));
+    assert_eq!(steps[1].release_pos, ReleasePos::Full);
+    assert_eq!(steps[2].condition, Some(StepCondition::Chance(50)));
+    assert_eq!(steps[2].params[1], Some(5));
+    assert_eq!(steps[3].condition, None);
+
+    let mut bytes = Vec::new();
+    write_markdown_song(&song, &mut bytes).unwrap();
+    let reparsed = parse_markdown_song(core::str::from_utf8(&bytes).unwrap()).unwrap();
+    assert_eq!(reparsed.patterns[0].instruments[0].steps, *steps);
+
+    for invalid in ["C-5 5:4", "C-5 0+", "C-5 101%", "C-5 1:4 2+"] {
+        assert!(parse_markdown_song(&format!(
+            "
+## Pattern 1
+
+|0  |
+|---|
+|{}|
+
+## Settings
+
+- InstrumentsFile: blah
+",
+            invalid
+        ))
+        .is_err());
+    }
+}
+
+#[test]
+fn ratchets() {
+    let song = parse_markdown_song(
+        "
+## Pattern 1
+
+|0            |
+|-------------|
+|C-5 x2       |
+|C-5 1:4 x4.  |
+|C-5          |
+
+## Settings
+
+- InstrumentsFile: blah
+",
+    )
+    .unwrap();
+    let steps = &song.patterns[0].instruments[0].steps;
+    assert_eq!(steps[0].ratchet, Some(2));
+    assert_eq!(steps[1].condition, Some(StepCondition::Every(1, 4)));
+    assert_eq!(steps[1].ratchet, Some(4));
+    assert_eq!(steps[1].release_pos, ReleasePos::Full);
+    assert_eq!(steps[2].ratchet, None);
+
+    let mut bytes = Vec::new();
+    write_markdown_song(&song, &mut bytes).unwrap();
+    let reparsed = parse_markdown_song(core::str::from_utf8(&bytes).unwrap()).unwrap();
+    assert_eq!(reparsed.patterns[0].instruments[0].steps, *steps);
+
+    for invalid in ["C-5 x1", "C-5 x5", "C-5 x2 1:4"] {
+        assert!(parse_markdown_song(&format!(
+            "
+## Pattern 1
+
+|0  |
+|---|
+|{}|
+
+## Settings
+
+- InstrumentsFile: blah
+",
+            invalid
+        ))
+        .is_err());
+    }
+}
+
+#[test]
+fn step_delays() {
+    let song = parse_markdown_song(
+        "
+## Pattern 1
+
+|0              |
+|---------------|
+|C-5 >3         |
+|C-5 50% x2 >1= |
+|C-5 >0         |
+
+## Settings
+
+- InstrumentsFile: blah
+",
+    );
+    assert!(song.is_err());
+
+    let song = parse_markdown_song(
+        "
+## Pattern 1
+
+|0              |
+|---------------|
+|C-5 >3         |
+|C-5 50% x2 >1= |
+
+## Settings
+
+- InstrumentsFile: blah
+",
+    )
+    .unwrap();
+    let steps = &song.patterns[0].instruments[0].steps;
+    assert_eq!(steps[0].delay, 3);
+    assert_eq!(steps[1].condition, Some(StepCondition::Chance(50)));
+    assert_eq!(steps[1].ratchet, Some(2));
+    assert_eq!(steps[1].delay, 1);
+    assert_eq!(steps[1].release_pos, ReleasePos::Half);
+
+    let mut bytes = Vec::new();
+    write_markdown_song(&song, &mut bytes).unwrap();
+    let reparsed = parse_markdown_song(core::str::from_utf8(&bytes).unwrap()).unwrap();
+    assert_eq!(reparsed.patterns[0].instruments[0].steps, *steps);
 }
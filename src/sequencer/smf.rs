@@ -0,0 +1,538 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Conversion of songs to and from Standard MIDI Files.
+
+use crate::sequencer::import::{build_song, ImportReport, ImportedInstrument, ImportedNote, FRAME_MICROS};
+use crate::sequencer::transpose_note;
+#[cfg(test)]
+use crate::sequencer::Instrument;
+use crate::sequencer::InstrumentStep;
+#[cfg(test)]
+use crate::sequencer::Pattern;
+#[cfg(test)]
+use crate::sequencer::ReleasePos;
+use crate::sequencer::SequencerSong;
+use crate::sequencer::SongPattern;
+use crate::sequencer::StepClock;
+#[cfg(test)]
+use crate::sound_engine::DEFAULT_NUM_STEPS;
+use crate::sound_engine::NUM_INSTRUMENT_PARAMS;
+
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::str::FromStr;
+
+// Steps are 16th notes.
+const STEPS_PER_BEAT: u32 = 4;
+const NOTE_VELOCITY: u8 = 100;
+
+pub struct SmfExportOptions {
+    /// The controller number on which each step parameter is sent.
+    /// Parameter values are offset by 64 so that values in [-64, 63] map to the controller's [0, 127].
+    pub param_controllers: [u8; NUM_INSTRUMENT_PARAMS],
+}
+
+impl Default for SmfExportOptions {
+    fn default() -> Self {
+        SmfExportOptions {
+            // General purpose controllers 1 to 4.
+            param_controllers: [16, 17, 18, 19],
+        }
+    }
+}
+
+fn param_to_controller_value(val: i8) -> u7 {
+    u7::new((val as i16 + 64).clamp(0, 127) as u8)
+}
+
+fn controller_value_to_param(value: u7) -> i8 {
+    (value.as_int() as i16 - 64) as i8
+}
+
+/// Collects events with absolute times, in the order they must happen.
+struct TrackBuilder<'a> {
+    channel: u4,
+    events: Vec<(u32, TrackEventKind<'a>)>,
+    sounding_note: Option<u8>,
+    /// When the sounding note is released, which can be after the start of the next step if it's delayed.
+    release_tick: Option<u32>,
+}
+
+impl<'a> TrackBuilder<'a> {
+    fn midi(&mut self, tick: u32, message: MidiMessage) {
+        self.events.push((
+            tick,
+            TrackEventKind::Midi {
+                channel: self.channel,
+                message,
+            },
+        ));
+    }
+
+    fn note_off(&mut self, tick: u32) {
+        if let Some(note) = self.sounding_note.take() {
+            self.midi(
+                tick,
+                MidiMessage::NoteOff {
+                    key: u7::new(note),
+                    vel: u7::new(0),
+                },
+            );
+        }
+    }
+
+    fn params(&mut self, tick: u32, step: &InstrumentStep, options: &SmfExportOptions) {
+        for (param, controller) in step.params.iter().zip(options.param_controllers) {
+            if let Some(val) = param {
+                self.midi(
+                    tick,
+                    MidiMessage::Controller {
+                        controller: u7::new(controller),
+                        value: param_to_controller_value(*val),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Adds the release of the sounding note if it happens before `tick`.
+    fn release_until(&mut self, tick: u32) {
+        if let Some(release_tick) = self.release_tick.filter(|t| *t <= tick) {
+            self.release_tick = None;
+            self.note_off(release_tick);
+        }
+    }
+
+    fn note_on(&mut self, tick: u32, note: u8) {
+        // Instruments are monophonic, a press replaces any note still playing.
+        self.release_tick = None;
+        self.note_off(tick);
+        self.midi(
+            tick,
+            MidiMessage::NoteOn {
+                key: u7::new(note),
+                vel: u7::new(NOTE_VELOCITY),
+            },
+        );
+        self.sounding_note = Some(note);
+    }
+
+    /// Follows what Sequencer::advance_frame does: presses and parameters are sent at the step start,
+    /// ratchet hits during the step, and releases half-way through or at the end of the step,
+    /// all of them later for delayed steps. Notes are transposed like in the song pattern playing them.
+    fn step(
+        &mut self,
+        step_tick: u32,
+        step_frames: u32,
+        step: &InstrumentStep,
+        transpose: i8,
+        options: &SmfExportOptions,
+    ) {
+        let start_tick = step_tick + step.delay_frames(step_frames);
+        self.release_until(start_tick);
+        self.params(start_tick, step, options);
+        if let Some(note) = step.press_note().map(|n| transpose_note(n, transpose)) {
+            self.note_on(start_tick, note);
+            for frame in step.retrigger_frames(step_frames) {
+                self.note_on(step_tick + frame, note);
+            }
+        }
+        if let Some(release_frame) = step.release_frame(step_frames) {
+            let tick = step_tick + release_frame;
+            self.release_tick = Some(self.release_tick.map_or(tick, |t| t.min(tick)));
+        }
+    }
+
+    fn into_track(mut self, name: &'a str, end_tick: u32) -> Vec<TrackEvent<'a>> {
+        let release_tick = self.release_tick.take().map_or(end_tick, |t| t.min(end_tick));
+        self.note_off(release_tick);
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes())),
+        }];
+        let mut last_tick = 0;
+        for (tick, kind) in self.events {
+            track.push(TrackEvent {
+                delta: u28::new(tick - last_tick),
+                kind,
+            });
+            last_tick = tick;
+        }
+        track.push(TrackEvent {
+            delta: u28::new(end_tick - last_tick),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        track
+    }
+}
+
+/// Writes a format 1 SMF with a tempo track followed by one track per instrument ID used in `song_patterns`,
+/// in order of appearance. Each MIDI tick is one frame, so that steps keep the number of frames they have
+/// during playback, and the tempo event only follows frames_per_step.
+pub fn save_smf(
+    song: &SequencerSong,
+    song_patterns: &[SongPattern],
+    options: &SmfExportOptions,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let frames_per_step = song.frames_per_step;
+    let ticks_per_beat = frames_per_step * STEPS_PER_BEAT;
+    let micros_per_beat = (ticks_per_beat as f64 * FRAME_MICROS).round() as u32;
+    let mut clock = StepClock::new(song);
+    let end_tick: u32 = song_patterns
+        .iter()
+        .map(|sp| song.song_pattern_frames(sp, &mut clock))
+        .sum();
+
+    let mut ids: Vec<&str> = Vec::new();
+    for instrument in song_patterns
+        .iter()
+        .flat_map(|sp| song.patterns[sp.pattern].instruments.iter())
+    {
+        if !ids.contains(&instrument.id.as_str()) {
+            ids.push(&instrument.id);
+        }
+    }
+
+    if ticks_per_beat > u15::max_value().as_int() as u32 {
+        return Err("FramesPerStep is too large for MIDI".into());
+    }
+
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(ticks_per_beat as u16)),
+    ));
+    smf.tracks.push(vec![
+        TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_beat))),
+        },
+        TrackEvent {
+            delta: u28::new(end_tick),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        },
+    ]);
+
+    for (i, id) in ids.iter().enumerate() {
+        let mut builder = TrackBuilder {
+            // There are more instruments than MIDI channels, but DAWs usually only care about the track.
+            channel: u4::new((i % 16) as u8),
+            events: Vec::new(),
+            sounding_note: None,
+            release_tick: None,
+        };
+        let is_untransposed = song.untransposed_instruments.iter().any(|u| u == id);
+        let mut step_tick = 0;
+        let mut clock = StepClock::new(song);
+        for sp in song_patterns {
+            let pattern = &song.patterns[sp.pattern];
+            let instrument = pattern.instruments.iter().find(|i| i.id == *id);
+            let transpose = if is_untransposed { 0 } else { sp.transpose };
+            for s in 0..pattern.num_steps {
+                let step_frames = song.step_frames(sp, s, &mut clock);
+                if let Some(instrument) = instrument {
+                    builder.step(step_tick, step_frames, &instrument.steps[s], transpose, options);
+                }
+                step_tick += step_frames;
+            }
+        }
+        smf.tracks.push(builder.into_track(id, end_tick));
+    }
+
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Notes coming from a MIDI track, or a MIDI channel across all tracks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmfSource {
+    /// Track numbers start at 0, which is often a tempo track in format 1 files.
+    Track(usize),
+    /// Channel numbers start at 1.
+    Channel(u8),
+}
+
+impl FromStr for SmfSource {
+    type Err = String;
+
+    /// Parses `3` as track 3 and `ch10` as channel 10.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid MIDI source {}, expected a track number or ch1 to ch16", s);
+        match s.strip_prefix("ch") {
+            Some(channel) => match channel.parse() {
+                Ok(c @ 1..=16) => Ok(SmfSource::Channel(c)),
+                _ => Err(invalid()),
+            },
+            None => s.parse().map(SmfSource::Track).map_err(|_| invalid()),
+        }
+    }
+}
+
+pub struct SmfImportOptions {
+    /// Which instrument ID receives the notes of each source. The first matching assignment is used.
+    /// Tracks without an assignment go to the instrument with the same ID as the track name, if there is one.
+    pub assignments: Vec<(SmfSource, String)>,
+    /// Controller changes on these controllers are imported as step parameters, the inverse of SmfExportOptions.
+    pub param_controllers: [u8; NUM_INSTRUMENT_PARAMS],
+}
+
+impl Default for SmfImportOptions {
+    fn default() -> Self {
+        SmfImportOptions {
+            assignments: Vec::new(),
+            param_controllers: SmfExportOptions::default().param_controllers,
+        }
+    }
+}
+
+/// Creates a song from the notes of a SMF, one pattern per 16 steps window, where each step is a 16th note.
+/// Windows with identical content share the same pattern.
+pub fn load_smf(
+    bytes: &[u8],
+    known_ids: &[String],
+    options: &SmfImportOptions,
+) -> Result<(SequencerSong, ImportReport), Box<dyn Error>> {
+    let smf = Smf::parse(bytes)?;
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int() as f64,
+        Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".into()),
+    };
+    let ticks_per_step = ticks_per_beat / 4.0;
+
+    let mut report = ImportReport::default();
+    let mut micros_per_beat = None;
+    // Keep instruments in the order that they are first assigned.
+    let mut instrument_ids: Vec<String> = Vec::new();
+    let mut instruments: BTreeMap<String, ImportedInstrument> = BTreeMap::new();
+
+    for (track_num, track) in smf.tracks.iter().enumerate() {
+        let track_name = track.iter().find_map(|e| match e.kind {
+            TrackEventKind::Meta(MetaMessage::TrackName(name)) => Some(String::from_utf8_lossy(name).into_owned()),
+            _ => None,
+        });
+        let describe = |channel: u8| match &track_name {
+            Some(name) => format!("track {} ({}) channel {}", track_num, name, channel),
+            None => format!("track {} channel {}", track_num, channel),
+        };
+        let assignment = |channel: u8| {
+            options
+                .assignments
+                .iter()
+                .find(|(source, _)| match source {
+                    SmfSource::Track(t) => *t == track_num,
+                    SmfSource::Channel(c) => *c == channel,
+                })
+                .map(|(_, id)| id.clone())
+                .or_else(|| {
+                    track_name
+                        .clone()
+                        .filter(|name| known_ids.iter().any(|id| id.as_str() == name.as_str()))
+                })
+        };
+
+        // The pressed notes by channel and key, with their start tick.
+        let mut pressed: BTreeMap<(u8, u8), u32> = BTreeMap::new();
+        let mut notes: BTreeMap<u8, Vec<ImportedNote>> = BTreeMap::new();
+        let mut params: BTreeMap<u8, Vec<(u32, u8, i8)>> = BTreeMap::new();
+        let mut tick = 0;
+        for event in track {
+            tick += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) if micros_per_beat.is_none() => {
+                    micros_per_beat = Some(tempo.as_int())
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    // Use 1-based channels like SmfSource.
+                    let channel = channel.as_int() + 1;
+                    let mut release = |key: u8| {
+                        if let Some(start_tick) = pressed.remove(&(channel, key)) {
+                            notes.entry(channel).or_default().push(ImportedNote {
+                                start_tick,
+                                end_tick: Some(tick),
+                                key,
+                            });
+                        }
+                    };
+                    match message {
+                        MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                            release(key.as_int());
+                            pressed.insert((channel, key.as_int()), tick);
+                        }
+                        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => release(key.as_int()),
+                        MidiMessage::Controller { controller, value } => {
+                            if let Some(param_num) =
+                                options.param_controllers.iter().position(|c| *c == controller.as_int())
+                            {
+                                params.entry(channel).or_default().push((
+                                    tick,
+                                    param_num as u8,
+                                    controller_value_to_param(value),
+                                ));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Release notes still pressed at the end of the track.
+        for ((channel, key), start_tick) in pressed {
+            notes.entry(channel).or_default().push(ImportedNote {
+                start_tick,
+                end_tick: Some(tick),
+                key,
+            });
+        }
+
+        for (channel, channel_notes) in notes {
+            match assignment(channel) {
+                Some(id) => {
+                    report.assigned.push((describe(channel), id.clone()));
+                    if !instrument_ids.contains(&id) {
+                        instrument_ids.push(id.clone());
+                    }
+                    let instrument = instruments.entry(id).or_default();
+                    instrument.notes.extend(channel_notes);
+                    instrument.params.extend(params.remove(&channel).unwrap_or_default());
+                }
+                None => report
+                    .unmatched
+                    .push(format!("{}: {} notes", describe(channel), channel_notes.len())),
+            }
+        }
+    }
+
+    // A beat is 4 steps.
+    let micros_per_step = micros_per_beat.unwrap_or(500_000) as f64 / 4.0;
+    let frames_per_step = (micros_per_step / FRAME_MICROS).round() as u32;
+
+    let mut instrument_steps: Vec<(String, BTreeMap<usize, InstrumentStep>)> = Vec::new();
+    for id in instrument_ids {
+        let (steps, dropped) = instruments.remove(&id).unwrap().into_steps(ticks_per_step);
+        report.dropped_notes += dropped;
+        instrument_steps.push((id, steps));
+    }
+
+    let song = build_song(&instrument_steps, None, frames_per_step, "MIDI file")?;
+    Ok((song, report))
+}
+
+#[cfg(test)]
+fn test_song() -> SequencerSong {
+    let mut steps = vec![InstrumentStep::default(); DEFAULT_NUM_STEPS];
+    steps[0] = InstrumentStep {
+        note: 60,
+        release_pos: ReleasePos::Half,
+        params: [Some(-1), None, None, None],
+        condition: None,
+        ratchet: None,
+        delay: 0,
+    };
+    steps[1] = InstrumentStep {
+        note: 62,
+        release_pos: ReleasePos::NotReleased,
+        params: [None; NUM_INSTRUMENT_PARAMS],
+        condition: None,
+        ratchet: None,
+        delay: 0,
+    };
+    steps[2].release_pos = ReleasePos::Full;
+    let mut song = SequencerSong::default();
+    song.patterns[3] = Pattern {
+        num_steps: DEFAULT_NUM_STEPS,
+        instruments: vec![Instrument {
+            id: "lead".to_owned(),
+            synth_index: None,
+            steps,
+        }],
+        groove: None,
+    };
+    song
+}
+
+#[test]
+fn export_notes_and_params() {
+    let bytes = save_smf(&test_song(), &[SongPattern::new(3)], &Default::default()).unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(smf.header.timing, Timing::Metrical(u15::new(28)));
+    assert_eq!(smf.tracks.len(), 2);
+
+    let mut tick = 0;
+    let events: Vec<(u32, MidiMessage)> = smf.tracks[1]
+        .iter()
+        .filter_map(|e| {
+            tick += e.delta.as_int();
+            match e.kind {
+                TrackEventKind::Midi { message, .. } => Some((tick, message)),
+                _ => None,
+            }
+        })
+        .collect();
+    let note_on = |key: u8| MidiMessage::NoteOn {
+        key: u7::new(key),
+        vel: u7::new(NOTE_VELOCITY),
+    };
+    let note_off = |key: u8| MidiMessage::NoteOff {
+        key: u7::new(key),
+        vel: u7::new(0),
+    };
+    assert_eq!(
+        events,
+        [
+            (
+                0,
+                MidiMessage::Controller {
+                    controller: u7::new(16),
+                    value: u7::new(63)
+                }
+            ),
+            (0, note_on(60)),
+            (4, note_off(60)),
+            (7, note_on(62)),
+            (21, note_off(62)),
+        ]
+    );
+}
+
+#[test]
+fn export_transposed_song_patterns() {
+    let mut transposed = SongPattern::new(3);
+    transposed.transpose = 12;
+    let pressed_notes = |song: &SequencerSong| -> Vec<u8> {
+        let bytes = save_smf(song, &[SongPattern::new(3), transposed], &Default::default()).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        smf.tracks[1]
+            .iter()
+            .filter_map(|e| match e.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, .. },
+                    ..
+                } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let mut song = test_song();
+    assert_eq!(pressed_notes(&song), [60, 62, 72, 74]);
+    song.untransposed_instruments = vec!["lead".to_owned()];
+    assert_eq!(pressed_notes(&song), [60, 62, 60, 62]);
+}
+
+#[test]
+fn import_exported_song() {
+    let exported = test_song();
+    let bytes = save_smf(&exported, &[3, 3].map(SongPattern::new), &Default::default()).unwrap();
+    let (song, report) = load_smf(&bytes, &["lead".into()], &Default::default()).unwrap();
+
+    assert_eq!(song.frames_per_step, 7);
+    assert_eq!(song.song_patterns, [0, 0].map(SongPattern::new));
+    assert_eq!(song.patterns[0].instruments, exported.patterns[3].instruments);
+    assert_eq!(report.assigned.len(), 1);
+    assert!(report.unmatched.is_empty());
+}
@@ -0,0 +1,464 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Imports the pattern data of ProTracker MOD and FastTracker 2 XM modules. Samples and instruments are ignored.
+
+use crate::sequencer::import::{build_song, ImportReport, ImportedInstrument, ImportedNote, FRAME_MICROS};
+#[cfg(test)]
+use crate::sequencer::ReleasePos;
+use crate::sequencer::SequencerSong;
+#[cfg(test)]
+use crate::sequencer::SongPattern;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+const EFFECT_POSITION_JUMP: u8 = 0xb;
+const EFFECT_SET_VOLUME: u8 = 0xc;
+const EFFECT_PATTERN_BREAK: u8 = 0xd;
+const EFFECT_EXTENDED: u8 = 0xe;
+const EXTENDED_NOTE_CUT: u8 = 0xc;
+const EFFECT_SET_SPEED: u8 = 0xf;
+// Kxx, only in XM.
+const EFFECT_KEY_OFF: u8 = 0x14;
+const MAX_VOLUME: u8 = 64;
+
+const XM_SIGNATURE: &[u8] = b"Extended Module: ";
+const XM_KEY_OFF: u8 = 97;
+
+pub struct ModuleImportOptions {
+    /// Which instrument ID receives the notes of each channel. Channel numbers start at 1.
+    pub assignments: Vec<(usize, String)>,
+    /// How many module rows are merged into one step.
+    pub rows_per_step: usize,
+    /// The parameter that receives the volume column and Cxx effects, scaled from 0-64 to 0-15.
+    pub volume_param: Option<u8>,
+    /// The parameter that receives the sample number of each note, starting at 0.
+    /// Chiptune modules often switch samples to change the duty cycle, which fits the Duty parameter.
+    pub sample_param: Option<u8>,
+}
+
+impl Default for ModuleImportOptions {
+    fn default() -> Self {
+        ModuleImportOptions {
+            assignments: Vec::new(),
+            rows_per_step: 1,
+            volume_param: None,
+            sample_param: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Cell {
+    /// A MIDI note number.
+    note: Option<u8>,
+    key_off: bool,
+    /// 0 when the cell doesn't set a sample.
+    sample: u8,
+    volume: Option<u8>,
+    effect: u8,
+    param: u8,
+}
+
+struct Module {
+    num_channels: usize,
+    /// The pattern played at each song position.
+    orders: Vec<usize>,
+    /// The cells of each pattern, by row and channel.
+    patterns: Vec<Vec<Vec<Cell>>>,
+    speed: u32,
+    bpm: u32,
+}
+
+fn truncated() -> String {
+    "The module file is truncated".to_owned()
+}
+
+fn no_channels() -> String {
+    "The module doesn't have any channel".to_owned()
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    let b = bytes.get(offset..offset + 2).ok_or_else(truncated)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    let b = bytes.get(offset..offset + 4).ok_or_else(truncated)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Maps ProTracker periods to notes the way FastTracker 2 does, period 428 (C-2) becoming middle C.
+fn period_to_note(period: u16) -> Option<u8> {
+    if period == 0 {
+        return None;
+    }
+    let note = 60.0 + 12.0 * (428.0 / period as f64).log2();
+    Some(note.round().clamp(1.0, 127.0) as u8)
+}
+
+fn parse_mod(bytes: &[u8]) -> Result<Module, Box<dyn Error>> {
+    const SONG_LENGTH_OFFSET: usize = 950;
+    const ORDERS_OFFSET: usize = 952;
+    const SIGNATURE_OFFSET: usize = 1080;
+    const PATTERNS_OFFSET: usize = 1084;
+    const NUM_ROWS: usize = 64;
+
+    let signature = bytes.get(SIGNATURE_OFFSET..PATTERNS_OFFSET).ok_or_else(truncated)?;
+    let num_channels = match signature {
+        b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => 4,
+        b"6CHN" => 6,
+        b"8CHN" | b"FLT8" | b"OCTA" | b"CD81" => 8,
+        [d, b'C', b'H', b'N'] if d.is_ascii_digit() => (d - b'0') as usize,
+        [d0, d1, b'C', b'H' | b'N'] if d0.is_ascii_digit() && d1.is_ascii_digit() => {
+            ((d0 - b'0') * 10 + (d1 - b'0')) as usize
+        }
+        _ => return Err("Unknown module format, only XM and 31 samples MOD files are supported".into()),
+    };
+    if num_channels == 0 {
+        return Err(no_channels().into());
+    }
+
+    let song_length = (bytes[SONG_LENGTH_OFFSET] as usize).clamp(1, 128);
+    let orders: Vec<usize> = bytes[ORDERS_OFFSET..ORDERS_OFFSET + song_length]
+        .iter()
+        .map(|&p| p as usize)
+        .collect();
+    // Unused patterns are also stored, up to the highest one in the whole order table.
+    let num_patterns = bytes[ORDERS_OFFSET..SIGNATURE_OFFSET]
+        .iter()
+        .max()
+        .map_or(0, |&p| p as usize + 1);
+
+    let pattern_len = NUM_ROWS * num_channels * 4;
+    let mut patterns = Vec::with_capacity(num_patterns);
+    for p in 0..num_patterns {
+        let offset = PATTERNS_OFFSET + p * pattern_len;
+        let data = bytes.get(offset..offset + pattern_len).ok_or_else(truncated)?;
+        patterns.push(
+            data.chunks(num_channels * 4)
+                .map(|row| {
+                    row.chunks(4)
+                        .map(|c| Cell {
+                            note: period_to_note(((c[0] as u16 & 0x0f) << 8) | c[1] as u16),
+                            key_off: false,
+                            sample: (c[0] & 0xf0) | (c[2] >> 4),
+                            volume: None,
+                            effect: c[2] & 0x0f,
+                            param: c[3],
+                        })
+                        .collect()
+                })
+                .collect(),
+        );
+    }
+
+    Ok(Module {
+        num_channels,
+        orders,
+        patterns,
+        speed: 6,
+        bpm: 125,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.bytes.get(self.pos).ok_or_else(truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+}
+
+fn parse_xm(bytes: &[u8]) -> Result<Module, Box<dyn Error>> {
+    const HEADER_OFFSET: usize = 60;
+    const ORDERS_OFFSET: usize = 80;
+
+    let header_size = u32_at(bytes, HEADER_OFFSET)? as usize;
+    let song_length = u16_at(bytes, 64)? as usize;
+    let num_channels = u16_at(bytes, 68)? as usize;
+    if num_channels == 0 {
+        return Err(no_channels().into());
+    }
+    let num_patterns = u16_at(bytes, 70)? as usize;
+    let speed = u16_at(bytes, 76)? as u32;
+    let bpm = u16_at(bytes, 78)? as u32;
+    let orders = bytes
+        .get(ORDERS_OFFSET..ORDERS_OFFSET + song_length)
+        .ok_or_else(truncated)?
+        .iter()
+        .map(|&p| p as usize)
+        // FastTracker 2 plays positions of missing patterns as empty patterns, skip them instead.
+        .filter(|&p| p < num_patterns)
+        .collect();
+
+    let mut offset = HEADER_OFFSET + header_size;
+    let mut patterns = Vec::with_capacity(num_patterns);
+    for _ in 0..num_patterns {
+        let pattern_header_len = u32_at(bytes, offset)? as usize;
+        let num_rows = u16_at(bytes, offset + 5)? as usize;
+        let packed_size = u16_at(bytes, offset + 7)? as usize;
+        let data_offset = offset + pattern_header_len;
+        let mut reader = Reader {
+            bytes: bytes
+                .get(data_offset..data_offset + packed_size)
+                .ok_or_else(truncated)?,
+            pos: 0,
+        };
+
+        let mut rows = vec![vec![Cell::default(); num_channels]; num_rows];
+        // Empty patterns have no data at all.
+        if packed_size > 0 {
+            for cell in rows.iter_mut().flatten() {
+                // Cells either have all 5 fields, or start with a mask of the fields that follow.
+                let first = reader.byte()?;
+                let (mask, note) = if first & 0x80 == 0 {
+                    (0x1e, first)
+                } else if first & 0x01 != 0 {
+                    (first, reader.byte()?)
+                } else {
+                    (first, 0)
+                };
+                let mut field = |bit: u8| if mask & bit != 0 { reader.byte() } else { Ok(0) };
+                let sample = field(0x02)?;
+                let volume = field(0x04)?;
+                let effect = field(0x08)?;
+                let param = field(0x10)?;
+                *cell = Cell {
+                    // Note 49 is C-4.
+                    note: match note {
+                        1..=96 => Some(note + 11),
+                        _ => None,
+                    },
+                    key_off: note == XM_KEY_OFF,
+                    sample,
+                    volume: match volume {
+                        0x10..=0x50 => Some(volume - 0x10),
+                        _ => None,
+                    },
+                    effect,
+                    param,
+                };
+            }
+        }
+        patterns.push(rows);
+        offset = data_offset + packed_size;
+    }
+
+    Ok(Module {
+        num_channels,
+        orders,
+        patterns,
+        speed,
+        bpm,
+    })
+}
+
+fn end_note(notes: &mut Vec<ImportedNote>, playing: &mut Option<(u32, u8)>, end_tick: Option<u32>) {
+    if let Some((start_tick, key)) = playing.take() {
+        notes.push(ImportedNote {
+            start_tick,
+            end_tick,
+            key,
+        });
+    }
+}
+
+/// Converts the cells of one channel to notes and parameter changes, in module ticks.
+fn import_channel<'a>(
+    cells: impl Iterator<Item = &'a Cell>,
+    speed: u32,
+    options: &ModuleImportOptions,
+) -> ImportedInstrument {
+    let mut imported = ImportedInstrument::default();
+    // The start tick and key of the note currently playing.
+    let mut playing = None;
+    for (row, cell) in cells.enumerate() {
+        let tick = row as u32 * speed;
+        if let Some(key) = cell.note {
+            end_note(&mut imported.notes, &mut playing, None);
+            playing = Some((tick, key));
+            if let (Some(param_num), 1..) = (options.sample_param, cell.sample) {
+                imported
+                    .params
+                    .push((tick, param_num, (cell.sample - 1).min(i8::MAX as u8) as i8));
+            }
+        }
+        if cell.key_off {
+            end_note(&mut imported.notes, &mut playing, Some(tick));
+        }
+
+        let volume = match cell.effect {
+            EFFECT_SET_VOLUME => Some(cell.param.min(MAX_VOLUME)),
+            _ => cell.volume,
+        };
+        match (volume, options.volume_param) {
+            // Silencing the channel is the usual way to stop a note in MOD files.
+            (Some(0), _) => end_note(&mut imported.notes, &mut playing, Some(tick)),
+            (Some(v), Some(param_num)) => imported.params.push((tick, param_num, (v as u32 * 15 / 64) as i8)),
+            _ => {}
+        }
+
+        match (cell.effect, cell.param >> 4) {
+            (EFFECT_EXTENDED, EXTENDED_NOTE_CUT) => end_note(
+                &mut imported.notes,
+                &mut playing,
+                Some(tick + (cell.param & 0x0f) as u32),
+            ),
+            (EFFECT_KEY_OFF, _) => end_note(&mut imported.notes, &mut playing, Some(tick + cell.param as u32)),
+            _ => {}
+        }
+    }
+    end_note(&mut imported.notes, &mut playing, None);
+    imported
+}
+
+/// Creates a song from the patterns of a MOD or XM file, played in the module's order.
+/// Pattern breaks and position jumps end their pattern on their row, but the song still continues
+/// with the next position. Speed changes are only taken into account on the first row.
+pub fn load_module(
+    bytes: &[u8],
+    options: &ModuleImportOptions,
+) -> Result<(SequencerSong, ImportReport), Box<dyn Error>> {
+    let (module, format_name) = if bytes.starts_with(XM_SIGNATURE) {
+        (parse_xm(bytes)?, "XM file")
+    } else {
+        (parse_mod(bytes)?, "MOD file")
+    };
+
+    let (mut speed, mut bpm) = (module.speed, module.bpm);
+    if let Some(first_row) = module.orders.first().and_then(|&p| module.patterns[p].first()) {
+        for cell in first_row.iter().filter(|c| c.effect == EFFECT_SET_SPEED && c.param > 0) {
+            if cell.param < 32 {
+                speed = cell.param as u32;
+            } else {
+                bpm = cell.param as u32;
+            }
+        }
+    }
+    let (speed, bpm) = (speed.max(1), bpm.max(1));
+
+    // Lay out the rows of every song position one after the other.
+    let rows: Vec<&[Cell]> = module
+        .orders
+        .iter()
+        .flat_map(|&p| {
+            let pattern = &module.patterns[p];
+            let end = pattern
+                .iter()
+                .position(|row| {
+                    row.iter()
+                        .any(|c| c.effect == EFFECT_PATTERN_BREAK || c.effect == EFFECT_POSITION_JUMP)
+                })
+                .map_or(pattern.len(), |r| r + 1);
+            pattern[..end].iter().map(|row| row.as_slice())
+        })
+        .collect();
+
+    let mut report = ImportReport::default();
+    // Keep instruments in the order that they are first assigned.
+    let mut instrument_ids: Vec<String> = Vec::new();
+    let mut instruments: BTreeMap<String, ImportedInstrument> = BTreeMap::new();
+    for channel in 0..module.num_channels {
+        let imported = import_channel(rows.iter().map(|row| &row[channel]), speed, options);
+        if imported.notes.is_empty() {
+            continue;
+        }
+        let describe = format!("channel {}", channel + 1);
+        match options.assignments.iter().find(|(c, _)| *c == channel + 1) {
+            Some((_, id)) => {
+                report.assigned.push((describe, id.clone()));
+                if !instrument_ids.contains(id) {
+                    instrument_ids.push(id.clone());
+                }
+                let instrument = instruments.entry(id.clone()).or_default();
+                instrument.notes.extend(imported.notes);
+                instrument.params.extend(imported.params);
+            }
+            None => report
+                .unmatched
+                .push(format!("{}: {} notes", describe, imported.notes.len())),
+        }
+    }
+
+    let rows_per_step = options.rows_per_step.max(1);
+    let ticks_per_step = (rows_per_step as u32 * speed) as f64;
+    // A tick lasts 2.5 / BPM seconds.
+    let frames_per_step = (ticks_per_step * 2_500_000.0 / bpm as f64 / FRAME_MICROS).round() as u32;
+
+    let mut instrument_steps = Vec::new();
+    for id in instrument_ids {
+        let (steps, dropped) = instruments.remove(&id).unwrap().into_steps(ticks_per_step);
+        report.dropped_notes += dropped;
+        instrument_steps.push((id, steps));
+    }
+
+    let num_steps = rows.len().div_ceil(rows_per_step);
+    let song = build_song(&instrument_steps, Some(num_steps), frames_per_step, format_name)?;
+    Ok((song, report))
+}
+
+#[test]
+fn import_mod_channels() {
+    let mut bytes = vec![0u8; 1084 + 64 * 4 * 4];
+    // Play pattern 0 twice.
+    bytes[950] = 2;
+    bytes[1080..1084].copy_from_slice(b"M.K.");
+    let mut set_cell = |row: usize, channel: usize, sample: u8, period: u16, effect: u8, param: u8| {
+        let offset = 1084 + (row * 4 + channel) * 4;
+        bytes[offset..offset + 4].copy_from_slice(&[
+            (sample & 0xf0) | (period >> 8) as u8,
+            period as u8,
+            (sample << 4) | effect,
+            param,
+        ]);
+    };
+    set_cell(0, 0, 2, 428, 0, 0);
+    set_cell(0, 1, 0, 0, EFFECT_SET_SPEED, 3);
+    // Cut after one tick.
+    set_cell(2, 0, 0, 0, EFFECT_EXTENDED, 0xc1);
+    set_cell(4, 0, 1, 214, 0, 0);
+    set_cell(8, 2, 1, 428, 0, 0);
+
+    let options = ModuleImportOptions {
+        assignments: vec![(1, "S0".to_owned())],
+        sample_param: Some(0),
+        ..Default::default()
+    };
+    let (song, report) = load_module(&bytes, &options).unwrap();
+
+    // 3 ticks at 125 BPM is 60ms.
+    assert_eq!(song.frames_per_step, 4);
+    assert_eq!(song.song_patterns, [0, 1, 1, 1, 0, 1, 1, 1].map(SongPattern::new));
+    assert!(song.patterns[1].instruments.is_empty());
+    let steps = &song.patterns[0].instruments[0].steps;
+    assert_eq!(steps[0].press_note(), Some(60));
+    assert_eq!(steps[0].params[0], Some(1));
+    assert_eq!(steps[2].release_pos(), ReleasePos::Half);
+    assert_eq!(steps[4].press_note(), Some(72));
+    assert_eq!(steps[4].params[0], Some(0));
+    assert_eq!(steps[4].release_pos(), ReleasePos::NotReleased);
+    assert_eq!(report.unmatched, ["channel 3: 2 notes"]);
+}
+
+#[test]
+fn reject_modules_without_channels() {
+    for signature in [b"0CHN", b"00CH", b"00CN"] {
+        let mut bytes = vec![0u8; 1084];
+        bytes[950] = 1;
+        bytes[1080..1084].copy_from_slice(signature);
+        let err = load_module(&bytes, &Default::default()).err().unwrap();
+        assert_eq!(err.to_string(), no_channels());
+    }
+
+    let mut bytes = vec![0u8; 80];
+    bytes[..XM_SIGNATURE.len()].copy_from_slice(XM_SIGNATURE);
+    let err = load_module(&bytes, &Default::default()).err().unwrap();
+    assert_eq!(err.to_string(), no_channels());
+}
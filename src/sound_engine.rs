@@ -3,25 +3,25 @@
 
 #[cfg(feature = "gba")]
 use crate::gba_platform;
+use crate::observer::EngineObserver;
 use crate::sequencer::OnEmpty;
 use crate::sequencer::Sequencer;
+use crate::sequencer::SongSettings;
 use crate::sequencer::StepEvent;
+#[cfg(feature = "desktop_native")]
+use crate::sequencer::{ImportReport, ModuleImportOptions, SmfImportOptions};
 #[cfg(feature = "desktop")]
 use crate::sound_renderer::emulated::invoke_on_sound_engine;
 use crate::sound_renderer::Synth;
 use crate::synth_script::SequencerInstrumentDef;
 use crate::synth_script::SynthScript;
-use crate::ui::GlobalEngine;
 use crate::ui::Settings;
-use crate::ui::SongSettings;
-use crate::utils::WeakWindowWrapper;
 
 #[cfg(feature = "desktop_native")]
 use native_dialog::FileDialog;
-use slint::Global;
-use slint::Model;
 
 use alloc::rc::Rc;
+use alloc::vec;
 use alloc::vec::Vec;
 use core::cell::RefCell;
 #[cfg(feature = "desktop")]
@@ -31,9 +31,21 @@ use std::path::{Path, PathBuf};
 
 pub const NUM_INSTRUMENTS: usize = 64;
 pub const NUM_INSTRUMENT_COLS: usize = 4;
-pub const NUM_INSTRUMENT_PARAMS: usize = 2;
-pub const NUM_STEPS: usize = 16;
+pub const NUM_INSTRUMENT_PARAMS: usize = 4;
+pub const DEFAULT_NUM_STEPS: usize = 16;
+pub const MAX_NUM_STEPS: usize = 64;
 pub const NUM_PATTERNS: usize = 64;
+// TODO: Support flash ROM in load_gba_sram to get access to 64kb or 128kb saves
+pub const GBA_SRAM_SIZE: usize = 32 * 1024;
+/// Replaces the song length in the header of saves that contain a song bank directory.
+/// Saves with a single song can't have such a length since it doesn't fit in the SRAM.
+const SONG_BANK_TAG: u32 = u32::from_le_bytes(*b"BANK");
+
+/// Whether a save of `len` bytes fits in the GBA SRAM, where it can use every byte.
+#[cfg(any(feature = "desktop_native", feature = "gba"))]
+pub fn fits_in_gba_sram(len: usize) -> bool {
+    len <= GBA_SRAM_SIZE
+}
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 enum NoteSource {
@@ -50,6 +62,9 @@ enum ProjectSource {
     #[cfg(feature = "desktop_native")]
     /// Contains the path to the song file and the instruments file
     MarkdownFile((PathBuf, PathBuf)),
+    #[cfg(feature = "desktop_native")]
+    /// Contains the path to the instruments file of a song that was imported from postcard or MIDI
+    SongImportFile(PathBuf),
     #[cfg(feature = "desktop")]
     /// Contains a copy of the WASM/WAT instrument bytes for exporting
     MarkdownGist(Vec<u8>),
@@ -65,62 +80,47 @@ pub struct SoundEngine {
     pub synth: Synth,
     script: SynthScript,
     frame_number: usize,
-    main_window: WeakWindowWrapper,
+    observer: Rc<dyn EngineObserver>,
     pressed_note: Option<NoteSource>,
     project_source: ProjectSource,
 }
 
 impl SoundEngine {
-    pub fn new(synth: Synth, main_window: WeakWindowWrapper) -> SoundEngine {
-        let sequencer = Self::default_sequencer(&main_window);
-        let script = Self::default_synth_script(&synth, &sequencer, &main_window);
+    pub fn new(synth: Synth, observer: Rc<dyn EngineObserver>) -> SoundEngine {
+        let sequencer = Self::default_sequencer(&observer);
+        let script = Self::default_synth_script(&synth, &sequencer);
 
         SoundEngine {
             sequencer,
             synth,
             script,
             frame_number: 0,
-            main_window,
+            observer,
             pressed_note: None,
             project_source: ProjectSource::New,
         }
     }
 
-    fn default_sequencer(main_window: &WeakWindowWrapper) -> Rc<RefCell<Sequencer>> {
-        Rc::new(RefCell::new(Sequencer::new(main_window.clone())))
+    fn default_sequencer(observer: &Rc<dyn EngineObserver>) -> Rc<RefCell<Sequencer>> {
+        Rc::new(RefCell::new(Sequencer::new(observer.clone())))
     }
 
-    fn default_synth_script(
-        synth: &Synth,
-        sequencer: &Rc<RefCell<Sequencer>>,
-        main_window: &WeakWindowWrapper,
-    ) -> SynthScript {
+    fn default_synth_script(synth: &Synth, sequencer: &Rc<RefCell<Sequencer>>) -> SynthScript {
         SynthScript::new(
             synth.set_sound_reg_callback(),
+            synth.set_sound_reg_at_callback(),
             synth.set_wave_table_callback(),
-            Self::apply_instrument_ids_callback(sequencer.clone(), main_window.clone()),
+            synth.set_sample_callback(),
+            synth.play_sample_callback(),
+            Self::apply_instrument_ids_callback(sequencer.clone()),
         )
     }
 
-    fn apply_instrument_ids_callback(
-        sequencer: Rc<RefCell<Sequencer>>,
-        main_window: WeakWindowWrapper,
-    ) -> impl Fn(SequencerInstrumentDef) {
+    fn apply_instrument_ids_callback(sequencer: Rc<RefCell<Sequencer>>) -> impl Fn(SequencerInstrumentDef) {
         move |instrument_def: SequencerInstrumentDef| {
-            let ids = instrument_def.ids.clone();
             sequencer
                 .borrow_mut()
                 .set_instrument_def(instrument_def.ids, instrument_def.params);
-            main_window
-                .upgrade_in_event_loop(move |handle| {
-                    let model = GlobalEngine::get(&handle).get_instruments();
-                    for (i, id) in ids.iter().enumerate() {
-                        let mut row_data = model.row_data(i).unwrap();
-                        row_data.id = id.clone();
-                        model.set_row_data(i, row_data);
-                    }
-                })
-                .unwrap();
         }
     }
 
@@ -170,10 +170,10 @@ impl SoundEngine {
         for (instrument, event) in note_events {
             let is_selected_instrument = instrument == self.sequencer.borrow().displayed_instrument;
 
-            let (_note_to_press, _note_to_release) = match event {
-                StepEvent::Press(note, p0, p1) => {
+            let (note_to_press, note_to_release) = match event {
+                StepEvent::Press(note, params) => {
                     self.script
-                        .press_instrument_note(self.frame_number, instrument, note, p0, p1);
+                        .press_instrument_note(self.frame_number, instrument, note, params);
                     let p = Some(note);
                     let r = if is_selected_instrument {
                         self.singularize_note_release(NoteSource::Sequencer(note), true)
@@ -197,37 +197,16 @@ impl SoundEngine {
                     (None, note_to_release)
                 }
                 StepEvent::SetParam(param_num, val) => {
-                    self.script.set_instrument_param(instrument, param_num, val);
+                    self.script
+                        .set_instrument_param(self.frame_number, instrument, param_num, val);
                     (None, None)
                 }
             };
 
-            let pressed = matches!(event, StepEvent::Press(_, _, _));
-            self.main_window
-                .upgrade_in_event_loop(move |handle| {
-                    #[cfg(feature = "desktop")]
-                    if is_selected_instrument {
-                        let notes_model = handle.get_notes();
-                        for row in 0..notes_model.row_count() {
-                            let mut row_data = notes_model.row_data(row).unwrap();
-                            // A note release might not happen if a press happened in-between.
-                            if _note_to_release.map_or(false, |n| n == row_data.note_number as u8) {
-                                row_data.active = false;
-                                notes_model.set_row_data(row, row_data.clone());
-                            }
-
-                            if _note_to_press.map_or(false, |n| n == row_data.note_number as u8) {
-                                row_data.active = true;
-                                notes_model.set_row_data(row, row_data);
-                            }
-                        }
-                    }
-                    let instruments_model = GlobalEngine::get(&handle).get_instruments();
-                    let mut row_data = instruments_model.row_data(instrument as usize).unwrap();
-                    row_data.active = pressed;
-                    instruments_model.set_row_data(instrument as usize, row_data);
-                })
-                .unwrap();
+            let pressed = matches!(event, StepEvent::Press(_, _));
+            let pressed_note = note_to_press.filter(|_| is_selected_instrument);
+            self.observer
+                .instrument_note_event(instrument, pressed, pressed_note, note_to_release);
         }
     }
 
@@ -238,6 +217,18 @@ impl SoundEngine {
         }
     }
 
+    /// Stops the sequencer without muting the synth, letting pressed instruments run their release.
+    #[cfg(feature = "desktop_native")]
+    pub fn stop_and_release_instruments(&mut self) {
+        if !self.sequencer.borrow().playing() {
+            return;
+        }
+        self.sequencer.borrow_mut().set_playing(false, false);
+        for instrument in 0..NUM_INSTRUMENTS as u8 {
+            self.script.release_instrument(self.frame_number, instrument);
+        }
+    }
+
     pub fn advance_frame(&mut self) {
         let (step_change, note_events) = self.sequencer.borrow_mut().advance_frame();
 
@@ -255,41 +246,19 @@ impl SoundEngine {
         self.pressed_note = None;
 
         // Release all notes visually that might have been pressed for the previous instrument.
-        #[cfg(feature = "desktop")]
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = handle.get_notes();
-                for row in 0..model.row_count() {
-                    let mut row_data = model.row_data(row).unwrap();
-                    row_data.active = false;
-                    model.set_row_data(row, row_data);
-                }
-            })
-            .unwrap();
+        self.observer.all_notes_released();
     }
 
-    fn release_note_visually(&mut self, _note: u8) {
-        #[cfg(feature = "desktop")]
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = handle.get_notes();
-                for row in 0..model.row_count() {
-                    let mut row_data = model.row_data(row).unwrap();
-                    if _note == row_data.note_number as u8 {
-                        row_data.active = false;
-                        model.set_row_data(row, row_data);
-                    }
-                }
-            })
-            .unwrap();
+    fn release_note_visually(&mut self, note: u8) {
+        self.observer.note_released(note);
     }
 
     pub fn cycle_instrument_param_start(&mut self) {
         let seq = self.sequencer.borrow();
         let note = seq.clipboard_note();
-        let [p0, p1] = seq.displayed_instrument_params();
+        let params = seq.displayed_instrument_params();
         self.script
-            .press_instrument_note(self.frame_number, seq.displayed_instrument, note, p0, p1);
+            .press_instrument_note(self.frame_number, seq.displayed_instrument, note, params);
     }
     pub fn cycle_instrument_param_end(&mut self) {
         self.script
@@ -310,20 +279,20 @@ impl SoundEngine {
         if self.script.instrument_has_set_param_fn(instrument, param_num) {
             // The instrument will get the new value without a press.
             self.script
-                .set_instrument_param(instrument, param_num, ps[param_num as usize])
+                .set_instrument_param(self.frame_number, instrument, param_num, ps[param_num as usize])
         } else {
             // There is no set param function set by the instrument, trigger a press as feedback like we do in cycle_step_note.
             self.script
-                .press_instrument_note(self.frame_number, instrument, note, ps[0], ps[1]);
+                .press_instrument_note(self.frame_number, instrument, note, ps);
         }
     }
 
     pub fn cycle_step_param_start(&mut self, step: usize, param_num: u8) {
         let mut seq = self.sequencer.borrow_mut();
-        let (note, p0, p1) = seq.cycle_step_param(step, param_num, None, false, OnEmpty::PasteOnEmpty);
+        let (note, params) = seq.cycle_step_param(step, param_num, None, false, OnEmpty::PasteOnEmpty);
         if !seq.playing() {
             self.script
-                .press_instrument_note(self.frame_number, seq.displayed_instrument, note, p0, p1);
+                .press_instrument_note(self.frame_number, seq.displayed_instrument, note, params);
         }
     }
     pub fn cycle_step_param_end(&mut self, step: usize, param_num: u8) {
@@ -336,7 +305,7 @@ impl SoundEngine {
         }
     }
     pub fn cycle_step_param(&mut self, step: usize, param_num: u8, forward: bool, large_inc: bool) {
-        let (note, p0, p1) = self.sequencer.borrow_mut().cycle_step_param(
+        let (note, params) = self.sequencer.borrow_mut().cycle_step_param(
             step,
             param_num,
             Some(forward),
@@ -349,11 +318,11 @@ impl SoundEngine {
             if self.script.instrument_has_set_param_fn(instrument, param_num) {
                 // The instrument will get the new value without a press.
                 self.script
-                    .set_instrument_param(instrument, param_num, if param_num == 0 { p0 } else { p1 })
+                    .set_instrument_param(self.frame_number, instrument, param_num, params[param_num as usize])
             } else {
                 // There is no set param function set by the instrument, trigger a press as feedback like we do in cycle_step_note.
                 self.script
-                    .press_instrument_note(self.frame_number, instrument, note, p0, p1);
+                    .press_instrument_note(self.frame_number, instrument, note, params);
             }
         }
     }
@@ -366,14 +335,15 @@ impl SoundEngine {
         large_inc: bool,
     ) {
         debug_assert!(step_range_first <= step_range_last);
-        let mut seq = self.sequencer.borrow_mut();
-        for step in step_range_first..=step_range_last {
-            seq.cycle_step_param(step, param_num, Some(forward), large_inc, OnEmpty::EmptyOnEmpty);
-        }
+        self.sequencer.borrow_mut().group_undo(|seq| {
+            for step in step_range_first..=step_range_last {
+                seq.cycle_step_param(step, param_num, Some(forward), large_inc, OnEmpty::EmptyOnEmpty);
+            }
+        });
     }
 
     pub fn cycle_step_note_start(&mut self, step: usize) {
-        let (new_note, p0, p1) = self
+        let (new_note, params) = self
             .sequencer
             .borrow_mut()
             .cycle_step_note(step, None, false, OnEmpty::PasteOnEmpty);
@@ -382,8 +352,7 @@ impl SoundEngine {
                 self.frame_number,
                 self.sequencer.borrow().displayed_instrument,
                 new_note,
-                p0,
-                p1,
+                params,
             );
         }
     }
@@ -396,7 +365,7 @@ impl SoundEngine {
         }
     }
     pub fn cycle_step_note(&mut self, step: usize, forward: bool, large_inc: bool) {
-        let (new_note, p0, p1) =
+        let (new_note, params) =
             self.sequencer
                 .borrow_mut()
                 .cycle_step_note(step, Some(forward), large_inc, OnEmpty::PasteOnEmpty);
@@ -405,8 +374,7 @@ impl SoundEngine {
                 self.frame_number,
                 self.sequencer.borrow().displayed_instrument,
                 new_note,
-                p0,
-                p1,
+                params,
             );
         }
     }
@@ -418,20 +386,20 @@ impl SoundEngine {
         large_inc: bool,
     ) {
         debug_assert!(step_range_first <= step_range_last);
-        let mut seq = self.sequencer.borrow_mut();
-        for step in step_range_first..=step_range_last {
-            seq.cycle_step_note(step, Some(forward), large_inc, OnEmpty::EmptyOnEmpty);
-        }
+        self.sequencer.borrow_mut().group_undo(|seq| {
+            for step in step_range_first..=step_range_last {
+                seq.cycle_step_note(step, Some(forward), large_inc, OnEmpty::EmptyOnEmpty);
+            }
+        });
     }
 
     pub fn press_note(&mut self, note: u8) {
-        let (p0, p1) = self.sequencer.borrow_mut().record_press(note);
+        let params = self.sequencer.borrow_mut().record_press(note);
         self.script.press_instrument_note(
             self.frame_number,
             self.sequencer.borrow().displayed_instrument,
             note,
-            p0,
-            p1,
+            params,
         );
 
         // Check which not
@@ -439,19 +407,7 @@ impl SoundEngine {
             self.release_note_visually(note_to_release)
         }
 
-        #[cfg(feature = "desktop")]
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let model = handle.get_notes();
-                for row in 0..model.row_count() {
-                    let mut row_data = model.row_data(row).unwrap();
-                    if row_data.note_number == note as i32 {
-                        row_data.active = true;
-                        model.set_row_data(row, row_data);
-                    }
-                }
-            })
-            .unwrap();
+        self.observer.note_pressed(note);
     }
 
     pub fn release_note(&mut self, note: u8) {
@@ -465,27 +421,33 @@ impl SoundEngine {
         }
     }
 
+    /// Starts recording the sound register writes of instruments, see synth_script::trace.
+    #[cfg(feature = "desktop_native")]
+    pub fn start_trace(&mut self) {
+        self.script.start_trace();
+    }
+
+    #[cfg(feature = "desktop_native")]
+    pub fn take_trace(&mut self) -> Vec<crate::synth_script::trace::TraceEntry> {
+        self.script.take_trace()
+    }
+
     pub fn mute_instruments(&mut self) {
         self.synth.mute_instruments();
         self.script.release_instruments();
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                #[cfg(feature = "desktop")]
-                {
-                    let notes_model = handle.get_notes();
-                    for (i, mut row_data) in notes_model.iter().enumerate() {
-                        row_data.active = false;
-                        notes_model.set_row_data(i, row_data.clone());
-                    }
-                }
-                let instruments_model = GlobalEngine::get(&handle).get_instruments();
-                for (i, mut row_data) in instruments_model.iter().enumerate() {
-                    row_data.active = false;
-                    instruments_model.set_row_data(i, row_data.clone());
-                }
-            })
-            .unwrap();
+        self.observer.instruments_muted();
+    }
+
+    /// Edits another song of the song bank, which stops playback.
+    pub fn select_bank_song(&mut self, bank_song: usize) {
+        self.sequencer.borrow_mut().select_bank_song(bank_song);
+        self.mute_instruments();
+    }
+
+    pub fn cycle_bank_song(&mut self, forward: bool) {
+        self.sequencer.borrow_mut().cycle_bank_song(forward);
+        self.mute_instruments();
     }
 
     pub fn clear_song_and_load_default_instruments(&mut self) {
@@ -508,14 +470,9 @@ impl SoundEngine {
                     .and_then(|f| f.to_str())
                     .map_or(false, |s| s.ends_with(".ct.md")) =>
             {
-                match self.load_md_file_internal(song_path) {
-                    Ok(instruments_path) => {
-                        self.project_source = ProjectSource::MarkdownFile((song_path.to_owned(), instruments_path))
-                    }
-                    Err(err) => {
-                        elog!("Error extracting project from file [{:?}]: {}", song_path, err);
-                        self.clear_song_and_load_default_instruments();
-                    }
+                if let Err(err) = self.try_load_md_file(song_path) {
+                    elog!("Error extracting project from file [{:?}]: {}", song_path, err);
+                    self.clear_song_and_load_default_instruments();
                 }
             }
             _ => {
@@ -525,6 +482,14 @@ impl SoundEngine {
         }
     }
 
+    /// Like load_file, but only for .ct.md files and returning the error instead of falling back to a new project.
+    #[cfg(all(feature = "desktop", not(target_arch = "wasm32")))]
+    pub fn try_load_md_file(&mut self, song_path: &Path) -> Result<(), Box<dyn Error>> {
+        let instruments_path = self.load_md_file_internal(song_path)?;
+        self.project_source = ProjectSource::MarkdownFile((song_path.to_owned(), instruments_path));
+        Ok(())
+    }
+
     #[cfg(feature = "desktop")]
     pub fn load_gist(&mut self, json: serde_json::Value) {
         match self.load_md_gist_internal(json) {
@@ -583,10 +548,7 @@ impl SoundEngine {
     }
 
     #[cfg(feature = "desktop_native")]
-    pub fn save_project_as_impl<F>(&mut self, save_instruments: F)
-    where
-        F: FnOnce(&Path) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> + Send + 'static,
-    {
+    pub fn save_project_as_impl(&mut self) {
         // On some platforms the native dialog needs to be invoked from the
         // main thread, but the state needed to decide whether or not we need
         // to show the dialog (e.g. save for new file) is on the sound engine thread.
@@ -608,26 +570,42 @@ impl SoundEngine {
                 {
                     song_path.set_extension("ct.md");
                 }
-                let song_dir = song_path.parent().expect("Not an absolute path?").to_path_buf();
 
                 invoke_on_sound_engine(move |engine| {
-                    move || -> Result<(), Box<dyn Error>> {
-                        // Songs shouldn't rely on the default instruments that will vary between versions,
-                        // so save a copy of the instruments and make the saved song point to that file.
-                        let instruments_path = save_instruments(song_dir.as_path())?;
-                        engine
-                            .sequencer
-                            .borrow_mut()
-                            .save_as(song_path.as_path(), instruments_path.as_path())?;
-                        engine.project_source = ProjectSource::MarkdownFile((song_path, instruments_path));
-                        Ok(())
-                    }()
-                    .unwrap_or_else(|e| elog!("Error saving the project: {}", e))
+                    engine
+                        .save_project_to(&song_path)
+                        .unwrap_or_else(|e| elog!("Error saving the project: {}", e))
                 });
             }
         })
         .unwrap();
     }
+
+    /// Saves the song as a .ct.md file and makes it point to a copy of the instruments next to it.
+    #[cfg(feature = "desktop_native")]
+    pub fn save_project_to(&mut self, song_path: &Path) -> Result<(), Box<dyn Error>> {
+        let song_dir = song_path.parent().unwrap_or(Path::new(""));
+        // Songs shouldn't rely on the default instruments that will vary between versions,
+        // so save a copy of the instruments and make the saved song point to that file.
+        let instruments_path = match &self.project_source {
+            ProjectSource::New => SynthScript::save_default_instruments_as(song_dir)?,
+            ProjectSource::GbaSavImportFile(sav_path) => {
+                Self::save_instruments_bytes_from_gba_sav_file(sav_path, song_dir)?
+            }
+            ProjectSource::MarkdownFile((_, instruments_path)) | ProjectSource::SongImportFile(instruments_path) => {
+                Self::copy_instruments_file(instruments_path, song_dir)?
+            }
+            ProjectSource::MarkdownGist(_) => {
+                return Err("Can't save a project loaded from a gist URL, please download it first.".into())
+            }
+        };
+        self.sequencer
+            .borrow_mut()
+            .save_as(song_path, instruments_path.as_path())?;
+        self.project_source = ProjectSource::MarkdownFile((song_path.to_owned(), instruments_path));
+        Ok(())
+    }
+
     #[cfg(not(feature = "desktop_native"))]
     pub fn save_project_as(&mut self) {}
 
@@ -650,29 +628,11 @@ impl SoundEngine {
 
     #[cfg(feature = "desktop_native")]
     pub fn save_project_as(&mut self) {
-        #[cfg(feature = "gba")]
-        self.save_song_to_gba_sram();
-
-        #[cfg(feature = "desktop")]
         match &self.project_source {
-            ProjectSource::New => {
-                self.save_project_as_impl(|song_dir| SynthScript::save_default_instruments_as(song_dir))
-            }
-            ProjectSource::GbaSavImportFile(sav_path) => {
-                let copy = sav_path.clone();
-                self.save_project_as_impl(move |song_dir| {
-                    Self::save_instruments_bytes_from_gba_sav_file(&copy, song_dir)
-                })
-            }
-
-            #[cfg(feature = "desktop_native")]
-            ProjectSource::MarkdownFile((_, instruments_path)) => {
-                let instruments_path_copy = instruments_path.to_owned();
-                self.save_project_as_impl(move |song_dir| Self::copy_instruments_file(&instruments_path_copy, song_dir))
-            }
             ProjectSource::MarkdownGist(_) => {
                 elog!("Can't save a project loaded from a gist URL, please download it first.")
             }
+            _ => self.save_project_as_impl(),
         }
     }
 
@@ -681,36 +641,68 @@ impl SoundEngine {
         || -> Result<(), Box<dyn Error>> {
             // TODO: Show a save as dialog.
             let p = Path::new("chiptrack.sav");
-            let instruments = self.instruments_bytes();
-            let instruments_wasm = wat::parse_bytes(&instruments).map_err(|e| e.to_string())?;
-            let song = self.sequencer.borrow().serialize_to_postcard()?;
+            let full = self.gba_sav_bytes()?;
+            let (instruments_len, songs_len) = Self::gba_sav_lengths(&full);
+            // Samples are part of the instruments, report them since they are what usually takes the most space.
             println!(
-                "Saving project song to file {:?}, instruments: {} bytes, song: {} bytes.",
+                "Saving project song to file {:?}, instruments: {} bytes (of which samples: {} bytes), songs: {} bytes.",
                 p,
-                instruments_wasm.len(),
-                song.len()
+                instruments_len,
+                self.synth.samples_len(),
+                songs_len
             );
-            let mut full = Vec::new();
 
-            // TODO: Support flash ROM in load_gba_sram to get access to 64kb or 128kb saves
-            if 8 + instruments_wasm.len() + song.len() >= 32 * 1024 {
+            if !fits_in_gba_sram(full.len()) {
                 return Err(format!(
                     "SRAM save games currently only support max 32kb but the song is {} bytes.",
-                    8 + instruments_wasm.len() + song.len()
+                    full.len()
                 )
                 .into());
             }
 
-            full.extend_from_slice(&(instruments_wasm.len() as u32).to_le_bytes());
-            full.extend_from_slice(&(song.len() as u32).to_le_bytes());
-            full.extend(instruments_wasm.iter());
-            full.extend(song);
             std::fs::write(p, full)?;
             Ok(())
         }()
         .unwrap_or_else(|e| elog!("Error exporting the project: {}", e))
     }
 
+    /// Encodes the instruments and song bank in the layout that load_gba_sav_from_buffer expects.
+    /// This doesn't check whether it fits in GBA_SRAM_SIZE.
+    #[cfg(feature = "desktop_native")]
+    pub fn gba_sav_bytes(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let instruments = self.instruments_bytes();
+        let instruments_wasm = wat::parse_bytes(&instruments).map_err(|e| e.to_string())?;
+        let songs = self.sequencer.borrow().serialize_bank_to_postcard()?;
+
+        let mut full = Vec::new();
+        full.extend_from_slice(&(instruments_wasm.len() as u32).to_le_bytes());
+        full.extend_from_slice(&SONG_BANK_TAG.to_le_bytes());
+        full.extend(instruments_wasm.iter());
+        full.extend(Self::song_bank_bytes(songs));
+        Ok(full)
+    }
+
+    /// Encodes the song bank directory followed by the songs.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn song_bank_bytes(songs: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(songs.len() as u32).to_le_bytes());
+        for song in &songs {
+            bytes.extend_from_slice(&(song.len() as u32).to_le_bytes());
+        }
+        for song in songs {
+            bytes.extend(song);
+        }
+        bytes
+    }
+
+    /// Returns the instruments length and the length of the song bank that follows them in gba_sav_bytes.
+    #[cfg(feature = "desktop_native")]
+    pub fn gba_sav_lengths(sav: &[u8]) -> (usize, usize) {
+        let instruments_len = u32::from_le_bytes(sav[0..4].try_into().unwrap()) as usize;
+        (instruments_len, sav.len() - 8 - instruments_len)
+    }
+
     fn read_u32_from_sram(sram: &mut [u8]) -> u32 {
         #[cfg(feature = "gba")]
         unsafe {
@@ -735,26 +727,74 @@ impl SoundEngine {
     }
     pub fn load_gba_sav_from_buffer(&mut self, sram: &mut [u8]) -> Option<()> {
         // 4 bytes: instruments_len
-        // 4 bytes: song_len
+        // 4 bytes: SONG_BANK_TAG, or song_len in saves with a single song
         // instruments_len bytes: instruments
-        // song_len bytes: song
+        // With SONG_BANK_TAG:
+        //   4 bytes: num_songs
+        //   num_songs * 4 bytes: the length of each song
+        //   the songs, one after the other
+        // Else:
+        //   song_len bytes: song
         // let mut buf = [0u8; 4];
         // let sram = 0x0E00_0000 as *mut u8;
+        if sram.len() < 8 {
+            elog!("The save is too small.");
+            return None;
+        }
         let instruments_len = Self::read_u32_from_sram(sram) as usize;
         if instruments_len == 0xffffffff {
             // SRAM is empty
             return None;
         }
-        let song_len = Self::read_u32_from_sram(&mut sram[4..]) as usize;
+        if instruments_len > sram.len() - 8 {
+            elog!("The instruments go past the end of SRAM.");
+            return None;
+        }
+        let song_len_or_tag = Self::read_u32_from_sram(&mut sram[4..]);
+        let songs_start = 8 + instruments_len;
+        let song_lens = if song_len_or_tag == SONG_BANK_TAG {
+            if songs_start + 4 > sram.len() {
+                elog!("The song bank directory goes past the end of SRAM.");
+                return None;
+            }
+            let num_songs = (Self::read_u32_from_sram(&mut sram[songs_start..]) as usize).min(MAX_BANK_SONGS);
+            if songs_start + 4 + num_songs * 4 > sram.len() {
+                elog!("The song bank directory goes past the end of SRAM.");
+                return None;
+            }
+            (0..num_songs)
+                .map(|i| Self::read_u32_from_sram(&mut sram[songs_start + 4 + i * 4..]) as usize)
+                .collect()
+        } else {
+            vec![song_len_or_tag as usize]
+        };
         log!(
-            "Loading song ({} bytes) and instruments ({} bytes) from SRAM.",
-            song_len,
+            "Loading {} songs ({:?} bytes) and instruments ({} bytes) from SRAM.",
+            song_lens.len(),
+            song_lens,
             instruments_len
         );
 
         {
-            let song_bytes = Self::copy_vec_from_sram(&mut sram[8 + instruments_len..], song_len);
-            self.sequencer.borrow_mut().load_postcard_bytes(&song_bytes).unwrap();
+            let mut song_pos = if song_len_or_tag == SONG_BANK_TAG {
+                songs_start + 4 + song_lens.len() * 4
+            } else {
+                songs_start
+            };
+            let mut songs = Vec::new();
+            for song_len in song_lens {
+                if song_len > sram.len() - song_pos {
+                    elog!("The song bank goes past the end of SRAM.");
+                    return None;
+                }
+                songs.push(Self::copy_vec_from_sram(&mut sram[song_pos..], song_len));
+                song_pos += song_len;
+            }
+            let song_slices: Vec<&[u8]> = songs.iter().map(|s| s.as_slice()).collect();
+            if let Err(e) = self.sequencer.borrow_mut().load_postcard_bank(&song_slices) {
+                elog!("Error loading the song: {}", e);
+                return None;
+            }
         }
 
         // let mut instrument_bytes = Vec::<u8>::with_capacity(instruments_len);
@@ -828,17 +868,80 @@ impl SoundEngine {
 
     #[cfg(feature = "desktop_native")]
     pub fn import_project_from_gba_sav(&mut self, song_path: &Path) {
-        let mut buf = match std::fs::read(song_path) {
-            Ok(buf) => buf,
-            Err(e) => {
-                elog!("Error reading file {:?}: {}", song_path, e);
-                return;
-            }
-        };
-        self.load_gba_sav_from_buffer(&mut buf);
+        if let Err(e) = self.try_import_project_from_gba_sav(song_path) {
+            elog!("Error importing file {:?}: {}", song_path, e);
+        }
+    }
+
+    #[cfg(feature = "desktop_native")]
+    pub fn try_import_project_from_gba_sav(&mut self, song_path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut buf = std::fs::read(song_path)?;
+        self.load_gba_sav_from_buffer(&mut buf)
+            .ok_or("The save file doesn't contain a valid song")?;
 
         // This uses the load file code path and menu but is more like an import.
         self.project_source = ProjectSource::GbaSavImportFile(song_path.to_owned());
+        Ok(())
+    }
+
+    /// Loads a song serialized with Sequencer::serialize_to_postcard, which doesn't contain instruments.
+    #[cfg(feature = "desktop_native")]
+    pub fn try_import_project_from_postcard(
+        &mut self,
+        song_path: &Path,
+        instruments_path: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let bytes = std::fs::read(song_path)?;
+        self.sequencer.borrow_mut().load_postcard_bytes(&bytes)?;
+        self.mute_instruments();
+        self.script.load_file(instruments_path)?;
+
+        self.project_source = ProjectSource::SongImportFile(instruments_path.to_owned());
+        Ok(())
+    }
+
+    /// Creates a new song from a Standard MIDI File, using the default instruments if no instruments file is given.
+    /// The instruments are loaded first so that tracks can be matched with instrument IDs by name.
+    #[cfg(feature = "desktop_native")]
+    pub fn try_import_project_from_smf(
+        &mut self,
+        midi_path: &Path,
+        instruments_path: Option<&Path>,
+        options: &SmfImportOptions,
+    ) -> Result<ImportReport, Box<dyn Error>> {
+        let bytes = std::fs::read(midi_path)?;
+        self.import_song(instruments_path, |sequencer| sequencer.import_smf(&bytes, options))
+    }
+
+    /// Creates a new song from the patterns of a MOD or XM file, using the default instruments if no instruments file is given.
+    #[cfg(feature = "desktop_native")]
+    pub fn try_import_project_from_module(
+        &mut self,
+        module_path: &Path,
+        instruments_path: Option<&Path>,
+        options: &ModuleImportOptions,
+    ) -> Result<ImportReport, Box<dyn Error>> {
+        let bytes = std::fs::read(module_path)?;
+        self.import_song(instruments_path, |sequencer| sequencer.import_module(&bytes, options))
+    }
+
+    #[cfg(feature = "desktop_native")]
+    fn import_song<F>(&mut self, instruments_path: Option<&Path>, import: F) -> Result<ImportReport, Box<dyn Error>>
+    where
+        F: FnOnce(&mut Sequencer) -> Result<ImportReport, Box<dyn Error>>,
+    {
+        self.mute_instruments();
+        match instruments_path {
+            Some(path) => self.script.load_file(path)?,
+            None => self.script.load_default()?,
+        }
+        let report = import(&mut self.sequencer.borrow_mut())?;
+
+        self.project_source = match instruments_path {
+            Some(path) => ProjectSource::SongImportFile(path.to_owned()),
+            None => ProjectSource::New,
+        };
+        Ok(report)
     }
 
     #[cfg(feature = "gba")]
@@ -847,10 +950,36 @@ impl SoundEngine {
             let mut buf = [0u8; 4];
             let sram = 0x0E00_0000 as *mut u8;
             gba::mem::copy_u8_unchecked(buf.as_mut_ptr(), sram, 4);
-            let mut instruments_len = u32::from_le_bytes(buf) as usize;
-            if instruments_len == 0xffffffff || self.project_source == ProjectSource::New {
-                // SRAM is empty, copy the default instruments from ROM.
-                instruments_len = SynthScript::DEFAULT_INSTRUMENTS.len();
+            let stored_instruments_len = u32::from_le_bytes(buf) as usize;
+            // Copy the default instruments from ROM if SRAM is empty.
+            let copy_default_instruments =
+                stored_instruments_len == 0xffffffff || self.project_source == ProjectSource::New;
+            let instruments_len = if copy_default_instruments {
+                SynthScript::DEFAULT_INSTRUMENTS.len()
+            } else {
+                stored_instruments_len
+            };
+            let songs = match self.sequencer.borrow().serialize_bank_to_postcard() {
+                Ok(songs) => songs,
+                Err(e) => {
+                    elog!("save error: {}", e);
+                    return;
+                }
+            };
+            let num_songs = songs.len();
+            let bank_bytes = Self::song_bank_bytes(songs);
+            // SRAM is mirrored, so anything written past its end would overwrite the start of the save.
+            let save_len = 8 + instruments_len + bank_bytes.len();
+            if !fits_in_gba_sram(save_len) {
+                gba_platform::renderer::draw_menu_status_text(&alloc::format!(
+                    "Not saved, {}B > {}B SRAM.",
+                    save_len,
+                    GBA_SRAM_SIZE
+                ));
+                return;
+            }
+
+            if copy_default_instruments {
                 buf = (instruments_len as u32).to_le_bytes();
                 gba::mem::copy_u8_unchecked(sram, buf.as_ptr(), 4);
                 gba::mem::copy_u8_unchecked(
@@ -859,20 +988,18 @@ impl SoundEngine {
                     instruments_len,
                 );
             }
-            match self.sequencer.borrow().serialize_to_postcard() {
-                Ok(song_bytes) => {
-                    buf = (song_bytes.len() as u32).to_le_bytes();
-                    gba::mem::copy_u8_unchecked(sram.offset(4), buf.as_ptr(), 4);
-                    gba::mem::copy_u8_unchecked(
-                        sram.offset(8 + instruments_len as isize),
-                        song_bytes.as_ptr(),
-                        song_bytes.len(),
-                    );
-                    let song_len = u32::from_le_bytes(buf) as usize;
-                    gba_platform::renderer::draw_menu_status_text(&alloc::format!("Saved {}B song to SRAM.", song_len));
-                }
-                Err(e) => elog!("save error: {}", e),
-            }
+            buf = SONG_BANK_TAG.to_le_bytes();
+            gba::mem::copy_u8_unchecked(sram.offset(4), buf.as_ptr(), 4);
+            gba::mem::copy_u8_unchecked(
+                sram.offset(8 + instruments_len as isize),
+                bank_bytes.as_ptr(),
+                bank_bytes.len(),
+            );
+            gba_platform::renderer::draw_menu_status_text(&alloc::format!(
+                "Saved {} songs, {}B to SRAM.",
+                num_songs,
+                bank_bytes.len()
+            ));
             self.project_source = ProjectSource::SRAM;
         }
     }
@@ -881,7 +1008,7 @@ impl SoundEngine {
     pub fn load_gba_sram(&mut self) -> Option<()> {
         unsafe {
             let sram = 0x0E00_0000 as *mut u8;
-            let r = self.load_gba_sav_from_buffer(core::slice::from_raw_parts_mut(sram, 32 * 1024));
+            let r = self.load_gba_sav_from_buffer(core::slice::from_raw_parts_mut(sram, GBA_SRAM_SIZE));
 
             if r.is_some() {
                 self.project_source = ProjectSource::SRAM;
@@ -903,7 +1030,7 @@ impl SoundEngine {
         match &self.project_source {
             ProjectSource::New => SynthScript::DEFAULT_INSTRUMENTS_TEXT.to_vec(),
             ProjectSource::GbaSavImportFile(sav_path) => Self::instruments_bytes_from_gba_sav_file(sav_path),
-            ProjectSource::MarkdownFile((_, instruments_path)) => {
+            ProjectSource::MarkdownFile((_, instruments_path)) | ProjectSource::SongImportFile(instruments_path) => {
                 std::fs::read(instruments_path).expect("Error reading instruments file")
             }
             ProjectSource::MarkdownGist(instruments) => instruments.clone(),
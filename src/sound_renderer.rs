@@ -4,6 +4,13 @@
 #[cfg(feature = "desktop")]
 pub mod emulated;
 #[cfg(feature = "desktop")]
+pub mod gba_apu;
+#[cfg(feature = "desktop_native")]
+pub mod offline;
+pub mod pcm;
+#[cfg(feature = "desktop_native")]
+pub mod vgm;
+#[cfg(feature = "desktop")]
 use std::sync::mpsc::Sender;
 
 #[cfg(feature = "desktop")]
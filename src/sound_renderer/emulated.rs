@@ -1,14 +1,12 @@
 // Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
 // SPDX-License-Identifier: MIT
 
+use crate::observer::EngineObserver;
 use crate::sound_engine::SoundEngine;
-use crate::ui::ChannelActiveNote;
-use crate::ui::ChannelTraceNote;
-use crate::ui::GlobalEngine;
 use crate::ui::GlobalSettings;
 use crate::ui::MainWindow;
 use crate::ui::Settings;
-use crate::utils::MidiNote;
+use crate::ui::SlintObserver;
 use crate::utils::WeakWindowWrapper;
 use core::iter::repeat;
 
@@ -18,7 +16,7 @@ use cpal::SampleFormat;
 use notify::{DebouncedEvent, RecursiveMode, Watcher};
 use once_cell::unsync::Lazy;
 use rboy::VizChunk;
-use slint::{ComponentHandle, Global, Model, SharedString, VecModel};
+use slint::{ComponentHandle, SharedString, VecModel};
 
 use std::cell::RefCell;
 use std::path::PathBuf;
@@ -29,6 +27,8 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
+use super::gba_apu::{GbaApu, NR51_WAVE_BITS, SOUNDBIAS, SOUNDBIAS_6BIT, SOUNDBIAS_8BIT};
+use super::pcm::{PcmMixer, MIX_RATE, NUM_FIFOS};
 use super::SoundRendererTrait;
 
 thread_local! {static SOUND_ENGINE: RefCell<Option<SoundEngine>> = RefCell::new(None);}
@@ -37,6 +37,10 @@ thread_local! {static SOUND_SENDER: RefCell<Option<std::sync::mpsc::Sender<Box<d
 // The pass-through channel is otherwise too loud compared to mixed content.
 const SYNC_GAIN: f32 = 1.0 / 3.0;
 const VBLANK_CYCLES: u32 = 70224;
+// Direct Sound at 100% covers the same range as the four PSG channels mixed together, which is [-1, 1] here.
+const PCM_GAIN: f32 = 1.0 / 128.0;
+// gba_set_sound_reg_at offsets are in GBA CPU cycles, which run 4 times faster than the Game Boy's.
+const GBA_CYCLES_PER_DMG_CYCLE: i32 = 4;
 
 enum PulseState {
     Zero,
@@ -54,8 +58,15 @@ struct SyncPulse {
 pub struct OutputData {
     pub buffer: Vec<f32>,
     pub viz_chunk: Option<VizChunk>,
+    /// When set, receives the Direct Sound part of each sample pair in buffer, before the gain.
+    pub pcm_stem: Option<Vec<f32>>,
     pub gain: f32,
     sync_pulse: SyncPulse,
+    pcm: PcmMixer,
+    /// Accumulates MIX_RATE for each output sample to know when to pull the next PCM sample.
+    pcm_phase: u32,
+    pcm_current: [i8; NUM_FIFOS],
+    apu: GbaApu,
 }
 
 struct FakePlayer {
@@ -63,10 +74,46 @@ struct FakePlayer {
     state: Arc<Mutex<OutputData>>,
 }
 
+/// A write to a Game Boy sound register, as recorded by Synth::start_recording_writes.
+#[cfg(feature = "desktop_native")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterWrite {
+    /// The number of frames rendered since the recording started.
+    pub frame: usize,
+    /// The Game Boy cycle within the frame at which the write was applied.
+    pub cycle: u32,
+    pub addr: u16,
+    pub value: u8,
+}
+
+#[cfg(feature = "desktop_native")]
+#[derive(Default)]
+struct WriteRecorder {
+    frame: usize,
+    writes: Vec<RegisterWrite>,
+}
+
+#[cfg(feature = "desktop_native")]
+impl WriteRecorder {
+    fn push(&mut self, cycle: u32, addr: u16, value: u8) {
+        let frame = self.frame;
+        self.writes.push(RegisterWrite {
+            frame,
+            cycle,
+            addr,
+            value,
+        });
+    }
+}
+
 pub struct Synth {
     dmg: Rc<RefCell<rboy::Sound>>,
     output_data: Arc<Mutex<OutputData>>,
-    main_window: WeakWindowWrapper,
+    observer: Rc<dyn EngineObserver>,
+    /// Writes queued through gba_set_sound_reg_at as (cycle, GBA addr, value), applied during the next frame.
+    timed_writes: Rc<RefCell<Vec<(u32, i32, i32)>>>,
+    #[cfg(feature = "desktop_native")]
+    recorder: Rc<RefCell<Option<WriteRecorder>>>,
 }
 
 impl SyncPulse {
@@ -122,23 +169,56 @@ impl Iterator for SyncPulse {
 }
 
 impl rboy::AudioPlayer for FakePlayer {
-    fn play(&mut self, left_channel: &[f32], right_channel: &[f32], viz_chunk: VizChunk) {
-        let left_iter = left_channel.iter();
-        let mut right_iter = right_channel.iter();
-        let mut state = self.state.lock().unwrap();
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32], mut viz_chunk: VizChunk) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
         let gain = state.gain;
-        state.viz_chunk = Some(viz_chunk);
+
+        // rboy's wave channel is kept silent, replace it with the GBA one.
+        viz_chunk.channels[2].clear();
+        viz_chunk.wave_start_offsets[2].clear();
 
         state.buffer.reserve(left_channel.len() * 2);
-        for left in left_iter {
-            if let Some(pulse_sample) = state.sync_pulse.next() {
-                let right = (left + right_iter.next().unwrap()) / 2.0;
-                state.buffer.push(pulse_sample);
-                state.buffer.push(right * gain);
+        for (i, (left, right)) in left_channel.iter().zip(right_channel).enumerate() {
+            let (wave, wave_restarted) = state.apu.next_wave(self.sample_rate);
+            viz_chunk.channels[2].push(wave);
+            if wave_restarted {
+                viz_chunk.wave_start_offsets[2].push(i);
+            }
+            let (left, right) = state.apu.mix_psg(*left, *right, wave);
+
+            let (left, right, pcm) = if let Some(pulse_sample) = state.sync_pulse.next() {
+                (pulse_sample, (left + right) / 2.0 * gain, 0.0)
             } else {
-                state.buffer.push(*left * gain);
-                state.buffer.push(*right_iter.next().unwrap() * gain);
+                // Like on the GBA, Direct Sound is only used for the sync pulse while it's enabled.
+                // Otherwise, hold each PCM sample of the mixer until the next one at MIX_RATE.
+                state.pcm_phase += MIX_RATE;
+                while state.pcm_phase >= self.sample_rate {
+                    state.pcm_phase -= self.sample_rate;
+                    state.pcm_current = state.pcm.next_sample();
+                }
+                let pcm = state.pcm_current.iter().map(|&s| s as f32).sum::<f32>() * PCM_GAIN;
+                ((left + pcm) * gain, (right + pcm) * gain, pcm)
+            };
+            if let Some(pcm_stem) = state.pcm_stem.as_mut() {
+                pcm_stem.push(pcm);
+            }
+            let (left, right) = state.apu.output(left, right, self.sample_rate);
+            state.buffer.push(left);
+            state.buffer.push(right);
+        }
+
+        // Timed writes split a frame into multiple calls, keep one chunk for the whole frame.
+        match state.viz_chunk.as_mut() {
+            Some(frame_chunk) => {
+                for chan in 0..4 {
+                    let offset = frame_chunk.channels[chan].len();
+                    frame_chunk.wave_start_offsets[chan]
+                        .extend(viz_chunk.wave_start_offsets[chan].iter().map(|o| o + offset));
+                    frame_chunk.channels[chan].extend_from_slice(&viz_chunk.channels[chan]);
+                }
             }
+            None => state.viz_chunk = Some(viz_chunk),
         }
     }
     fn samples_rate(&self) -> u32 {
@@ -157,15 +237,27 @@ pub enum Channel {
     Noise = 0xff1f,
 }
 
+/// Powers the APU on and enables all channels on both sides.
+pub const POWER_ON_WRITES: [(u16, u8); 3] = [(0xff26, 0x80), (0xff24, 0xff), (0xff25, 0xff)];
+
 impl Synth {
-    pub fn new(main_window: WeakWindowWrapper, sample_rate: u32, settings: Settings) -> Synth {
+    pub fn new(observer: Rc<dyn EngineObserver>, sample_rate: u32, settings: Settings) -> Synth {
         let gain = if settings.sync_enabled { SYNC_GAIN } else { 1.0 };
 
         let output_data = Arc::new(Mutex::new(OutputData {
             buffer: Vec::new(),
             viz_chunk: None,
+            pcm_stem: None,
             gain,
             sync_pulse: SyncPulse::new(settings.sync_enabled, sample_rate, 300, 2),
+            pcm: Default::default(),
+            pcm_phase: 0,
+            pcm_current: [0; NUM_FIFOS],
+            apu: {
+                let mut apu = GbaApu::default();
+                apu.write(SOUNDBIAS, Synth::soundbias(settings.sync_enabled));
+                apu
+            },
         }));
 
         let player = Box::new(FakePlayer {
@@ -173,16 +265,17 @@ impl Synth {
             state: output_data.clone(),
         });
         let mut dmg = rboy::Sound::new_cgb(player);
-        // Already power it on.
-        dmg.wb(0xff26, 0x80);
-        // And enable all channels
-        dmg.wb(0xff24, 0xff);
-        dmg.wb(0xff25, 0xff);
+        for (addr, value) in POWER_ON_WRITES {
+            dmg.wb(addr, Synth::to_dmg_value(addr, value));
+        }
 
         Synth {
             dmg: Rc::new(RefCell::new(dmg)),
             output_data,
-            main_window,
+            observer,
+            timed_writes: Default::default(),
+            #[cfg(feature = "desktop_native")]
+            recorder: Default::default(),
         }
     }
 
@@ -190,63 +283,180 @@ impl Synth {
     // to also drive the sound chip. To keep the song timing, also use the same 59.73hz
     // frame refresh rate.
     pub fn advance_frame(&mut self, frame_number: usize, step_change: Option<u32>) {
-        {
-            let dmg = &mut self.dmg.borrow_mut();
-
-            // The sequencer step changed, check if we need to send a pulse to sync downstream devices.
-            if let Some(next_step) = step_change {
-                // Pocket Operator and Volca devices use 2 ppqm.
-                let ppqm = 2;
-                if next_step % ppqm == 0 {
-                    self.output_data.lock().unwrap().sync_pulse.pulse();
-                }
+        // The sequencer step changed, check if we need to send a pulse to sync downstream devices.
+        if let Some(next_step) = step_change {
+            // Pocket Operator and Volca devices use 2 ppqm.
+            let ppqm = 2;
+            if next_step % ppqm == 0 {
+                self.output_data.lock().unwrap().sync_pulse.pulse();
             }
+        }
 
-            // Generate one frame of mixed output.
-            // For 44100hz audio, this will put 44100/59.73 audio samples in output_data.buffer.
-            dmg.do_cycle(VBLANK_CYCLES);
+        // Generate one frame of mixed output.
+        // For 44100hz audio, this will put 44100/59.73 audio samples in output_data.buffer.
+        // Split the frame around timed writes so that they are applied at their cycle.
+        let mut timed_writes = core::mem::take(&mut *self.timed_writes.borrow_mut());
+        // The sort is stable, which keeps writes at the same cycle in the order they were queued.
+        timed_writes.sort_by_key(|&(cycle, _, _)| cycle);
+        let write_gba_reg = self.write_gba_reg_fn();
+        let mut cycle = 0;
+        for (write_cycle, addr, value) in timed_writes {
+            if write_cycle > cycle {
+                self.dmg.borrow_mut().do_cycle(write_cycle - cycle);
+                cycle = write_cycle;
+            }
+            write_gba_reg(addr, value, cycle);
+        }
+        self.dmg.borrow_mut().do_cycle(VBLANK_CYCLES - cycle);
+
+        #[cfg(feature = "desktop_native")]
+        if let Some(recorder) = self.recorder.borrow_mut().as_mut() {
+            recorder.frame += 1;
         }
 
         self.update_ui_channel_states(frame_number as i32);
     }
 
     pub fn set_sound_reg_callback(&self) -> impl Fn(i32, i32) {
+        let write_gba_reg = self.write_gba_reg_fn();
+        move |addr: i32, value: i32| write_gba_reg(addr, value, 0)
+    }
+
+    /// Queues writes to be applied cycle_offset GBA CPU cycles into the next rendered frame.
+    pub fn set_sound_reg_at_callback(&self) -> impl Fn(i32, i32, i32) {
+        let timed_writes = self.timed_writes.clone();
+        move |cycle_offset: i32, addr: i32, value: i32| {
+            // Writes past the end of the frame are applied on its last cycle.
+            let cycle = (cycle_offset.max(0) / GBA_CYCLES_PER_DMG_CYCLE).min(VBLANK_CYCLES as i32 - 1) as u32;
+            timed_writes.borrow_mut().push((cycle, addr, value));
+        }
+    }
+
+    pub fn set_wave_table_callback(&self) -> impl Fn(&[u8]) {
+        let write_reg = self.write_reg_fn();
+        let output_data = self.output_data.clone();
+        move |table: &[u8]| {
+            output_data.lock().unwrap().apu.write_wave_table(table);
+            // Only for recordings, the Game Boy has a single bank of wave RAM.
+            for (i, v) in table.iter().take(16).enumerate() {
+                write_reg((0xff30 + i) as u16, *v);
+            }
+        }
+    }
+
+    pub fn set_sample_callback(&self) -> impl Fn(i32, &[u8]) {
+        let output_data = self.output_data.clone();
+        move |sample: i32, data: &[u8]| output_data.lock().unwrap().pcm.set_sample(sample, data)
+    }
+
+    pub fn play_sample_callback(&self) -> impl Fn(i32, i32, i32) {
+        let output_data = self.output_data.clone();
+        move |fifo: i32, sample: i32, rate: i32| output_data.lock().unwrap().pcm.play(fifo, sample, rate)
+    }
+
+    /// The total size of the samples registered by instruments.
+    #[cfg(feature = "desktop_native")]
+    pub fn samples_len(&self) -> usize {
+        self.output_data.lock().unwrap().pcm.samples_len()
+    }
+
+    fn write_reg_fn(&self) -> impl Fn(u16, u8) {
+        let write_reg_at = self.write_reg_at_fn();
+        move |addr: u16, value: u8| write_reg_at(addr, value, 0)
+    }
+
+    fn write_reg_at_fn(&self) -> impl Fn(u16, u8, u32) {
         let dmg_cell = self.dmg.clone();
-        move |addr: i32, value: i32| {
+        #[cfg(feature = "desktop_native")]
+        let recorder_cell = self.recorder.clone();
+        move |addr: u16, value: u8, _cycle: u32| {
+            dmg_cell.borrow_mut().wb(addr, Synth::to_dmg_value(addr, value));
+            #[cfg(feature = "desktop_native")]
+            if let Some(recorder) = recorder_cell.borrow_mut().as_mut() {
+                recorder.push(_cycle, addr, value);
+            }
+        }
+    }
+
+    /// Writes a GBA sound register to the GBA APU model and its Game Boy equivalents to rboy.
+    fn write_gba_reg_fn(&self) -> impl Fn(i32, i32, u32) {
+        let write_reg_at = self.write_reg_at_fn();
+        let output_data = self.output_data.clone();
+        move |addr: i32, value: i32, cycle: u32| {
+            output_data.lock().unwrap().apu.write(addr as u32, value as u16);
             let (maybe_lsb, maybe_msb) = Synth::gba_to_gb_addr(addr);
-            let mut dmg = dmg_cell.borrow_mut();
             if let Some(a) = maybe_lsb {
-                dmg.wb(a, value as u8);
+                write_reg_at(a, value as u8, cycle);
             }
             if let Some(a) = maybe_msb {
-                dmg.wb(a, (value >> 8) as u8);
+                write_reg_at(a, (value >> 8) as u8, cycle);
             }
         }
     }
 
-    pub fn set_wave_table_callback(&self) -> impl Fn(&[u8]) {
-        let dmg_cell = self.dmg.clone();
-        move |table: &[u8]| {
-            let mut dmg = dmg_cell.borrow_mut();
-            for (i, v) in table.iter().take(16).enumerate() {
-                dmg.wb((0xff30 + i) as u16, *v);
-            }
+    /// The GBA APU model plays the wave channel instead of rboy, which still gets its writes so
+    /// that recordings keep a Game Boy approximation of it.
+    fn to_dmg_value(addr: u16, value: u8) -> u8 {
+        if addr == 0xff25 {
+            value & !NR51_WAVE_BITS
+        } else {
+            value
         }
     }
 
+    /// Starts keeping every register write done by instruments, with frame 0 being the current frame.
+    #[cfg(feature = "desktop_native")]
+    pub fn start_recording_writes(&mut self) {
+        *self.recorder.borrow_mut() = Some(WriteRecorder::default());
+    }
+
+    /// Returns the writes recorded since the recording started or since the last call.
+    #[cfg(feature = "desktop_native")]
+    pub fn take_recorded_writes(&mut self) -> Vec<RegisterWrite> {
+        self.recorder
+            .borrow_mut()
+            .as_mut()
+            .map(|r| core::mem::take(&mut r.writes))
+            .unwrap_or_default()
+    }
+
     pub fn apply_settings(&mut self, settings: &Settings) {
         let mut output_data = self.output_data.lock().unwrap();
         output_data.gain = if settings.sync_enabled { SYNC_GAIN } else { 1.0 };
         output_data.sync_pulse.enabled = settings.sync_enabled;
+        output_data
+            .apu
+            .write(SOUNDBIAS, Synth::soundbias(settings.sync_enabled));
+    }
+
+    /// Uses the same SOUNDBIAS resolution as the GBA renderer.
+    fn soundbias(sync_enabled: bool) -> u16 {
+        if sync_enabled {
+            SOUNDBIAS_8BIT
+        } else {
+            SOUNDBIAS_6BIT
+        }
     }
 
     pub fn mute_instruments(&mut self) {
-        let dmg = &mut self.dmg.borrow_mut();
+        // Don't let writes queued for the next frame unmute them.
+        self.timed_writes.borrow_mut().clear();
+        let mut output_data = self.output_data.lock().unwrap();
+        output_data.pcm.stop_all();
+        output_data.apu.mute_wave();
+        drop(output_data);
+        let write_reg = self.write_reg_fn();
         // Set the envelopes to 0.
-        dmg.wb(Channel::Square1 as u16 + 2, 0);
-        dmg.wb(Channel::Square2 as u16 + 2, 0);
-        dmg.wb(Channel::Wave as u16 + 2, 0);
-        dmg.wb(Channel::Noise as u16 + 2, 0);
+        write_reg(Channel::Square1 as u16 + 2, 0);
+        write_reg(Channel::Square2 as u16 + 2, 0);
+        write_reg(Channel::Wave as u16 + 2, 0);
+        write_reg(Channel::Noise as u16 + 2, 0);
+    }
+
+    /// Returns the (left, right) gain that NR50, NR51 and the PSG ratio currently apply to each channel's output.
+    #[cfg(feature = "desktop_native")]
+    pub fn channel_gains(&self) -> [(f32, f32); 4] {
+        self.output_data.lock().unwrap().apu.channel_gains()
     }
 
     pub fn output_data(&self) -> Arc<Mutex<OutputData>> {
@@ -258,128 +468,8 @@ impl Synth {
         // Let square channels be rendered on top of the wave channel.
         states.reverse();
 
-        self.main_window
-            .upgrade_in_event_loop(move |handle| {
-                let global = GlobalEngine::get(&handle);
-                global.set_last_synth_tick(frame_number);
-
-                let trace_model = global.get_synth_trace_notes();
-                let trace_vec_model = trace_model
-                    .as_any()
-                    .downcast_ref::<VecModel<ChannelTraceNote>>()
-                    .unwrap();
-
-                let active_model = global.get_synth_active_notes();
-                let active_vec_model = active_model
-                    .as_any()
-                    .downcast_ref::<VecModel<ChannelActiveNote>>()
-                    .unwrap();
-
-                // FIXME: Provide the oldest tick number to the UI or get it from it
-                let visible_ticks = 6 * 16 * 2;
-
-                // First remove traces that would disappear after being shrunk as this affects indices.
-                while trace_vec_model.row_count() > 0 {
-                    let trace = trace_vec_model.row_data(0).unwrap();
-                    if frame_number - (trace.start_tick + trace.num_ticks) >= visible_ticks {
-                        trace_vec_model.remove(0);
-                    } else {
-                        break;
-                    }
-                }
-
-                let mut last_chan_trace_index: [Option<usize>; 4] = [None; 4];
-                for i in 0..trace_vec_model.row_count() {
-                    let mut trace = trace_vec_model.row_data(i).unwrap();
-
-                    // Remember whether this trace is a candidate to be merged with current channel traces.
-                    if trace.start_tick + trace.num_ticks == frame_number {
-                        last_chan_trace_index[trace.channel as usize] = Some(i);
-                    }
-
-                    // Shrink old traces that span past the trace history range.
-                    if frame_number - trace.start_tick >= visible_ticks {
-                        let diff = frame_number - trace.start_tick - visible_ticks;
-                        trace.start_tick += diff;
-                        trace.num_ticks -= diff;
-                        trace_vec_model.set_row_data(i, trace);
-                    }
-                }
-
-                // FIXME: Keep notes that are still active instead of re-adding?
-                active_vec_model.set_vec(Vec::new());
-
-                for (channel, &(freq, vol)) in states.iter().enumerate() {
-                    if vol > 0 {
-                        let (trace, active) = if let Some((note, cent_adj)) = freq.map(MidiNote::from_freq) {
-                            let semitone = note.semitone();
-                            // Stretch the between-notes range for white notes not followed by a black note.
-                            let cent_factor = if (semitone == 4 || semitone == 11) && cent_adj > 0.0
-                                || (semitone == 5 || semitone == 0) && cent_adj < 0.0
-                            {
-                                1.0
-                            } else {
-                                0.5
-                            };
-
-                            let trace = ChannelTraceNote {
-                                channel: channel as i32,
-                                start_tick: frame_number,
-                                num_ticks: 1,
-                                octave: note.octave(),
-                                key_pos: note.key_pos(),
-                                cent_adj: cent_adj * cent_factor,
-                                is_black: note.is_black(),
-                                volume: vol as f32 / 15.0,
-                            };
-                            let active = ChannelActiveNote {
-                                trace: trace.clone(),
-                                note_name: note.name().into(),
-                            };
-                            (trace, active)
-                        } else {
-                            let trace = ChannelTraceNote {
-                                channel: channel as i32,
-                                start_tick: frame_number,
-                                num_ticks: 1,
-                                octave: 0,
-                                key_pos: 0,
-                                cent_adj: 0.0,
-                                is_black: false,
-                                volume: vol as f32 / 15.0,
-                            };
-                            let active = ChannelActiveNote {
-                                trace: trace.clone(),
-                                note_name: "*".into(),
-                            };
-                            (trace, active)
-                        };
-
-                        let maybe_last_index = last_chan_trace_index[trace.channel as usize];
-                        maybe_last_index
-                            .and_then(|last_index| {
-                                let mut last = trace_vec_model.row_data(last_index).unwrap();
-                                if last.channel == trace.channel
-                                    && last.octave == trace.octave
-                                    && last.key_pos == trace.key_pos
-                                    && last.cent_adj == trace.cent_adj
-                                    && last.is_black == trace.is_black
-                                    && last.volume == trace.volume
-                                {
-                                    last.num_ticks += trace.num_ticks;
-                                    trace_vec_model.set_row_data(last_index, last);
-                                    Some(())
-                                } else {
-                                    None
-                                }
-                            })
-                            .unwrap_or_else(|| trace_vec_model.push(trace));
-
-                        active_vec_model.push(active);
-                    }
-                }
-            })
-            .unwrap();
+        let states: Vec<(Option<f64>, u8)> = states.into_iter().map(|(freq, vol)| (freq, vol as u8)).collect();
+        self.observer.synth_channels_changed(frame_number, &states);
     }
 
     fn gba_to_gb_addr(gba_addr: i32) -> (Option<u16>, Option<u16>) {
@@ -712,8 +802,9 @@ pub fn new_sound_renderer(window: &MainWindow) -> SoundRenderer<impl FnOnce() ->
                     SOUND_ENGINE.with(|maybe_engine_cell| {
                         let mut maybe_engine = maybe_engine_cell.borrow_mut();
                         if maybe_engine.is_none() {
-                            let synth = Synth::new(window_weak.clone(), sample_rate, initial_settings.clone());
-                            *maybe_engine = Some(SoundEngine::new(synth, window_weak.clone()));
+                            let observer: Rc<dyn EngineObserver> = Rc::new(SlintObserver::new(window_weak.clone()));
+                            let synth = Synth::new(observer.clone(), sample_rate, initial_settings.clone());
+                            *maybe_engine = Some(SoundEngine::new(synth, observer));
                         }
                         let engine = maybe_engine.as_mut().unwrap();
 
@@ -0,0 +1,336 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Models the parts of the GBA's sound hardware that differ from the Game Boy APU that rboy emulates:
+//! the wave channel with its two banks of wave RAM and forced 75% volume, and the SOUNDCNT_H PSG
+//! ratio and SOUNDBIAS resolution applied to the mix.
+
+const DMG_CLOCK: u64 = 4194304;
+// Length counters are clocked at 256 Hz.
+const LENGTH_CYCLES: u64 = DMG_CLOCK / 256;
+/// The NR51 bits that send the wave channel to the right and left outputs.
+pub const NR51_WAVE_BITS: u8 = 0x44;
+/// The emulator's mixer scales each channel by 0.25 to avoid clipping when all of them play.
+const CHANNEL_MIXER_GAIN: f32 = 0.25;
+
+const SOUND3CNT_L: u32 = 0x4000070;
+const SOUND3CNT_H: u32 = 0x4000072;
+const SOUND3CNT_X: u32 = 0x4000074;
+const SOUNDCNT_L: u32 = 0x4000080;
+const SOUNDCNT_H: u32 = 0x4000082;
+pub const SOUNDBIAS: u32 = 0x4000088;
+const WAVE_RAM: u32 = 0x4000090;
+
+/// The SOUNDBIAS values that the GBA renderer sets, centered with 6 bits at 262.144kHz or 8 bits at 65.536kHz.
+pub const SOUNDBIAS_6BIT: u16 = 0xc200;
+pub const SOUNDBIAS_8BIT: u16 = 0x4200;
+
+#[derive(Default)]
+pub struct WaveChannel {
+    banks: [[u8; 16]; 2],
+    /// NR30 bit 5, plays both banks one after the other as 64 samples.
+    two_banks: bool,
+    /// NR30 bit 6, the bank that plays first, the CPU can only write to the other one.
+    bank: usize,
+    dac_enabled: bool,
+    enabled: bool,
+    volume_code: u8,
+    force_75: bool,
+    freq: u16,
+    length_enabled: bool,
+    length: u16,
+    /// The index of the playing sample in the 32 or 64 samples.
+    position: usize,
+    /// DMG cycles since the last sample change, multiplied by the output sample rate.
+    timer_progress: u64,
+    length_progress: u64,
+}
+
+impl WaveChannel {
+    fn write(&mut self, addr: u32, value: u16) {
+        match addr {
+            SOUND3CNT_L => {
+                self.two_banks = value & 0x20 != 0;
+                self.bank = (value >> 6) as usize & 1;
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            SOUND3CNT_H => {
+                self.length = 256 - (value & 0xff);
+                self.volume_code = (value >> 13) as u8 & 0x3;
+                self.force_75 = value & 0x8000 != 0;
+            }
+            SOUND3CNT_X => {
+                self.freq = value & 0x7ff;
+                self.length_enabled = value & 0x4000 != 0;
+                if value & 0x8000 != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.position = 0;
+                    self.timer_progress = 0;
+                    if self.length == 0 {
+                        self.length = 256;
+                    }
+                }
+            }
+            WAVE_RAM..=0x400009F => {
+                let offset = (addr - WAVE_RAM) as usize;
+                self.write_ram(offset, value as u8);
+                if offset + 1 < 16 {
+                    self.write_ram(offset + 1, (value >> 8) as u8);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_ram(&mut self, offset: usize, value: u8) {
+        self.banks[self.bank ^ 1][offset] = value;
+    }
+
+    fn level(&self, sample: u8) -> u8 {
+        if self.force_75 {
+            sample * 3 / 4
+        } else {
+            match self.volume_code {
+                0 => 0,
+                1 => sample,
+                2 => sample >> 1,
+                _ => sample >> 2,
+            }
+        }
+    }
+
+    /// The output of the playing wave sample, from -1.0 to 1.0.
+    fn sample_output(&self) -> f32 {
+        let bank = if self.two_banks {
+            (self.bank + self.position / 32) & 1
+        } else {
+            self.bank
+        };
+        let byte = self.banks[bank][(self.position % 32) / 2];
+        let sample = if self.position % 2 == 0 { byte >> 4 } else { byte & 0xf };
+        // Center the output around 0 whatever the volume.
+        (self.level(sample) as f32 * 2.0 - self.level(0xf) as f32) / 15.0
+    }
+
+    /// Returns the output from -1.0 to 1.0 for the current output sample, and whether the wave
+    /// started over during it, then advances the channel to the next output sample.
+    /// The output is the average of the wave samples played during the output sample, like a box filter,
+    /// so that high notes don't alias more than the other channels.
+    fn next(&mut self, sample_rate: u32) -> (f32, bool) {
+        if !self.enabled {
+            return (0.0, false);
+        }
+
+        let mut restarted = false;
+        let num_samples = if self.two_banks { 64 } else { 32 };
+        let period = (2048 - self.freq as u64) * 2 * sample_rate as u64;
+        // Both in DMG cycles multiplied by the output sample rate.
+        let mut remaining = DMG_CLOCK;
+        let mut sum = 0.0f64;
+        loop {
+            let until_next_sample = period.saturating_sub(self.timer_progress);
+            if until_next_sample > remaining {
+                sum += self.sample_output() as f64 * remaining as f64;
+                self.timer_progress += remaining;
+                break;
+            }
+            sum += self.sample_output() as f64 * until_next_sample as f64;
+            remaining -= until_next_sample;
+            self.timer_progress = 0;
+            self.position = (self.position + 1) % num_samples;
+            restarted |= self.position == 0;
+        }
+        let output = (sum / DMG_CLOCK as f64) as f32;
+
+        if self.length_enabled {
+            self.length_progress += DMG_CLOCK;
+            while self.length_progress >= LENGTH_CYCLES * sample_rate as u64 {
+                self.length_progress -= LENGTH_CYCLES * sample_rate as u64;
+                self.length = self.length.saturating_sub(1);
+                if self.length == 0 {
+                    self.enabled = false;
+                }
+            }
+        }
+        (output, restarted)
+    }
+}
+
+pub struct GbaApu {
+    wave: WaveChannel,
+    nr50: u8,
+    nr51: u8,
+    psg_ratio: f32,
+    bias_level: i32,
+    /// SOUNDBIAS bits 14-15, from 0 for 9 bits at 32.768kHz to 3 for 6 bits at 262.144kHz.
+    resolution: u32,
+    /// Output samples multiplied by the SOUNDBIAS sampling rate, since the last time it sampled the mix.
+    bias_progress: u64,
+    held_output: (f32, f32),
+}
+
+impl Default for GbaApu {
+    fn default() -> Self {
+        GbaApu {
+            wave: Default::default(),
+            // Like POWER_ON_WRITES.
+            nr50: 0xff,
+            nr51: 0xff,
+            psg_ratio: 1.0,
+            bias_level: 0x200,
+            resolution: 3,
+            bias_progress: 0,
+            held_output: (0.0, 0.0),
+        }
+    }
+}
+
+impl GbaApu {
+    /// Applies the write to the registers modeled here, any other register is ignored.
+    pub fn write(&mut self, addr: u32, value: u16) {
+        match addr {
+            SOUND3CNT_L | SOUND3CNT_H | SOUND3CNT_X | WAVE_RAM..=0x400009F => self.wave.write(addr, value),
+            SOUNDCNT_L => {
+                self.nr50 = value as u8;
+                self.nr51 = (value >> 8) as u8;
+            }
+            SOUNDCNT_H => {
+                self.psg_ratio = match value & 0x3 {
+                    0 => 0.25,
+                    1 => 0.5,
+                    _ => 1.0,
+                }
+            }
+            SOUNDBIAS => {
+                self.bias_level = (value & 0x3fe) as i32;
+                self.resolution = (value >> 14) as u32;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn mute_wave(&mut self) {
+        self.wave.volume_code = 0;
+        self.wave.force_75 = false;
+    }
+
+    pub fn write_wave_table(&mut self, table: &[u8]) {
+        for (i, v) in table.iter().take(16).enumerate() {
+            self.wave.write_ram(i, *v);
+        }
+    }
+
+    /// Returns the (left, right) gain that NR50, NR51 and the PSG ratio currently apply to each channel's output.
+    pub fn channel_gains(&self) -> [(f32, f32); 4] {
+        let left_vol = ((self.nr50 >> 4) & 0x7) as f32 + 1.0;
+        let right_vol = (self.nr50 & 0x7) as f32 + 1.0;
+        core::array::from_fn(|chan| {
+            let left = if self.nr51 & (0x10 << chan) != 0 {
+                left_vol / 8.0 * self.psg_ratio
+            } else {
+                0.0
+            };
+            let right = if self.nr51 & (0x01 << chan) != 0 {
+                right_vol / 8.0 * self.psg_ratio
+            } else {
+                0.0
+            };
+            (left, right)
+        })
+    }
+
+    /// Advances the wave channel by one output sample, see WaveChannel::next.
+    pub fn next_wave(&mut self, sample_rate: u32) -> (f32, bool) {
+        self.wave.next(sample_rate)
+    }
+
+    /// Adds the wave channel output to the mix of the other PSG channels and applies the PSG ratio.
+    pub fn mix_psg(&self, left: f32, right: f32, wave: f32) -> (f32, f32) {
+        let (wave_left, wave_right) = self.channel_gains()[2];
+        // The wave gains already include the PSG ratio.
+        (
+            left * self.psg_ratio + wave * wave_left * CHANNEL_MIXER_GAIN,
+            right * self.psg_ratio + wave * wave_right * CHANNEL_MIXER_GAIN,
+        )
+    }
+
+    /// Samples the final mix at the SOUNDBIAS rate and resolution, holding the value between samples.
+    pub fn output(&mut self, left: f32, right: f32, sample_rate: u32) -> (f32, f32) {
+        self.bias_progress += 32768 << self.resolution;
+        if self.bias_progress >= sample_rate as u64 {
+            self.bias_progress %= sample_rate as u64;
+            let shift = 1 + self.resolution;
+            let quantize = |v: f32| {
+                // The mix and bias are 10 bits, of which the resolution only keeps the upper ones.
+                let level = ((v * 512.0) as i32 + self.bias_level).clamp(0, 0x3ff);
+                (((level >> shift) << shift) - self.bias_level) as f32 / 512.0
+            };
+            self.held_output = (quantize(left), quantize(right));
+        }
+        self.held_output
+    }
+}
+
+#[test]
+fn wave_banks_and_volumes() {
+    let mut apu = GbaApu::default();
+    // One output sample per wave sample.
+    let sample_rate = (DMG_CLOCK / 128) as u32;
+    let freq = 2048 - 64;
+    let next_outputs =
+        |apu: &mut GbaApu, n: usize| -> Vec<f32> { (0..n).map(|_| apu.next_wave(sample_rate).0).collect() };
+
+    // Bank 0 is selected, so the table goes to bank 1.
+    apu.write(SOUND3CNT_L, 0x00);
+    apu.write_wave_table(&[0xf0; 16]);
+    apu.write(SOUND3CNT_L, 0xc0);
+    apu.write(SOUND3CNT_H, 0x2000);
+    apu.write(SOUND3CNT_X, 0x8000 | freq);
+    assert_eq!(next_outputs(&mut apu, 4), [1.0, -1.0, 1.0, -1.0]);
+
+    // Forced 75% volume.
+    apu.write(SOUND3CNT_H, 0x8000);
+    assert_eq!(next_outputs(&mut apu, 2), [11.0 / 15.0, -11.0 / 15.0]);
+
+    // With bank 1 selected, writes go to bank 0, which plays after bank 1 in 64 samples mode.
+    apu.write_wave_table(&[0x88; 16]);
+    apu.write(SOUND3CNT_L, 0xe0);
+    apu.write(SOUND3CNT_H, 0x2000);
+    apu.write(SOUND3CNT_X, 0x8000 | freq);
+    let outputs = next_outputs(&mut apu, 66);
+    assert!(outputs[..32].iter().all(|o| o.abs() == 1.0));
+    assert!(outputs[32..64].iter().all(|&o| o == 1.0 / 15.0));
+    assert_eq!(outputs[64..], [1.0, -1.0]);
+
+    // Samples shorter than an output sample are averaged instead of aliasing.
+    apu.write(SOUND3CNT_L, 0xc0);
+    apu.write(SOUND3CNT_X, 0x8000 | 2047);
+    assert!((0..100).all(|_| apu.next_wave(44100).0.abs() < 0.05));
+
+    // Turning the DAC off silences the channel.
+    apu.write(SOUND3CNT_L, 0x00);
+    assert_eq!(next_outputs(&mut apu, 1), [0.0]);
+}
+
+#[test]
+fn psg_ratio_and_bias_resolution() {
+    let mut apu = GbaApu::default();
+    apu.write(SOUNDCNT_H, 0x1);
+    assert_eq!(apu.mix_psg(0.5, -0.5, 0.0), (0.25, -0.25));
+    assert_eq!(apu.channel_gains()[0], (0.5, 0.5));
+
+    // 6 bits keep steps of 16 out of 1024 levels.
+    apu.write(SOUNDBIAS, SOUNDBIAS_6BIT);
+    assert_eq!(apu.output(0.01, 0.04, 44100), (0.0, 0.03125));
+    // 9 bits at 32.768kHz holds some of the samples at 44.1kHz.
+    let mut apu = GbaApu::default();
+    apu.write(SOUNDBIAS, SOUNDBIAS_6BIT & 0x3ff);
+    let outputs: Vec<f32> = [0.5, 0.25, 0.125, 0.0]
+        .iter()
+        .map(|&v| apu.output(v, v, 44100).0)
+        .collect();
+    assert_eq!(outputs, [0.0, 0.25, 0.125, 0.125]);
+}
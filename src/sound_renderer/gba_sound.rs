@@ -3,13 +3,22 @@
 
 use gba::prelude::*;
 
+use super::pcm::{PcmMixer, CYCLES_PER_SAMPLE, NUM_FIFOS, SAMPLES_PER_FRAME};
 use super::SoundRendererTrait;
+use crate::elog;
 use crate::sound_engine::SoundEngine;
+use crate::synth_script::FRAME_CYCLES;
 use crate::ui::MainWindow;
 use crate::ui::Settings;
+use crate::ui::SlintObserver;
 use crate::utils::WeakWindowWrapper;
 
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ffi::c_void;
 use core::ptr::addr_of;
+use core::ptr::addr_of_mut;
 #[cfg(feature = "desktop")]
 use std::sync::mpsc;
 #[cfg(feature = "desktop")]
@@ -21,12 +30,156 @@ const SQUARE_WAVE: u32 = 0x80_7f_80_7f;
 // This must be in RAM to support a Fixed source DMA transfer
 static mut ZEROES: u32 = 0x00000000;
 
+// TIMER0 runs at 16.78MHz / 64, which fits the 280896 cycles of a frame in 4389 ticks.
+const TIMED_WRITE_CYCLES_PER_TICK: u32 = 64;
+// Leaves room for a few instruments ticking 4 times per frame.
+const MAX_TIMED_WRITES: usize = 128;
+
+#[derive(Clone, Copy)]
+struct TimedWrite {
+    tick: u16,
+    addr: u32,
+    value: u16,
+}
+
+/// Writes sorted by tick, the TIMER0 interrupt applies the front ones during the current frame
+/// while advance_frame schedules the next frame's in the back ones.
+static mut SCHEDULED_WRITES: [[TimedWrite; MAX_TIMED_WRITES]; 2] = [[TimedWrite {
+    tick: 0,
+    addr: 0,
+    value: 0,
+}; MAX_TIMED_WRITES]; 2];
+static mut NUM_SCHEDULED_WRITES: [usize; 2] = [0; 2];
+static mut WRITES_FRONT: usize = 0;
+static mut WRITES_BACK_READY: bool = false;
+static mut NEXT_SCHEDULED_WRITE: usize = 0;
+
+fn write_sound_reg(addr: u32, value: u16) {
+    unsafe {
+        *(addr as *mut u16) = value;
+    }
+}
+
+fn start_timer0_in(ticks: u16) {
+    TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
+    TIMER0_RELOAD.write(0u16.wrapping_sub(ticks.max(1)));
+    TIMER0_CONTROL.write(
+        TimerControl::new()
+            .with_scale(TimerScale::_64)
+            .with_overflow_irq(true)
+            .with_enabled(true),
+    );
+}
+
+/// Applies the scheduled writes that are due and sets TIMER0 to overflow when the next ones are.
+/// Called by the interrupt handler on TIMER0 overflows.
+#[link_section = ".iwram"]
+pub fn on_timer0_overflow() {
+    unsafe {
+        let num_writes = NUM_SCHEDULED_WRITES[WRITES_FRONT];
+        if NEXT_SCHEDULED_WRITE >= num_writes {
+            return;
+        }
+        let writes = &(*addr_of!(SCHEDULED_WRITES))[WRITES_FRONT];
+        let tick = writes[NEXT_SCHEDULED_WRITE].tick;
+        while NEXT_SCHEDULED_WRITE < num_writes && writes[NEXT_SCHEDULED_WRITE].tick == tick {
+            let TimedWrite { addr, value, .. } = writes[NEXT_SCHEDULED_WRITE];
+            write_sound_reg(addr, value);
+            NEXT_SCHEDULED_WRITE += 1;
+        }
+        if NEXT_SCHEDULED_WRITE < num_writes {
+            start_timer0_in(writes[NEXT_SCHEDULED_WRITE].tick - tick);
+        } else {
+            TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
+        }
+    }
+}
+
+/// Flushes what the last frame didn't get to apply and starts TIMER0 on the writes that
+/// advance_frame scheduled for this one, so that their ticks count from the vblank.
+#[link_section = ".iwram"]
+fn start_scheduled_writes() {
+    TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
+    unsafe {
+        let writes = &*addr_of!(SCHEDULED_WRITES);
+        for w in &writes[WRITES_FRONT][NEXT_SCHEDULED_WRITE..NUM_SCHEDULED_WRITES[WRITES_FRONT]] {
+            write_sound_reg(w.addr, w.value);
+        }
+        NEXT_SCHEDULED_WRITE = NUM_SCHEDULED_WRITES[WRITES_FRONT];
+        if !WRITES_BACK_READY {
+            return;
+        }
+        WRITES_FRONT ^= 1;
+        WRITES_BACK_READY = false;
+        NEXT_SCHEDULED_WRITE = 0;
+        if NUM_SCHEDULED_WRITES[WRITES_FRONT] > 0 {
+            start_timer0_in(writes[WRITES_FRONT][0].tick);
+        }
+    }
+}
+
+#[repr(C, align(4))]
+struct PcmBuffers([[[i8; SAMPLES_PER_FRAME]; 2]; NUM_FIFOS]);
+
+/// Two buffers per FIFO, DMA streams the front one while advance_frame mixes the next frame in the other.
+static mut PCM_BUFFERS: PcmBuffers = PcmBuffers([[[0; SAMPLES_PER_FRAME]; 2]; NUM_FIFOS]);
+static mut PCM_FRONT: usize = 0;
+static mut PCM_BACK_MIXED: bool = false;
+/// Direct Sound is only used for the sync pulse while it's enabled.
+static mut PCM_ENABLED: bool = false;
+
+/// Restarts the DMA of both FIFOs from the start of the front buffers.
+fn stream_pcm_buffers() {
+    let control = DmaControl::new()
+        .with_enabled(true)
+        .with_start_time(DmaStartTime::Special)
+        .with_transfer_32bit(true)
+        .with_repeat(true)
+        .with_dest_addr_control(DestAddrControl::Fixed)
+        .with_src_addr_control(SrcAddrControl::Increment);
+    unsafe {
+        let buffers = &*addr_of!(PCM_BUFFERS);
+        DMA1_CONTROL.write(DmaControl::new());
+        DMA2_CONTROL.write(DmaControl::new());
+        DMA1_SRC.write(buffers.0[0][PCM_FRONT].as_ptr() as *const c_void);
+        DMA2_SRC.write(buffers.0[1][PCM_FRONT].as_ptr() as *const c_void);
+        DMA1_DEST.write(FIFO_A.as_mut_ptr() as *mut c_void);
+        DMA2_DEST.write(FIFO_B.as_mut_ptr() as *mut c_void);
+        DMA1_CONTROL.write(control);
+        DMA2_CONTROL.write(control);
+    }
+}
+
+/// Starts the timed writes scheduled during the last frame and swaps to the PCM buffers mixed during it,
+/// or replays the current ones if advance_frame didn't get to run.
+/// Called by the interrupt handler on vblank, where each frame's 304 samples start.
+#[link_section = ".iwram"]
+pub fn on_vblank() {
+    start_scheduled_writes();
+    unsafe {
+        if !PCM_ENABLED {
+            return;
+        }
+        if PCM_BACK_MIXED {
+            PCM_FRONT ^= 1;
+            PCM_BACK_MIXED = false;
+        }
+    }
+    stream_pcm_buffers();
+}
+
 pub struct Synth {
     sync_enabled: bool,
+    /// Writes queued through gba_set_sound_reg_at, scheduled on the next frame.
+    timed_writes: Rc<RefCell<Vec<TimedWrite>>>,
+    pcm: Rc<RefCell<PcmMixer>>,
 }
 
 impl Synth {
     pub fn advance_frame(&mut self, _frame_number: usize, step_change: Option<u32>) {
+        self.schedule_timed_writes();
+        self.mix_pcm_frame();
+
         if self.sync_enabled {
             // The sequencer step changed, check if we need to send a pulse to sync slave devices.
             if let Some(next_step) = step_change {
@@ -51,16 +204,95 @@ impl Synth {
         }
     }
 
+    /// Sorts the writes queued during this frame in the back buffer, for on_vblank to start
+    /// applying them with offsets relative to the next vblank.
+    fn schedule_timed_writes(&mut self) {
+        unsafe {
+            // Prevent on_vblank from picking the back writes while they are being replaced.
+            IME.write(false);
+            let unstarted = WRITES_BACK_READY;
+            WRITES_BACK_READY = false;
+            IME.write(true);
+
+            let back = WRITES_FRONT ^ 1;
+            let writes = &mut (*addr_of_mut!(SCHEDULED_WRITES))[back];
+            // Flush the writes of a frame that on_vblank didn't get to start before replacing them.
+            if unstarted {
+                for w in &writes[..NUM_SCHEDULED_WRITES[back]] {
+                    write_sound_reg(w.addr, w.value);
+                }
+            }
+
+            let mut timed_writes = self.timed_writes.borrow_mut();
+            if timed_writes.len() > MAX_TIMED_WRITES {
+                elog!(
+                    "Too many gba_set_sound_reg_at writes in one frame, dropping {}",
+                    timed_writes.len() - MAX_TIMED_WRITES
+                );
+                timed_writes.truncate(MAX_TIMED_WRITES);
+            }
+            // The sort is stable, which keeps the order of writes queued for the same tick.
+            timed_writes.sort_by_key(|w| w.tick);
+            writes[..timed_writes.len()].copy_from_slice(&timed_writes);
+            NUM_SCHEDULED_WRITES[back] = timed_writes.len();
+            timed_writes.clear();
+            WRITES_BACK_READY = true;
+        }
+    }
+
+    /// Mixes the next frame of samples in the back PCM buffers, for on_vblank to stream them.
+    fn mix_pcm_frame(&mut self) {
+        unsafe {
+            if !PCM_ENABLED {
+                return;
+            }
+            // Prevent on_vblank from swapping buffers while they are being mixed.
+            PCM_BACK_MIXED = false;
+            let back = PCM_FRONT ^ 1;
+            let buffers = &mut *addr_of_mut!(PCM_BUFFERS);
+            let mut pcm = self.pcm.borrow_mut();
+            for i in 0..SAMPLES_PER_FRAME {
+                let mixed = pcm.next_sample();
+                for fifo in 0..NUM_FIFOS {
+                    buffers.0[fifo][back][i] = mixed[fifo];
+                }
+            }
+            PCM_BACK_MIXED = true;
+        }
+    }
+
     pub fn set_sound_reg_callback(&self) -> impl Fn(i32, i32) {
         // FIXME: Check the address allowed bounds
         move |addr: i32, value: i32| {
             // log!("{:#x}: {:#04x} ({:#010b})", addr, value, value);
-            unsafe {
-                *(addr as *mut u16) = value as u16;
-            }
+            write_sound_reg(addr as u32, value as u16);
         }
     }
 
+    /// Queues writes to be applied by the TIMER0 interrupt cycle_offset CPU cycles into the next frame.
+    pub fn set_sound_reg_at_callback(&self) -> impl Fn(i32, i32, i32) {
+        let timed_writes = self.timed_writes.clone();
+        move |cycle_offset: i32, addr: i32, value: i32| {
+            // Writes past the end of the frame are applied on its last tick.
+            let cycle = (cycle_offset.max(0) as u32).min(FRAME_CYCLES - 1);
+            timed_writes.borrow_mut().push(TimedWrite {
+                tick: (cycle / TIMED_WRITE_CYCLES_PER_TICK) as u16,
+                addr: addr as u32,
+                value: value as u16,
+            });
+        }
+    }
+
+    pub fn set_sample_callback(&self) -> impl Fn(i32, &[u8]) {
+        let pcm = self.pcm.clone();
+        move |sample: i32, data: &[u8]| pcm.borrow_mut().set_sample(sample, data)
+    }
+
+    pub fn play_sample_callback(&self) -> impl Fn(i32, i32, i32) {
+        let pcm = self.pcm.clone();
+        move |fifo: i32, sample: i32, rate: i32| pcm.borrow_mut().play(fifo, sample, rate)
+    }
+
     pub fn set_wave_table_callback(&self) -> impl Fn(&[u8]) {
         move |table: &[u8]| {
             // log!("set_wave_table: {:?}", table);
@@ -88,6 +320,10 @@ impl Synth {
             .with_wave_left(true)
             .with_noise_left(true);
 
+        unsafe {
+            PCM_ENABLED = !self.sync_enabled;
+        }
+
         if self.sync_enabled {
             // Mute PSG channels on the left.
             LEFT_RIGHT_VOLUME.write(volume_val.with_left_volume(0));
@@ -114,6 +350,8 @@ impl Synth {
 
             unsafe {
                 // Set up the DMA to continually fill the FIFO with 00 samples so that the sound hardware keeps running.
+                DMA1_CONTROL.write(DmaControl::new());
+                DMA2_CONTROL.write(DmaControl::new());
                 DMA1_SRC.write(addr_of!(ZEROES) as *const core::ffi::c_void);
                 DMA1_DEST.write(FIFO_A.as_mut_ptr() as *mut core::ffi::c_void);
                 DMA1_CONTROL.write(
@@ -143,14 +381,40 @@ impl Synth {
                     .with_sample_cycle(SampleCycle::_6bit),
             );
 
+            // Both FIFOs take one sample of the PCM buffers every CYCLES_PER_SAMPLE.
             TIMER1_CONTROL.write(TimerControl::new().with_enabled(false));
+            TIMER1_RELOAD.write(0u16.wrapping_sub(CYCLES_PER_SAMPLE as u16));
+            TIMER1_CONTROL.write(TimerControl::new().with_enabled(true).with_scale(TimerScale::_1));
 
-            // 100% volume for the PSG, 0% for the DMA channels
-            SOUND_MIX.write(SoundMix::new().with_psg(PsgMix::_100));
+            // 100% volume for the PSG and the samples played by instruments on both FIFOs
+            SOUND_MIX.write(
+                SoundMix::new()
+                    .with_psg(PsgMix::_100)
+                    .with_sound_a_full(true)
+                    .with_sound_a_left(true)
+                    .with_sound_a_right(true)
+                    .with_sound_a_timer(true) // true => timer1
+                    .with_sound_a_reset(true)
+                    .with_sound_b_full(true)
+                    .with_sound_b_left(true)
+                    .with_sound_b_right(true)
+                    .with_sound_b_timer(true)
+                    .with_sound_b_reset(true),
+            );
+            stream_pcm_buffers();
         }
     }
 
     pub fn mute_instruments(&mut self) {
+        // Don't let writes scheduled for this frame or queued for the next one unmute them.
+        unsafe {
+            WRITES_BACK_READY = false;
+            NUM_SCHEDULED_WRITES = [0; 2];
+            NEXT_SCHEDULED_WRITE = 0;
+        }
+        TIMER0_CONTROL.write(TimerControl::new().with_enabled(false));
+        self.timed_writes.borrow_mut().clear();
+        self.pcm.borrow_mut().stop_all();
         TONE1_PATTERN.write(TonePattern::new().with_volume(0));
         TONE2_PATTERN.write(TonePattern::new().with_volume(0));
         WAVE_LEN_VOLUME.write(WaveLenVolume::new().with_volume(0));
@@ -166,11 +430,18 @@ pub fn new_sound_renderer(window: &MainWindow) -> SoundRenderer {
     // Already power it on
     SOUND_ENABLED.write(SoundEnable::new().with_enabled(true));
 
-    let mut synth = Synth { sync_enabled: false };
+    let mut synth = Synth {
+        sync_enabled: false,
+        timed_writes: Default::default(),
+        pcm: Default::default(),
+    };
     // Set-up the mixing and bias for sync disabled.
     synth.apply_settings(&Default::default());
 
-    let sound_engine = SoundEngine::new(synth, WeakWindowWrapper::new(window.as_weak()));
+    let sound_engine = SoundEngine::new(
+        synth,
+        Rc::new(SlintObserver::new(WeakWindowWrapper::new(window.as_weak()))),
+    );
 
     SoundRenderer { sound_engine }
 }
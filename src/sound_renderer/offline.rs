@@ -0,0 +1,220 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Renders a song to a WAV file without opening a window or an audio device.
+
+use crate::observer::{EngineObserver, NoopObserver};
+use crate::sound_engine::SoundEngine;
+use crate::sound_renderer::Synth;
+use crate::ui::Settings;
+
+use alloc::rc::Rc;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    Int16,
+    Float32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    /// How many frames to keep rendering after the last step so that releases can ring out.
+    pub release_tail_frames: usize,
+    pub format: SampleFormat,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: 44100,
+            // About 2 seconds.
+            release_tail_frames: 120,
+            format: SampleFormat::Int16,
+        }
+    }
+}
+
+// Names used as file suffixes for each PSG channel, in VizChunk.channels order, followed by Direct Sound samples.
+pub const STEM_NAMES: [&str; 5] = ["square1", "square2", "wave", "noise", "pcm"];
+const PCM_STEM: usize = 4;
+// The emulator's mixer scales each channel by 0.25 to avoid clipping when all of them play,
+// apply it to stems as well so that their levels match the mix.
+const STEM_MIXER_GAIN: f32 = 0.25;
+
+pub struct RenderedSong {
+    /// Interleaved stereo samples.
+    pub mix: Vec<f32>,
+    /// Interleaved stereo samples of each channel, with the NR50/NR51 panning and SOUNDCNT_H PSG ratio applied.
+    /// Direct Sound samples play on both sides.
+    /// They are taken before the SOUNDBIAS quantization and sample-and-hold of the mix, so they only add up
+    /// to the mix approximately.
+    pub stems: [Vec<f32>; 5],
+}
+
+/// Creates a SoundEngine that doesn't need a window or an audio device.
+/// Its output needs to be drained from the synth's output_data after each frame.
+pub fn new_headless_engine(sample_rate: u32) -> SoundEngine {
+    let observer: Rc<dyn EngineObserver> = Rc::new(NoopObserver);
+    let synth = Synth::new(observer.clone(), sample_rate, Settings::default());
+    SoundEngine::new(synth, observer)
+}
+
+/// Plays each song pattern once in song mode and returns the rendered samples.
+pub fn render_song<F>(load_project: F, options: &RenderOptions) -> Result<RenderedSong, Box<dyn Error>>
+where
+    F: FnOnce(&mut SoundEngine) -> Result<(), Box<dyn Error>>,
+{
+    let mut engine = new_headless_engine(options.sample_rate);
+    let output_data = engine.synth.output_data();
+    output_data.lock().unwrap().pcm_stem = Some(Vec::new());
+    load_project(&mut engine)?;
+
+    let song_frames = engine.sequencer.borrow().song_length_in_frames();
+    let mut rendered = RenderedSong {
+        mix: Vec::new(),
+        stems: Default::default(),
+    };
+    let mut render_frame = |engine: &mut SoundEngine| {
+        engine.advance_frame();
+        let gains = engine.synth.channel_gains();
+        let mut output = output_data.lock().unwrap();
+        rendered.mix.append(&mut output.buffer);
+        let viz_chunk = output.viz_chunk.take().unwrap();
+        for pcm in output.pcm_stem.as_mut().unwrap().drain(..) {
+            rendered.stems[PCM_STEM].push(pcm);
+            rendered.stems[PCM_STEM].push(pcm);
+        }
+        for (chan, stem) in rendered.stems[..PCM_STEM].iter_mut().enumerate() {
+            let (left, right) = gains[chan];
+            stem.reserve(viz_chunk.channels[chan].len() * 2);
+            for s in viz_chunk.channels[chan].iter() {
+                stem.push(s * left * STEM_MIXER_GAIN);
+                stem.push(s * right * STEM_MIXER_GAIN);
+            }
+        }
+    };
+
+    engine.sequencer.borrow_mut().activate_step(0);
+    engine.set_playing(true, true);
+    for _ in 0..song_frames {
+        render_frame(&mut engine);
+    }
+    engine.stop_and_release_instruments();
+    for _ in 0..options.release_tail_frames {
+        render_frame(&mut engine);
+    }
+
+    Ok(rendered)
+}
+
+pub fn write_wav_file(
+    path: &Path,
+    samples: &[f32],
+    num_channels: u16,
+    options: &RenderOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_wav(&mut writer, samples, num_channels, options.sample_rate, options.format)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes each channel next to wav_path as `<wav_path stem>-<channel>.wav` and returns the written paths.
+pub fn write_stem_files(
+    wav_path: &Path,
+    rendered: &RenderedSong,
+    options: &RenderOptions,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let file_stem = wav_path
+        .file_stem()
+        .ok_or_else(|| format!("Invalid output path {:?}", wav_path))?
+        .to_string_lossy();
+    let mut written = Vec::new();
+    for (name, samples) in STEM_NAMES.iter().zip(rendered.stems.iter()) {
+        let stem_path = wav_path.with_file_name(format!("{}-{}.wav", file_stem, name));
+        write_wav_file(&stem_path, samples, 2, options)?;
+        written.push(stem_path);
+    }
+    Ok(written)
+}
+
+/// Writes interleaved samples in the [-1.0, 1.0] range as a RIFF WAVE stream.
+pub fn write_wav<W: Write>(
+    w: &mut W,
+    samples: &[f32],
+    num_channels: u16,
+    sample_rate: u32,
+    format: SampleFormat,
+) -> std::io::Result<()> {
+    let (format_tag, bytes_per_sample): (u16, u16) = match format {
+        SampleFormat::Int16 => (1, 2),
+        SampleFormat::Float32 => (3, 4),
+    };
+    let data_len = samples.len() as u32 * bytes_per_sample as u32;
+    let block_align = num_channels * bytes_per_sample;
+    // Non-PCM formats need a cbSize field in fmt and a fact chunk.
+    let (fmt_len, fact_len) = match format {
+        SampleFormat::Int16 => (16, 0),
+        SampleFormat::Float32 => (18, 12),
+    };
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(4 + 8 + fmt_len + fact_len + 8 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&fmt_len.to_le_bytes())?;
+    w.write_all(&format_tag.to_le_bytes())?;
+    w.write_all(&num_channels.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+    if format == SampleFormat::Float32 {
+        w.write_all(&0u16.to_le_bytes())?;
+        w.write_all(b"fact")?;
+        w.write_all(&4u32.to_le_bytes())?;
+        w.write_all(&(samples.len() as u32 / num_channels as u32).to_le_bytes())?;
+    }
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    for s in samples {
+        match format {
+            SampleFormat::Int16 => w.write_all(&((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())?,
+            SampleFormat::Float32 => w.write_all(&s.to_le_bytes())?,
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn wav_header() {
+    let mut bytes = Vec::new();
+    write_wav(&mut bytes, &[0.0, 1.0, -1.0, 0.5], 2, 44100, SampleFormat::Int16).unwrap();
+    assert_eq!(bytes.len(), 44 + 8);
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(
+        u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        bytes.len() as u32 - 8
+    );
+    assert_eq!(&bytes[36..40], b"data");
+    assert_eq!(i16::from_le_bytes(bytes[46..48].try_into().unwrap()), i16::MAX);
+    assert_eq!(i16::from_le_bytes(bytes[48..50].try_into().unwrap()), -i16::MAX);
+
+    let mut bytes = Vec::new();
+    write_wav(&mut bytes, &[0.0, 1.0], 2, 44100, SampleFormat::Float32).unwrap();
+    assert_eq!(bytes.len(), 58 + 8);
+    assert_eq!(u16::from_le_bytes(bytes[20..22].try_into().unwrap()), 3);
+    assert_eq!(&bytes[38..42], b"fact");
+    assert_eq!(
+        u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        bytes.len() as u32 - 8
+    );
+}
@@ -0,0 +1,152 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Mixes the 8 bits PCM samples that instruments play on the GBA's Direct Sound FIFOs.
+//! Both the GBA and the emulated synth pull the same stream from it, one sample at a time at MIX_RATE.
+
+use crate::elog;
+use crate::synth_script::FRAME_CYCLES;
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// FIFO A and B.
+pub const NUM_FIFOS: usize = 2;
+pub const MAX_SAMPLES: usize = 16;
+/// Samples are copied in RAM, and also take space in the instruments of a .sav export.
+pub const MAX_SAMPLES_LEN: usize = 16 * 1024;
+pub const SAMPLES_PER_FRAME: usize = 304;
+/// The GBA timer reload that gives an integer number of samples per frame.
+pub const CYCLES_PER_SAMPLE: u32 = FRAME_CYCLES / SAMPLES_PER_FRAME as u32;
+/// About 18157 Hz.
+pub const MIX_RATE: u32 = 16 * 1024 * 1024 / CYCLES_PER_SAMPLE;
+
+#[derive(Clone, Copy)]
+struct Voice {
+    sample: usize,
+    /// 16.16 fixed point position in the sample.
+    pos: u32,
+    /// 16.16 fixed point number of sample frames to advance per mixed sample.
+    step: u32,
+}
+
+pub struct PcmMixer {
+    samples: Vec<Vec<i8>>,
+    voices: [Option<Voice>; NUM_FIFOS],
+}
+
+impl Default for PcmMixer {
+    fn default() -> Self {
+        PcmMixer {
+            samples: vec![Vec::new(); MAX_SAMPLES],
+            voices: [None; NUM_FIFOS],
+        }
+    }
+}
+
+impl PcmMixer {
+    /// Registers signed 8 bits PCM data for the sample, an empty one unregisters it.
+    pub fn set_sample(&mut self, sample: i32, data: &[u8]) {
+        if sample < 0 || sample >= MAX_SAMPLES as i32 {
+            elog!(
+                "gba_set_sample: sample must be 0 <= sample < {}, got {}. Ignoring sample.",
+                MAX_SAMPLES,
+                sample
+            );
+            return;
+        }
+        let others_len = self.samples_len() - self.samples[sample as usize].len();
+        if others_len + data.len() > MAX_SAMPLES_LEN {
+            elog!(
+                "gba_set_sample: samples can only take {} bytes in total, sample {} would need {}. Ignoring sample.",
+                MAX_SAMPLES_LEN,
+                sample,
+                others_len + data.len()
+            );
+            return;
+        }
+        // Stop voices that might otherwise continue playing past the end of the new data.
+        for voice in &mut self.voices {
+            if voice.map_or(false, |v| v.sample == sample as usize) {
+                *voice = None;
+            }
+        }
+        self.samples[sample as usize] = data.iter().map(|&b| b as i8).collect();
+    }
+
+    /// Starts playing the sample from its beginning on the FIFO at the given rate in Hz, a rate of 0 stops the FIFO.
+    pub fn play(&mut self, fifo: i32, sample: i32, rate: i32) {
+        if fifo < 0 || fifo >= NUM_FIFOS as i32 {
+            elog!(
+                "gba_play_sample: fifo must be 0 (A) or 1 (B), got {}. Ignoring play.",
+                fifo
+            );
+            return;
+        }
+        let voice = &mut self.voices[fifo as usize];
+        if rate <= 0 {
+            *voice = None;
+            return;
+        }
+        if sample < 0 || sample >= MAX_SAMPLES as i32 || self.samples[sample as usize].is_empty() {
+            elog!("gba_play_sample: sample {} isn't set. Ignoring play.", sample);
+            return;
+        }
+        *voice = Some(Voice {
+            sample: sample as usize,
+            pos: 0,
+            step: ((rate as u64 * CYCLES_PER_SAMPLE as u64 * 65536) / (16 * 1024 * 1024)) as u32,
+        });
+    }
+
+    pub fn stop_all(&mut self) {
+        self.voices = [None; NUM_FIFOS];
+    }
+
+    /// The total size of registered samples.
+    pub fn samples_len(&self) -> usize {
+        self.samples.iter().map(|s| s.len()).sum()
+    }
+
+    /// Returns the next MIX_RATE sample of each FIFO, 0 for those not playing.
+    pub fn next_sample(&mut self) -> [i8; NUM_FIFOS] {
+        let mut mixed = [0; NUM_FIFOS];
+        for (fifo, maybe_voice) in self.voices.iter_mut().enumerate() {
+            if let Some(voice) = maybe_voice {
+                match self.samples[voice.sample].get((voice.pos >> 16) as usize) {
+                    Some(&value) => {
+                        mixed[fifo] = value;
+                        voice.pos += voice.step;
+                    }
+                    None => *maybe_voice = None,
+                }
+            }
+        }
+        mixed
+    }
+}
+
+#[test]
+fn mixed_samples_follow_the_rate() {
+    let mut mixer = PcmMixer::default();
+    mixer.set_sample(3, &[10, 20, 30, 0xff]);
+    // Slightly more than half the mixing rate repeats each sample frame twice.
+    mixer.play(1, 3, 9079);
+    let mixed: Vec<[i8; NUM_FIFOS]> = (0..10).map(|_| mixer.next_sample()).collect();
+    let fifo_b: Vec<i8> = mixed.iter().map(|m| m[1]).collect();
+    assert_eq!(fifo_b, [10, 10, 20, 20, 30, 30, -1, -1, 0, 0]);
+    assert!(mixed.iter().all(|m| m[0] == 0));
+
+    // The total size of samples is limited, replacing a sample frees its previous size.
+    mixer.set_sample(0, &vec![0; MAX_SAMPLES_LEN - 4]);
+    assert_eq!(mixer.samples_len(), MAX_SAMPLES_LEN);
+    mixer.set_sample(1, &[1]);
+    assert_eq!(mixer.samples_len(), MAX_SAMPLES_LEN);
+    mixer.set_sample(3, &[]);
+    mixer.set_sample(1, &[1]);
+    assert_eq!(mixer.samples_len(), MAX_SAMPLES_LEN - 3);
+
+    // Unset samples can't be played.
+    mixer.play(0, 3, 8000);
+    assert_eq!(mixer.next_sample(), [0, 0]);
+}
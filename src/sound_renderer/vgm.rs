@@ -0,0 +1,240 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Exports the sound register writes of a song as a VGM file that chiptune players can play.
+
+use crate::sound_engine::SoundEngine;
+use crate::sound_renderer::emulated::{RegisterWrite, POWER_ON_WRITES};
+use crate::sound_renderer::offline::new_headless_engine;
+
+use std::error::Error;
+use std::io::Write;
+
+// VGM timestamps are in 44100hz samples, whatever the sample rate used for playback.
+const VGM_SAMPLE_RATE: u64 = 44100;
+const DMG_CLOCK: u32 = 4194304;
+const VBLANK_CYCLES: u64 = 70224;
+// 1.61 is the first version with Game Boy DMG support.
+const VGM_VERSION: u32 = 0x161;
+const HEADER_LEN: usize = 0x100;
+
+const CMD_DMG_WRITE: u8 = 0xb3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_END: u8 = 0x66;
+
+/// The register writes of a song played once, split between the writes that set up
+/// the instruments and those done while playing.
+pub struct RecordedSong {
+    pub setup: Vec<RegisterWrite>,
+    pub song: Vec<RegisterWrite>,
+    pub num_frames: usize,
+    /// The frame of the song's loop start, or None if the song stops at the end.
+    pub loop_frame: Option<usize>,
+}
+
+/// Plays each song pattern once in song mode and returns every sound register write.
+pub fn record_song<F>(load_project: F) -> Result<RecordedSong, Box<dyn Error>>
+where
+    F: FnOnce(&mut SoundEngine) -> Result<(), Box<dyn Error>>,
+{
+    let mut engine = new_headless_engine(VGM_SAMPLE_RATE as u32);
+    let output_data = engine.synth.output_data();
+    engine.synth.start_recording_writes();
+    load_project(&mut engine)?;
+
+    let num_frames = engine.sequencer.borrow().song_length_in_frames();
+    let loop_frame = engine.sequencer.borrow().song_loop_start_in_frames();
+    engine.sequencer.borrow_mut().activate_step(0);
+    engine.set_playing(true, true);
+    let setup = engine.synth.take_recorded_writes();
+
+    for _ in 0..num_frames {
+        engine.advance_frame();
+        // Only the register writes are needed.
+        output_data.lock().unwrap().buffer.clear();
+    }
+
+    Ok(RecordedSong {
+        setup,
+        song: engine.synth.take_recorded_writes(),
+        num_frames,
+        loop_frame,
+    })
+}
+
+/// The position in VGM samples of a Game Boy cycle within a frame.
+fn frame_to_samples(frame: usize, cycle: u32) -> u64 {
+    (frame as u64 * VBLANK_CYCLES + cycle as u64) * VGM_SAMPLE_RATE / DMG_CLOCK as u64
+}
+
+fn push_dmg_write(data: &mut Vec<u8>, addr: u16, value: u8) {
+    // Registers are relative to NR10, the wave RAM at 0xff30 ends up at 0x20.
+    data.extend_from_slice(&[CMD_DMG_WRITE, (addr - 0xff10) as u8, value]);
+}
+
+fn push_wait(data: &mut Vec<u8>, mut samples: u64) {
+    while samples > 0 {
+        let chunk = samples.min(u16::MAX as u64);
+        data.push(CMD_WAIT);
+        data.extend_from_slice(&(chunk as u16).to_le_bytes());
+        samples -= chunk;
+    }
+}
+
+/// Writes a VGM file that plays the recorded song, looping back to its loop start if it has one.
+pub fn write_vgm<W: Write>(w: &mut W, recorded: &RecordedSong) -> std::io::Result<()> {
+    let mut data = Vec::new();
+    for (addr, value) in POWER_ON_WRITES {
+        push_dmg_write(&mut data, addr, value);
+    }
+    for write in &recorded.setup {
+        push_dmg_write(&mut data, write.addr, write.value);
+    }
+
+    let mut loop_offset = None;
+    let mut writes = recorded.song.iter().peekable();
+    let mut position = 0;
+    for frame in 0..recorded.num_frames {
+        if recorded.loop_frame == Some(frame) {
+            loop_offset = Some(HEADER_LEN + data.len());
+        }
+        while let Some(write) = writes.next_if(|w| w.frame == frame) {
+            // Writes queued with gba_set_sound_reg_at happen within the frame.
+            let write_position = frame_to_samples(frame, write.cycle);
+            push_wait(&mut data, write_position - position);
+            position = write_position;
+            push_dmg_write(&mut data, write.addr, write.value);
+        }
+        let next_frame_position = frame_to_samples(frame + 1, 0);
+        push_wait(&mut data, next_frame_position - position);
+        position = next_frame_position;
+    }
+    data.push(CMD_END);
+
+    let total_samples = frame_to_samples(recorded.num_frames, 0) as u32;
+    let mut header = [0u8; HEADER_LEN];
+    let mut set_u32 = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    // Offsets in the header are relative to the field's own position.
+    set_u32(0x04, (HEADER_LEN + data.len() - 0x04) as u32);
+    set_u32(0x08, VGM_VERSION);
+    set_u32(0x18, total_samples);
+    // A loop offset of 0 means that the song doesn't loop.
+    if let (Some(offset), Some(frame)) = (loop_offset, recorded.loop_frame) {
+        set_u32(0x1c, (offset - 0x1c) as u32);
+        set_u32(0x20, total_samples - frame_to_samples(frame, 0) as u32);
+    }
+    set_u32(0x34, (HEADER_LEN - 0x34) as u32);
+    set_u32(0x80, DMG_CLOCK);
+    header[0..4].copy_from_slice(b"Vgm ");
+
+    w.write_all(&header)?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+#[test]
+fn vgm_stream() {
+    let recorded = RecordedSong {
+        setup: vec![RegisterWrite {
+            frame: 0,
+            cycle: 0,
+            addr: 0xff30,
+            value: 0x12,
+        }],
+        song: vec![
+            RegisterWrite {
+                frame: 0,
+                cycle: 0,
+                addr: 0xff12,
+                value: 0xf0,
+            },
+            RegisterWrite {
+                frame: 1,
+                cycle: 0,
+                addr: 0xff14,
+                value: 0x87,
+            },
+        ],
+        num_frames: 2,
+        loop_frame: Some(0),
+    };
+    let mut bytes = Vec::new();
+    write_vgm(&mut bytes, &recorded).unwrap();
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+    assert_eq!(&bytes[0..4], b"Vgm ");
+    assert_eq!(u32_at(0x04) as usize, bytes.len() - 4);
+    assert_eq!(u32_at(0x18), 1476);
+    assert_eq!(u32_at(0x20), 1476);
+    assert_eq!(0x34 + u32_at(0x34) as usize, HEADER_LEN);
+
+    let loop_start = 0x1c + u32_at(0x1c) as usize;
+    assert_eq!(loop_start, HEADER_LEN + 4 * 3);
+    assert_eq!(&bytes[loop_start - 3..loop_start], &[CMD_DMG_WRITE, 0x20, 0x12]);
+    assert_eq!(
+        &bytes[loop_start..],
+        &[
+            CMD_DMG_WRITE,
+            0x02,
+            0xf0,
+            CMD_WAIT,
+            0xe2,
+            0x02,
+            CMD_DMG_WRITE,
+            0x04,
+            0x87,
+            CMD_WAIT,
+            0xe2,
+            0x02,
+            CMD_END
+        ]
+    );
+
+    let recorded = RecordedSong {
+        loop_frame: Some(1),
+        ..recorded
+    };
+    let mut bytes = Vec::new();
+    write_vgm(&mut bytes, &recorded).unwrap();
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    assert_eq!(u32_at(0x20), 738);
+    let loop_start = 0x1c + u32_at(0x1c) as usize;
+    assert_eq!(&bytes[loop_start..loop_start + 3], &[CMD_DMG_WRITE, 0x04, 0x87]);
+
+    let recorded = RecordedSong {
+        loop_frame: None,
+        ..recorded
+    };
+    let mut bytes = Vec::new();
+    write_vgm(&mut bytes, &recorded).unwrap();
+    assert_eq!(&bytes[0x1c..0x24], &[0; 8]);
+
+    // A write in the middle of a frame splits its wait.
+    let recorded = RecordedSong {
+        song: vec![RegisterWrite {
+            frame: 0,
+            cycle: VBLANK_CYCLES as u32 / 2,
+            addr: 0xff12,
+            value: 0xf0,
+        }],
+        num_frames: 1,
+        ..recorded
+    };
+    let mut bytes = Vec::new();
+    write_vgm(&mut bytes, &recorded).unwrap();
+    assert_eq!(
+        &bytes[HEADER_LEN + 4 * 3..],
+        &[
+            CMD_WAIT,
+            0x71,
+            0x01,
+            CMD_DMG_WRITE,
+            0x02,
+            0xf0,
+            CMD_WAIT,
+            0x71,
+            0x01,
+            CMD_END
+        ]
+    );
+}
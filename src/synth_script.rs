@@ -5,32 +5,40 @@ use crate::sequencer::InstrumentParamDef;
 use crate::sound_engine::NUM_INSTRUMENTS;
 use crate::sound_engine::NUM_INSTRUMENT_COLS;
 use crate::sound_engine::NUM_INSTRUMENT_PARAMS;
+use crate::sound_renderer::pcm::MAX_SAMPLES;
+#[cfg(feature = "desktop_native")]
+use crate::synth_script::trace::{ScriptCallback, TraceRecorder};
 use crate::synth_script::wasm::WasmIndirectFunction;
 use crate::synth_script::wasm::WasmModule;
 use crate::synth_script::wasm::WasmModuleInst;
 use crate::synth_script::wasm::WasmRuntime;
 use crate::utils::NOTE_FREQUENCIES;
 
-use slint::SharedString;
-
 use alloc::boxed::Box;
 use alloc::rc::Rc;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::ffi::CStr;
 #[cfg(feature = "desktop")]
 use std::fs::File;
 #[cfg(feature = "desktop")]
 use std::io::Write;
 
+#[cfg(feature = "desktop_native")]
+pub mod trace;
 pub mod wasm;
 #[cfg(not(feature = "desktop_web"))]
 pub mod wasm_host;
 #[cfg(feature = "desktop_web")]
 pub mod wasm_web;
 
+/// The number of GBA CPU cycles in a 59.73 Hz frame, in which gba_set_sound_reg_at offsets are.
+pub const FRAME_CYCLES: u32 = 280896;
+/// 4 ticks per frame gives instruments 239 Hz envelopes.
+const MAX_TICKS_PER_FRAME: i32 = 4;
+
 fn instrument_print(s: &CStr) {
     log!("print: {}", s.to_str().expect("Invalid UTF-8"));
 }
@@ -45,10 +53,14 @@ struct PressedNote {
 #[derive(Clone, Default)]
 struct InstrumentState {
     press_function: WasmIndirectFunction,
+    /// Takes all NUM_INSTRUMENT_PARAMS params, used instead of press_function if defined.
+    press_with_params_function: WasmIndirectFunction,
     release_function: WasmIndirectFunction,
     frame_function: WasmIndirectFunction,
     set_param_functions: [WasmIndirectFunction; NUM_INSTRUMENT_PARAMS],
     frames_after_release: i32,
+    /// How many times per frame the frame function runs, 1 unless set with set_instrument_ticks_per_frame.
+    ticks_per_frame: u32,
     pressed_note: Option<PressedNote>,
 }
 
@@ -67,7 +79,7 @@ impl InstrumentColArrayExt for [Vec<InstrumentState>; NUM_INSTRUMENT_COLS] {
 /// Accumulates instrument definitions during loading, then apply them to the sequencer.
 #[derive(Default, Clone)]
 pub struct SequencerInstrumentDef {
-    pub ids: Vec<SharedString>,
+    pub ids: Vec<String>,
     pub params: Vec<[Option<InstrumentParamDef>; NUM_INSTRUMENT_PARAMS]>,
 }
 
@@ -77,6 +89,11 @@ pub struct SynthScript {
     sequencer_instrument_def: Rc<RefCell<SequencerInstrumentDef>>,
     instrument_states: Rc<RefCell<[Vec<InstrumentState>; NUM_INSTRUMENT_COLS]>>,
     apply_instrument_def_callback: Rc<dyn Fn(SequencerInstrumentDef)>,
+    /// The offset in the frame of the frame function tick currently running.
+    tick_cycle_offset: Rc<Cell<u32>>,
+    synth_set_sample: Rc<dyn Fn(i32, &[u8])>,
+    #[cfg(feature = "desktop_native")]
+    trace: Rc<TraceRecorder>,
 }
 
 impl SynthScript {
@@ -91,16 +108,64 @@ impl SynthScript {
     pub const DEFAULT_INSTRUMENTS: &'static [u8] =
         include_bytes!(concat!(env!("OUT_DIR"), "/default-instruments.wasm"));
 
-    pub fn new<F, G, H>(synth_set_sound_reg: F, synth_set_wave_table: G, apply_instrument_def: H) -> SynthScript
+    pub fn new<F, FA, G, S, P, H>(
+        synth_set_sound_reg: F,
+        synth_set_sound_reg_at: FA,
+        synth_set_wave_table: G,
+        synth_set_sample: S,
+        synth_play_sample: P,
+        apply_instrument_def: H,
+    ) -> SynthScript
     where
         F: Fn(i32, i32) + 'static,
+        FA: Fn(i32, i32, i32) + 'static,
         G: Fn(&[u8]) + 'static,
+        S: Fn(i32, &[u8]) + 'static,
+        P: Fn(i32, i32, i32) + 'static,
         H: Fn(SequencerInstrumentDef) + 'static,
     {
         let sequencer_instrument_def: Rc<RefCell<SequencerInstrumentDef>> =
             Rc::new(RefCell::new(SequencerInstrumentDef::default()));
         let instrument_states: Rc<RefCell<[Vec<InstrumentState>; NUM_INSTRUMENT_COLS]>> = Default::default();
 
+        #[cfg(feature = "desktop_native")]
+        let trace: Rc<TraceRecorder> = Default::default();
+        #[cfg(feature = "desktop_native")]
+        let (synth_set_sound_reg, synth_set_sound_reg_at, synth_set_wave_table) = {
+            let (trace_reg, trace_reg_at, trace_wave) = (trace.clone(), trace.clone(), trace.clone());
+            (
+                move |addr: i32, value: i32| {
+                    trace_reg.record_sound_reg(addr, value);
+                    synth_set_sound_reg(addr, value)
+                },
+                move |cycle_offset: i32, addr: i32, value: i32| {
+                    trace_reg_at.record_sound_reg(addr, value);
+                    synth_set_sound_reg_at(cycle_offset, addr, value)
+                },
+                move |table: &[u8]| {
+                    trace_wave.record_wave_table(table);
+                    synth_set_wave_table(table)
+                },
+            )
+        };
+
+        // Writes of frame function ticks after the first one are delayed to their fraction of the frame.
+        let tick_cycle_offset: Rc<Cell<u32>> = Default::default();
+        let (synth_set_sound_reg, synth_set_sound_reg_at) = {
+            let (offset_reg, offset_reg_at) = (tick_cycle_offset.clone(), tick_cycle_offset.clone());
+            let synth_set_sound_reg_at = Rc::new(synth_set_sound_reg_at);
+            let set_reg_at = synth_set_sound_reg_at.clone();
+            (
+                move |addr: i32, value: i32| match offset_reg.get() {
+                    0 => synth_set_sound_reg(addr, value),
+                    offset => (*set_reg_at)(offset as i32, addr, value),
+                },
+                move |cycle_offset: i32, addr: i32, value: i32| {
+                    (*synth_set_sound_reg_at)(offset_reg_at.get() as i32 + cycle_offset, addr, value)
+                },
+            )
+        };
+
         let sequencer_instrument_def_clone = sequencer_instrument_def.clone();
         let instrument_states_clone = instrument_states.clone();
         let set_instrument_at_column = move |cid: &CStr,
@@ -169,6 +234,7 @@ impl SynthScript {
             sequencer_instrument_def_clone.borrow_mut().ids[index] = id.into();
 
             state.frames_after_release = frames_after_release;
+            state.ticks_per_frame = 1;
             state.press_function = press;
             state.release_function = release;
             state.frame_function = frame;
@@ -240,6 +306,77 @@ impl SynthScript {
             }
         };
 
+        let instrument_states_clone = instrument_states.clone();
+        // Instruments set with set_instrument_at_column get only the first two params in their press function.
+        // Instruments that need more can set a press function taking all of them with this.
+        let set_instrument_press_with_params = move |instrument: i32, press: WasmIndirectFunction| {
+            log!(
+                "Setting press with params for instrument [{}]: {}",
+                instrument,
+                press.is_defined()
+            );
+            if instrument < 0 || instrument >= NUM_INSTRUMENTS as i32 {
+                elog!(
+                    "set_instrument_press_with_params: instrument must be 0 <= instrument < {}, got {}. Ignoring press.",
+                    NUM_INSTRUMENTS,
+                    instrument
+                );
+                return;
+            }
+
+            let mut states = instrument_states_clone.borrow_mut();
+            if let Some(state) = states.get_instrument(instrument as u8) {
+                state.press_with_params_function = press;
+            } else {
+                elog!(
+                    "set_instrument_press_with_params: instrument {} not found. Ignoring press.",
+                    instrument
+                );
+            }
+        };
+
+        let instrument_states_clone = instrument_states.clone();
+        // Lets instruments run their frame function more than once per frame for faster envelopes.
+        let set_instrument_ticks_per_frame = move |instrument: i32, ticks_per_frame: i32| {
+            log!(
+                "Setting ticks per frame for instrument [{}]: {}",
+                instrument,
+                ticks_per_frame
+            );
+            if instrument < 0 || instrument >= NUM_INSTRUMENTS as i32 {
+                elog!(
+                    "set_instrument_ticks_per_frame: instrument must be 0 <= instrument < {}, got {}. Ignoring ticks.",
+                    NUM_INSTRUMENTS,
+                    instrument
+                );
+                return;
+            }
+            if !(1..=MAX_TICKS_PER_FRAME).contains(&ticks_per_frame) {
+                elog!(
+                    "set_instrument_ticks_per_frame: ticks_per_frame must be 1 <= ticks_per_frame <= {}, got {}. Ignoring ticks.",
+                    MAX_TICKS_PER_FRAME,
+                    ticks_per_frame
+                );
+                return;
+            }
+
+            let mut states = instrument_states_clone.borrow_mut();
+            if let Some(state) = states.get_instrument(instrument as u8) {
+                state.ticks_per_frame = ticks_per_frame as u32;
+            } else {
+                elog!(
+                    "set_instrument_ticks_per_frame: instrument {} not found. Ignoring ticks.",
+                    instrument
+                );
+            }
+        };
+
+        let synth_set_sample: Rc<dyn Fn(i32, &[u8])> = Rc::new(synth_set_sample);
+        let set_sample = {
+            let synth_set_sample = synth_set_sample.clone();
+            move |sample: i32, data: &[u8]| synth_set_sample(sample, data)
+        };
+
         let functions: Vec<Box<dyn wasm::HostFunction>> = vec![
             Box::new(wasm::HostFunctionS::new("print", instrument_print)),
             Box::new(wasm::HostFunctionSIINNN::new(
@@ -247,8 +384,22 @@ impl SynthScript {
                 set_instrument_at_column,
             )),
             Box::new(wasm::HostFunctionIISIIIN::new("define_param", define_param)),
+            Box::new(wasm::HostFunctionIN::new(
+                "set_instrument_press_with_params",
+                set_instrument_press_with_params,
+            )),
+            Box::new(wasm::HostFunctionII::new(
+                "set_instrument_ticks_per_frame",
+                set_instrument_ticks_per_frame,
+            )),
             Box::new(wasm::HostFunctionII::new("gba_set_sound_reg", synth_set_sound_reg)),
+            Box::new(wasm::HostFunctionIII::new(
+                "gba_set_sound_reg_at",
+                synth_set_sound_reg_at,
+            )),
             Box::new(wasm::HostFunctionA::new("gba_set_wave_table", synth_set_wave_table)),
+            Box::new(wasm::HostFunctionIA::new("gba_set_sample", set_sample)),
+            Box::new(wasm::HostFunctionIII::new("gba_play_sample", synth_play_sample)),
         ];
 
         let runtime = Rc::new(WasmRuntime::new(functions).unwrap());
@@ -259,6 +410,10 @@ impl SynthScript {
             sequencer_instrument_def,
             instrument_states,
             apply_instrument_def_callback: Rc::new(apply_instrument_def),
+            tick_cycle_offset,
+            synth_set_sample,
+            #[cfg(feature = "desktop_native")]
+            trace,
         }
     }
 
@@ -266,6 +421,10 @@ impl SynthScript {
         for state_col in &mut *self.instrument_states.borrow_mut() {
             state_col.clear();
         }
+        // Samples are registered by the instruments while loading, free those of the previous ones.
+        for sample in 0..MAX_SAMPLES {
+            (self.synth_set_sample)(sample as i32, &[]);
+        }
         self.wasm_module_inst = None;
     }
 
@@ -285,6 +444,8 @@ impl SynthScript {
         };
         let callback = self.apply_instrument_def_callback.clone();
         let instrument_def = self.sequencer_instrument_def.clone();
+        #[cfg(feature = "desktop_native")]
+        self.trace.set_context(0, None, ScriptCallback::Start);
 
         let module = Rc::new(WasmModule::new(encoded, self.wasm_runtime.clone())?);
         self.wasm_module_inst = Some(WasmModuleInst::new(module, move || callback(instrument_def.take()))?);
@@ -348,25 +509,48 @@ impl SynthScript {
         Ok(instruments_path)
     }
 
-    pub fn press_instrument_note(&mut self, frame_number: usize, instrument: u8, note: u8, param0: i8, param1: i8) {
+    pub fn press_instrument_note(
+        &mut self,
+        frame_number: usize,
+        instrument: u8,
+        note: u8,
+        params: [i8; NUM_INSTRUMENT_PARAMS],
+    ) {
         let mut states = self.instrument_states.borrow_mut();
         if let Some(state) = states.get_instrument(instrument) {
+            #[cfg(feature = "desktop_native")]
+            self.trace
+                .set_context(frame_number, Some(instrument), ScriptCallback::Press);
             state.pressed_note = Some(PressedNote {
                 note,
                 pressed_frame: frame_number,
                 extended_frames: None,
             });
-            if state.press_function.is_defined() {
-                if let Some(wasm_module_inst) = &self.wasm_module_inst {
-                    if let Err(e) = wasm_module_inst.call_indirect_iiii(
+            if let Some(wasm_module_inst) = &self.wasm_module_inst {
+                let [p0, p1, p2, p3] = params.map(|p| p as i32);
+                let result = if state.press_with_params_function.is_defined() {
+                    Some(wasm_module_inst.call_indirect_iiiiii(
+                        &state.press_with_params_function,
+                        Self::note_to_freq(note),
+                        note as i32,
+                        p0,
+                        p1,
+                        p2,
+                        p3,
+                    ))
+                } else if state.press_function.is_defined() {
+                    Some(wasm_module_inst.call_indirect_iiii(
                         &state.press_function,
                         Self::note_to_freq(note),
                         note as i32,
-                        param0 as i32,
-                        param1 as i32,
-                    ) {
-                        elog!("press: {:?}", e);
-                    }
+                        p0,
+                        p1,
+                    ))
+                } else {
+                    None
+                };
+                if let Some(Err(e)) = result {
+                    elog!("press: {:?}", e);
                 }
             }
         }
@@ -381,13 +565,16 @@ impl SynthScript {
                 extended_frames,
             }) = &mut state.pressed_note
             {
+                #[cfg(feature = "desktop_native")]
+                self.trace
+                    .set_context(frame_number, Some(instrument), ScriptCallback::Release);
                 if state.release_function.is_defined() {
                     if let Some(wasm_module_inst) = &self.wasm_module_inst {
                         if let Err(e) = wasm_module_inst.call_indirect_iii(
                             &state.release_function,
                             Self::note_to_freq(*note),
                             *note as i32,
-                            (frame_number - *pressed_frame) as i32,
+                            ((frame_number - *pressed_frame) as u32 * state.ticks_per_frame) as i32,
                         ) {
                             elog!("release: {:?}", e);
                         }
@@ -408,9 +595,12 @@ impl SynthScript {
         }
     }
 
-    pub fn set_instrument_param(&mut self, instrument: u8, param_num: u8, val: i8) {
+    pub fn set_instrument_param(&mut self, frame_number: usize, instrument: u8, param_num: u8, val: i8) {
         let mut states = self.instrument_states.borrow_mut();
         if let Some(state) = states.get_instrument(instrument) {
+            #[cfg(feature = "desktop_native")]
+            self.trace
+                .set_context(frame_number, Some(instrument), ScriptCallback::SetParam);
             let function = &state.set_param_functions[param_num as usize];
             if function.is_defined() {
                 if let Err(e) = self
@@ -440,9 +630,11 @@ impl SynthScript {
         }
     }
 
+    // The column and row are only used to identify the instrument in traces.
+    #[cfg_attr(not(feature = "desktop_native"), allow(unused_variables))]
     pub fn advance_frame(&mut self, frame_number: usize) {
-        for state_col in &mut *self.instrument_states.borrow_mut() {
-            for state in state_col {
+        for (col, state_col) in self.instrument_states.borrow_mut().iter_mut().enumerate() {
+            for (row, state) in state_col.iter_mut().enumerate() {
                 // Only run the frame function on instruments currently pressed.
                 if let Some(PressedNote {
                     note,
@@ -451,15 +643,25 @@ impl SynthScript {
                 }) = &mut state.pressed_note
                 {
                     if state.frame_function.is_defined() {
+                        #[cfg(feature = "desktop_native")]
+                        self.trace
+                            .set_context(frame_number, Some(((row << 2) + col) as u8), ScriptCallback::Frame);
                         if let Some(wasm_module_inst) = &self.wasm_module_inst {
-                            if let Err(e) = wasm_module_inst.call_indirect_iii(
-                                &state.frame_function,
-                                Self::note_to_freq(*note),
-                                *note as i32,
-                                (frame_number - *pressed_frame) as i32,
-                            ) {
-                                elog!("frame: {:?}", e);
+                            // The frame function runs for every tick at once, but the register writes
+                            // of each tick are applied at their fraction of the frame.
+                            let ticks = state.ticks_per_frame;
+                            for tick in 0..ticks {
+                                self.tick_cycle_offset.set(tick * FRAME_CYCLES / ticks);
+                                if let Err(e) = wasm_module_inst.call_indirect_iii(
+                                    &state.frame_function,
+                                    Self::note_to_freq(*note),
+                                    *note as i32,
+                                    ((frame_number - *pressed_frame) as u32 * ticks + tick) as i32,
+                                ) {
+                                    elog!("frame: {:?}", e);
+                                }
                             }
+                            self.tick_cycle_offset.set(0);
                         }
                         if let Some(remaining) = extended_frames {
                             *remaining -= 1;
@@ -475,6 +677,18 @@ impl SynthScript {
         }
     }
 
+    /// Starts recording the sound register writes of instruments, discarding any previous trace.
+    #[cfg(feature = "desktop_native")]
+    pub fn start_trace(&self) {
+        self.trace.start();
+    }
+
+    /// Stops recording and returns the trace.
+    #[cfg(feature = "desktop_native")]
+    pub fn take_trace(&self) -> Vec<trace::TraceEntry> {
+        self.trace.take()
+    }
+
     fn note_to_freq(note: u8) -> i32 {
         NOTE_FREQUENCIES[note as usize] as i32
     }
@@ -0,0 +1,293 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+//! Keeps track of the sound registers that instruments write to, to help debugging them.
+
+use crate::sound_engine::SoundEngine;
+use crate::sound_renderer::offline::new_headless_engine;
+
+use serde::Serialize;
+
+use core::cell::{Cell, RefCell};
+use std::error::Error;
+use std::io::Write;
+
+/// The instrument script function that was running when a register was written.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScriptCallback {
+    /// The instruments' main function, while loading.
+    Start,
+    Press,
+    Release,
+    Frame,
+    SetParam,
+}
+
+impl ScriptCallback {
+    fn name(self) -> &'static str {
+        match self {
+            ScriptCallback::Start => "start",
+            ScriptCallback::Press => "press",
+            ScriptCallback::Release => "release",
+            ScriptCallback::Frame => "frame",
+            ScriptCallback::SetParam => "set_param",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CallContext {
+    frame: usize,
+    instrument: Option<u8>,
+    callback: ScriptCallback,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub frame: usize,
+    /// The synth index of the instrument, None while loading the instruments.
+    pub instrument: Option<u8>,
+    pub callback: ScriptCallback,
+    pub gba_addr: u32,
+    /// The 16 bits written to gba_set_sound_reg, or the bytes given to gba_set_wave_table.
+    pub value: Vec<u8>,
+}
+
+/// Records gba_set_sound_reg and gba_set_wave_table calls along with the script function that made them.
+/// gba_set_sound_reg_at calls are recorded on the frame that queued them, without their cycle offset.
+pub struct TraceRecorder {
+    context: Cell<CallContext>,
+    entries: RefCell<Option<Vec<TraceEntry>>>,
+}
+
+impl Default for TraceRecorder {
+    fn default() -> Self {
+        TraceRecorder {
+            context: Cell::new(CallContext {
+                frame: 0,
+                instrument: None,
+                callback: ScriptCallback::Start,
+            }),
+            entries: RefCell::new(None),
+        }
+    }
+}
+
+impl TraceRecorder {
+    pub fn set_context(&self, frame: usize, instrument: Option<u8>, callback: ScriptCallback) {
+        self.context.set(CallContext {
+            frame,
+            instrument,
+            callback,
+        });
+    }
+
+    pub fn start(&self) {
+        *self.entries.borrow_mut() = Some(Vec::new());
+    }
+
+    pub fn take(&self) -> Vec<TraceEntry> {
+        self.entries.borrow_mut().take().unwrap_or_default()
+    }
+
+    fn push(&self, gba_addr: u32, value: Vec<u8>) {
+        if let Some(entries) = self.entries.borrow_mut().as_mut() {
+            let CallContext {
+                frame,
+                instrument,
+                callback,
+            } = self.context.get();
+            entries.push(TraceEntry {
+                frame,
+                instrument,
+                callback,
+                gba_addr,
+                value,
+            });
+        }
+    }
+
+    pub fn record_sound_reg(&self, addr: i32, value: i32) {
+        self.push(addr as u32, (value as u16).to_le_bytes().to_vec());
+    }
+
+    pub fn record_wave_table(&self, table: &[u8]) {
+        self.push(WAVE_RAM_ADDR, table.to_vec());
+    }
+}
+
+const WAVE_RAM_ADDR: u32 = 0x4000090;
+
+/// Returns the name that GBA documentation uses for a sound register.
+pub fn gba_reg_name(addr: u32) -> &'static str {
+    match addr {
+        0x4000060 => "SOUND1CNT_L",
+        0x4000062 => "SOUND1CNT_H",
+        0x4000064 => "SOUND1CNT_X",
+        0x4000068 => "SOUND2CNT_L",
+        0x400006C => "SOUND2CNT_H",
+        0x4000070 => "SOUND3CNT_L",
+        0x4000072 => "SOUND3CNT_H",
+        0x4000074 => "SOUND3CNT_X",
+        0x4000078 => "SOUND4CNT_L",
+        0x400007C => "SOUND4CNT_H",
+        0x4000080 => "SOUNDCNT_L",
+        0x4000082 => "SOUNDCNT_H",
+        0x4000084 => "SOUNDCNT_X",
+        0x4000088 => "SOUNDBIAS",
+        WAVE_RAM_ADDR..=0x400009F => "WAVE_RAM",
+        _ => "UNKNOWN",
+    }
+}
+
+#[derive(Serialize)]
+struct ExportedEntry<'a> {
+    frame: usize,
+    instrument: &'a str,
+    callback: ScriptCallback,
+    register: &'static str,
+    address: String,
+    value: String,
+}
+
+fn to_exported<'a>(entry: &TraceEntry, instrument_ids: &'a [String]) -> ExportedEntry<'a> {
+    ExportedEntry {
+        frame: entry.frame,
+        instrument: entry
+            .instrument
+            .and_then(|i| instrument_ids.get(i as usize))
+            .map_or("", |id| id.as_str()),
+        callback: entry.callback,
+        register: gba_reg_name(entry.gba_addr),
+        address: format!("0x{:07X}", entry.gba_addr),
+        value: match entry.value[..] {
+            [lsb, msb] => format!("0x{:04X}", u16::from_le_bytes([lsb, msb])),
+            ref table => table.iter().map(|b| format!("{:02X}", b)).collect(),
+        },
+    }
+}
+
+/// Writes the trace as a JSON array, resolving instruments to the IDs at their synth index.
+pub fn write_json<W: Write>(w: &mut W, trace: &[TraceEntry], instrument_ids: &[String]) -> Result<(), Box<dyn Error>> {
+    let exported: Vec<ExportedEntry> = trace.iter().map(|e| to_exported(e, instrument_ids)).collect();
+    serde_json::to_writer_pretty(&mut *w, &exported)?;
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Writes the trace as CSV with a header line, resolving instruments to the IDs at their synth index.
+pub fn write_csv<W: Write>(w: &mut W, trace: &[TraceEntry], instrument_ids: &[String]) -> std::io::Result<()> {
+    writeln!(w, "frame,instrument,callback,register,address,value")?;
+    for entry in trace {
+        let e = to_exported(entry, instrument_ids);
+        // Instrument IDs are the only user-provided strings, quote them in case they contain a comma.
+        writeln!(
+            w,
+            "{},\"{}\",{},{},{},{}",
+            e.frame,
+            e.instrument.replace('"', "\"\""),
+            e.callback.name(),
+            e.register,
+            e.address,
+            e.value
+        )?;
+    }
+    Ok(())
+}
+
+fn instrument_ids(engine: &SoundEngine) -> Vec<String> {
+    engine
+        .sequencer
+        .borrow()
+        .synth_instrument_ids()
+        .iter()
+        .map(|id| id.to_string())
+        .collect()
+}
+
+/// Plays each song pattern once in song mode and returns the trace with the instrument IDs.
+pub fn trace_song<F>(load_project: F) -> Result<(Vec<TraceEntry>, Vec<String>), Box<dyn Error>>
+where
+    F: FnOnce(&mut SoundEngine) -> Result<(), Box<dyn Error>>,
+{
+    let mut engine = new_headless_engine(44100);
+    engine.start_trace();
+    load_project(&mut engine)?;
+
+    let num_frames = engine.sequencer.borrow().song_length_in_frames();
+    engine.sequencer.borrow_mut().activate_step(0);
+    engine.set_playing(true, true);
+    run_frames(&mut engine, num_frames);
+    engine.stop_and_release_instruments();
+
+    Ok((engine.take_trace(), instrument_ids(&engine)))
+}
+
+/// Presses a note on a single instrument, releases it after `press_frames` and keeps running
+/// its frame function for `release_frames`.
+pub fn trace_audition<F>(
+    load_project: F,
+    instrument_id: &str,
+    note: u8,
+    press_frames: usize,
+    release_frames: usize,
+) -> Result<(Vec<TraceEntry>, Vec<String>), Box<dyn Error>>
+where
+    F: FnOnce(&mut SoundEngine) -> Result<(), Box<dyn Error>>,
+{
+    let mut engine = new_headless_engine(44100);
+    engine.start_trace();
+    load_project(&mut engine)?;
+
+    let ids = instrument_ids(&engine);
+    let instrument = ids
+        .iter()
+        .position(|id| id == instrument_id)
+        .ok_or_else(|| format!("Unknown instrument id {}", instrument_id))?;
+    engine.display_instrument(instrument as u8);
+    engine.press_note(note);
+    run_frames(&mut engine, press_frames);
+    engine.release_note(note);
+    run_frames(&mut engine, release_frames);
+
+    Ok((engine.take_trace(), ids))
+}
+
+fn run_frames(engine: &mut SoundEngine, num_frames: usize) {
+    let output_data = engine.synth.output_data();
+    for _ in 0..num_frames {
+        engine.advance_frame();
+        // Only the register writes are needed.
+        output_data.lock().unwrap().buffer.clear();
+    }
+}
+
+#[test]
+fn exported_entries() {
+    let recorder = TraceRecorder::default();
+    recorder.record_sound_reg(0x4000062, 0xf080);
+    recorder.start();
+    recorder.set_context(3, Some(1), ScriptCallback::Press);
+    recorder.record_sound_reg(0x4000062, 0xf080);
+    recorder.set_context(4, Some(1), ScriptCallback::Frame);
+    recorder.record_wave_table(&[0x01, 0x23, 0xab]);
+    let trace = recorder.take();
+    assert_eq!(trace.len(), 2);
+
+    let ids = vec!["lead".to_string(), "bass, low".to_string()];
+    let mut csv = Vec::new();
+    write_csv(&mut csv, &trace, &ids).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "frame,instrument,callback,register,address,value\n\
+         3,\"bass, low\",press,SOUND1CNT_H,0x4000062,0xF080\n\
+         4,\"bass, low\",frame,WAVE_RAM,0x4000090,0123AB\n"
+    );
+
+    let mut json = Vec::new();
+    write_json(&mut json, &trace, &ids).unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(parsed[0]["callback"], "press");
+    assert_eq!(parsed[1]["register"], "WAVE_RAM");
+}
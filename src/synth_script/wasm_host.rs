@@ -166,6 +166,62 @@ impl<F: FnMut(i32, i32)> HostFunction for HostFunctionII<F> {
     }
 }
 
+pub struct HostFunctionIII<F> {
+    closure: F,
+    name: CString,
+}
+impl<F> HostFunctionIII<F> {
+    pub fn new(name: &str, closure: F) -> HostFunctionIII<F> {
+        HostFunctionIII {
+            closure,
+            name: CString::new(name).unwrap(),
+        }
+    }
+}
+const III_SIG: &str = "(iii)\0";
+unsafe extern "C" fn trampoline_iii_<F: FnMut(i32, i32, i32)>(exec_env: wasm_exec_env_t, v1: i32, v2: i32, v3: i32) {
+    let f = &mut *(wasm_runtime_get_function_attachment(exec_env) as *mut F);
+    f(v1, v2, v3)
+}
+impl<F: FnMut(i32, i32, i32)> HostFunction for HostFunctionIII<F> {
+    fn to_native_symbol(&mut self) -> NativeSymbol {
+        NativeSymbol {
+            symbol: self.name.as_ptr(),
+            func_ptr: trampoline_iii_::<F> as *mut c_void,
+            signature: III_SIG.as_ptr() as *const c_char,
+            attachment: &mut self.closure as *mut _ as *mut c_void,
+        }
+    }
+}
+
+pub struct HostFunctionIN<F> {
+    closure: F,
+    name: CString,
+}
+impl<F> HostFunctionIN<F> {
+    pub fn new(name: &str, closure: F) -> HostFunctionIN<F> {
+        HostFunctionIN {
+            closure,
+            name: CString::new(name).unwrap(),
+        }
+    }
+}
+const IN_SIG: &str = "(ii)\0";
+unsafe extern "C" fn trampoline_in_<F: FnMut(i32, WasmIndirectFunction)>(exec_env: wasm_exec_env_t, v1: i32, v2: u32) {
+    let f = &mut *(wasm_runtime_get_function_attachment(exec_env) as *mut F);
+    f(v1, WasmIndirectFunction::new(v2))
+}
+impl<F: FnMut(i32, WasmIndirectFunction)> HostFunction for HostFunctionIN<F> {
+    fn to_native_symbol(&mut self) -> NativeSymbol {
+        NativeSymbol {
+            symbol: self.name.as_ptr(),
+            func_ptr: trampoline_in_::<F> as *mut c_void,
+            signature: IN_SIG.as_ptr() as *const c_char,
+            attachment: &mut self.closure as *mut _ as *mut c_void,
+        }
+    }
+}
+
 pub struct HostFunctionA<F> {
     closure: F,
     name: CString,
@@ -194,6 +250,34 @@ impl<F: FnMut(&[u8])> HostFunction for HostFunctionA<F> {
     }
 }
 
+pub struct HostFunctionIA<F> {
+    closure: F,
+    name: CString,
+}
+impl<F> HostFunctionIA<F> {
+    pub fn new(name: &str, closure: F) -> HostFunctionIA<F> {
+        HostFunctionIA {
+            closure,
+            name: CString::new(name).unwrap(),
+        }
+    }
+}
+const IA_SIG: &str = "(i*~)\0";
+unsafe extern "C" fn trampoline_ia_<F: FnMut(i32, &[u8])>(exec_env: wasm_exec_env_t, v1: i32, v2: *const u8, v2l: i32) {
+    let f = &mut *(wasm_runtime_get_function_attachment(exec_env) as *mut F);
+    f(v1, core::slice::from_raw_parts(v2, v2l as usize))
+}
+impl<F: FnMut(i32, &[u8])> HostFunction for HostFunctionIA<F> {
+    fn to_native_symbol(&mut self) -> NativeSymbol {
+        NativeSymbol {
+            symbol: self.name.as_ptr(),
+            func_ptr: trampoline_ia_::<F> as *mut c_void,
+            signature: IA_SIG.as_ptr() as *const c_char,
+            attachment: &mut self.closure as *mut _ as *mut c_void,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Default)]
 pub struct WasmIndirectFunction {
     table_index: u32,
@@ -405,6 +489,20 @@ impl WasmModuleInst {
         self.call_indirect_argv(function, argv)
     }
 
+    pub fn call_indirect_iiiiii(
+        &self,
+        function: &WasmIndirectFunction,
+        a1: i32,
+        a2: i32,
+        a3: i32,
+        a4: i32,
+        a5: i32,
+        a6: i32,
+    ) -> Result<(), String> {
+        let argv: [u32; 6] = [a1 as u32, a2 as u32, a3 as u32, a4 as u32, a5 as u32, a6 as u32];
+        self.call_indirect_argv(function, argv)
+    }
+
     fn call_argv<const ARGC: usize>(
         &self,
         function: wasm_function_inst_t,
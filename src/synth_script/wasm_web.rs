@@ -217,6 +217,63 @@ impl HostFunction for HostFunctionII {
     }
 }
 
+pub struct HostFunctionIII {
+    closure: Option<Closure<dyn FnMut(i32, i32, i32)>>,
+    name: String,
+}
+impl HostFunctionIII {
+    pub fn new<F>(name: &str, mut closure: F) -> HostFunctionIII
+    where
+        F: FnMut(i32, i32, i32) + 'static,
+    {
+        let native_closure = Closure::new(move |v1: i32, v2: i32, v3: i32| closure(v1, v2, v3));
+
+        HostFunctionIII {
+            closure: Some(native_closure),
+            name: name.to_owned(),
+        }
+    }
+}
+impl HostFunction for HostFunctionIII {
+    fn move_into_import(&mut self, env: &Object) -> () {
+        Reflect::set(
+            &env,
+            &mem::take(&mut self.name).into(),
+            &self.closure.take().unwrap().into_js_value(),
+        )
+        .unwrap();
+    }
+}
+
+pub struct HostFunctionIN {
+    closure: Option<Closure<dyn FnMut(i32, u32)>>,
+    name: String,
+}
+impl HostFunctionIN {
+    pub fn new<F>(name: &str, mut closure: F) -> HostFunctionIN
+    where
+        F: FnMut(i32, WasmIndirectFunction) + 'static,
+    {
+        let native_closure =
+            Closure::new(move |v1: i32, v2: u32| closure(v1, WasmModuleInst::lookup_indirect_function(v2)));
+
+        HostFunctionIN {
+            closure: Some(native_closure),
+            name: name.to_owned(),
+        }
+    }
+}
+impl HostFunction for HostFunctionIN {
+    fn move_into_import(&mut self, env: &Object) -> () {
+        Reflect::set(
+            &env,
+            &mem::take(&mut self.name).into(),
+            &self.closure.take().unwrap().into_js_value(),
+        )
+        .unwrap();
+    }
+}
+
 pub struct HostFunctionA {
     closure: Option<Closure<dyn FnMut(*const u8, i32)>>,
     name: String,
@@ -261,6 +318,50 @@ impl HostFunction for HostFunctionA {
     }
 }
 
+pub struct HostFunctionIA {
+    closure: Option<Closure<dyn FnMut(i32, *const u8, i32)>>,
+    name: String,
+}
+impl HostFunctionIA {
+    pub fn new<F>(name: &str, mut closure: F) -> HostFunctionIA
+    where
+        F: FnMut(i32, &[u8]) + 'static,
+    {
+        let native_closure = Closure::new(move |v1: i32, v2: *const u8, v2l: i32| {
+            CURRENT_INSTANCE.with(|current_instance| {
+                let maybe_instance = current_instance.borrow();
+                let exports = maybe_instance
+                    .as_ref()
+                    .expect("CURRENT_INSTANCE hasn't been initialized yet, async race condition?")
+                    .exports();
+                let mem = Reflect::get(exports.as_ref(), &"memory".into())
+                    .unwrap()
+                    .dyn_into::<WebAssembly::Memory>()
+                    .unwrap();
+                let typebuf = js_sys::Uint8Array::new(&mem.buffer());
+                let vec = typebuf.slice(v2 as u32, v2 as u32 + v2l as u32).to_vec();
+
+                closure(v1, &vec);
+            });
+        });
+
+        HostFunctionIA {
+            closure: Some(native_closure),
+            name: name.to_owned(),
+        }
+    }
+}
+impl HostFunction for HostFunctionIA {
+    fn move_into_import(&mut self, env: &Object) -> () {
+        Reflect::set(
+            &env,
+            &mem::take(&mut self.name).into(),
+            &self.closure.take().unwrap().into_js_value(),
+        )
+        .unwrap();
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct WasmIndirectFunction {
     function: Option<Function>,
@@ -400,4 +501,26 @@ impl WasmModuleInst {
             .apply(&JsValue::undefined(), &array)?;
         Ok(())
     }
+
+    pub fn call_indirect_iiiiii(
+        &self,
+        function: &WasmIndirectFunction,
+        a1: i32,
+        a2: i32,
+        a3: i32,
+        a4: i32,
+        a5: i32,
+        a6: i32,
+    ) -> Result<(), JsValue> {
+        let array = Array::new();
+        for a in [a1, a2, a3, a4, a5, a6] {
+            array.push(&a.into());
+        }
+        function
+            .function
+            .as_ref()
+            .expect("Attempted to call an undefined function")
+            .apply(&JsValue::undefined(), &array)?;
+        Ok(())
+    }
 }
@@ -3,6 +3,7 @@
 
 use core::cell::RefCell;
 
+use crate::sound_engine::NUM_INSTRUMENT_PARAMS;
 use crate::sound_renderer::SoundRendererTrait;
 
 use alloc::rc::Rc;
@@ -14,6 +15,33 @@ use slint::Model;
 // By putting this here, every generated Slint type is imported into crate::ui.
 slint::include_modules!();
 
+mod observer;
+pub use observer::SlintObserver;
+
+impl From<&crate::sequencer::SongSettings> for SongSettings {
+    fn from(settings: &crate::sequencer::SongSettings) -> Self {
+        SongSettings {
+            frames_per_step: settings.frames_per_step as i32,
+            groove: settings.groove.as_str().into(),
+            bpm: settings.bpm,
+            loop_start: settings.loop_start as i32,
+            stop_at_end: settings.stop_at_end,
+        }
+    }
+}
+
+impl From<&SongSettings> for crate::sequencer::SongSettings {
+    fn from(settings: &SongSettings) -> Self {
+        crate::sequencer::SongSettings {
+            frames_per_step: settings.frames_per_step as u32,
+            groove: settings.groove.as_str().into(),
+            bpm: settings.bpm,
+            loop_start: settings.loop_start.max(0) as usize,
+            stop_at_end: settings.stop_at_end,
+        }
+    }
+}
+
 pub fn set_window_handlers<SR: SoundRendererTrait + 'static>(window: &MainWindow, _sound_renderer: Rc<RefCell<SR>>) {
     #[cfg(feature = "gba")]
     {
@@ -211,6 +239,13 @@ pub fn set_global_engine_handlers<SR: SoundRendererTrait + 'static>(
             .invoke_on_sound_engine(move |se| se.sequencer.borrow_mut().cycle_step_release(step as usize, forward));
     });
 
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_cycle_step_delay(move |step, forward| {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(move |se| se.sequencer.borrow_mut().cycle_step_delay(step as usize, forward));
+    });
+
     let cloned_sound_renderer = sound_renderer.clone();
     global_engine.on_toggle_step_release(move |step| {
         cloned_sound_renderer
@@ -303,6 +338,13 @@ pub fn set_global_engine_handlers<SR: SoundRendererTrait + 'static>(
             .invoke_on_sound_engine(move |se| se.set_playing(playing, song_mode));
     });
 
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_pin_selection_to_active_changed(move |pinned| {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(move |se| se.sequencer.borrow_mut().set_pin_selection_to_active(pinned));
+    });
+
     #[cfg(feature = "desktop")]
     {
         let cloned_sound_renderer = sound_renderer.clone();
@@ -345,6 +387,50 @@ pub fn set_global_engine_handlers<SR: SoundRendererTrait + 'static>(
             .invoke_on_sound_engine(|se| se.sequencer.borrow_mut().clone_displayed_song_pattern());
     });
 
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_cycle_pattern_num_steps(move |forward| {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(move |se| se.sequencer.borrow_mut().cycle_displayed_pattern_num_steps(forward));
+    });
+
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_cycle_song_pattern_transpose(move |forward| {
+        cloned_sound_renderer.borrow_mut().invoke_on_sound_engine(move |se| {
+            se.sequencer
+                .borrow_mut()
+                .cycle_displayed_song_pattern_transpose(forward)
+        });
+    });
+
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_undo(move || {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(|se| se.sequencer.borrow_mut().undo());
+    });
+
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_redo(move || {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(|se| se.sequencer.borrow_mut().redo());
+    });
+
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_select_bank_song(move |bank_song| {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(move |se| se.select_bank_song(bank_song as usize));
+    });
+
+    let cloned_sound_renderer = sound_renderer.clone();
+    global_engine.on_cycle_bank_song(move |forward| {
+        cloned_sound_renderer
+            .borrow_mut()
+            .invoke_on_sound_engine(move |se| se.cycle_bank_song(forward));
+    });
+
     let cloned_sound_renderer = sound_renderer.clone();
     global_engine.on_activate_song_pattern(move |song_pattern_idx| {
         cloned_sound_renderer.borrow_mut().invoke_on_sound_engine(move |se| {
@@ -497,23 +583,39 @@ pub fn set_global_ui_handlers(window: &MainWindow) {
     global_ui.on_cycle_selected_column(move |forward| {
         let handle = weak.upgrade().unwrap();
         let this = handle.global::<GlobalUI>();
-        let engine = handle.global::<GlobalEngine>();
 
         let selected_column = this.get_selected_column();
+        let selected_param = this.invoke_selected_param();
+        let param_defined = |param_num: &i32| this.invoke_param_def(*param_num).defined;
+        let select_param = |param_num: i32| {
+            // Scroll the param columns to the pair containing that param.
+            this.set_first_displayed_param(param_num / 2 * 2);
+            this.invoke_select_column(param_num % 2);
+        };
         if forward {
             match selected_column {
-                0 if engine.get_instrument_param_1().defined => this.invoke_select_column(1),
-                0..=1 => this.invoke_select_column(2),
-                // Don't enter the release column while in selection mode.
+                0..=1 => match (selected_param + 1..NUM_INSTRUMENT_PARAMS as i32).find(param_defined) {
+                    Some(param_num) => select_param(param_num),
+                    None => this.invoke_select_column(2),
+                },
+                // Don't enter the release and delay columns while in selection mode.
                 2 if !this.invoke_in_selection_mode() => this.invoke_select_column(3),
+                3 => this.invoke_select_column(4),
                 _ => (),
             };
         } else {
             match selected_column {
-                3 => this.invoke_select_column(2),
-                2 if engine.get_instrument_param_1().defined => this.invoke_select_column(1),
-                _ if engine.get_instrument_param_0().defined => this.invoke_select_column(0),
-                _ => (),
+                3..=4 => this.invoke_select_column(selected_column - 1),
+                _ => {
+                    let params_end = if selected_column == 2 {
+                        NUM_INSTRUMENT_PARAMS as i32
+                    } else {
+                        selected_param
+                    };
+                    if let Some(param_num) = (0..params_end).rev().find(param_defined) {
+                        select_param(param_num);
+                    }
+                }
             };
         }
     });
@@ -535,6 +637,7 @@ pub fn set_global_settings_handlers<SR: SoundRendererTrait + 'static>(
     let cloned_sound_renderer = sound_renderer.clone();
     global_settings.on_song_settings_changed(move |settings| {
         log!("SET {:?}", settings);
+        let settings = crate::sequencer::SongSettings::from(&settings);
         cloned_sound_renderer
             .borrow_mut()
             .invoke_on_sound_engine(move |se| se.apply_song_settings(&settings));
@@ -0,0 +1,624 @@
+// Copyright © 2023 Jocelyn Turcotte <turcotte.j@gmail.com>
+// SPDX-License-Identifier: MIT
+
+use crate::observer::EngineObserver;
+use crate::sequencer::SongSettings;
+use crate::sequencer::{Instrument, InstrumentStep, ReleasePos};
+use crate::sound_engine::NUM_INSTRUMENT_PARAMS;
+#[cfg(feature = "desktop")]
+use crate::ui::{ChannelActiveNote, ChannelTraceNote};
+use crate::ui::{GlobalEngine, GlobalSettings, GlobalUI, ParamData, SongPatternData, StepData};
+#[cfg(feature = "desktop")]
+use crate::utils::MidiNote;
+use crate::utils::WeakWindowWrapper;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use slint::{Global, Model, SharedString, VecModel};
+
+/// Forwards engine changes to the Slint models and properties of the MainWindow.
+pub struct SlintObserver {
+    main_window: WeakWindowWrapper,
+}
+
+impl SlintObserver {
+    pub fn new(main_window: WeakWindowWrapper) -> SlintObserver {
+        SlintObserver { main_window }
+    }
+}
+
+fn to_ui_release_pos(release_pos: ReleasePos) -> crate::ui::ReleasePos {
+    match release_pos {
+        ReleasePos::NotReleased => crate::ui::ReleasePos::NotReleased,
+        ReleasePos::Half => crate::ui::ReleasePos::Half,
+        ReleasePos::Full => crate::ui::ReleasePos::Full,
+    }
+}
+
+fn set_step_data_params(row_data: &mut StepData, params: [Option<i8>; NUM_INSTRUMENT_PARAMS]) {
+    let [(p0_set, p0_val), (p1_set, p1_val), (p2_set, p2_val), (p3_set, p3_val)] =
+        params.map(|p| (p.is_some(), p.unwrap_or(0) as i32));
+    row_data.param0_set = p0_set;
+    row_data.param0_val = p0_val;
+    row_data.param1_set = p1_set;
+    row_data.param1_val = p1_val;
+    row_data.param2_set = p2_set;
+    row_data.param2_val = p2_val;
+    row_data.param3_set = p3_set;
+    row_data.param3_val = p3_val;
+}
+
+impl EngineObserver for SlintObserver {
+    fn song_pattern_activated(&self, song_pattern: usize) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                GlobalEngine::get(&handle).set_sequencer_song_pattern_active(song_pattern as i32);
+            })
+            .unwrap();
+    }
+
+    fn step_activated(&self, active_step: i32) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                GlobalEngine::get(&handle).set_sequencer_step_active(active_step);
+                GlobalUI::get(&handle).invoke_update_selected_step();
+            })
+            .unwrap();
+    }
+
+    fn song_pattern_displayed(&self, song_pattern: usize, prev_selected: usize) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                GlobalEngine::get(&handle).set_sequencer_song_pattern_selected(song_pattern as i32);
+                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+                if let Some(mut row_data) = model.row_data(prev_selected) {
+                    row_data.selected = false;
+                    model.set_row_data(prev_selected, row_data);
+                }
+                let mut row_data = model.row_data(song_pattern).unwrap();
+                row_data.selected = true;
+                model.set_row_data(song_pattern, row_data);
+            })
+            .unwrap();
+    }
+
+    fn instrument_displayed(&self, instrument: u8, param_names: [Option<&str>; NUM_INSTRUMENT_PARAMS]) {
+        let [param_0, param_1, param_2, param_3] = param_names.map(|maybe_name| {
+            maybe_name
+                .map(|name| ParamData {
+                    name: name.into(),
+                    defined: true,
+                })
+                .unwrap_or_default()
+        });
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let engine = &GlobalEngine::get(&handle);
+                engine.set_displayed_instrument(instrument as i32);
+                engine.set_instrument_param_0(param_0);
+                engine.set_instrument_param_1(param_1);
+                engine.set_instrument_param_2(param_2);
+                engine.set_instrument_param_3(param_3);
+
+                GlobalUI::get(&handle).invoke_adjust_user_selected_column();
+            })
+            .unwrap();
+    }
+
+    fn instrument_selection_unpinned(&self) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                GlobalUI::get(&handle).invoke_disable_pin_selection_to_active_if_playing();
+            })
+            .unwrap();
+    }
+
+    fn instrument_muted(&self, instrument: u8, muted: bool) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_instruments();
+                let mut row_data = model.row_data(instrument as usize).unwrap();
+                row_data.muted = muted;
+                model.set_row_data(instrument as usize, row_data);
+            })
+            .unwrap();
+    }
+
+    fn steps_changed(
+        &self,
+        steps: Option<&[InstrumentStep]>,
+        num_steps: usize,
+        active_step: i32,
+        pattern_instruments: &[Instrument],
+        instruments_to_skip: usize,
+        instruments_len: usize,
+    ) {
+        let maybe_steps = steps.map(|ss| ss.to_vec());
+        // Don't clone the pattern instruments if the closure will be handled synchronously.
+        #[cfg(not(feature = "std"))]
+        let instruments = pattern_instruments;
+        #[cfg(feature = "std")]
+        let instruments = pattern_instruments.to_vec();
+
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_sequencer_steps();
+                if model.row_count() != num_steps {
+                    let vec_model = model.as_any().downcast_ref::<VecModel<StepData>>().unwrap();
+                    vec_model.set_vec(vec![StepData::default(); num_steps]);
+                }
+                for i in 0..num_steps {
+                    let step = maybe_steps.as_ref().map_or_else(InstrumentStep::default, |ss| ss[i]);
+                    let mut row_data = model.row_data(i).unwrap();
+                    row_data.press = step.press();
+                    row_data.release_pos = to_ui_release_pos(step.release_pos());
+                    row_data.note = step.press_note().unwrap_or(0) as i32;
+                    row_data.delay = step.delay() as i32;
+                    set_step_data_params(&mut row_data, step.params());
+
+                    model.set_row_data(i, row_data);
+                }
+
+                GlobalEngine::get(&handle).set_sequencer_step_active(active_step);
+
+                // Keep the selection within the displayed pattern if it's shorter than the previous one.
+                let ui = GlobalUI::get(&handle);
+                let last_step = num_steps as i32 - 1;
+                if ui.get_selected_step() > last_step {
+                    ui.set_selected_step(last_step);
+                }
+                if ui.get_selected_step_range_first() > last_step {
+                    ui.set_selected_step_range_first(last_step);
+                }
+
+                let model2 = GlobalEngine::get(&handle).get_sequencer_pattern_instruments();
+                let len = instruments_len.min(model2.row_count());
+                GlobalEngine::get(&handle).set_sequencer_pattern_instruments_len(len as i32);
+
+                instruments
+                    .iter()
+                    .cycle()
+                    .skip(instruments_to_skip)
+                    .take(len)
+                    .enumerate()
+                    .for_each(|(idx, mi)| {
+                        let mut instrument_data = model2.row_data(idx).unwrap();
+
+                        let notes_model = &instrument_data.notes;
+                        // Only the beginning of longer patterns fits in the overview.
+                        for idx in 0..notes_model.row_count() {
+                            let note = mi.steps().get(idx).and_then(|s| s.press_note());
+                            notes_model.set_row_data(idx, note.map_or(-1, |n| n as i32));
+                        }
+
+                        instrument_data.id = mi.id().into();
+                        instrument_data.synth_index = mi.synth_index().map_or(-1, |i| i as i32);
+                        model2.set_row_data(idx, instrument_data);
+                    });
+            })
+            .unwrap();
+    }
+
+    fn step_changed(
+        &self,
+        step: usize,
+        set_press_note: Option<Option<u8>>,
+        set_release_pos: Option<ReleasePos>,
+        set_params: Option<[Option<i8>; NUM_INSTRUMENT_PARAMS]>,
+    ) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let steps = GlobalEngine::get(&handle).get_sequencer_steps();
+                let mut step_row_data = steps.row_data(step).unwrap();
+                if let Some(maybe_note) = set_press_note {
+                    step_row_data.press = maybe_note.is_some();
+                    step_row_data.note = maybe_note.unwrap_or(0) as i32;
+                }
+                if let Some(release_pos) = set_release_pos {
+                    step_row_data.release_pos = to_ui_release_pos(release_pos);
+                }
+                if let Some(params) = set_params {
+                    set_step_data_params(&mut step_row_data, params);
+                }
+                steps.set_row_data(step, step_row_data);
+            })
+            .unwrap();
+    }
+
+    fn step_delay_changed(&self, step: usize, delay: u8) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let steps = GlobalEngine::get(&handle).get_sequencer_steps();
+                let mut step_row_data = steps.row_data(step).unwrap();
+                step_row_data.delay = delay as i32;
+                steps.set_row_data(step, step_row_data);
+            })
+            .unwrap();
+    }
+
+    fn playing_changed(&self, playing: bool) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                GlobalUI::get(&handle).set_playing(playing);
+            })
+            .unwrap();
+    }
+
+    fn recording_changed(&self, recording: bool) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                GlobalUI::get(&handle).set_recording(recording);
+            })
+            .unwrap();
+    }
+
+    fn instrument_param_changed(&self, instrument: u8, param_num: u8, value: i8) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let instruments_model = GlobalEngine::get(&handle).get_instruments();
+                let mut row_data = instruments_model.row_data(instrument as usize).unwrap();
+                match param_num {
+                    0 => row_data.param0 = value as i32,
+                    1 => row_data.param1 = value as i32,
+                    2 => row_data.param2 = value as i32,
+                    _ => row_data.param3 = value as i32,
+                }
+                instruments_model.set_row_data(instrument as usize, row_data);
+            })
+            .unwrap();
+    }
+
+    fn song_pattern_changed(&self, song_pattern: usize, pattern: usize) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+
+                let mut row_data = model.row_data(song_pattern).unwrap();
+                row_data.number = pattern as i32;
+                model.set_row_data(song_pattern, row_data);
+            })
+            .unwrap();
+    }
+
+    fn song_pattern_committed(&self, song_pattern: usize, pattern: usize) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+                let vec_model = model.as_any().downcast_ref::<VecModel<SongPatternData>>().unwrap();
+
+                // Show the real pattern number for the previous stub
+                let mut row_data = vec_model.row_data(song_pattern).unwrap();
+                row_data.number = pattern as i32;
+                vec_model.set_row_data(song_pattern, row_data);
+
+                // Append a new UI-only stub
+                vec_model.push(SongPatternData {
+                    number: -1,
+                    transpose: 0,
+                    selected: false,
+                });
+            })
+            .unwrap();
+    }
+
+    fn song_pattern_transpose_changed(&self, song_pattern: usize, transpose: i8) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+
+                let mut row_data = model.row_data(song_pattern).unwrap();
+                row_data.transpose = transpose as i32;
+                model.set_row_data(song_pattern, row_data);
+            })
+            .unwrap();
+    }
+
+    fn bank_song_selected(&self, bank_song: usize, num_bank_songs: usize) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let global_engine = GlobalEngine::get(&handle);
+                global_engine.set_bank_song(bank_song as i32);
+                global_engine.set_num_bank_songs(num_bank_songs as i32);
+            })
+            .unwrap();
+    }
+
+    fn last_song_pattern_removed(&self) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+                let vec_model = model.as_any().downcast_ref::<VecModel<SongPatternData>>().unwrap();
+                // The last index is the UI-only stub, so remove the one before.
+                vec_model.remove(vec_model.row_count() - 2);
+            })
+            .unwrap();
+    }
+
+    fn song_changed(&self, song_patterns: &[(usize, i8)], settings: &SongSettings) {
+        let settings = crate::ui::SongSettings::from(settings);
+        let mut vec = Vec::new();
+        for (number, transpose) in song_patterns.iter() {
+            vec.push(SongPatternData {
+                number: *number as i32,
+                transpose: *transpose as i32,
+                selected: false,
+            });
+        }
+        // Append a UI-only stub
+        vec.push(SongPatternData {
+            number: -1,
+            transpose: 0,
+            selected: false,
+        });
+
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = GlobalEngine::get(&handle).get_sequencer_song_patterns();
+                let vec_model = model.as_any().downcast_ref::<VecModel<SongPatternData>>().unwrap();
+                vec_model.set_vec(vec);
+
+                GlobalSettings::get(&handle).set_song_settings(settings);
+            })
+            .unwrap();
+    }
+
+    fn instruments_defined(&self, ids: &[String], default_params: &[[Option<i8>; NUM_INSTRUMENT_PARAMS]]) {
+        let ui_copy: Vec<SharedString> = ids.iter().map(|id| id.as_str().into()).collect();
+        let default_param_values = default_params
+            .iter()
+            .map(|p| p.map(|p| p.map_or(i32::MIN, |p| p as i32)))
+            .collect::<Vec<_>>();
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let engine = &GlobalEngine::get(&handle);
+                let instruments_model = engine.get_instruments();
+                for (i, id) in ui_copy.iter().enumerate() {
+                    let mut row_data = instruments_model.row_data(i).unwrap();
+                    row_data.id = id.clone();
+                    instruments_model.set_row_data(i, row_data);
+                }
+
+                let model = engine.get_script_instrument_ids();
+                let vec_model = model.as_any().downcast_ref::<VecModel<SharedString>>().unwrap();
+                vec_model.set_vec(ui_copy);
+
+                for (i, [p0, p1, p2, p3]) in default_param_values.iter().enumerate() {
+                    let mut row_data = instruments_model.row_data(i).unwrap();
+                    row_data.param0 = *p0;
+                    row_data.param1 = *p1;
+                    row_data.param2 = *p2;
+                    row_data.param3 = *p3;
+                    instruments_model.set_row_data(i, row_data);
+                }
+            })
+            .unwrap();
+    }
+
+    fn instrument_note_event(
+        &self,
+        instrument: u8,
+        pressed: bool,
+        _pressed_note: Option<u8>,
+        _released_note: Option<u8>,
+    ) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                #[cfg(feature = "desktop")]
+                {
+                    let notes_model = handle.get_notes();
+                    for row in 0..notes_model.row_count() {
+                        let mut row_data = notes_model.row_data(row).unwrap();
+                        // A note release might not happen if a press happened in-between.
+                        if _released_note.map_or(false, |n| n == row_data.note_number as u8) {
+                            row_data.active = false;
+                            notes_model.set_row_data(row, row_data.clone());
+                        }
+
+                        if _pressed_note.map_or(false, |n| n == row_data.note_number as u8) {
+                            row_data.active = true;
+                            notes_model.set_row_data(row, row_data);
+                        }
+                    }
+                }
+                let instruments_model = GlobalEngine::get(&handle).get_instruments();
+                let mut row_data = instruments_model.row_data(instrument as usize).unwrap();
+                row_data.active = pressed;
+                instruments_model.set_row_data(instrument as usize, row_data);
+            })
+            .unwrap();
+    }
+
+    #[cfg(feature = "desktop")]
+    fn note_pressed(&self, note: u8) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = handle.get_notes();
+                for row in 0..model.row_count() {
+                    let mut row_data = model.row_data(row).unwrap();
+                    if row_data.note_number == note as i32 {
+                        row_data.active = true;
+                        model.set_row_data(row, row_data);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    #[cfg(feature = "desktop")]
+    fn note_released(&self, note: u8) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = handle.get_notes();
+                for row in 0..model.row_count() {
+                    let mut row_data = model.row_data(row).unwrap();
+                    if note == row_data.note_number as u8 {
+                        row_data.active = false;
+                        model.set_row_data(row, row_data);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    #[cfg(feature = "desktop")]
+    fn all_notes_released(&self) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let model = handle.get_notes();
+                for row in 0..model.row_count() {
+                    let mut row_data = model.row_data(row).unwrap();
+                    row_data.active = false;
+                    model.set_row_data(row, row_data);
+                }
+            })
+            .unwrap();
+    }
+
+    fn instruments_muted(&self) {
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                #[cfg(feature = "desktop")]
+                {
+                    let notes_model = handle.get_notes();
+                    for (i, mut row_data) in notes_model.iter().enumerate() {
+                        row_data.active = false;
+                        notes_model.set_row_data(i, row_data.clone());
+                    }
+                }
+                let instruments_model = GlobalEngine::get(&handle).get_instruments();
+                for (i, mut row_data) in instruments_model.iter().enumerate() {
+                    row_data.active = false;
+                    instruments_model.set_row_data(i, row_data.clone());
+                }
+            })
+            .unwrap();
+    }
+
+    #[cfg(feature = "desktop")]
+    fn synth_channels_changed(&self, frame_number: i32, states: &[(Option<f64>, u8)]) {
+        let states = states.to_vec();
+        self.main_window
+            .upgrade_in_event_loop(move |handle| {
+                let global = GlobalEngine::get(&handle);
+                global.set_last_synth_tick(frame_number);
+
+                let trace_model = global.get_synth_trace_notes();
+                let trace_vec_model = trace_model
+                    .as_any()
+                    .downcast_ref::<VecModel<ChannelTraceNote>>()
+                    .unwrap();
+
+                let active_model = global.get_synth_active_notes();
+                let active_vec_model = active_model
+                    .as_any()
+                    .downcast_ref::<VecModel<ChannelActiveNote>>()
+                    .unwrap();
+
+                // FIXME: Provide the oldest tick number to the UI or get it from it
+                let visible_ticks = 6 * 16 * 2;
+
+                // First remove traces that would disappear after being shrunk as this affects indices.
+                while trace_vec_model.row_count() > 0 {
+                    let trace = trace_vec_model.row_data(0).unwrap();
+                    if frame_number - (trace.start_tick + trace.num_ticks) >= visible_ticks {
+                        trace_vec_model.remove(0);
+                    } else {
+                        break;
+                    }
+                }
+
+                let mut last_chan_trace_index: [Option<usize>; 4] = [None; 4];
+                for i in 0..trace_vec_model.row_count() {
+                    let mut trace = trace_vec_model.row_data(i).unwrap();
+
+                    // Remember whether this trace is a candidate to be merged with current channel traces.
+                    if trace.start_tick + trace.num_ticks == frame_number {
+                        last_chan_trace_index[trace.channel as usize] = Some(i);
+                    }
+
+                    // Shrink old traces that span past the trace history range.
+                    if frame_number - trace.start_tick >= visible_ticks {
+                        let diff = frame_number - trace.start_tick - visible_ticks;
+                        trace.start_tick += diff;
+                        trace.num_ticks -= diff;
+                        trace_vec_model.set_row_data(i, trace);
+                    }
+                }
+
+                // FIXME: Keep notes that are still active instead of re-adding?
+                active_vec_model.set_vec(Vec::new());
+
+                for (channel, &(freq, vol)) in states.iter().enumerate() {
+                    if vol > 0 {
+                        let (trace, active) = if let Some((note, cent_adj)) = freq.map(MidiNote::from_freq) {
+                            let semitone = note.semitone();
+                            // Stretch the between-notes range for white notes not followed by a black note.
+                            let cent_factor = if (semitone == 4 || semitone == 11) && cent_adj > 0.0
+                                || (semitone == 5 || semitone == 0) && cent_adj < 0.0
+                            {
+                                1.0
+                            } else {
+                                0.5
+                            };
+
+                            let trace = ChannelTraceNote {
+                                channel: channel as i32,
+                                start_tick: frame_number,
+                                num_ticks: 1,
+                                octave: note.octave(),
+                                key_pos: note.key_pos(),
+                                cent_adj: cent_adj * cent_factor,
+                                is_black: note.is_black(),
+                                volume: vol as f32 / 15.0,
+                            };
+                            let active = ChannelActiveNote {
+                                trace: trace.clone(),
+                                note_name: note.name().into(),
+                            };
+                            (trace, active)
+                        } else {
+                            let trace = ChannelTraceNote {
+                                channel: channel as i32,
+                                start_tick: frame_number,
+                                num_ticks: 1,
+                                octave: 0,
+                                key_pos: 0,
+                                cent_adj: 0.0,
+                                is_black: false,
+                                volume: vol as f32 / 15.0,
+                            };
+                            let active = ChannelActiveNote {
+                                trace: trace.clone(),
+                                note_name: "*".into(),
+                            };
+                            (trace, active)
+                        };
+
+                        let maybe_last_index = last_chan_trace_index[trace.channel as usize];
+                        maybe_last_index
+                            .and_then(|last_index| {
+                                let mut last = trace_vec_model.row_data(last_index).unwrap();
+                                if last.channel == trace.channel
+                                    && last.octave == trace.octave
+                                    && last.key_pos == trace.key_pos
+                                    && last.cent_adj == trace.cent_adj
+                                    && last.is_black == trace.is_black
+                                    && last.volume == trace.volume
+                                {
+                                    last.num_ticks += trace.num_ticks;
+                                    trace_vec_model.set_row_data(last_index, last);
+                                    Some(())
+                                } else {
+                                    None
+                                }
+                            })
+                            .unwrap_or_else(|| trace_vec_model.push(trace));
+
+                        active_vec_model.push(active);
+                    }
+                }
+            })
+            .unwrap();
+    }
+}